@@ -0,0 +1,201 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use serde_json::json;
+
+use crate::source::{self, Value};
+
+/// An append-only log of registry mutations attempted through the projection.
+/// Each entry is written as a single line of JSON.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Modify,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Rename,
+    Delete,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The provider vetoed the operation.
+    Denied,
+    /// The operation happened inside the virtualization root, but was not
+    /// written back to the registry.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    NotApplied,
+    /// The operation was recorded into a patch instead of being applied.
+    Recorded,
+}
+
+pub struct Mutation<'a> {
+    pub operation: Operation,
+    pub path: &'a str,
+    pub dest_path: Option<&'a str>,
+    pub is_key: bool,
+    pub old_value: Option<&'a Value>,
+    pub new_value: Option<&'a Value>,
+    pub outcome: Outcome,
+    pub process_id: u32,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> std::io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, mutation: &Mutation) {
+        let entry = json!({
+            "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            "operation": mutation.operation.as_str(),
+            "path": mutation.path,
+            "dest_path": mutation.dest_path,
+            "kind": if mutation.is_key { "key" } else { "value" },
+            "old_type": mutation.old_value.map(|v| source::type_name(v.vtype)),
+            "old_data": mutation.old_value.map(|v| hex(&v.bytes)),
+            "new_type": mutation.new_value.map(|v| source::type_name(v.vtype)),
+            "new_data": mutation.new_value.map(|v| hex(&v.bytes)),
+            "outcome": mutation.outcome.as_str(),
+            "pid": mutation.process_id,
+        });
+
+        let mut line = entry.to_string();
+        line.push('\n');
+        // Write the whole line at once, so that concurrent callbacks can
+        // never interleave their entries.
+        if let Err(err) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("Failed to write audit log entry: {}", err);
+        }
+    }
+}
+
+impl Operation {
+    fn as_str(self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Modify => "modify",
+            Operation::Rename => "rename",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Denied => "denied",
+            Outcome::NotApplied => "not_applied",
+            Outcome::Recorded => "recorded",
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut s, b| {
+            write!(s, "{:02x}", b).unwrap();
+            s
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value as Json;
+
+    use super::*;
+    use crate::source::{REG_DWORD, REG_SZ};
+
+    #[test]
+    fn json_lines() {
+        let path = std::env::temp_dir().join(format!("regfs-audit-{}.jsonl", uuid::Uuid::new_v4()));
+        let log = AuditLog::open(&path).unwrap();
+        let old = Value {
+            vtype: REG_DWORD,
+            bytes: vec![1, 0, 0, 0],
+        };
+        let new = Value {
+            vtype: REG_SZ,
+            bytes: vec![b'A', 0, 0, 0],
+        };
+        let mutation = |operation, outcome| Mutation {
+            operation,
+            path: "HKEY_TEST\\Keys\\Value",
+            dest_path: None,
+            is_key: false,
+            old_value: Some(&old),
+            new_value: Some(&new),
+            outcome,
+            process_id: 42,
+        };
+        log.record(&mutation(Operation::Modify, Outcome::NotApplied));
+        log.record(&Mutation {
+            path: "HKEY_TEST\\Keys",
+            is_key: true,
+            old_value: None,
+            new_value: None,
+            ..mutation(Operation::Delete, Outcome::Denied)
+        });
+        log.record(&Mutation {
+            dest_path: Some("HKEY_TEST\\Keys\\Renamed"),
+            ..mutation(Operation::Rename, Outcome::Recorded)
+        });
+        drop(log);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries: Vec<Json> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 3);
+        for entry in &entries {
+            assert!(humantime::parse_rfc3339(entry["timestamp"].as_str().unwrap()).is_ok());
+            assert_eq!(entry["pid"], 42);
+        }
+
+        let allowed = &entries[0];
+        assert_eq!(allowed["operation"], "modify");
+        assert_eq!(allowed["outcome"], "not_applied");
+        assert_eq!(allowed["kind"], "value");
+        assert_eq!(allowed["path"], "HKEY_TEST\\Keys\\Value");
+        assert_eq!(allowed["dest_path"], Json::Null);
+        assert_eq!(allowed["old_type"], "REG_DWORD");
+        assert_eq!(allowed["old_data"], "01000000");
+        assert_eq!(allowed["new_type"], "REG_SZ");
+        assert_eq!(allowed["new_data"], "41000000");
+
+        let denied = &entries[1];
+        assert_eq!(denied["operation"], "delete");
+        assert_eq!(denied["outcome"], "denied");
+        assert_eq!(denied["kind"], "key");
+        assert_eq!(denied["old_type"], Json::Null);
+        assert_eq!(denied["new_data"], Json::Null);
+
+        let recorded = &entries[2];
+        assert_eq!(recorded["operation"], "rename");
+        assert_eq!(recorded["outcome"], "recorded");
+        assert_eq!(recorded["dest_path"], "HKEY_TEST\\Keys\\Renamed");
+    }
+
+    #[test]
+    fn open_fails_in_missing_directory() {
+        let path = std::env::temp_dir()
+            .join(format!("regfs-missing-{}", uuid::Uuid::new_v4()))
+            .join("audit.jsonl");
+        assert!(AuditLog::open(&path).is_err());
+    }
+}
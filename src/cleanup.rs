@@ -0,0 +1,451 @@
+//! Removing the copies of registry data a virtualization root keeps after a
+//! session.
+//!
+//! Placeholders, hydrated or not, are removed, since ProjFS can always bring
+//! them back from the source. Full files and directories hold changes made
+//! locally, and so do tombstones, which mark entries deleted locally; they are
+//! kept unless the whole root is removed.
+//!
+//! Placeholders are deleted by the provider rather than through the file
+//! system, which would leave a tombstone in place of each, hiding the entry
+//! from later sessions. Directories no session has listed cannot even be
+//! read without a provider running, so a projection serving nothing runs
+//! while the root is cleaned. When the whole root goes, everything in it is
+//! deleted that way too, and only the instance ID file and the emptied root
+//! are removed once the projection has stopped.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    virt_root::{self, RootRecords, INSTANCE_ID_FILE},
+    watch::Invalidate,
+};
+
+/// What ProjFS keeps on disk for a file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    /// Only the name and attributes, without data.
+    Placeholder,
+    /// A placeholder with a copy of the data.
+    HydratedPlaceholder,
+    /// A placeholder whose attributes were changed locally.
+    DirtyPlaceholder,
+    /// Created or modified locally, and no longer backed by the source.
+    Full,
+    /// Marks a deleted entry.
+    Tombstone,
+}
+
+impl FileState {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileState::Placeholder => "placeholder",
+            FileState::HydratedPlaceholder => "hydrated",
+            FileState::DirtyPlaceholder => "dirty",
+            FileState::Full => "full",
+            FileState::Tombstone => "tombstone",
+        }
+    }
+
+    /// Checks whether the entry only holds what the source can provide again.
+    fn is_projected(self) -> bool {
+        matches!(
+            self,
+            FileState::Placeholder | FileState::HydratedPlaceholder | FileState::DirtyPlaceholder
+        )
+    }
+}
+
+/// Tells the state of files in a virtualization root. Stubbed out in tests,
+/// since only ProjFS knows.
+pub trait FileStates {
+    fn file_state(&self, path: &Path) -> anyhow::Result<FileState>;
+}
+
+/// Runs a projection serving nothing from a virtualization root while `f`
+/// runs, giving it the means to delete placeholders. Stubbed out in tests,
+/// since only ProjFS can do this.
+pub trait RunEmpty {
+    fn run_empty(
+        &self,
+        root: &Path,
+        f: &mut dyn FnMut(&dyn Invalidate) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct CleanupReport {
+    /// The entries removed, relative to the root, children first.
+    pub removed: Vec<(PathBuf, FileState)>,
+    /// The entries kept since they hold local changes.
+    pub kept: Vec<(PathBuf, FileState)>,
+    /// The size of the files removed.
+    pub bytes: u64,
+    /// Whether the root itself is gone.
+    pub root_removed: bool,
+}
+
+impl CleanupReport {
+    pub fn print(&self, out: &mut dyn Write) -> anyhow::Result<()> {
+        for (path, state) in &self.removed {
+            writeln!(out, "removed\t{}\t{}", state.name(), path.display())?;
+        }
+        for (path, state) in &self.kept {
+            writeln!(out, "kept\t{}\t{}", state.name(), path.display())?;
+        }
+        writeln!(
+            out,
+            "Removed {} entries ({} bytes){}; kept {} with local changes.",
+            self.removed.len(),
+            self.bytes,
+            if self.root_removed {
+                " and the root"
+            } else {
+                ""
+            },
+            self.kept.len(),
+        )?;
+        Ok(())
+    }
+}
+
+/// Removes the placeholders in a virtualization root, or, with `remove_root`,
+/// everything including the root and its record. The projection must not be
+/// running.
+pub fn cleanup(
+    root: &Path,
+    remove_root: bool,
+    states: &dyn FileStates,
+    projection: &dyn RunEmpty,
+    records: &RootRecords,
+) -> anyhow::Result<CleanupReport> {
+    if virt_root::read_instance_id(root)
+        .with_context(|| format!("check {}", root.display()))?
+        .is_none()
+    {
+        bail!(
+            "{} is not a virtualization root; nothing is removed",
+            root.display()
+        );
+    }
+
+    let mut report = CleanupReport::default();
+    projection.run_empty(root, &mut |placeholders| {
+        let mut cleaner = Cleaner {
+            root,
+            remove_all: remove_root,
+            states,
+            placeholders,
+            report: &mut report,
+        };
+        cleaner.clean_dir(root).map(drop)
+    })?;
+    if remove_root {
+        std::fs::remove_file(root.join(INSTANCE_ID_FILE))
+            .with_context(|| format!("remove instance ID file of {}", root.display()))?;
+        report
+            .removed
+            .push((PathBuf::from(INSTANCE_ID_FILE), FileState::Full));
+        std::fs::remove_dir(root)
+            .with_context(|| format!("remove virtualization root {}", root.display()))?;
+        report.root_removed = true;
+        // Only forgotten once it is gone, so that a root left behind is
+        // still recognized.
+        records.set(root, None)?;
+    }
+    Ok(report)
+}
+
+struct Cleaner<'a> {
+    root: &'a Path,
+    remove_all: bool,
+    states: &'a dyn FileStates,
+    placeholders: &'a dyn Invalidate,
+    report: &'a mut CleanupReport,
+}
+
+impl Cleaner<'_> {
+    /// Cleans the contents of a directory, returning whether anything was
+    /// kept.
+    fn clean_dir(&mut self, dir: &Path) -> anyhow::Result<bool> {
+        let mut kept_any = false;
+        let entries = std::fs::read_dir(dir).with_context(|| format!("read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("read {}", dir.display()))?;
+            let path = entry.path();
+            if dir == self.root && entry.file_name() == INSTANCE_ID_FILE {
+                kept_any = true;
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            let state = self
+                .states
+                .file_state(&path)
+                .with_context(|| format!("get state of {}", path.display()))?;
+
+            let relative = path.strip_prefix(self.root).unwrap_or(&path).to_owned();
+            // A tombstone is a local deletion, so it stays along with
+            // anything under it.
+            let kept_children =
+                file_type.is_dir() && state != FileState::Tombstone && self.clean_dir(&path)?;
+            let remove = !kept_children && (self.remove_all || state.is_projected());
+            if !remove {
+                // A directory kept for its contents is not worth reporting.
+                if !kept_children {
+                    self.report.kept.push((relative, state));
+                }
+                kept_any = true;
+                continue;
+            }
+
+            if !file_type.is_dir() {
+                self.report.bytes += entry.metadata().map_or(0, |m| m.len());
+            }
+            let name = relative
+                .to_str()
+                .with_context(|| format!("{} is not valid Unicode", path.display()))?;
+            let deleted = if self.remove_all {
+                self.placeholders.delete_changed(name)
+            } else {
+                self.placeholders.delete(name)
+            };
+            deleted.with_context(|| format!("remove {}", path.display()))?;
+            self.report.removed.push((relative, state));
+        }
+        Ok(kept_any)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashMap};
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        vfs::{EntryInfo, VersionInfo},
+        virt_root::RootRecord,
+    };
+
+    /// Knows the state of some files by name; everything else is hydrated.
+    struct StubStates(HashMap<&'static str, FileState>);
+
+    impl FileStates for StubStates {
+        fn file_state(&self, path: &Path) -> anyhow::Result<FileState> {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            Ok(self
+                .0
+                .get(name)
+                .copied()
+                .unwrap_or(FileState::HydratedPlaceholder))
+        }
+    }
+
+    /// Deletes placeholders as ProjFS would, recording their names, and
+    /// failing for any named `fail`.
+    #[derive(Default)]
+    struct StubProjection {
+        deleted: RefCell<Vec<String>>,
+        fail: Option<&'static str>,
+    }
+
+    struct StubPlaceholders<'a> {
+        root: &'a Path,
+        deleted: &'a RefCell<Vec<String>>,
+        fail: Option<&'static str>,
+    }
+
+    impl RunEmpty for StubProjection {
+        fn run_empty(
+            &self,
+            root: &Path,
+            f: &mut dyn FnMut(&dyn Invalidate) -> anyhow::Result<()>,
+        ) -> anyhow::Result<()> {
+            f(&StubPlaceholders {
+                root,
+                deleted: &self.deleted,
+                fail: self.fail,
+            })
+        }
+    }
+
+    impl Invalidate for StubPlaceholders<'_> {
+        fn update(&self, _: &str, _: &EntryInfo, _: &VersionInfo) -> anyhow::Result<()> {
+            unreachable!()
+        }
+
+        fn delete(&self, path: &str) -> anyhow::Result<()> {
+            if self.fail.is_some_and(|fail| path.ends_with(fail)) {
+                bail!("stub failure");
+            }
+            let path_on_disk = self.root.join(path);
+            if path_on_disk.is_dir() {
+                std::fs::remove_dir(path_on_disk)?;
+            } else {
+                std::fs::remove_file(path_on_disk)?;
+            }
+            self.deleted.borrow_mut().push(path.replace('\\', "/"));
+            Ok(())
+        }
+    }
+
+    fn root() -> (PathBuf, RootRecords) {
+        let dir = std::env::temp_dir().join(format!("regfs-cleanup-{}", Uuid::new_v4()));
+        let root = dir.join("root");
+        for key in ["HKEY_TEST\\Software", "HKEY_TEST\\Local\\Changes", "Empty"] {
+            std::fs::create_dir_all(root.join(key.replace('\\', "/"))).unwrap();
+        }
+        std::fs::write(root.join(INSTANCE_ID_FILE), Uuid::new_v4().as_bytes()).unwrap();
+        std::fs::write(root.join("HKEY_TEST/Software/Secret"), b"hunter2").unwrap();
+        std::fs::write(root.join("HKEY_TEST/Software/Name"), b"").unwrap();
+        std::fs::write(root.join("HKEY_TEST/Local/Changes/Edited"), b"new").unwrap();
+        let records = RootRecords::with_path(dir.join("roots.json"));
+        (root, records)
+    }
+
+    fn states() -> StubStates {
+        StubStates(HashMap::from([
+            ("Name", FileState::Placeholder),
+            ("Edited", FileState::Full),
+            ("Empty", FileState::Placeholder),
+        ]))
+    }
+
+    fn names(entries: &[(PathBuf, FileState)]) -> Vec<String> {
+        let mut names: Vec<_> = entries
+            .iter()
+            .map(|(path, _)| path.to_string_lossy().replace('\\', "/"))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn placeholders() {
+        let (root, records) = root();
+        let projection = StubProjection::default();
+        let report = cleanup(&root, false, &states(), &projection, &records).unwrap();
+        let mut deleted = projection.deleted.take();
+        deleted.sort();
+        assert_eq!(deleted, names(&report.removed));
+        assert_eq!(
+            names(&report.removed),
+            [
+                "Empty",
+                "HKEY_TEST/Software",
+                "HKEY_TEST/Software/Name",
+                "HKEY_TEST/Software/Secret",
+            ],
+        );
+        assert_eq!(names(&report.kept), ["HKEY_TEST/Local/Changes/Edited"]);
+        assert_eq!(report.bytes, 7);
+        assert!(root.join(INSTANCE_ID_FILE).exists());
+        assert!(root.join("HKEY_TEST/Local/Changes/Edited").exists());
+        assert!(!root.join("HKEY_TEST/Software").exists());
+
+        let mut out = Vec::new();
+        report.print(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("removed\thydrated\t"), "{}", out);
+        assert!(out.ends_with("Removed 4 entries (7 bytes); kept 1 with local changes.\n"));
+
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn whole_root() {
+        let (root, records) = root();
+        let record = RootRecord {
+            instance_id: Uuid::new_v4().to_string(),
+            source: String::from("live"),
+        };
+        records.set(&root, Some(record)).unwrap();
+
+        // Everything is deleted by the provider, even local changes, before
+        // the root and its record go.
+        let projection = StubProjection::default();
+        let report = cleanup(&root, true, &states(), &projection, &records).unwrap();
+        assert!(report.root_removed);
+        assert!(report.kept.is_empty());
+        assert_eq!(report.removed.len(), 9);
+        assert!(!root.exists());
+        assert_eq!(records.get(&root).unwrap(), None);
+        let mut deleted = projection.deleted.take();
+        deleted.push(String::from(INSTANCE_ID_FILE));
+        deleted.sort();
+        assert_eq!(deleted, names(&report.removed));
+
+        // Anything else is left alone.
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(root.join("file"), b"").unwrap();
+        assert!(cleanup(&root, true, &states(), &projection, &records).is_err());
+        assert!(root.join("file").exists());
+
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn whole_root_kept() {
+        let (root, records) = root();
+        let record = RootRecord {
+            instance_id: Uuid::new_v4().to_string(),
+            source: String::from("live"),
+        };
+        records.set(&root, Some(record.clone())).unwrap();
+
+        // A root which cannot be emptied is kept, and so is its record.
+        let projection = StubProjection {
+            fail: Some("Edited"),
+            ..StubProjection::default()
+        };
+        assert!(cleanup(&root, true, &states(), &projection, &records).is_err());
+        assert!(root.join(INSTANCE_ID_FILE).exists());
+        assert!(root.join("HKEY_TEST/Local/Changes/Edited").exists());
+        assert_eq!(records.get(&root).unwrap(), Some(record));
+
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tombstones() {
+        let (root, records) = root();
+        std::fs::write(root.join("HKEY_TEST/Software/Deleted"), b"").unwrap();
+        std::fs::create_dir(root.join("HKEY_TEST/Gone")).unwrap();
+        let mut states = states();
+        states.0.insert("Deleted", FileState::Tombstone);
+        states.0.insert("Gone", FileState::Tombstone);
+        let projection = StubProjection::default();
+        let report = cleanup(&root, false, &states, &projection, &records).unwrap();
+
+        // Only placeholders are deleted, and only by the provider, so that
+        // no new tombstones hide them.
+        assert_eq!(
+            names(&report.kept),
+            [
+                "HKEY_TEST/Gone",
+                "HKEY_TEST/Local/Changes/Edited",
+                "HKEY_TEST/Software/Deleted",
+            ],
+        );
+        let mut deleted = projection.deleted.take();
+        deleted.sort();
+        assert_eq!(
+            deleted,
+            [
+                "Empty",
+                "HKEY_TEST/Software/Name",
+                "HKEY_TEST/Software/Secret"
+            ],
+        );
+        assert!(root.join("HKEY_TEST/Software/Deleted").exists());
+        assert!(root.join("HKEY_TEST/Gone").exists());
+
+        std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,383 @@
+//! Commands which query a registry source directly, without mounting it.
+//! Output is written to the given writer, so that it can be piped into other
+//! tools.
+
+use std::io::Write;
+
+use anyhow::{bail, Context};
+
+use crate::{
+    file_name,
+    index::{self, LazyIndex},
+    reg_file, reg_name,
+    source::{self, KeyEntries, RegSource, ValueEntry},
+};
+
+/// How `cat` prints a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatFormat {
+    /// The data as stored, byte for byte
+    Raw,
+    /// The data interpreted according to the type
+    Decoded,
+    /// The type name only
+    Type,
+}
+
+/// Converts a path given on the command line to a source path. Only `\`
+/// separates keys, since `/` may be part of a name.
+pub fn source_path(path: &str) -> String {
+    path.split('\\')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+/// Lists a key, with subkeys first and then values, each sorted by name.
+fn list(source: &dyn RegSource, path: &str) -> anyhow::Result<KeyEntries> {
+    let mut entries = source
+        .enum_key(path)
+        .context("enumerate key")?
+        .with_context(|| format!("key {:?} not found", path))?;
+    entries.sort_by(|(a, a_len), (b, b_len)| {
+        a_len
+            .is_some()
+            .cmp(&b_len.is_some())
+            .then_with(|| reg_name::cmp(a, b))
+    });
+    Ok(entries)
+}
+
+/// Lists a key as `list` does, with the type and size of each value, which
+/// are looked up without reading the data.
+fn list_values(
+    source: &dyn RegSource,
+    path: &str,
+) -> anyhow::Result<Vec<(String, Option<ValueEntry>)>> {
+    let subkeys = list(source, path)?
+        .into_iter()
+        .filter(|(_, len)| len.is_none())
+        .map(|(name, _)| name);
+    let mut values = source
+        .enum_values(path)
+        .context("enumerate values")?
+        .with_context(|| format!("key {:?} not found", path))?;
+    values.sort_by(|a, b| reg_name::cmp(&a.name, &b.name));
+    Ok(subkeys
+        .map(|name| (name, None))
+        .chain(
+            values
+                .into_iter()
+                .map(|value| (value.name.clone(), Some(value))),
+        )
+        .collect())
+}
+
+/// Prints the subkeys and values of a key, one per line. Subkeys end with a
+/// backslash. In the long format, each line also has the type and size of
+/// the value, separated by tabs.
+pub fn ls(
+    source: &dyn RegSource,
+    path: &str,
+    long: bool,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    if !long {
+        for (name, len) in list(source, path)? {
+            writeln!(out, "{}{}", name, if len.is_none() { "\\" } else { "" })?;
+        }
+        return Ok(());
+    }
+    for (name, value) in list_values(source, path)? {
+        match value {
+            None => writeln!(out, "KEY\t-\t{}\\", name)?,
+            Some(value) => {
+                let vtype = source::type_name(value.vtype);
+                writeln!(out, "{}\t{}\t{}", vtype, value.size, name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints a value in the given format.
+pub fn cat(
+    source: &dyn RegSource,
+    path: &str,
+    format: CatFormat,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let value = match source.read_value(path).context("read value")? {
+        Some(value) => value,
+        None => bail!("value {:?} not found", path),
+    };
+    match format {
+        CatFormat::Raw => out.write_all(&value.bytes)?,
+        CatFormat::Type => writeln!(out, "{}", source::type_name(value.vtype))?,
+        CatFormat::Decoded => out.write_all(value.decode().to_text().as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Prints a key and everything below it as a tree, with the type of each
+/// value.
+pub fn tree(source: &dyn RegSource, path: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+    fn print_children(
+        source: &dyn RegSource,
+        path: &str,
+        prefix: &str,
+        out: &mut dyn Write,
+    ) -> anyhow::Result<()> {
+        let entries = list_values(source, path)?;
+        let count = entries.len();
+        for (i, (name, value)) in entries.into_iter().enumerate() {
+            let last = i + 1 == count;
+            let branch = if last { "└── " } else { "├── " };
+            match value {
+                None => {
+                    writeln!(out, "{}{}{}", prefix, branch, name)?;
+                    let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                    print_children(source, &source::join_path(path, &name), &prefix, out)?;
+                }
+                Some(value) => {
+                    let vtype = source::type_name(value.vtype);
+                    writeln!(out, "{}{}{} ({})", prefix, branch, name, vtype)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    writeln!(out, "{}", if path.is_empty() { "\\" } else { path })?;
+    print_children(source, path, "", out)
+}
+
+/// Prints the paths of all keys and values below a key whose names match a
+/// wildcard pattern, as used in directory listings (e.g. `*Version*`).
+pub fn find(
+    source: &dyn RegSource,
+    pattern: &str,
+    path: &str,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    for (name, len) in list(source, path)? {
+        let child = source::join_path(path, &name);
+        if file_name::matches(&name, pattern) {
+            writeln!(out, "{}{}", child, if len.is_none() { "\\" } else { "" })?;
+        }
+        if len.is_none() {
+            find(source, pattern, &child, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Prints the paths of all keys and values whose names or decoded data
+/// contain a pattern, as listed in the `@search` directory.
+pub fn search(
+    source: &dyn RegSource,
+    index: Option<&LazyIndex>,
+    pattern: &str,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    for (path, len) in index::search(source, index, pattern)? {
+        writeln!(out, "{}{}", path, if len.is_none() { "\\" } else { "" })?;
+    }
+    Ok(())
+}
+
+/// Exports a key and everything below it as a `.reg` file.
+pub fn export(source: &dyn RegSource, path: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+    let text = reg_file::export(source, path)?;
+    out.write_all(&reg_file::encode(&format!("\u{feff}{}", text)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        source::{Data, Value, REG_BINARY, REG_DWORD, REG_MULTI_SZ, REG_SZ},
+    };
+
+    fn source() -> MemSource {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_TEST\\Software\\Vendor\\Product");
+        source.create_key("HKEY_TEST\\System");
+        let values = [
+            (
+                "HKEY_TEST\\Software",
+                "Version",
+                REG_SZ,
+                Data::String(String::from("1.0")),
+            ),
+            ("HKEY_TEST\\Software", "count", REG_DWORD, Data::Number(42)),
+            (
+                "HKEY_TEST\\Software\\Vendor",
+                "Paths",
+                REG_MULTI_SZ,
+                Data::MultiString(vec![String::from("a"), String::from("b")]),
+            ),
+            (
+                "HKEY_TEST\\Software\\Vendor\\Product",
+                "ProductVersion",
+                REG_BINARY,
+                Data::Binary(b"\x01\x02abc".to_vec()),
+            ),
+        ];
+        for (key, name, vtype, data) in values {
+            source.set_value(key, name, Value::encode(vtype, &data).unwrap());
+        }
+        source
+    }
+
+    /// Lists keys and value types, but fails to read any value.
+    struct NoReads(MemSource);
+
+    impl RegSource for NoReads {
+        fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+            self.0.enum_key(path)
+        }
+
+        fn enum_values(&self, path: &str) -> anyhow::Result<Option<Vec<ValueEntry>>> {
+            self.0.enum_values(path)
+        }
+
+        fn key_exists(&self, path: &str) -> anyhow::Result<bool> {
+            self.0.key_exists(path)
+        }
+
+        fn read_value(&self, _path: &str) -> anyhow::Result<Option<source::Value>> {
+            bail!("value data read")
+        }
+    }
+
+    fn run(f: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>) -> String {
+        let mut out = Vec::new();
+        f(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            source_path("\\HKEY_TEST\\Software\\"),
+            "HKEY_TEST\\Software"
+        );
+        assert_eq!(source_path("HKEY_TEST\\A/B"), "HKEY_TEST\\A/B");
+        assert_eq!(source_path("HKEY_TEST\\\\Software"), "HKEY_TEST\\Software");
+        assert_eq!(source_path(""), "");
+    }
+
+    #[test]
+    fn ls_and_cat() {
+        let no_reads = NoReads(source());
+        let source = source();
+        let path = "HKEY_TEST\\software";
+        assert_eq!(
+            run(|out| ls(&source, path, false, out)),
+            "Vendor\\\ncount\nVersion\n",
+        );
+        assert_eq!(
+            run(|out| ls(&no_reads, path, true, out)),
+            "KEY\t-\tVendor\\\nREG_DWORD\t4\tcount\nREG_SZ\t8\tVersion\n",
+        );
+        assert!(ls(&source, "HKEY_TEST\\Missing", false, &mut Vec::new()).is_err());
+
+        let cat = |path, format| run(|out| cat(&source, path, format, out));
+        assert_eq!(
+            cat("HKEY_TEST\\Software\\Version", CatFormat::Decoded),
+            "1.0\n"
+        );
+        assert_eq!(
+            cat("HKEY_TEST\\Software\\Version", CatFormat::Type),
+            "REG_SZ\n"
+        );
+        assert_eq!(
+            cat("HKEY_TEST\\Software\\Count", CatFormat::Decoded),
+            "42\n"
+        );
+        assert_eq!(
+            cat("HKEY_TEST\\Software\\Vendor\\Paths", CatFormat::Decoded),
+            "a\nb\n"
+        );
+        assert_eq!(
+            cat(
+                "HKEY_TEST\\Software\\Vendor\\Product\\ProductVersion",
+                CatFormat::Decoded
+            ),
+            format!("00000000  01 02 61 62 63{}  |..abc|\n", "   ".repeat(11)),
+        );
+        let mut out = Vec::new();
+        super::cat(
+            &source,
+            "HKEY_TEST\\Software\\Count",
+            CatFormat::Raw,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out, [42, 0, 0, 0]);
+    }
+
+    #[test]
+    fn tree_and_find() {
+        // Neither lists value types by reading the values.
+        let source = NoReads(source());
+        assert_eq!(
+            run(|out| tree(&source, "HKEY_TEST", out)),
+            "HKEY_TEST\n\
+             ├── Software\n\
+             │   ├── Vendor\n\
+             │   │   ├── Product\n\
+             │   │   │   └── ProductVersion (REG_BINARY)\n\
+             │   │   └── Paths (REG_MULTI_SZ)\n\
+             │   ├── count (REG_DWORD)\n\
+             │   └── Version (REG_SZ)\n\
+             └── System\n",
+        );
+        assert_eq!(
+            run(|out| find(&source, "*version", "", out)),
+            "HKEY_TEST\\Software\\Vendor\\Product\\ProductVersion\n\
+             HKEY_TEST\\Software\\Version\n",
+        );
+        assert_eq!(
+            run(|out| find(&source, "?y*", "HKEY_TEST", out)),
+            "HKEY_TEST\\System\\\n",
+        );
+    }
+
+    #[test]
+    fn export_reg_file() {
+        let source = source();
+        let mut out = Vec::new();
+        export(&source, "HKEY_TEST\\Software\\Vendor", &mut out).unwrap();
+        let wide: Vec<u16> = out
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        let text = String::from_utf16(&wide).unwrap();
+        assert_eq!(
+            text,
+            "\u{feff}Windows Registry Editor Version 5.00\r\n\
+             \r\n\
+             [HKEY_TEST\\Software\\Vendor]\r\n\
+             \"Paths\"=hex(7):61,00,00,00,62,00,00,00,00,00\r\n\
+             \r\n\
+             [HKEY_TEST\\Software\\Vendor\\Product]\r\n\
+             \"ProductVersion\"=hex:01,02,61,62,63\r\n",
+        );
+
+        // The export reads back as the same tree.
+        let parsed = reg_file::parse(text.trim_start_matches('\u{feff}')).unwrap();
+        assert_eq!(
+            run(|out| tree(&parsed, "HKEY_TEST", out)),
+            "HKEY_TEST\n\
+             └── Software\n\
+             \u{20}   └── Vendor\n\
+             \u{20}       ├── Product\n\
+             \u{20}       │   └── ProductVersion (REG_BINARY)\n\
+             \u{20}       └── Paths (REG_MULTI_SZ)\n",
+        );
+    }
+}
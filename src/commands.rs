@@ -0,0 +1,192 @@
+//! Requests which are answered off the callback thread, as ProjFS allows by
+//! returning `ERROR_IO_PENDING` and finishing them with `PrjCompleteCommand`.
+//!
+//! Each command gets a `CancelToken`, which is set when the client cancels
+//! it or the projection stops. Backends check it between slow steps; a
+//! cancelled command is not completed.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use anyhow::Context;
+
+use crate::vfs::{VfsError, VfsResult};
+
+/// Tells a command that its result is no longer wanted.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fails with `VfsError::Cancelled` once the command is cancelled.
+    pub fn check(&self) -> VfsResult<()> {
+        if self.is_cancelled() {
+            Err(VfsError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs commands on a fixed set of worker threads, and keeps the tokens of
+/// those which have not finished, by command ID.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Commands {
+    pending: Arc<Mutex<HashMap<i32, CancelToken>>>,
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Commands {
+    pub fn new(threads: usize) -> anyhow::Result<Commands> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("command-{}", i))
+                    .spawn(move || loop {
+                        let job = receiver.lock().unwrap().recv();
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                    .context("spawn command worker")
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Commands {
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+        })
+    }
+
+    /// Queues a command. `run` is given the command's token, and is still
+    /// run if the command is cancelled before it starts, so that it can
+    /// clean up.
+    pub fn spawn(
+        &self,
+        command_id: i32,
+        run: impl FnOnce(&CancelToken) + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let token = CancelToken::new();
+        let sender = self.sender.lock().unwrap();
+        let sender = sender.as_ref().context("commands are shut down")?;
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(command_id, token.clone());
+        let pending = self.pending.clone();
+        let job = Box::new(move || {
+            run(&token);
+            pending.lock().unwrap().remove(&command_id);
+        });
+        if sender.send(job).is_err() {
+            self.pending.lock().unwrap().remove(&command_id);
+            anyhow::bail!("command workers are gone");
+        }
+        Ok(())
+    }
+
+    /// Cancels a command, returning whether it was still pending.
+    pub fn cancel(&self, command_id: i32) -> bool {
+        match self.pending.lock().unwrap().get(&command_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Gets the token of a pending command.
+    #[cfg(test)]
+    pub fn token(&self, command_id: i32) -> Option<CancelToken> {
+        self.pending.lock().unwrap().get(&command_id).cloned()
+    }
+
+    /// Cancels every pending command, and waits for the workers to finish.
+    pub fn shutdown(&self) {
+        // No more commands are queued once the sender is gone, so none
+        // escapes being cancelled.
+        self.sender.lock().unwrap().take();
+        for token in self.pending.lock().unwrap().values() {
+            token.cancel();
+        }
+        for worker in self.workers.lock().unwrap().drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Drop for Commands {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn cancel() {
+        let commands = Commands::new(2).unwrap();
+        let (done, results) = mpsc::channel();
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Mutex::new(gate);
+
+        // The first command waits until it is cancelled.
+        let first = done.clone();
+        commands
+            .spawn(1, move |token| {
+                while !token.is_cancelled() {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                first.send((1, token.check().is_err())).unwrap();
+            })
+            .unwrap();
+        commands
+            .spawn(2, move |token| {
+                gate.lock().unwrap().recv().unwrap();
+                done.send((2, token.check().is_err())).unwrap();
+            })
+            .unwrap();
+
+        assert!(commands.cancel(1));
+        assert_eq!(results.recv().unwrap(), (1, true));
+        release.send(()).unwrap();
+        assert_eq!(results.recv().unwrap(), (2, false));
+
+        commands.shutdown();
+        assert!(!commands.cancel(2));
+        assert!(commands.spawn(3, |_| ()).is_err());
+    }
+}
@@ -0,0 +1,413 @@
+//! The configuration file, in TOML. Every setting is optional, and flags on
+//! the command line override individual settings. A full example:
+//!
+//! ```toml
+//! [source]
+//! hive = "D:/evidence/SOFTWARE"   # or `reg = "..."`; the live registry if neither
+//!
+//! [mount]
+//! root = "C:/regfs"
+//! render = "raw"                  # or "text"
+//! daemon = false                  # stop on signals only, not on Enter
+//! pid_file = "regfs.pid"
+//! watch = true                    # follow changes to the source
+//!
+//! [write]
+//! audit_log = "audit.jsonl"
+//! dry_run = "changes.reg"
+//! patch_root = 'HKEY_LOCAL_MACHINE\SOFTWARE'  # where a hive's keys are patched
+//!
+//! [search]
+//! cache_index = true              # save the index of hive and .reg sources
+//!
+//! [projfs]
+//! pool_threads = 0               # 0 lets ProjFS choose
+//! concurrent_threads = 0
+//!
+//! [[projfs.notifications]]
+//! path = ""                       # the whole projection
+//! events = ["file-opened", "pre-delete", "pre-rename"]
+//!
+//! [[projfs.notifications]]
+//! path = "HKEY_LOCAL_MACHINE\\HARDWARE"
+//! events = []                     # no notifications for this subtree
+//!
+//! [log]
+//! level = "info"
+//! file = "regfs.log"
+//! ```
+//!
+//! Relative paths are relative to the directory of the configuration file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::{reg_name, regfs::Rendering, source::SourceSpec, vfs::NotificationKind};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub source: SourceConfig,
+    #[serde(default)]
+    pub mount: MountConfig,
+    #[serde(default)]
+    pub write: WriteConfig,
+    #[serde(default)]
+    pub search: SearchConfig,
+    #[serde(default)]
+    pub projfs: ProjFsConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub hive: Option<PathBuf>,
+    pub reg: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub root: Option<PathBuf>,
+    #[serde(default)]
+    pub render: Rendering,
+    /// Runs without console interaction, until a termination signal.
+    #[serde(default)]
+    pub daemon: bool,
+    /// A file to write the process ID to once the projection is ready.
+    pub pid_file: Option<PathBuf>,
+    /// Follows changes to the source, and updates the placeholders they
+    /// make stale.
+    #[serde(default)]
+    pub watch: bool,
+    /// Deletes and re-creates an existing virtualization root. Only set from
+    /// the command line, since it is meant for one run.
+    #[serde(skip)]
+    pub reinit: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteConfig {
+    pub audit_log: Option<PathBuf>,
+    pub dry_run: Option<PathBuf>,
+    /// The key that the root of a hive source is written as in the patch.
+    /// Guessed from the hive's file name if not set.
+    pub patch_root: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SearchConfig {
+    /// Saves the search index of a hive or `.reg` file in the user's cache
+    /// directory, for later runs. The index holds the decoded data of every
+    /// value, so it is off unless asked for.
+    #[serde(default)]
+    pub cache_index: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjFsConfig {
+    /// The number of threads ProjFS keeps for callbacks, and the number of
+    /// requests served at once for a backend which can cancel them; 0 lets
+    /// ProjFS choose.
+    #[serde(default)]
+    pub pool_threads: u32,
+    /// The number of callbacks ProjFS runs at once; 0 lets it choose.
+    #[serde(default)]
+    pub concurrent_threads: u32,
+    #[serde(default = "default_notifications")]
+    pub notifications: Vec<NotificationMapping>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationMapping {
+    /// The subtree, relative to the virtualization root.
+    #[serde(default)]
+    pub path: String,
+    pub events: Vec<NotificationKind>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    /// Overridden by `RUST_LOG`, which in turn is overridden by `--log-level`.
+    pub level: Option<LogLevel>,
+    /// A file to append log messages to, instead of standard error.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// Everything the registry backend handles.
+fn default_notifications() -> Vec<NotificationMapping> {
+    use NotificationKind as K;
+
+    vec![NotificationMapping {
+        path: String::new(),
+        events: vec![
+            K::FileOpened,
+            K::NewFileCreated,
+            K::FileOverwritten,
+            K::PreRename,
+            K::PreDelete,
+            K::FileRenamed,
+            K::FileHandleClosedFileModified,
+            K::FileHandleClosedFileDeleted,
+        ],
+    }]
+}
+
+impl Default for ProjFsConfig {
+    fn default() -> ProjFsConfig {
+        ProjFsConfig {
+            pool_threads: 0,
+            concurrent_threads: 0,
+            notifications: default_notifications(),
+        }
+    }
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> log::LevelFilter {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read config file {}", path.display()))?;
+        let mut config =
+            Config::parse(&text).with_context(|| format!("in config file {}", path.display()))?;
+        if let Some(dir) = path.parent() {
+            config.resolve_paths(dir);
+        }
+        Ok(config)
+    }
+
+    /// Parses a configuration. The errors of the TOML parser point at the
+    /// offending line, and name the expected keys and values.
+    pub fn parse(text: &str) -> anyhow::Result<Config> {
+        Ok(toml::from_str(text)?)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let paths = [
+            &mut self.source.hive,
+            &mut self.source.reg,
+            &mut self.mount.root,
+            &mut self.mount.pid_file,
+            &mut self.write.audit_log,
+            &mut self.write.dry_run,
+            &mut self.log.file,
+        ];
+        for path in paths.into_iter().flatten() {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+
+    /// Checks the settings which cannot be checked one at a time. This
+    /// should be called after the command line flags are applied, since they
+    /// can resolve conflicts.
+    pub fn validate(&mut self) -> anyhow::Result<()> {
+        if self.source.hive.is_some() && self.source.reg.is_some() {
+            bail!("source: only one of `hive` and `reg` can be set");
+        }
+        if self.mount.render == Rendering::Text && self.write.dry_run.is_some() {
+            bail!(
+                "mount.render: changes to values rendered as text cannot be recorded; \
+                 use `render = \"raw\"` with `write.dry_run`"
+            );
+        }
+
+        let projfs = &mut self.projfs;
+        if projfs.pool_threads != 0
+            && projfs.concurrent_threads != 0
+            && projfs.pool_threads < projfs.concurrent_threads
+        {
+            bail!(
+                "projfs.pool_threads ({}) must be at least projfs.concurrent_threads ({})",
+                projfs.pool_threads,
+                projfs.concurrent_threads,
+            );
+        }
+        for mapping in &mut projfs.notifications {
+            if mapping.path.starts_with(['\\', '/']) {
+                bail!(
+                    "projfs.notifications: path {:?} must be relative to the root",
+                    mapping.path,
+                );
+            }
+            mapping.path = mapping
+                .path
+                .split(['\\', '/'])
+                .filter(|c| !c.is_empty())
+                .collect::<Vec<_>>()
+                .join("\\");
+            if mapping.path.split('\\').any(|c| c == "." || c == "..") {
+                bail!(
+                    "projfs.notifications: path {:?} must not contain `.` or `..`",
+                    mapping.path,
+                );
+            }
+        }
+        // ProjFS requires the mapping for a subtree to come after the
+        // mappings for its ancestors.
+        projfs
+            .notifications
+            .sort_by(|a, b| reg_name::cmp(&a.path, &b.path));
+        if let Some(pair) = projfs
+            .notifications
+            .windows(2)
+            .find(|pair| reg_name::eq(&pair[0].path, &pair[1].path))
+        {
+            bail!(
+                "projfs.notifications: path {:?} is mapped more than once",
+                pair[1].path,
+            );
+        }
+        Ok(())
+    }
+
+    pub fn source_spec(&self) -> SourceSpec {
+        match (&self.source.hive, &self.source.reg) {
+            (Some(path), _) => SourceSpec::Hive(path.clone()),
+            (None, Some(path)) => SourceSpec::RegFile(path.clone()),
+            (None, None) => SourceSpec::Live,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.source_spec(), SourceSpec::Live);
+        assert_eq!(config.mount.render, Rendering::Raw);
+        assert!(!config.search.cache_index);
+        assert_eq!(config.projfs.pool_threads, 0);
+        assert_eq!(config.projfs.notifications, default_notifications());
+        assert_eq!(config.log.level, None);
+    }
+
+    #[test]
+    fn full() {
+        let mut config = Config::parse(
+            r#"
+            [source]
+            reg = "export.reg"
+            [mount]
+            root = "C:/regfs"
+            render = "text"
+            watch = true
+            [search]
+            cache_index = true
+            [projfs]
+            pool_threads = 4
+            concurrent_threads = 2
+            [[projfs.notifications]]
+            path = "HKEY_TEST/Software/"
+            events = []
+            [[projfs.notifications]]
+            events = ["pre-delete", "file-handle-closed-file-modified"]
+            [log]
+            level = "debug"
+            "#,
+        )
+        .unwrap();
+        config.resolve_paths(Path::new("/etc/regfs"));
+        config.validate().unwrap();
+
+        assert_eq!(
+            config.source_spec(),
+            SourceSpec::RegFile(PathBuf::from("/etc/regfs/export.reg")),
+        );
+        assert_eq!(config.mount.render, Rendering::Text);
+        assert!(config.mount.watch);
+        assert!(config.search.cache_index);
+        assert_eq!(config.projfs.concurrent_threads, 2);
+        assert_eq!(
+            config.projfs.notifications,
+            [
+                NotificationMapping {
+                    path: String::new(),
+                    events: vec![
+                        NotificationKind::PreDelete,
+                        NotificationKind::FileHandleClosedFileModified,
+                    ],
+                },
+                NotificationMapping {
+                    path: String::from("HKEY_TEST\\Software"),
+                    events: Vec::new(),
+                },
+            ],
+        );
+        assert_eq!(config.log.level, Some(LogLevel::Debug));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| format!("{:#}", Config::parse(text).unwrap_err());
+        let message = error("[mount]\nroot = 'x'\nrender = 'html'\n");
+        assert!(message.contains("line 3"), "{}", message);
+        assert!(message.contains("expected `raw` or `text`"), "{}", message);
+        let message = error("[projfs]\npool_thread = 2\n");
+        assert!(
+            message.contains("unknown field `pool_thread`"),
+            "{}",
+            message
+        );
+        let message = error("[[projfs.notifications]]\nevents = ['opened']\n");
+        assert!(message.contains("file-opened"), "{}", message);
+
+        let invalid = |text| {
+            let mut config = Config::parse(text).unwrap();
+            format!("{:#}", config.validate().unwrap_err())
+        };
+        assert!(invalid("[source]\nhive = 'a'\nreg = 'b'\n").contains("only one of"));
+        assert!(
+            invalid("[mount]\nrender = 'text'\n[write]\ndry_run = 'p.reg'\n")
+                .contains("mount.render")
+        );
+        assert!(
+            invalid("[projfs]\npool_threads = 1\nconcurrent_threads = 2\n").contains("at least")
+        );
+        assert!(invalid(
+            "[[projfs.notifications]]\npath = 'a'\nevents = []\n\
+             [[projfs.notifications]]\npath = 'A\\'\nevents = []\n"
+        )
+        .contains("more than once"));
+        assert!(
+            invalid("[[projfs.notifications]]\npath = 'a/../b'\nevents = []\n").contains("`..`")
+        );
+    }
+}
@@ -1,11 +1,14 @@
-use std::{ffi::OsStr, iter::Peekable, os::windows::prelude::OsStrExt};
+use std::{
+    collections::HashMap,
+    iter::Peekable,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use uuid::Uuid;
 
-use windows::{
-    core::PCWSTR,
-    Win32::{
-        Foundation::{BOOLEAN, ERROR_INSUFFICIENT_BUFFER},
-        Storage::ProjectedFileSystem::*,
-    },
+use crate::{
+    file_name,
+    vfs::{DirEntrySink, EntryInfo},
 };
 
 pub struct SimpleDirEnumerator<I>
@@ -28,44 +31,37 @@ where
         }
     }
 
-    pub unsafe fn get_dir_enum(
+    pub fn get_dir_enum(
         &mut self,
-        callback_data: *const PRJ_CALLBACK_DATA,
-        search_expr: PCWSTR,
-        dir_entry_buffer_handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
+        search_expr: Option<&str>,
+        restart: bool,
+        sink: &mut dyn DirEntrySink,
     ) {
-        if (*callback_data).Flags.0 & PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN.0 != 0 {
+        if restart {
             self.cur = self.start.clone().peekable();
         }
 
         while let Some((name, len)) = self.cur.peek().as_ref() {
-            let name_wstr: Vec<u16> = OsStr::new(name.as_ref())
-                .encode_wide()
-                .chain(std::iter::once(0))
-                .collect();
             // Check if the file name matches the search condition
-            if PrjFileNameMatch(PCWSTR::from_raw(name_wstr.as_ptr()), search_expr).0 == 0 {
-                self.cur.next();
-                continue;
+            if let Some(search_expr) = search_expr {
+                if !file_name::matches(name.as_ref(), search_expr) {
+                    self.cur.next();
+                    continue;
+                }
             }
 
-            let file_info = PRJ_FILE_BASIC_INFO {
-                IsDirectory: BOOLEAN(len.is_none() as u8),
-                FileSize: len.unwrap_or(0) as i64,
-                ..Default::default()
+            let info = match len {
+                Some(len) => EntryInfo::file(*len as u64),
+                None => EntryInfo::dir(),
             };
 
-            match PrjFillDirEntryBuffer(
-                PCWSTR::from_raw(name_wstr.as_ptr()),
-                &file_info,
-                dir_entry_buffer_handle,
-            ) {
-                Ok(()) => {
+            match sink.add(name.as_ref(), &info) {
+                Ok(true) => {
                     self.cur.next();
                 }
-                Err(err) if err.code() == ERROR_INSUFFICIENT_BUFFER.to_hresult() => {
-                    // The dir_entry_buffer is full, stop so that the client
-                    // can process the items in the buffer.
+                Ok(false) => {
+                    // The sink is full, stop so that the client can process
+                    // the items in the buffer.
                     log::debug!("Directory entry buffer full");
                     break;
                 }
@@ -73,7 +69,7 @@ where
                     // This branch will be reached if the file name supplied is
                     // invalid, such as when the file name contains '*' or '/'.
                     log::warn!(
-                        "Failed to fill directory entry buffer for {:?}: {}",
+                        "Failed to fill directory entry buffer for {:?}: {:#}",
                         name.as_ref(),
                         err,
                     );
@@ -84,3 +80,45 @@ where
         }
     }
 }
+
+/// Open enumeration sessions, keyed by their enumeration IDs. The map is
+/// only locked to look a session up, mostly for reading; each session has a
+/// lock of its own, which is held while it fills a buffer.
+pub struct DirEnumSessions<E> {
+    sessions: RwLock<HashMap<Uuid, Arc<Mutex<E>>>>,
+}
+
+impl<E> DirEnumSessions<E> {
+    pub fn new() -> DirEnumSessions<E> {
+        DirEnumSessions {
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn insert(&self, enumeration_id: Uuid, session: E) {
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(enumeration_id, Arc::new(Mutex::new(session)));
+    }
+
+    pub fn remove(&self, enumeration_id: Uuid) {
+        self.sessions.write().unwrap().remove(&enumeration_id);
+    }
+
+    pub fn get(&self, enumeration_id: Uuid) -> Option<Arc<Mutex<E>>> {
+        self.sessions.read().unwrap().get(&enumeration_id).cloned()
+    }
+
+    /// Drops all sessions, returning how many there were.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn clear(&self) -> usize {
+        self.sessions.write().unwrap().drain().count()
+    }
+}
+
+impl<E> Default for DirEnumSessions<E> {
+    fn default() -> DirEnumSessions<E> {
+        DirEnumSessions::new()
+    }
+}
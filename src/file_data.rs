@@ -0,0 +1,162 @@
+//! Delivering file data to clients which take it in aligned buffers of
+//! limited size, as ProjFS does with `PrjAllocateAlignedBuffer` and
+//! `PrjWriteFileData`.
+//!
+//! Backends stream data into a `FileDataSink`; a `ChunkedWriter` copies it
+//! into buffers of at most `MAX_CHUNK_SIZE` bytes, and hands each one to the
+//! client once it is full, so that only one chunk is held at a time however
+//! large the file.
+
+use std::ops::DerefMut;
+
+use crate::vfs::FileDataSink;
+
+/// The most bytes written to the client at once.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Allocates buffers for a client and writes them out.
+pub trait ChunkWriter {
+    type Buffer: DerefMut<Target = [u8]>;
+
+    fn alloc(&mut self, len: usize) -> anyhow::Result<Self::Buffer>;
+
+    /// Writes the first `len` bytes of a buffer at `byte_offset`.
+    fn write(&mut self, buffer: &Self::Buffer, len: usize, byte_offset: u64) -> anyhow::Result<()>;
+}
+
+/// Widens a requested range to whole units of `alignment`, which must be a
+/// power of two, and returns its start and length.
+pub fn aligned_range(byte_offset: u64, length: u32, alignment: u32) -> (u64, u64) {
+    let mask = alignment.max(1) as u64 - 1;
+    let start = byte_offset & !mask;
+    let end = (byte_offset + length as u64 + mask) & !mask;
+    (start, end - start)
+}
+
+/// Splits the data streamed into it into chunks. Every chunk but the last
+/// is `chunk_size` bytes long, unless more data comes than was expected.
+pub struct ChunkedWriter<W: ChunkWriter> {
+    writer: W,
+    chunk_size: usize,
+    /// The number of bytes still expected, which bounds the size of buffers.
+    expected: u64,
+    buffer: Option<W::Buffer>,
+    filled: usize,
+    /// Where the current buffer goes.
+    byte_offset: u64,
+}
+
+impl<W: ChunkWriter> ChunkedWriter<W> {
+    /// Starts writing at `byte_offset`, expecting `expected` bytes. With
+    /// aligned clients, `byte_offset`, `expected` and `chunk_size` should
+    /// all be aligned.
+    pub fn new(writer: W, byte_offset: u64, expected: u64, chunk_size: usize) -> ChunkedWriter<W> {
+        assert!(chunk_size > 0);
+        ChunkedWriter {
+            writer,
+            chunk_size,
+            expected,
+            buffer: None,
+            filled: 0,
+            byte_offset,
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(buffer) = self.buffer.take() {
+            self.writer.write(&buffer, self.filled, self.byte_offset)?;
+            self.byte_offset += self.filled as u64;
+            self.filled = 0;
+        }
+        Ok(())
+    }
+
+    /// Writes out what is left, and returns the client.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: ChunkWriter> FileDataSink for ChunkedWriter<W> {
+    fn write(&mut self, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            let buffer = match &mut self.buffer {
+                Some(buffer) => buffer,
+                None => {
+                    let wanted = self.expected.max(data.len() as u64);
+                    let len = (wanted as usize).min(self.chunk_size);
+                    self.buffer.insert(self.writer.alloc(len)?)
+                }
+            };
+            let len = data.len().min(buffer.len() - self.filled);
+            buffer[self.filled..self.filled + len].copy_from_slice(&data[..len]);
+            self.filled += len;
+            self.expected = self.expected.saturating_sub(len as u64);
+            data = &data[len..];
+            if self.filled == buffer.len() {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the writes, in buffers which remember their allocated size.
+    #[derive(Default)]
+    struct StubWriter {
+        allocs: Vec<usize>,
+        writes: Vec<(u64, Vec<u8>)>,
+    }
+
+    impl ChunkWriter for StubWriter {
+        type Buffer = Vec<u8>;
+
+        fn alloc(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+            self.allocs.push(len);
+            Ok(vec![0; len])
+        }
+
+        fn write(&mut self, buffer: &Vec<u8>, len: usize, byte_offset: u64) -> anyhow::Result<()> {
+            self.writes.push((byte_offset, buffer[..len].to_vec()));
+            Ok(())
+        }
+    }
+
+    fn joined(writes: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        writes.iter().flat_map(|(_, data)| data.clone()).collect()
+    }
+
+    #[test]
+    fn chunks() {
+        assert_eq!(aligned_range(0, 10, 1), (0, 10));
+        assert_eq!(aligned_range(5, 10, 8), (0, 16));
+        assert_eq!(aligned_range(4096, 4096, 4096), (4096, 4096));
+
+        let data: Vec<u8> = (0..40).collect();
+        let mut writer = ChunkedWriter::new(StubWriter::default(), 8, 40, 16);
+        for piece in data.chunks(7) {
+            writer.write(piece).unwrap();
+        }
+        let stub = writer.finish().unwrap();
+        assert_eq!(stub.allocs, [16, 16, 8]);
+        let offsets: Vec<_> = stub.writes.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [8, 24, 40]);
+        assert_eq!(joined(&stub.writes), data);
+
+        // More than expected still goes out, in chunks no larger than the
+        // cap; less leaves a short last chunk.
+        let mut writer = ChunkedWriter::new(StubWriter::default(), 0, 4, 16);
+        writer.write(&data).unwrap();
+        let stub = writer.finish().unwrap();
+        assert_eq!(stub.allocs, [16, 16, 8]);
+        assert_eq!(joined(&stub.writes), data);
+        let mut writer = ChunkedWriter::new(StubWriter::default(), 0, 100, 64);
+        writer.write(&data[..3]).unwrap();
+        assert_eq!(writer.finish().unwrap().writes, [(0, vec![0, 1, 2])]);
+    }
+}
@@ -0,0 +1,190 @@
+//! A portable version of `PrjFileNameMatch`, and the order of
+//! `PrjFileNameCompare`, which follow the rules used by NTFS and ProjFS for
+//! file names.
+//!
+//! Names are handled as UTF-16 code units, and compared case-insensitively by
+//! converting each code unit to uppercase, with the same upcase table as is
+//! used for registry names. `PrjFileNameCompare` therefore orders names as
+//! `reg_name::cmp` does.
+
+use crate::reg_name::{self, upcase_unit as upcase};
+
+/// Matches any single character, or zero characters at a period or at the end
+/// of the name.
+const DOS_QM: u16 = b'>' as u16;
+/// Matches a period, or zero characters at the end of the name.
+const DOS_DOT: u16 = b'"' as u16;
+/// Matches zero or more characters, up to the final period of the name.
+const DOS_STAR: u16 = b'<' as u16;
+const STAR: u16 = b'*' as u16;
+const QM: u16 = b'?' as u16;
+const DOT: u16 = b'.' as u16;
+
+/// Sorts items into the order of `reg_name::cmp` by their names, upcasing
+/// each name only once.
+pub fn sort_by_name<T>(items: &mut [T], name: impl Fn(&T) -> &str) {
+    items.sort_by_cached_key(|item| reg_name::sort_key(name(item)));
+}
+
+/// Checks whether a file name matches a search expression the way
+/// `PrjFileNameMatch` does, supporting the `*` and `?` wildcards and the DOS
+/// wildcards `<`, `>` and `"`.
+///
+/// The name is walked once, keeping the set of positions in the expression
+/// reached so far, so that neither time nor memory depends on how the
+/// wildcards can be combined; names may be long, e.g. value data searched
+/// for a pattern.
+pub fn matches(name: &str, expr: &str) -> bool {
+    if expr == "*" {
+        return true;
+    }
+
+    let expr: Vec<u16> = expr.encode_utf16().map(upcase).collect();
+    let last_dot = name
+        .rfind('.')
+        .map(|pos| name[..pos].encode_utf16().count());
+    let mut matcher = Matcher {
+        expr: &expr,
+        reached: vec![false; expr.len() + 1],
+        next: vec![false; expr.len() + 1],
+    };
+    matcher.reached[0] = true;
+    for (i, c) in name.encode_utf16().map(upcase).enumerate() {
+        if !matcher.step(c, Some(i) == last_dot) {
+            return false;
+        }
+    }
+    matcher.skip_empty(None);
+    matcher.reached[expr.len()]
+}
+
+struct Matcher<'a> {
+    expr: &'a [u16],
+    /// Whether each prefix of the expression matches the name so far
+    reached: Vec<bool>,
+    next: Vec<bool>,
+}
+
+impl Matcher<'_> {
+    /// Moves past the parts of the expression which can match nothing before
+    /// `c`, or at the end of the name if `c` is `None`.
+    fn skip_empty(&mut self, c: Option<u16>) {
+        for j in 0..self.expr.len() {
+            if !self.reached[j] {
+                continue;
+            }
+            let empty = match self.expr[j] {
+                STAR | DOS_STAR => true,
+                DOS_QM => matches!(c, None | Some(DOT)),
+                DOS_DOT => c.is_none(),
+                _ => false,
+            };
+            if empty {
+                self.reached[j + 1] = true;
+            }
+        }
+    }
+
+    /// Matches the next character of the name, returning whether any prefix
+    /// of the expression still matches.
+    fn step(&mut self, c: u16, is_last_dot: bool) -> bool {
+        self.skip_empty(Some(c));
+        self.next.fill(false);
+        let mut any = false;
+        for (j, &e) in self.expr.iter().enumerate() {
+            if !self.reached[j] {
+                continue;
+            }
+            let (stays, advances) = match e {
+                STAR => (true, false),
+                // The final period may not be consumed.
+                DOS_STAR => (!is_last_dot, false),
+                QM => (false, true),
+                DOS_QM => (false, c != DOT),
+                DOS_DOT => (false, c == DOT),
+                e => (false, c == e),
+            };
+            if stays {
+                self.next[j] = true;
+            }
+            if advances {
+                self.next[j + 1] = true;
+            }
+            any |= stays || advances;
+        }
+        std::mem::swap(&mut self.reached, &mut self.next);
+        any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    #[test]
+    fn plain_wildcards() {
+        assert!(matches("Hello.txt", "*"));
+        assert!(matches("Hello.txt", "*.TXT"));
+        assert!(matches("Hello.txt", "h?llo.*"));
+        assert!(matches("Hello.txt", "*l*l*"));
+        assert!(!matches("Hello.txt", "*.doc"));
+        assert!(!matches("Hello.txt", "Hello.tx"));
+        assert!(!matches("Hello.txt", "?Hello.txt"));
+        assert!(matches("", ""));
+        assert!(!matches("a", ""));
+    }
+
+    #[test]
+    fn dos_wildcards() {
+        // "*.*" and "*." are sent as "<.<" and "<\"" by Win32.
+        assert!(matches("Hello.txt", "<.<"));
+        assert!(matches("Hello", "<\""));
+        assert!(!matches("Hello.txt", "<\""));
+        assert!(matches("Hello", "Hello\""));
+        assert!(matches("Hello.", "Hello\""));
+
+        // DOS_STAR may cross periods other than the final one.
+        assert!(matches("a.b.c", "<.c"));
+        assert!(!matches("a.b.c", "<c"));
+
+        // DOS_QM matches nothing at periods and at the end of the name.
+        assert!(matches("ab.txt", ">>>>.txt"));
+        assert!(matches("ab", ">>>>"));
+        assert!(!matches("abcde", ">>>>"));
+        assert!(!matches("ab.txt", ">.txt"));
+
+        // A match may need an earlier star to take the final period.
+        assert!(matches("a.ab", "*a<b"));
+        assert!(!matches("a.ab", "a<b"));
+    }
+
+    #[test]
+    fn long_names() {
+        let name = "a".repeat(1 << 16);
+        assert!(matches(&name, "*a*a*a*a*a*a*a*a"));
+        assert!(!matches(&name, "*a*a*a*a*a*a*a*b"));
+        assert!(matches(&format!("{}.b", name), "<.<"));
+    }
+
+    #[test]
+    fn case_insensitivity() {
+        assert!(!matches("straße", "STRASSE"));
+        assert!(matches("ÄÖÜ", "äöü"));
+        assert!(matches("Σίσυφος", "ΣΊΣΥΦΟΣ"));
+        assert_eq!(reg_name::cmp("ÄBC", "äbc"), Ordering::Equal);
+    }
+
+    #[test]
+    fn ordering() {
+        let mut names = vec!["b", "A", "_x", "a1", "Z", "é", "[", "~"];
+        let mut sorted = names.clone();
+        names.sort_by(|a, b| reg_name::cmp(a, b));
+        // '[' and '_' sort between uppercase and lowercase letters in ASCII,
+        // but after letters once everything is uppercase.
+        assert_eq!(names, ["A", "a1", "b", "Z", "[", "_x", "~", "é"]);
+        sort_by_name(&mut sorted, |name| name);
+        assert_eq!(sorted, names);
+    }
+}
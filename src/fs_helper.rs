@@ -1,9 +1,17 @@
-use windows::Win32::{
-    Foundation::{BOOLEAN, E_OUTOFMEMORY},
-    Storage::ProjectedFileSystem::*,
+use windows::{
+    core::{GUID, HRESULT, PCWSTR},
+    Win32::{
+        Foundation::{BOOLEAN, E_OUTOFMEMORY},
+        Storage::ProjectedFileSystem::*,
+    },
 };
 
-#[derive(Default)]
+use crate::{
+    file_data::ChunkWriter,
+    vfs::{EntryInfo, VersionInfo},
+};
+
+#[derive(Default, Clone, Copy)]
 pub struct SimpleFsHelper {
     instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
 }
@@ -25,24 +33,17 @@ impl SimpleFsHelper {
         callback_data.FilePathName.to_string()
     }
 
+    /// Writes placeholder info for the file at `path`, a null-terminated
+    /// path relative to the root, such as `PRJ_CALLBACK_DATA::FilePathName`.
     pub unsafe fn write_placeholder_info(
         &self,
-        callback_data: &PRJ_CALLBACK_DATA,
-        file_size: Option<i64>,
+        path: PCWSTR,
+        placeholder_info: &PRJ_PLACEHOLDER_INFO,
     ) -> windows::core::Result<()> {
-        let placeholder_info = PRJ_PLACEHOLDER_INFO {
-            FileBasicInfo: PRJ_FILE_BASIC_INFO {
-                IsDirectory: BOOLEAN(file_size.is_none() as u8),
-                FileSize: file_size.unwrap_or(0),
-                ..Default::default()
-            },
-            ..Default::default()
-        };
-
         PrjWritePlaceholderInfo(
             self.instance_handle,
-            callback_data.FilePathName,
-            &placeholder_info,
+            path,
+            placeholder_info,
             std::mem::size_of::<PRJ_PLACEHOLDER_INFO>() as u32,
         )
     }
@@ -59,22 +60,83 @@ impl SimpleFsHelper {
         }
     }
 
-    pub unsafe fn write_file_data(
+    /// Returns the alignment ProjFS requires of the offsets and lengths of
+    /// file data writes, which do not reach the end of the file.
+    pub fn write_alignment(&self) -> windows::core::Result<u32> {
+        let info = unsafe { PrjGetVirtualizationInstanceInfo(self.instance_handle) }?;
+        Ok(info.WriteAlignment)
+    }
+
+    pub fn write_file_data(
         &self,
-        callback_data: &PRJ_CALLBACK_DATA,
+        data_stream_id: &GUID,
         buffer: &[u8],
         byte_offset: u64,
     ) -> windows::core::Result<()> {
-        PrjWriteFileData(
-            self.instance_handle,
-            &callback_data.DataStreamId,
-            buffer.as_ptr().cast(),
-            byte_offset,
-            buffer.len().try_into().expect("buffer too large"),
-        )
+        unsafe {
+            PrjWriteFileData(
+                self.instance_handle,
+                data_stream_id,
+                buffer.as_ptr().cast(),
+                byte_offset,
+                buffer.len().try_into().expect("buffer too large"),
+            )
+        }
+    }
+
+    /// Finishes a command for which a callback returned `ERROR_IO_PENDING`.
+    pub fn complete_command(&self, command_id: i32, result: HRESULT) -> windows::core::Result<()> {
+        unsafe { PrjCompleteCommand(self.instance_handle, command_id, result, std::ptr::null()) }
     }
 }
 
+/// Writes file data for a request in aligned buffers.
+pub struct FileDataWriter {
+    pub helper: SimpleFsHelper,
+    pub data_stream_id: GUID,
+}
+
+impl ChunkWriter for FileDataWriter {
+    type Buffer = FsBuffer;
+
+    fn alloc(&mut self, len: usize) -> anyhow::Result<FsBuffer> {
+        Ok(self.helper.alloc_aligned_buffer(len)?)
+    }
+
+    fn write(&mut self, buffer: &FsBuffer, len: usize, byte_offset: u64) -> anyhow::Result<()> {
+        self.helper
+            .write_file_data(&self.data_stream_id, &buffer[..len], byte_offset)?;
+        Ok(())
+    }
+}
+
+/// Describes an entry for `PrjWritePlaceholderInfo` and
+/// `PrjUpdateFileIfNeeded`. IDs longer than ProjFS allows are cut short.
+pub fn placeholder_info(info: &EntryInfo, version: &VersionInfo) -> PRJ_PLACEHOLDER_INFO {
+    fn copy_id(dest: &mut [u8], id: &[u8]) {
+        let len = id.len().min(dest.len());
+        dest[..len].copy_from_slice(&id[..len]);
+    }
+
+    let mut placeholder_info = PRJ_PLACEHOLDER_INFO {
+        FileBasicInfo: PRJ_FILE_BASIC_INFO {
+            IsDirectory: BOOLEAN(info.is_dir as u8),
+            FileSize: if info.is_dir { 0 } else { info.size as i64 },
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    copy_id(
+        &mut placeholder_info.VersionInfo.ProviderID,
+        &version.provider_id,
+    );
+    copy_id(
+        &mut placeholder_info.VersionInfo.ContentID,
+        &version.content_id,
+    );
+    placeholder_info
+}
+
 impl std::ops::Deref for FsBuffer {
     type Target = [u8];
 
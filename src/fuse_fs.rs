@@ -0,0 +1,316 @@
+//! A FUSE frontend, which serves a `VirtualFs` backend as a read-only file
+//! system, laid out the same way as the ProjFS projection.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use fuser::{
+    BackgroundSession, Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags,
+    Generation, INodeNo, LockOwner, MountOption, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, Request,
+};
+
+use crate::{
+    reg_name, source,
+    vfs::{self, EntryInfo, VfsError, VirtualFs},
+};
+
+const TTL: Duration = Duration::from_secs(1);
+
+pub struct RegFuse<B> {
+    backend: B,
+    inodes: Mutex<InodeTable>,
+    /// The entries of each open directory, listed once when it is opened and
+    /// then read out at whatever offsets `readdir` is called with.
+    dirs: Mutex<HashMap<u64, Arc<[DirEntry]>>>,
+    next_fh: AtomicU64,
+}
+
+type DirEntry = (INodeNo, FileType, String);
+
+/// Assigns inode numbers to paths as they are discovered. Inode numbers are
+/// never reused, since the registry tree is assumed to be small enough.
+struct InodeTable {
+    paths: Vec<String>,
+    /// Keyed by the folded path, since names differing only in case are the
+    /// same entry.
+    inodes: HashMap<String, u64>,
+}
+
+impl InodeTable {
+    fn new() -> InodeTable {
+        let root = String::new();
+        InodeTable {
+            paths: vec![root.clone()],
+            inodes: HashMap::from([(root, INodeNo::ROOT.0)]),
+        }
+    }
+
+    fn path(&self, ino: INodeNo) -> Option<String> {
+        self.paths.get((ino.0 as usize).checked_sub(1)?).cloned()
+    }
+
+    fn inode(&mut self, path: &str) -> INodeNo {
+        let key = reg_name::fold(path);
+        if let Some(&ino) = self.inodes.get(&key) {
+            return INodeNo(ino);
+        }
+        self.paths.push(String::from(path));
+        let ino = self.paths.len() as u64;
+        self.inodes.insert(key, ino);
+        INodeNo(ino)
+    }
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('\\')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+impl From<VfsError> for Errno {
+    fn from(err: VfsError) -> Errno {
+        match err {
+            VfsError::NotFound => Errno::ENOENT,
+            VfsError::AccessDenied => Errno::EACCES,
+            VfsError::CannotDelete => Errno::EPERM,
+            VfsError::InvalidArgument => Errno::EINVAL,
+            VfsError::Cancelled => Errno::EINTR,
+            VfsError::Other(err) => {
+                log::error!("Error serving FUSE request: {:#}", err);
+                Errno::EIO
+            }
+        }
+    }
+}
+
+impl<B: VirtualFs> RegFuse<B> {
+    pub fn new(backend: B) -> RegFuse<B> {
+        RegFuse {
+            backend,
+            inodes: Mutex::new(InodeTable::new()),
+            dirs: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+        }
+    }
+
+    fn path(&self, ino: INodeNo) -> Result<String, Errno> {
+        self.inodes.lock().unwrap().path(ino).ok_or(Errno::ENOENT)
+    }
+
+    /// Lists a directory, in the backend's order, with `.` and `..` first.
+    fn list_dir(&self, ino: INodeNo, path: &str) -> Result<Vec<DirEntry>, Errno> {
+        let items = vfs::list_dir(&self.backend, path)?;
+        let mut inodes = self.inodes.lock().unwrap();
+        let parent_ino = inodes.inode(parent(path));
+        let entries = [
+            (ino, FileType::Directory, String::from(".")),
+            (parent_ino, FileType::Directory, String::from("..")),
+        ]
+        .into_iter()
+        .chain(
+            items
+                .into_iter()
+                // Names with slashes cannot be represented on Linux.
+                .filter(|(name, _)| !name.contains('/') && !name.is_empty())
+                .map(|(name, info)| {
+                    let kind = if info.is_dir {
+                        FileType::Directory
+                    } else {
+                        FileType::RegularFile
+                    };
+                    (inodes.inode(&source::join_path(path, &name)), kind, name)
+                }),
+        );
+        Ok(entries.collect())
+    }
+
+    fn attr(&self, req: &Request, ino: INodeNo, info: &EntryInfo) -> FileAttr {
+        let size = info.size;
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: if info.is_dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            },
+            perm: if info.is_dir { 0o555 } else { 0o444 },
+            nlink: if info.is_dir { 2 } else { 1 },
+            uid: req.uid(),
+            gid: req.gid(),
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+}
+
+impl<B: VirtualFs + 'static> Filesystem for RegFuse<B> {
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        let result = (|| {
+            let name = name.to_str().ok_or(Errno::ENOENT)?;
+            // Each name is a single registry component.
+            if name.contains('\\') {
+                return Err(Errno::ENOENT);
+            }
+            let path = source::join_path(&self.path(parent)?, name);
+            log::trace!("Lookup: {:?}", path);
+            let info = self.backend.get_entry_info(&path)?;
+            let ino = self.inodes.lock().unwrap().inode(&path);
+            Ok(self.attr(req, ino, &info))
+        })();
+        match result {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn getattr(&self, req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        let result = (|| {
+            let path = self.path(ino)?;
+            log::trace!("Get attributes: {:?}", path);
+            let info = self.backend.get_entry_info(&path)?;
+            Ok(self.attr(req, ino, &info))
+        })();
+        match result {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn opendir(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        let result = (|| {
+            let path = self.path(ino)?;
+            log::trace!("Open directory: {:?}", path);
+            self.list_dir(ino, &path)
+        })();
+        match result {
+            Ok(entries) => {
+                let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+                self.dirs.lock().unwrap().insert(fh, entries.into());
+                reply.opened(FileHandle(fh), FopenFlags::empty());
+            }
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        log::trace!("Read directory: {}, offset {}", ino.0, offset);
+        let entries = match self.dirs.lock().unwrap().get(&fh.0) {
+            Some(entries) => entries.clone(),
+            None => return reply.error(Errno::EBADF),
+        };
+        for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, i as u64 + 1, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        reply: ReplyEmpty,
+    ) {
+        self.dirs.lock().unwrap().remove(&fh.0);
+        reply.ok();
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let path = match self.path(ino) {
+            Ok(path) => path,
+            Err(err) => return reply.error(err),
+        };
+        log::trace!("Read: {:?}; offset {}, len {}", path, offset, size);
+
+        match vfs::read_to_vec(&self.backend, &path, offset, size) {
+            Ok(mut data) => {
+                data.truncate(size as usize);
+                reply.data(&data);
+            }
+            Err(err) => reply.error(err.into()),
+        }
+    }
+
+    fn flush(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _lock_owner: LockOwner,
+        reply: ReplyEmpty,
+    ) {
+        // Nothing is ever written, so there is nothing to flush.
+        reply.ok();
+    }
+}
+
+/// Mounts the backend at the given directory, and serves requests in the
+/// background until the session is dropped or the file system gets
+/// unmounted.
+pub fn spawn_mount<B: VirtualFs + 'static>(
+    backend: B,
+    mountpoint: &Path,
+) -> anyhow::Result<BackgroundSession> {
+    let mut config = Config::default();
+    config.mount_options = vec![
+        MountOption::RO,
+        MountOption::FSName(String::from("regfs")),
+        MountOption::Subtype(String::from("regfs")),
+    ];
+    fuser::spawn_mount(RegFuse::new(backend), mountpoint, &config).context("mount FUSE file system")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inodes() {
+        let mut inodes = InodeTable::new();
+        assert_eq!(inodes.inode(""), INodeNo::ROOT);
+        let ino = inodes.inode("HKEY_TEST\\Software");
+        assert_ne!(ino, INodeNo::ROOT);
+        // Names are looked up whatever their case, and keep the case they
+        // were first seen in.
+        assert_eq!(inodes.inode("hkey_test\\SOFTWARE"), ino);
+        assert_eq!(inodes.path(ino).unwrap(), "HKEY_TEST\\Software");
+        assert_ne!(inodes.inode("HKEY_TEST\\Softwar"), ino);
+    }
+}
@@ -0,0 +1,871 @@
+//! A read-only registry source backed by an offline hive file (the "regf"
+//! format used by `SYSTEM`, `SOFTWARE`, `NTUSER.DAT` and friends).
+//!
+//! Only the primary hive file is read; transaction logs are not replayed.
+
+use std::{cmp::Ordering, collections::HashMap, path::Path, sync::Mutex};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    reg_name,
+    source::{self, entry_cmp, split_value_path, KeyEntries, RegSource, Value, ValueEntry},
+};
+
+pub struct HiveSource {
+    data: Vec<u8>,
+    root_cell: u32,
+    minor_version: u32,
+    /// Whether the subkeys of each key listed so far are in the order of
+    /// `reg_name::cmp`, by the offset of its key node.
+    ordered_keys: Mutex<HashMap<u32, bool>>,
+}
+
+const BASE_BLOCK_SIZE: usize = 4096;
+const KEY_COMP_NAME: u16 = 0x0020;
+const VALUE_COMP_NAME: u16 = 0x0001;
+const DATA_INLINE: u32 = 0x8000_0000;
+const BIG_DATA_SEGMENT_SIZE: usize = 16344;
+const NO_CELL: u32 = 0xffff_ffff;
+
+fn u16_at(bytes: &[u8], offset: usize) -> anyhow::Result<u16> {
+    let bytes = bytes.get(offset..offset + 2).context("truncated cell")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = bytes.get(offset..offset + 4).context("truncated cell")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn decode_name(bytes: &[u8], compressed: bool) -> String {
+    if compressed {
+        // Compressed names are stored as Latin-1.
+        bytes.iter().map(|&b| b as char).collect()
+    } else {
+        let wide: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&wide)
+    }
+}
+
+impl HiveSource {
+    pub fn open(path: &Path) -> anyhow::Result<HiveSource> {
+        HiveSource::from_bytes(std::fs::read(path).context("read file")?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> anyhow::Result<HiveSource> {
+        if data.len() < BASE_BLOCK_SIZE || &data[0..4] != b"regf" {
+            bail!("not a registry hive file");
+        }
+        let primary_seq = u32_at(&data, 0x04)?;
+        let secondary_seq = u32_at(&data, 0x08)?;
+        if primary_seq != secondary_seq {
+            log::warn!(
+                "Hive was not written completely (sequence numbers {} and {}); \
+                 recent changes may be missing",
+                primary_seq,
+                secondary_seq,
+            );
+        }
+
+        let minor_version = u32_at(&data, 0x18)?;
+        let root_cell = u32_at(&data, 0x24)?;
+        let hive = HiveSource {
+            data,
+            root_cell,
+            minor_version,
+            ordered_keys: Mutex::new(HashMap::new()),
+        };
+        hive.key_node(root_cell).context("read root key")?;
+        Ok(hive)
+    }
+
+    /// Returns the data of a cell, excluding its size field.
+    fn cell(&self, offset: u32) -> anyhow::Result<&[u8]> {
+        let start = BASE_BLOCK_SIZE + offset as usize;
+        let size = u32_at(&self.data, start).context("cell offset out of bounds")? as i32;
+        let end = start + size.unsigned_abs() as usize;
+        self.data
+            .get(start + 4..end)
+            .with_context(|| format!("cell at {:#x} out of bounds", offset))
+    }
+
+    fn key_node(&self, offset: u32) -> anyhow::Result<&[u8]> {
+        let cell = self.cell(offset)?;
+        if !cell.starts_with(b"nk") {
+            bail!("cell at {:#x} is not a key node", offset);
+        }
+        Ok(cell)
+    }
+
+    fn value_node(&self, offset: u32) -> anyhow::Result<&[u8]> {
+        let cell = self.cell(offset)?;
+        if !cell.starts_with(b"vk") {
+            bail!("cell at {:#x} is not a value node", offset);
+        }
+        Ok(cell)
+    }
+
+    fn key_name(&self, nk: &[u8]) -> anyhow::Result<String> {
+        let flags = u16_at(nk, 0x02)?;
+        let len = u16_at(nk, 0x48)? as usize;
+        let name = nk.get(0x4c..0x4c + len).context("truncated key name")?;
+        Ok(decode_name(name, flags & KEY_COMP_NAME != 0))
+    }
+
+    fn value_name(&self, vk: &[u8]) -> anyhow::Result<String> {
+        let len = u16_at(vk, 0x02)? as usize;
+        let flags = u16_at(vk, 0x10)?;
+        let name = vk.get(0x14..0x14 + len).context("truncated value name")?;
+        Ok(decode_name(name, flags & VALUE_COMP_NAME != 0))
+    }
+
+    fn data_size(&self, vk: &[u8]) -> anyhow::Result<u32> {
+        Ok(u32_at(vk, 0x04)? & !DATA_INLINE)
+    }
+
+    fn value_data(&self, vk: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // The size is not trusted for preallocation, as it is read from the
+        // file; the data is bounded by the cells it is read from.
+        let mut data = Vec::new();
+        self.visit_data(vk, &mut |piece| {
+            data.extend_from_slice(piece);
+            Ok(true)
+        })?;
+        Ok(data)
+    }
+
+    /// Passes the data of a value to `f` as it is stored, a segment at a
+    /// time, until `f` returns `false`.
+    fn visit_data(
+        &self,
+        vk: &[u8],
+        f: &mut dyn FnMut(&[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<()> {
+        let raw_size = u32_at(vk, 0x04)?;
+        let size = (raw_size & !DATA_INLINE) as usize;
+        if raw_size & DATA_INLINE != 0 {
+            // Small values are stored in the data offset field itself.
+            f(vk.get(0x08..0x08 + size.min(4))
+                .context("truncated value")?)?;
+            return Ok(());
+        }
+
+        let offset = u32_at(vk, 0x08)?;
+        let cell = self.cell(offset)?;
+        if size > BIG_DATA_SEGMENT_SIZE && self.minor_version > 3 && cell.starts_with(b"db") {
+            // Big data is split into segments, which are listed in another
+            // cell.
+            let count = u16_at(cell, 0x02)? as usize;
+            let list = self.cell(u32_at(cell, 0x04)?)?;
+            if count * 4 > list.len() {
+                bail!("segment list of big data at {:#x} is truncated", offset);
+            }
+            let mut left = size;
+            for i in 0..count {
+                let segment = self.cell(u32_at(list, i * 4)?)?;
+                let len = left.min(BIG_DATA_SEGMENT_SIZE).min(segment.len());
+                left -= len;
+                if !f(&segment[..len])? {
+                    return Ok(());
+                }
+            }
+            if left != 0 {
+                bail!("big data value is truncated");
+            }
+            Ok(())
+        } else {
+            f(cell.get(..size).context("truncated value")?)?;
+            Ok(())
+        }
+    }
+
+    fn subkeys(&self, nk: &[u8]) -> anyhow::Result<Vec<u32>> {
+        let count = u32_at(nk, 0x14)?;
+        let list = u32_at(nk, 0x1c)?;
+        let mut subkeys = Vec::new();
+        if count != 0 && list != NO_CELL {
+            self.collect_subkey_list(list, &mut subkeys, true)?;
+        }
+        Ok(subkeys)
+    }
+
+    fn collect_subkey_list(
+        &self,
+        offset: u32,
+        subkeys: &mut Vec<u32>,
+        allow_index_root: bool,
+    ) -> anyhow::Result<()> {
+        let list = self.cell(offset)?;
+        let count = u16_at(list, 0x02)? as usize;
+        let entry_size = if matches!(&list[..2], b"lf" | b"lh") {
+            8
+        } else {
+            4
+        };
+        if 4 + count * entry_size > list.len() {
+            bail!("subkey list at {:#x} is truncated", offset);
+        }
+        match &list[..2] {
+            b"lf" | b"lh" => {
+                // Each entry is an offset followed by a name hint or hash.
+                for i in 0..count {
+                    subkeys.push(u32_at(list, 4 + i * 8)?);
+                }
+            }
+            b"li" => {
+                for i in 0..count {
+                    subkeys.push(u32_at(list, 4 + i * 4)?);
+                }
+            }
+            b"ri" if allow_index_root => {
+                for i in 0..count {
+                    self.collect_subkey_list(u32_at(list, 4 + i * 4)?, subkeys, false)?;
+                }
+            }
+            _ => bail!("cell at {:#x} is not a subkey list", offset),
+        }
+        Ok(())
+    }
+
+    fn values(&self, nk: &[u8]) -> anyhow::Result<Vec<u32>> {
+        let count = u32_at(nk, 0x24)? as usize;
+        let list = u32_at(nk, 0x28)?;
+        if count == 0 || list == NO_CELL {
+            return Ok(Vec::new());
+        }
+        let cell = self.cell(list)?;
+        if count * 4 > cell.len() {
+            bail!("value list at {:#x} is truncated", list);
+        }
+        (0..count).map(|i| u32_at(cell, i * 4)).collect()
+    }
+
+    /// Finds the key node at the given path.
+    fn find_key(&self, path: &str) -> anyhow::Result<Option<&[u8]>> {
+        Ok(self.walk_key(path)?.map(|(nk, _)| nk))
+    }
+
+    /// Finds the key node at the given path, along with the offsets of the
+    /// keys on the way there. A damaged hive may list a key below itself, so
+    /// passing through a key twice is an error rather than a deeper path.
+    fn walk_key(&self, path: &str) -> anyhow::Result<Option<(&[u8], Vec<u32>)>> {
+        let mut offsets = vec![self.root_cell];
+        let mut nk = self.key_node(self.root_cell)?;
+        for name in path.split('\\').filter(|name| !name.is_empty()) {
+            match self.find_subkey(nk, name)? {
+                Some(offset) => {
+                    if offsets.contains(&offset) {
+                        bail!("key at {:#x} is listed below itself", offset);
+                    }
+                    offsets.push(offset);
+                    nk = self.key_node(offset)?;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some((nk, offsets)))
+    }
+
+    /// Finds a subkey by name. Subkey lists are kept sorted by name, so each
+    /// is searched by bisection; an index root is walked until the list
+    /// which would hold the name.
+    fn find_subkey(&self, nk: &[u8], name: &str) -> anyhow::Result<Option<u32>> {
+        let count = u32_at(nk, 0x14)?;
+        let list = u32_at(nk, 0x1c)?;
+        if count == 0 || list == NO_CELL {
+            return Ok(None);
+        }
+        let cell = self.cell(list)?;
+        if cell.starts_with(b"ri") {
+            for i in 0..u16_at(cell, 0x02)? as usize {
+                let leaf = self.cell(u32_at(cell, 4 + i * 4)?)?;
+                let leaf_count = u16_at(leaf, 0x02)? as usize;
+                if leaf_count == 0 {
+                    continue;
+                }
+                let last = self.key_node(self.leaf_entry(leaf, leaf_count - 1)?)?;
+                if reg_name::cmp(&self.key_name(last)?, name).is_ge() {
+                    return self.search_leaf(leaf, name);
+                }
+            }
+            Ok(None)
+        } else {
+            self.search_leaf(cell, name)
+        }
+    }
+
+    /// Returns the key node offset of an entry of an `lf`, `lh` or `li` list.
+    fn leaf_entry(&self, leaf: &[u8], i: usize) -> anyhow::Result<u32> {
+        match leaf.get(..2) {
+            Some(b"lf" | b"lh") => u32_at(leaf, 4 + i * 8),
+            Some(b"li") => u32_at(leaf, 4 + i * 4),
+            _ => bail!("cell is not a subkey list"),
+        }
+    }
+
+    fn search_leaf(&self, leaf: &[u8], name: &str) -> anyhow::Result<Option<u32>> {
+        let (mut low, mut high) = (0, u16_at(leaf, 0x02)? as usize);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let offset = self.leaf_entry(leaf, mid)?;
+            match reg_name::cmp(&self.key_name(self.key_node(offset)?)?, name) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(Some(offset)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the value node at the given path.
+    fn find_value(&self, path: &str) -> anyhow::Result<Option<&[u8]>> {
+        let (key, name) = split_value_path(path);
+        let nk = match self.find_key(key)? {
+            Some(nk) => nk,
+            None => return Ok(None),
+        };
+        for value in self.values(nk)? {
+            let vk = self.value_node(value)?;
+            if reg_name::eq(&self.value_name(vk)?, name) {
+                return Ok(Some(vk));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl RegSource for HiveSource {
+    fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+        let (nk, ancestors) = match self.walk_key(path)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let mut items = Vec::new();
+        for subkey in self.subkeys(nk)? {
+            // Otherwise, walking the tree would never end.
+            if ancestors.contains(&subkey) {
+                bail!("key at {:#x} is listed below itself", subkey);
+            }
+            items.push((self.key_name(self.key_node(subkey)?)?, None));
+        }
+        for value in self.values(nk)? {
+            let vk = self.value_node(value)?;
+            items.push((self.value_name(vk)?, Some(self.data_size(vk)?)));
+        }
+        Ok(Some(items))
+    }
+
+    fn visit_key(
+        &self,
+        path: &str,
+        after: Option<(&str, bool)>,
+        f: &mut dyn FnMut(String, Option<u32>) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        let (nk, ancestors) = match self.walk_key(path)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        let subkey_name = |subkey| {
+            // Otherwise, walking the tree would never end.
+            if ancestors.contains(&subkey) {
+                bail!("key at {:#x} is listed below itself", subkey);
+            }
+            self.key_name(self.key_node(subkey)?)
+        };
+        let is_after = |name: &str, is_value| {
+            after.is_none_or(|after| entry_cmp((name, is_value), after).is_gt())
+        };
+
+        // Subkeys are kept sorted, so the listing carries on from the first
+        // one after `after`, found by bisection. A damaged hive, or one
+        // whose upcase table differs from ours, may not be in our order, and
+        // its keys are sorted like any other source's.
+        let subkeys = self.subkeys(nk)?;
+        let key = *ancestors.last().unwrap();
+        let ordered = self.ordered_keys.lock().unwrap().get(&key).copied();
+        let ordered = match ordered {
+            Some(ordered) => ordered,
+            None => {
+                let mut ordered = true;
+                let mut last: Option<String> = None;
+                for &subkey in &subkeys {
+                    let name = subkey_name(subkey)?;
+                    if last
+                        .as_deref()
+                        .is_some_and(|last| reg_name::cmp(last, &name).is_ge())
+                    {
+                        ordered = false;
+                        break;
+                    }
+                    last = Some(name);
+                }
+                self.ordered_keys.lock().unwrap().insert(key, ordered);
+                ordered
+            }
+        };
+        if !ordered {
+            log::debug!("Subkeys of {:?} are out of order, sorting them", path);
+            let entries = self.enum_key(path)?.unwrap_or_default();
+            source::visit_sorted(entries, after, f)?;
+            return Ok(true);
+        }
+        let (mut low, mut high) = (0, subkeys.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if is_after(&subkey_name(subkeys[mid])?, false) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        // Values are not kept sorted, but only their names are read to sort
+        // them.
+        let mut values = Vec::new();
+        for value in self.values(nk)? {
+            let vk = self.value_node(value)?;
+            let name = self.value_name(vk)?;
+            if is_after(&name, true) {
+                values.push((name, self.data_size(vk)?));
+            }
+        }
+        values.sort_by_cached_key(|(name, _)| reg_name::sort_key(name));
+        let mut values = values.into_iter().peekable();
+
+        for &subkey in &subkeys[low..] {
+            let name = subkey_name(subkey)?;
+            while let Some((value, size)) =
+                values.next_if(|(value, _)| reg_name::cmp(value, &name).is_lt())
+            {
+                if !f(value, Some(size))? {
+                    return Ok(true);
+                }
+            }
+            if !f(name, None)? {
+                return Ok(true);
+            }
+        }
+        for (value, size) in values {
+            if !f(value, Some(size))? {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    fn streams_keys(&self) -> bool {
+        true
+    }
+
+    fn enum_values(&self, path: &str) -> anyhow::Result<Option<Vec<ValueEntry>>> {
+        let nk = match self.find_key(path)? {
+            Some(nk) => nk,
+            None => return Ok(None),
+        };
+        let mut values = Vec::new();
+        for value in self.values(nk)? {
+            let vk = self.value_node(value)?;
+            values.push(ValueEntry {
+                name: self.value_name(vk)?,
+                vtype: u32_at(vk, 0x0c)?,
+                size: self.data_size(vk)?,
+            });
+        }
+        Ok(Some(values))
+    }
+
+    fn key_exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(self.find_key(path)?.is_some())
+    }
+
+    fn read_value(&self, path: &str) -> anyhow::Result<Option<Value>> {
+        match self.find_value(path)? {
+            Some(vk) => Ok(Some(Value {
+                vtype: u32_at(vk, 0x0c)?,
+                bytes: self.value_data(vk)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn visit_value(
+        &self,
+        path: &str,
+        f: &mut dyn FnMut(u32, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        match self.find_value(path)? {
+            Some(vk) => {
+                let vtype = u32_at(vk, 0x0c)?;
+                self.visit_data(vk, &mut |piece| f(vtype, piece))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn value_exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(self.find_value(path)?.is_some())
+    }
+
+    fn value_size(&self, path: &str) -> anyhow::Result<Option<u32>> {
+        self.find_value(path)?
+            .map(|vk| self.data_size(vk))
+            .transpose()
+    }
+
+    fn key_last_write(&self, path: &str) -> anyhow::Result<Option<u64>> {
+        match self.find_key(path)? {
+            Some(nk) => {
+                let time = nk.get(0x04..0x0c).context("truncated key node")?;
+                Ok(Some(u64::from_le_bytes(time.try_into().unwrap())))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{REG_BINARY, REG_DWORD, REG_SZ};
+
+    /// Builds hive files cell by cell, in a single bin.
+    struct HiveBuilder {
+        bin: Vec<u8>,
+    }
+
+    impl HiveBuilder {
+        fn new() -> HiveBuilder {
+            let mut bin = b"hbin".to_vec();
+            bin.resize(0x20, 0);
+            HiveBuilder { bin }
+        }
+
+        /// Appends an allocated cell, returning its offset.
+        fn cell(&mut self, data: &[u8]) -> u32 {
+            let offset = self.bin.len() as u32;
+            let size = (4 + data.len()).next_multiple_of(8);
+            self.bin.extend_from_slice(&(-(size as i32)).to_le_bytes());
+            self.bin.extend_from_slice(data);
+            self.bin.resize(offset as usize + size, 0);
+            offset
+        }
+
+        fn key(&mut self, name: &str, subkeys: Option<(u32, u32)>, values: &[u32]) -> u32 {
+            let (name, flags) = encode_name(name);
+            let mut nk = vec![0; 0x4c];
+            nk[0..2].copy_from_slice(b"nk");
+            nk[0x02..0x04].copy_from_slice(&(flags * KEY_COMP_NAME).to_le_bytes());
+            let (count, list) = subkeys.unwrap_or((0, NO_CELL));
+            nk[0x14..0x18].copy_from_slice(&count.to_le_bytes());
+            nk[0x1c..0x20].copy_from_slice(&list.to_le_bytes());
+            let value_list = if values.is_empty() {
+                NO_CELL
+            } else {
+                let list: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                self.cell(&list)
+            };
+            nk[0x24..0x28].copy_from_slice(&(values.len() as u32).to_le_bytes());
+            nk[0x28..0x2c].copy_from_slice(&value_list.to_le_bytes());
+            nk[0x48..0x4a].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            nk.extend_from_slice(&name);
+            self.cell(&nk)
+        }
+
+        /// Appends a subkey list of the given kind (`lf`, `lh`, `li` or `ri`).
+        fn list(&mut self, kind: &[u8; 2], items: &[u32]) -> (u32, u32) {
+            let mut list = kind.to_vec();
+            list.extend_from_slice(&(items.len() as u16).to_le_bytes());
+            for item in items {
+                list.extend_from_slice(&item.to_le_bytes());
+                if matches!(kind, b"lf" | b"lh") {
+                    // The name hint or hash, which the parser does not use.
+                    list.extend_from_slice(&[0; 4]);
+                }
+            }
+            (items.len() as u32, self.cell(&list))
+        }
+
+        fn value(&mut self, name: &str, vtype: u32, data: &[u8]) -> u32 {
+            let (raw_size, data_offset) = if data.len() <= 4 {
+                let mut inline = [0; 4];
+                inline[..data.len()].copy_from_slice(data);
+                (data.len() as u32 | DATA_INLINE, u32::from_le_bytes(inline))
+            } else if data.len() <= BIG_DATA_SEGMENT_SIZE {
+                (data.len() as u32, self.cell(data))
+            } else {
+                let segments: Vec<u8> = data
+                    .chunks(BIG_DATA_SEGMENT_SIZE)
+                    .map(|segment| self.cell(segment))
+                    .flat_map(u32::to_le_bytes)
+                    .collect();
+                let count = segments.len() / 4;
+                let list = self.cell(&segments);
+                let mut db = b"db".to_vec();
+                db.extend_from_slice(&(count as u16).to_le_bytes());
+                db.extend_from_slice(&list.to_le_bytes());
+                (data.len() as u32, self.cell(&db))
+            };
+            let (name, flags) = encode_name(name);
+            let mut vk = vec![0; 0x14];
+            vk[0..2].copy_from_slice(b"vk");
+            vk[0x02..0x04].copy_from_slice(&(name.len() as u16).to_le_bytes());
+            vk[0x04..0x08].copy_from_slice(&raw_size.to_le_bytes());
+            vk[0x08..0x0c].copy_from_slice(&data_offset.to_le_bytes());
+            vk[0x0c..0x10].copy_from_slice(&vtype.to_le_bytes());
+            vk[0x10..0x12].copy_from_slice(&(flags * VALUE_COMP_NAME).to_le_bytes());
+            vk.extend_from_slice(&name);
+            self.cell(&vk)
+        }
+
+        fn finish(mut self, root: u32) -> HiveSource {
+            let bin_size = (self.bin.len() as u32).next_multiple_of(4096);
+            self.bin.resize(bin_size as usize, 0);
+            self.bin[0x08..0x0c].copy_from_slice(&bin_size.to_le_bytes());
+
+            let mut data = vec![0; BASE_BLOCK_SIZE];
+            data[0..4].copy_from_slice(b"regf");
+            data[0x04..0x08].copy_from_slice(&1u32.to_le_bytes());
+            data[0x08..0x0c].copy_from_slice(&1u32.to_le_bytes());
+            data[0x18..0x1c].copy_from_slice(&5u32.to_le_bytes());
+            data[0x24..0x28].copy_from_slice(&root.to_le_bytes());
+            data.extend_from_slice(&self.bin);
+            HiveSource::from_bytes(data).unwrap()
+        }
+    }
+
+    /// Encodes a name compressed if it fits in Latin-1, like Windows does.
+    fn encode_name(name: &str) -> (Vec<u8>, u16) {
+        if name.chars().all(|c| (c as u32) < 0x100) {
+            (name.chars().map(|c| c as u8).collect(), 1)
+        } else {
+            (name.encode_utf16().flat_map(u16::to_le_bytes).collect(), 0)
+        }
+    }
+
+    fn sz(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain(std::iter::once(0))
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn subkey_lists() {
+        let mut hive = HiveBuilder::new();
+        let empty = hive.key("Empty", None, &[]);
+        let a = hive.key("Alpha", None, &[]);
+        let b = hive.key("Beta", None, &[]);
+        let c_list = hive.list(b"li", &[empty]);
+        let c = hive.key("Gamma", Some(c_list), &[]);
+        let d = hive.key("Ωmega", None, &[]);
+        let (_, lf) = hive.list(b"lf", &[a, b]);
+        let (_, li) = hive.list(b"li", &[c]);
+        let (_, lh) = hive.list(b"lh", &[d]);
+        let (_, ri) = hive.list(b"ri", &[lf, li, lh]);
+        let root = hive.key("ROOT", Some((4, ri)), &[]);
+        let hive = hive.finish(root);
+
+        let names: Vec<_> = hive
+            .enum_key("")
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|(name, size)| {
+                assert_eq!(size, None);
+                name
+            })
+            .collect();
+        assert_eq!(names, ["Alpha", "Beta", "Gamma", "Ωmega"]);
+        assert!(hive.key_exists("gamma\\EMPTY").unwrap());
+        assert!(hive.key_exists("ωMEGA").unwrap());
+        assert!(!hive.key_exists("Alpha\\Empty").unwrap());
+        for name in ["alpha", "BETA", "Gamma", "Ωmega"] {
+            assert!(hive.key_exists(name).unwrap(), "{}", name);
+        }
+        for name in ["", "A", "Bet", "Delta", "Zeta", "Ωmegas"] {
+            assert!(!hive.key_exists(&format!("{}\\x", name)).unwrap());
+            assert!(
+                name.is_empty() || !hive.key_exists(name).unwrap(),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(hive.enum_key("Missing").unwrap(), None);
+        assert_eq!(hive.enum_key("Gamma\\Empty").unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn large_subkey_list() {
+        let mut hive = HiveBuilder::new();
+        let mut names: Vec<String> = (0..50).map(|i| format!("Key{}", i * 7)).collect();
+        names.push(String::from("_"));
+        names.push(String::from("ä"));
+        names.sort_by(|a, b| reg_name::cmp(a, b));
+        let keys: Vec<u32> = names.iter().map(|name| hive.key(name, None, &[])).collect();
+        let list = hive.list(b"lh", &keys);
+        let root = hive.key("ROOT", Some(list), &[]);
+        let hive = hive.finish(root);
+
+        for name in &names {
+            assert!(hive.key_exists(&name.to_lowercase()).unwrap(), "{}", name);
+        }
+        for name in ["Key1", "Key350", "KEY", "a", "Ä0"] {
+            assert!(!hive.key_exists(name).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn value_data() {
+        let big: Vec<u8> = (0..BIG_DATA_SEGMENT_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect();
+        let mut hive = HiveBuilder::new();
+        let values = [
+            hive.value("", REG_SZ, &sz("default")),
+            hive.value("Dword", REG_DWORD, &[1, 2, 3, 4]),
+            hive.value("Short", REG_BINARY, &[9, 8]),
+            hive.value("Big", REG_BINARY, &big),
+            hive.value("Größe", REG_SZ, &sz("groß")),
+        ];
+        let root = hive.key("ROOT", None, &values);
+        let hive = hive.finish(root);
+
+        let read = |path| hive.read_value(path).unwrap().unwrap();
+        assert_eq!(read("\\").bytes, sz("default"));
+        assert_eq!(
+            read("dword"),
+            Value {
+                vtype: REG_DWORD,
+                bytes: vec![1, 2, 3, 4],
+            }
+        );
+        assert_eq!(read("Short").bytes, [9, 8]);
+        assert_eq!(read("Big").bytes, big);
+        assert_eq!(read("GRÖßE").bytes, sz("groß"));
+        assert_eq!(hive.read_value("Missing").unwrap(), None);
+
+        assert_eq!(hive.value_size("Big").unwrap(), Some(big.len() as u32));
+        assert_eq!(hive.value_size("Short").unwrap(), Some(2));
+        assert_eq!(hive.value_size("Missing").unwrap(), None);
+        assert!(hive.value_exists("größe").unwrap());
+        let types: Vec<_> = hive
+            .enum_values("")
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|value| (value.vtype, value.size))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                (REG_SZ, 16),
+                (REG_DWORD, 4),
+                (REG_BINARY, 2),
+                (REG_BINARY, big.len() as u32),
+                (REG_SZ, 10),
+            ]
+        );
+        assert_eq!(
+            hive.enum_key("").unwrap().unwrap(),
+            [
+                (String::new(), Some(16)),
+                (String::from("Dword"), Some(4)),
+                (String::from("Short"), Some(2)),
+                (String::from("Big"), Some(big.len() as u32)),
+                (String::from("Größe"), Some(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn visit_in_order() {
+        let mut hive = HiveBuilder::new();
+        let keys = ["Alpha", "Beta", "Gamma"].map(|name| hive.key(name, None, &[]));
+        let values = ["gamma", "Zed", "a"].map(|name| hive.value(name, REG_BINARY, &[1, 2]));
+        let list = hive.list(b"lf", &keys);
+        let root = hive.key("ROOT", Some(list), &values);
+        let unsorted_list = hive.list(b"li", &[keys[1], keys[0]]);
+        let unsorted = hive.key("Unsorted", Some(unsorted_list), &[]);
+        let root_list = hive.list(b"li", &[root, unsorted]);
+        let top = hive.key("TOP", Some(root_list), &[]);
+        let hive = hive.finish(top);
+
+        // Subkeys are marked with a backslash.
+        let visit = |after| {
+            let mut names = Vec::new();
+            let found = hive
+                .visit_key("ROOT", after, &mut |name, size| {
+                    names.push(match size {
+                        Some(size) => format!("{}={}", name, size),
+                        None => format!("{}\\", name),
+                    });
+                    Ok(names.len() < 3)
+                })
+                .unwrap();
+            assert!(found);
+            names
+        };
+        assert_eq!(visit(None), ["a=2", "Alpha\\", "Beta\\"]);
+        assert_eq!(
+            visit(Some(("alpha", false))),
+            ["Beta\\", "Gamma\\", "gamma=2"]
+        );
+        assert_eq!(visit(Some(("Gamma", false))), ["gamma=2", "Zed=2"]);
+        assert_eq!(visit(Some(("Zed", true))), Vec::<String>::new());
+        assert!(!hive
+            .visit_key("Missing", None, &mut |_, _| Ok(true))
+            .unwrap());
+        // Subkeys out of order are sorted instead.
+        let mut names = Vec::new();
+        let found = hive
+            .visit_key("Unsorted", Some(("alpha", false)), &mut |name, _| {
+                names.push(name);
+                Ok(true)
+            })
+            .unwrap();
+        assert!(found);
+        assert_eq!(names, ["Beta"]);
+    }
+
+    #[test]
+    fn damaged_keys() {
+        let mut hive = HiveBuilder::new();
+        // A key which lists itself as its subkey.
+        let list = hive.list(b"li", &[0]);
+        let cycle = hive.key("Cycle", Some(list), &[]);
+        let entry = list.1 as usize + 8;
+        hive.bin[entry..entry + 4].copy_from_slice(&cycle.to_le_bytes());
+        // Keys whose counts are far larger than their lists.
+        let value = hive.value("Value", REG_DWORD, &[1, 2, 3, 4]);
+        let values = hive.key("Values", None, &[value]);
+        let count = values as usize + 4 + 0x24;
+        hive.bin[count..count + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let (_, short_list) = hive.list(b"lf", &[value]);
+        let count = short_list as usize + 6;
+        hive.bin[count..count + 2].copy_from_slice(&u16::MAX.to_le_bytes());
+        let subkeys = hive.key("Subkeys", Some((u32::MAX, short_list)), &[]);
+        let root_list = hive.list(b"li", &[cycle, subkeys, values]);
+        let root = hive.key("ROOT", Some(root_list), &[]);
+        let hive = hive.finish(root);
+
+        assert!(hive.key_exists("Cycle").unwrap());
+        assert!(hive.enum_key("Cycle").is_err());
+        assert!(hive.visit_key("Cycle", None, &mut |_, _| Ok(true)).is_err());
+        assert!(hive.key_exists("Cycle\\Cycle").is_err());
+        assert!(hive.enum_key("Subkeys").is_err());
+        assert!(hive.enum_key("Values").is_err());
+        assert!(hive.read_value("Values\\Value").is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(HiveSource::from_bytes(vec![0; BASE_BLOCK_SIZE * 2]).is_err());
+        assert!(HiveSource::from_bytes(b"regf".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! A small HTTP server exposing the registry tree as JSON, for scripts and
+//! dashboards. It only listens on localhost.
+//!
+//! - `GET /keys/{path}` lists the subkeys and values of a key.
+//! - `GET /values/{path}` returns the data of a value, decoded according to
+//!   its type; `?format=raw` returns the raw bytes instead.
+//! - `PUT /values/{path}` sets a value, from a body such as
+//!   `{"type": "REG_DWORD", "data": 1}`.
+//! - `DELETE /keys/{path}` deletes a key.
+//! - `GET /search?q={pattern}` lists the keys and values whose names or data
+//!   contain the pattern, as in the `@search` directory.
+//!
+//! Path components are separated by `/`, and percent-encoded where needed.
+//! Changes follow the write policy of `RegFs`, so they are only recorded in
+//! dry-run mode.
+//!
+//! Listening on localhost does not keep web pages out: a page can send
+//! requests to it from the browser, or reach it under its own host name with
+//! DNS rebinding. So requests are only served if their `Host` names the
+//! server itself, and if they carry an `Origin`, it must be the server's too.
+
+use std::io::Read;
+
+use anyhow::Context;
+use serde_json::json;
+
+use crate::{
+    audit, reg_name,
+    regfs::RegFs,
+    source::{self, Data, Value},
+    vfs::VfsError,
+};
+
+/// The largest request body accepted.
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::json(status, json!({ "error": message }))
+    }
+
+    fn empty() -> Response {
+        Response {
+            status: 204,
+            content_type: "application/json",
+            body: Vec::new(),
+        }
+    }
+}
+
+impl From<VfsError> for Response {
+    fn from(err: VfsError) -> Response {
+        match err {
+            VfsError::NotFound => Response::error(404, "not found"),
+            VfsError::AccessDenied | VfsError::CannotDelete => {
+                Response::error(403, "the registry is read-only")
+            }
+            VfsError::InvalidArgument => Response::error(400, "invalid argument"),
+            VfsError::Cancelled => Response::error(503, "cancelled"),
+            VfsError::Other(err) => {
+                // The details, such as file paths, are only for the log.
+                log::error!("Error serving HTTP request: {:#}", err);
+                Response::error(500, "internal error")
+            }
+        }
+    }
+}
+
+/// Converts the path part of a URL into a registry path.
+fn registry_path(url_path: &str) -> Option<String> {
+    let components: Option<Vec<String>> = url_path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(percent_decode)
+        .collect();
+    Some(components?.join("\\"))
+}
+
+/// Parses a byte from two hex digits. `from_str_radix` alone would also
+/// take a sign, as in `+1`.
+fn hex_byte(hex: &[u8]) -> Option<u8> {
+    if hex.len() != 2 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            bytes.push(hex_byte(&[iter.next()?, iter.next()?])?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn data_to_json(data: Data) -> serde_json::Value {
+    match data {
+        Data::String(s) => json!(s),
+        Data::MultiString(list) => json!(list),
+        Data::Number(n) => json!(n),
+        Data::Binary(bytes) => json!({ "hex": audit::hex(&bytes) }),
+    }
+}
+
+fn data_from_json(data: &serde_json::Value) -> Option<Data> {
+    use serde_json::Value as Json;
+
+    match data {
+        Json::String(s) => Some(Data::String(s.clone())),
+        Json::Array(list) => list
+            .iter()
+            .map(|s| s.as_str().map(String::from))
+            .collect::<Option<_>>()
+            .map(Data::MultiString),
+        Json::Number(n) => n.as_u64().map(Data::Number),
+        Json::Object(object) => {
+            let hex = object.get("hex")?.as_str()?;
+            if !hex.len().is_multiple_of(2) {
+                return None;
+            }
+            hex.as_bytes()
+                .chunks(2)
+                .map(hex_byte)
+                .collect::<Option<_>>()
+                .map(Data::Binary)
+        }
+        _ => None,
+    }
+}
+
+fn get_key(fs: &RegFs, path: &str) -> Result<Response, VfsError> {
+    let source = fs.source();
+    let mut subkeys: Vec<String> = source
+        .enum_key(path)
+        .context("enumerate key")?
+        .ok_or(VfsError::NotFound)?
+        .into_iter()
+        .filter(|(_, size)| size.is_none())
+        .map(|(name, _)| name)
+        .collect();
+    subkeys.sort_unstable_by(|a, b| reg_name::cmp(a, b));
+    let mut values = source
+        .enum_values(path)
+        .context("enumerate values")?
+        .ok_or(VfsError::NotFound)?;
+    values.sort_unstable_by(|a, b| reg_name::cmp(&a.name, &b.name));
+
+    let values: Vec<_> = values
+        .into_iter()
+        .map(|value| {
+            json!({
+                "name": value.name,
+                "type": source::type_name(value.vtype),
+                "size": value.size,
+            })
+        })
+        .collect();
+    Ok(Response::json(
+        200,
+        json!({ "path": path, "subkeys": subkeys, "values": values }),
+    ))
+}
+
+fn get_value(fs: &RegFs, path: &str, raw: bool) -> Result<Response, VfsError> {
+    let value = fs
+        .source()
+        .read_value(path)
+        .context("read value")?
+        .ok_or(VfsError::NotFound)?;
+    if raw {
+        return Ok(Response {
+            status: 200,
+            content_type: "application/octet-stream",
+            body: value.bytes,
+        });
+    }
+    Ok(Response::json(
+        200,
+        json!({
+            "path": path,
+            "type": source::type_name(value.vtype),
+            "data": data_to_json(value.decode()),
+        }),
+    ))
+}
+
+fn put_value(fs: &RegFs, path: &str, body: &[u8]) -> Result<Response, VfsError> {
+    let body: serde_json::Value = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(err) => return Ok(Response::error(400, &format!("invalid JSON: {}", err))),
+    };
+    let vtype = match body["type"].as_str().and_then(source::type_from_name) {
+        Some(vtype) => vtype,
+        None => return Ok(Response::error(400, "missing or unknown type")),
+    };
+    let value = match data_from_json(&body["data"]).and_then(|data| Value::encode(vtype, &data)) {
+        Some(value) => value,
+        None => return Ok(Response::error(400, "data does not match the type")),
+    };
+
+    fs.set_value(path, &value)?;
+    Ok(Response::empty())
+}
+
+fn search(fs: &RegFs, query: &str) -> Result<Response, VfsError> {
+    let pattern = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("q="))
+        .map(|pattern| percent_decode(&pattern.replace('+', " ")));
+    let pattern = match pattern {
+        Some(Some(pattern)) => pattern,
+        Some(None) => return Ok(Response::error(400, "invalid query")),
+        None => return Ok(Response::error(400, "missing query")),
+    };
+
+    let results: Vec<_> = fs
+        .search(&pattern)
+        .context("search")?
+        .into_iter()
+        .map(|(path, size)| match size {
+            None => json!({ "path": path, "kind": "key" }),
+            Some(size) => json!({ "path": path, "kind": "value", "size": size }),
+        })
+        .collect();
+    Ok(Response::json(
+        200,
+        json!({ "query": pattern, "results": results }),
+    ))
+}
+
+/// Handles a request, given its method, URL and body.
+pub fn handle(fs: &RegFs, method: &str, url: &str, body: &[u8]) -> Response {
+    let (url_path, query) = url.split_once('?').unwrap_or((url, ""));
+    let raw = query.split('&').any(|param| param == "format=raw");
+    let (collection, path) = match url_path.trim_start_matches('/').split_once('/') {
+        Some((collection, path)) => (collection, path),
+        None => (url_path.trim_start_matches('/'), ""),
+    };
+    let path = match registry_path(path) {
+        Some(path) => path,
+        None => return Response::error(400, "invalid path"),
+    };
+
+    let result = match (method, collection) {
+        ("GET", "keys") => get_key(fs, &path),
+        ("DELETE", "keys") => fs.delete_key(&path).map(|()| Response::empty()),
+        ("GET", "values") => get_value(fs, &path, raw),
+        ("PUT", "values") => put_value(fs, &path, body),
+        ("GET", "search") if path.is_empty() => search(fs, query),
+        (_, "search") if path.is_empty() => Ok(Response::error(405, "method not allowed")),
+        (_, "keys" | "values") => Ok(Response::error(405, "method not allowed")),
+        _ => Ok(Response::error(404, "unknown endpoint")),
+    };
+    result.unwrap_or_else(Response::from)
+}
+
+/// Checks that a request was meant for this server, and not sent by a web
+/// page on another site.
+fn is_local_request(host: Option<&str>, origin: Option<&str>, port: u16) -> bool {
+    let is_local = |host: &str| {
+        let (name, host_port) = match host.rsplit_once(':') {
+            Some((name, host_port)) => (name, host_port.parse().ok()),
+            None => (host, Some(80)),
+        };
+        host_port == Some(port) && (name.eq_ignore_ascii_case("localhost") || name == "127.0.0.1")
+    };
+    let host_ok = host.is_some_and(is_local);
+    let origin_ok = match origin {
+        None => true,
+        Some(origin) => origin.strip_prefix("http://").is_some_and(is_local),
+    };
+    host_ok && origin_ok
+}
+
+/// Serves requests on the given port of localhost, one at a time.
+pub fn serve(fs: RegFs, port: u16) -> anyhow::Result<()> {
+    let server = tiny_http::Server::http(("127.0.0.1", port))
+        .map_err(|err| anyhow::anyhow!(err))
+        .context("listen")?;
+
+    for mut request in server.incoming_requests() {
+        let header = |name: &'static str| {
+            request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv(name))
+                .map(|header| header.value.as_str())
+        };
+        let mut body = Vec::new();
+        let response = if !is_local_request(header("Host"), header("Origin"), port) {
+            Response::error(403, "requests must come from localhost")
+        } else {
+            match request
+                .as_reader()
+                .take(MAX_BODY_SIZE)
+                .read_to_end(&mut body)
+            {
+                Ok(_) => handle(&fs, request.method().as_str(), request.url(), &body),
+                Err(err) => Response::error(400, &format!("failed to read body: {}", err)),
+            }
+        };
+        log::debug!(
+            "{} {}: {}",
+            request.method(),
+            request.url(),
+            response.status,
+        );
+
+        let header = tiny_http::Header::from_bytes("Content-Type", response.content_type)
+            .expect("invalid header");
+        let http_response = tiny_http::Response::from_data(response.body)
+            .with_status_code(response.status)
+            .with_header(header);
+        if let Err(err) = request.respond(http_response) {
+            log::warn!("Failed to send HTTP response: {}", err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        patch::PatchRecorder,
+        regfs::WriteMode,
+        source::{REG_BINARY, REG_DWORD, REG_MULTI_SZ, REG_SZ},
+    };
+
+    fn reg_fs() -> RegFs {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_TEST\\Software\\Vendor");
+        let values = [
+            (
+                "Name",
+                Value::encode(REG_SZ, &Data::String(String::from("Reg"))),
+            ),
+            ("Count", Value::encode(REG_DWORD, &Data::Number(3))),
+            (
+                "List",
+                Value::encode(
+                    REG_MULTI_SZ,
+                    &Data::MultiString(vec![String::from("a"), String::from("b")]),
+                ),
+            ),
+            (
+                "Blob",
+                Value::encode(REG_BINARY, &Data::Binary(vec![1, 0xab])),
+            ),
+            ("a/b", Value::encode(REG_DWORD, &Data::Number(0))),
+        ];
+        for (name, value) in values {
+            source.set_value("HKEY_TEST\\Software", name, value.unwrap());
+        }
+        RegFs::new(PathBuf::new(), Arc::new(source))
+    }
+
+    fn json(response: Response) -> (u16, serde_json::Value) {
+        let body = serde_json::from_slice(&response.body).unwrap_or_default();
+        (response.status, body)
+    }
+
+    #[test]
+    fn get() {
+        let fs = reg_fs();
+        let (status, body) = json(handle(&fs, "GET", "/keys/HKEY_TEST/software", b""));
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!({
+                "path": "HKEY_TEST\\software",
+                "subkeys": ["Vendor"],
+                "values": [
+                    { "name": "a/b", "type": "REG_DWORD", "size": 4 },
+                    { "name": "Blob", "type": "REG_BINARY", "size": 2 },
+                    { "name": "Count", "type": "REG_DWORD", "size": 4 },
+                    { "name": "List", "type": "REG_MULTI_SZ", "size": 10 },
+                    { "name": "Name", "type": "REG_SZ", "size": 8 },
+                ],
+            }),
+        );
+        let (_, body) = json(handle(&fs, "GET", "/keys", b""));
+        assert_eq!(body["subkeys"], json!(["HKEY_TEST"]));
+
+        let get = |url| json(handle(&fs, "GET", url, b"")).1["data"].clone();
+        assert_eq!(get("/values/HKEY_TEST/Software/Name"), json!("Reg"));
+        assert_eq!(get("/values/HKEY_TEST/Software/Count"), json!(3));
+        assert_eq!(get("/values/HKEY_TEST/Software/List"), json!(["a", "b"]));
+        assert_eq!(
+            get("/values/HKEY_TEST/Software/Blob"),
+            json!({ "hex": "01ab" })
+        );
+        assert_eq!(get("/values/HKEY_TEST/Software/a%2Fb"), json!(0));
+        assert_eq!(percent_decode("a%2fb%C3%A4"), Some(String::from("a/bä")));
+        for invalid in ["%+1", "%-1", "% 1", "%1", "%zz"] {
+            assert_eq!(percent_decode(invalid), None, "{}", invalid);
+        }
+        assert_eq!(handle(&fs, "GET", "/values/HKEY_TEST/%+1", b"").status, 400);
+
+        let response = handle(
+            &fs,
+            "GET",
+            "/values/HKEY_TEST/Software/Name?format=raw",
+            b"",
+        );
+        assert_eq!(response.content_type, "application/octet-stream");
+        assert_eq!(response.body, b"R\0e\0g\0\0\0");
+
+        assert_eq!(
+            handle(&fs, "GET", "/keys/HKEY_TEST/Missing", b"").status,
+            404
+        );
+        assert_eq!(
+            handle(&fs, "GET", "/values/HKEY_TEST/Software", b"").status,
+            404
+        );
+        assert_eq!(handle(&fs, "GET", "/other", b"").status, 404);
+        assert_eq!(handle(&fs, "POST", "/keys/HKEY_TEST", b"").status, 405);
+    }
+
+    #[test]
+    fn search() {
+        let fs = reg_fs();
+        let (status, body) = json(handle(&fs, "GET", "/search?q=re%67", b""));
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!({
+                "query": "reg",
+                "results": [{ "path": "HKEY_TEST\\Software\\Name", "kind": "value", "size": 8 }],
+            }),
+        );
+        let (_, body) = json(handle(&fs, "GET", "/search?q=vend", b""));
+        assert_eq!(
+            body["results"],
+            json!([{ "path": "HKEY_TEST\\Software\\Vendor", "kind": "key" }])
+        );
+        assert_eq!(handle(&fs, "GET", "/search", b"").status, 400);
+        assert_eq!(handle(&fs, "GET", "/search/x?q=a", b"").status, 404);
+        assert_eq!(handle(&fs, "POST", "/search?q=a", b"").status, 405);
+    }
+
+    #[test]
+    fn local_requests() {
+        let local = |host, origin| is_local_request(host, origin, 8080);
+        assert!(local(Some("127.0.0.1:8080"), None));
+        assert!(local(Some("LocalHost:8080"), Some("http://localhost:8080")));
+        assert!(local(Some("localhost:8080"), Some("http://127.0.0.1:8080")));
+        assert!(is_local_request(Some("localhost"), None, 80));
+
+        // DNS rebinding: a page's own name, resolved to localhost
+        assert!(!local(Some("attacker.example:8080"), None));
+        assert!(!local(Some("localhost.attacker.example:8080"), None));
+        assert!(!local(Some("localhost:8081"), None));
+        assert!(!local(Some("localhost"), None));
+        assert!(!local(None, None));
+        // Cross-site requests from another page
+        assert!(!local(
+            Some("localhost:8080"),
+            Some("https://attacker.example")
+        ));
+        assert!(!local(Some("localhost:8080"), Some("null")));
+        assert!(!local(
+            Some("localhost:8080"),
+            Some("http://localhost:8081")
+        ));
+    }
+
+    #[test]
+    fn read_only() {
+        let fs = reg_fs();
+        let body = br#"{"type": "REG_DWORD", "data": 5}"#;
+        let response = handle(&fs, "PUT", "/values/HKEY_TEST/Software/Count", body);
+        assert_eq!(response.status, 403);
+        assert_eq!(
+            handle(&fs, "DELETE", "/keys/HKEY_TEST/Software", b"").status,
+            403
+        );
+    }
+
+    #[test]
+    fn dry_run() {
+        let patch_path = std::env::temp_dir().join(format!("{}.reg", uuid::Uuid::new_v4()));
+        let recorder = PatchRecorder::create(&patch_path).unwrap();
+        let fs = reg_fs().with_mode(WriteMode::DryRun(recorder));
+
+        let put = |url, body: &str| handle(&fs, "PUT", url, body.as_bytes()).status;
+        assert_eq!(
+            put(
+                "/values/HKEY_TEST/Software/Count",
+                r#"{"type": "REG_DWORD", "data": 5}"#
+            ),
+            204
+        );
+        assert_eq!(
+            put(
+                "/values/HKEY_TEST/Software/New",
+                r#"{"type": "REG_SZ", "data": "x"}"#
+            ),
+            204
+        );
+        assert_eq!(
+            put(
+                "/values/HKEY_TEST/Missing/New",
+                r#"{"type": "REG_SZ", "data": "x"}"#
+            ),
+            404
+        );
+        assert_eq!(
+            put(
+                "/values/HKEY_TEST/Software/X",
+                r#"{"type": "REG_DWORD", "data": "x"}"#
+            ),
+            400
+        );
+        assert_eq!(
+            put(
+                "/values/HKEY_TEST/Software/X",
+                r#"{"type": "REG_FOO", "data": 1}"#
+            ),
+            400
+        );
+        assert_eq!(
+            put(
+                "/values/HKEY_TEST/Software/X",
+                r#"{"type": "REG_BINARY", "data": {"hex": "+1"}}"#
+            ),
+            400
+        );
+        assert_eq!(put("/values/HKEY_TEST/Software/X", "not json"), 400);
+        assert_eq!(
+            handle(&fs, "DELETE", "/keys/HKEY_TEST/Software/Vendor", b"").status,
+            204
+        );
+        assert_eq!(handle(&fs, "DELETE", "/keys/HKEY_TEST", b"").status, 403);
+
+        // Nothing is applied to the source.
+        let (_, body) = json(handle(&fs, "GET", "/values/HKEY_TEST/Software/Count", b""));
+        assert_eq!(body["data"], json!(3));
+
+        drop(fs);
+        let patch = std::fs::read(&patch_path).unwrap();
+        std::fs::remove_file(&patch_path).unwrap();
+        let wide: Vec<u16> = patch
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        let patch = String::from_utf16(&wide).unwrap();
+        assert!(patch.contains("[HKEY_TEST\\Software]\r\n\"Count\"=dword:00000005"));
+        assert!(patch.contains("[HKEY_TEST\\Software]\r\n\"New\"=\"x\""));
+        assert!(patch.contains("[-HKEY_TEST\\Software\\Vendor]"));
+    }
+}
@@ -0,0 +1,561 @@
+//! A search index over an offline source, so that queries do not have to walk
+//! every key of a large hive.
+//!
+//! The index maps every three-character sequence (trigram) of the folded key
+//! names, value names and decoded value data to the entries containing it.
+//! A query looks up the entries containing all trigrams of its pattern, then
+//! checks only those. Patterns shorter than three characters, or with
+//! wildcards, are checked against every entry, which is still much faster
+//! than decoding the source again.
+//!
+//! Sources are loaded into memory when they are opened, so an index built
+//! from a source stays valid for as long as the source is used. Building it
+//! takes as long as a walk of the source, so it can also be saved in the
+//! user's cache directory, and reused by later runs as long as the size,
+//! modification time and (for hives) sequence numbers of the file are
+//! unchanged. Since the saved index holds the decoded data of every value,
+//! this is only done if asked for.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    reg_name,
+    regfs::stable_hash,
+    search::Query,
+    source::{Data, KeyEntries, RegSource},
+};
+
+const MAGIC: &[u8; 8] = b"RGFSIDX1";
+
+/// Identifies the state of a source file. An index saved for a different
+/// stamp is stale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    size: u64,
+    modified: u64,
+    /// The primary and secondary sequence numbers of a hive, which change on
+    /// every write to it; zero for other files.
+    sequence: (u32, u32),
+}
+
+impl Stamp {
+    pub fn of(file: &Path) -> anyhow::Result<Stamp> {
+        let metadata = std::fs::metadata(file).context("read metadata")?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_nanos() as u64);
+
+        let mut header = [0; 12];
+        let sequence = match File::open(file).and_then(|mut f| f.read_exact(&mut header)) {
+            Ok(()) if header.starts_with(b"regf") => (
+                u32::from_le_bytes(header[4..8].try_into().unwrap()),
+                u32::from_le_bytes(header[8..12].try_into().unwrap()),
+            ),
+            _ => (0, 0),
+        };
+        Ok(Stamp {
+            size: metadata.len(),
+            modified,
+            sequence,
+        })
+    }
+}
+
+struct Entry {
+    path: String,
+    len: Option<u32>,
+    /// The folded name, followed by the folded data of a value.
+    texts: Vec<String>,
+}
+
+type Trigram = [char; 3];
+
+pub struct SearchIndex {
+    entries: Vec<Entry>,
+    /// The ascending indices of the entries containing each trigram.
+    trigrams: HashMap<Trigram, Vec<u32>>,
+}
+
+impl SearchIndex {
+    /// Builds an index by walking the whole source.
+    pub fn build(source: &dyn RegSource) -> anyhow::Result<SearchIndex> {
+        fn walk(source: &dyn RegSource, key: &str, entries: &mut Vec<Entry>) -> anyhow::Result<()> {
+            for (name, len) in source.enum_key(key)?.unwrap_or_default() {
+                let path = if key.is_empty() {
+                    name.clone()
+                } else {
+                    format!("{}\\{}", key, name)
+                };
+                let mut texts = vec![reg_name::fold(&name)];
+                if len.is_some() {
+                    if let Some(value) = source.read_value(&path)? {
+                        match value.decode() {
+                            Data::String(s) => texts.push(reg_name::fold(&s)),
+                            Data::MultiString(list) => {
+                                texts.extend(list.iter().map(|s| reg_name::fold(s)))
+                            }
+                            Data::Number(n) => texts.push(n.to_string()),
+                            Data::Binary(_) => (),
+                        }
+                    }
+                }
+                entries.push(Entry {
+                    path: path.clone(),
+                    len,
+                    texts,
+                });
+                if len.is_none() {
+                    walk(source, &path, entries)?;
+                }
+            }
+            Ok(())
+        }
+
+        let mut entries = Vec::new();
+        walk(source, "", &mut entries)?;
+        Ok(SearchIndex::from_entries(entries))
+    }
+
+    fn from_entries(entries: Vec<Entry>) -> SearchIndex {
+        let mut trigrams: HashMap<Trigram, Vec<u32>> = HashMap::new();
+        for (id, entry) in entries.iter().enumerate() {
+            let id = id as u32;
+            for text in &entry.texts {
+                let chars: Vec<char> = text.chars().collect();
+                for window in chars.windows(3) {
+                    let ids = trigrams
+                        .entry([window[0], window[1], window[2]])
+                        .or_default();
+                    if ids.last() != Some(&id) {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+        SearchIndex { entries, trigrams }
+    }
+
+    /// Returns the entries which may contain a pattern, or `None` if the
+    /// pattern is too short to narrow them down.
+    fn candidates(&self, pattern: &str) -> Option<Vec<u32>> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut lists = Vec::new();
+        for window in chars.windows(3) {
+            match self.trigrams.get(&[window[0], window[1], window[2]]) {
+                Some(ids) => lists.push(ids),
+                None => return Some(Vec::new()),
+            }
+        }
+        lists.sort_unstable_by_key(|ids| ids.len());
+        let (first, rest) = lists.split_first()?;
+        let mut ids = first.to_vec();
+        for list in rest {
+            ids.retain(|id| list.binary_search(id).is_ok());
+        }
+        Some(ids)
+    }
+
+    /// Runs a query, returning the same results as walking the source.
+    pub fn lookup(&self, query: &Query) -> KeyEntries {
+        let candidates = match query.plain_pattern() {
+            Some(pattern) => self.candidates(pattern),
+            None => None,
+        };
+        let matches = |entry: &Entry| entry.texts.iter().any(|text| query.matches_folded(text));
+        let results = |entry: &Entry| (entry.path.clone(), entry.len);
+        match candidates {
+            Some(ids) => ids
+                .into_iter()
+                .map(|id| &self.entries[id as usize])
+                .filter(|entry| matches(entry))
+                .map(results)
+                .collect(),
+            None => self
+                .entries
+                .iter()
+                .filter(|entry| matches(entry))
+                .map(results)
+                .collect(),
+        }
+    }
+
+    fn save(&self, path: &Path, stamp: Stamp) -> anyhow::Result<()> {
+        fn write_str(out: &mut impl Write, s: &str) -> std::io::Result<()> {
+            out.write_all(&(s.len() as u32).to_le_bytes())?;
+            out.write_all(s.as_bytes())
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("create cache directory")?;
+        }
+        let mut out = BufWriter::new(File::create(path).context("create file")?);
+        out.write_all(MAGIC)?;
+        out.write_all(&stamp.size.to_le_bytes())?;
+        out.write_all(&stamp.modified.to_le_bytes())?;
+        out.write_all(&stamp.sequence.0.to_le_bytes())?;
+        out.write_all(&stamp.sequence.1.to_le_bytes())?;
+        out.write_all(&(self.entries.len() as u32).to_le_bytes())?;
+        for entry in &self.entries {
+            write_str(&mut out, &entry.path)?;
+            // Values are never as large as 4 GiB.
+            out.write_all(&entry.len.unwrap_or(u32::MAX).to_le_bytes())?;
+            out.write_all(&(entry.texts.len() as u32).to_le_bytes())?;
+            for text in &entry.texts {
+                write_str(&mut out, text)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Loads a saved index, or returns `None` if it was saved for a different
+    /// state of the file. Lengths read from the file are not trusted: a
+    /// damaged file fails to load rather than make for huge allocations.
+    fn load(path: &Path, stamp: Stamp) -> anyhow::Result<Option<SearchIndex>> {
+        fn read_u32(input: &mut impl Read) -> std::io::Result<u32> {
+            let mut buf = [0; 4];
+            input.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        }
+        fn read_u64(input: &mut impl Read) -> std::io::Result<u64> {
+            let mut buf = [0; 8];
+            input.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        }
+        fn read_str(input: &mut impl Read) -> anyhow::Result<String> {
+            let len = read_u32(input)? as u64;
+            let mut buf = Vec::new();
+            input.take(len).read_to_end(&mut buf)?;
+            if buf.len() as u64 != len {
+                bail!("truncated index file");
+            }
+            Ok(String::from_utf8(buf)?)
+        }
+
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut input = BufReader::new(file);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not an index file");
+        }
+        let saved = Stamp {
+            size: read_u64(&mut input)?,
+            modified: read_u64(&mut input)?,
+            sequence: (read_u32(&mut input)?, read_u32(&mut input)?),
+        };
+        if saved != stamp {
+            return Ok(None);
+        }
+
+        let count = read_u32(&mut input)?;
+        // Each entry takes at least 12 bytes of the file.
+        if count as u64 > file_size / 12 {
+            bail!("corrupt index file");
+        }
+        let mut entries = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let path = read_str(&mut input)?;
+            let len = Some(read_u32(&mut input)?).filter(|&len| len != u32::MAX);
+            let mut texts = Vec::new();
+            for _ in 0..read_u32(&mut input)? {
+                texts.push(read_str(&mut input)?);
+            }
+            entries.push(Entry { path, len, texts });
+        }
+        Ok(Some(SearchIndex::from_entries(entries)))
+    }
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("regfs").join("cache"))
+    } else {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|dir| dir.join("regfs"))
+    }
+}
+
+/// Names the saved index of a file after the file, and a hash of its full
+/// path to tell apart files with the same name.
+fn cache_path(file: &Path) -> Option<PathBuf> {
+    let file = file.canonicalize().ok()?;
+    let hash = stable_hash(file.as_os_str().as_encoded_bytes());
+    let name = file.file_name()?.to_string_lossy();
+    Some(cache_dir()?.join(format!("{}-{:016x}.idx", name, hash)))
+}
+
+/// The index of an offline source file, which is only loaded or built once a
+/// query needs it.
+pub struct LazyIndex {
+    file: PathBuf,
+    /// The state of the file when the source was read from it, which a saved
+    /// index must have been built from.
+    stamp: Option<Stamp>,
+    /// Where the index is saved, if it is.
+    cache_path: Option<PathBuf>,
+    index: OnceLock<Option<SearchIndex>>,
+}
+
+impl LazyIndex {
+    /// Creates the index of a file, which should be called before the
+    /// source is read from the file. Should the file change in between, the
+    /// index is then saved as that of the earlier state, and not reused.
+    pub fn new(file: PathBuf) -> LazyIndex {
+        let stamp = Stamp::of(&file)
+            .map_err(|err| log::warn!("Cannot stamp {}: {:#}", file.display(), err))
+            .ok();
+        LazyIndex {
+            file,
+            stamp,
+            cache_path: None,
+            index: OnceLock::new(),
+        }
+    }
+
+    /// Saves and loads the index in the user's cache directory.
+    pub fn cached(mut self) -> LazyIndex {
+        self.cache_path = cache_path(&self.file);
+        self
+    }
+
+    /// Removes the index saved for the file, if any, returning its path.
+    pub fn remove_cached(&self) -> anyhow::Result<Option<PathBuf>> {
+        let path = match cache_path(&self.file) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(Some(path)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("remove {}", path.display())),
+        }
+    }
+
+    /// Saves and loads the index at the given path, instead of the cache
+    /// directory.
+    #[cfg(test)]
+    pub fn with_cache_path(mut self, cache_path: PathBuf) -> LazyIndex {
+        self.cache_path = Some(cache_path);
+        self
+    }
+
+    /// Returns the index of the source, which must have been loaded from the
+    /// file. If the index can neither be loaded nor built, `None` is
+    /// returned, and queries should walk the source instead.
+    pub fn get(&self, source: &dyn RegSource) -> Option<&SearchIndex> {
+        self.index
+            .get_or_init(|| {
+                let stamp = self.stamp;
+                if let (Some(cache_path), Some(stamp)) = (&self.cache_path, stamp) {
+                    match SearchIndex::load(cache_path, stamp) {
+                        Ok(Some(index)) => {
+                            log::debug!("Loaded search index from {}", cache_path.display());
+                            return Some(index);
+                        }
+                        Ok(None) => log::info!("Search index of {} is stale", self.file.display()),
+                        Err(err) => {
+                            log::debug!("No search index at {}: {:#}", cache_path.display(), err,)
+                        }
+                    }
+                }
+
+                let start = Instant::now();
+                let index = SearchIndex::build(source)
+                    .map_err(|err| log::warn!("Failed to build search index: {:#}", err))
+                    .ok()?;
+                log::info!(
+                    "Built search index of {} entries in {:?}",
+                    index.entries.len(),
+                    start.elapsed(),
+                );
+                if let (Some(cache_path), Some(stamp)) = (&self.cache_path, stamp) {
+                    if let Err(err) = index.save(cache_path, stamp) {
+                        log::warn!(
+                            "Failed to save search index to {}: {:#}",
+                            cache_path.display(),
+                            err,
+                        );
+                    }
+                }
+                Some(index)
+            })
+            .as_ref()
+    }
+}
+
+/// Runs a query with the index if there is one, or by walking the source.
+pub fn search(
+    source: &dyn RegSource,
+    index: Option<&LazyIndex>,
+    pattern: &str,
+) -> anyhow::Result<KeyEntries> {
+    let query = Query::new(pattern);
+    match index.and_then(|index| index.get(source)) {
+        Some(index) => Ok(index.lookup(&query)),
+        None => query.run(source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        reg_file,
+        source::{Value, REG_BINARY, REG_DWORD, REG_MULTI_SZ, REG_SZ},
+    };
+
+    fn source() -> MemSource {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_TEST\\Software\\Contoso\\Version Info");
+        source.create_key("HKEY_TEST\\Software\\Fabrikam");
+        let value = |vtype, data| Value::encode(vtype, &data).unwrap();
+        let values = [
+            (
+                "Contoso",
+                "Name",
+                value(REG_SZ, Data::String("Widget Pro".into())),
+            ),
+            ("Contoso", "Build", value(REG_DWORD, Data::Number(19041))),
+            (
+                "Fabrikam",
+                "Paths",
+                value(
+                    REG_MULTI_SZ,
+                    Data::MultiString(vec!["C:\\Widgets".into(), "D:\\".into()]),
+                ),
+            ),
+            (
+                "Fabrikam",
+                "Blob",
+                value(REG_BINARY, Data::Binary(b"widget".to_vec())),
+            ),
+        ];
+        for (key, name, value) in values {
+            source.set_value(&format!("HKEY_TEST\\Software\\{}", key), name, value);
+        }
+        source
+    }
+
+    #[test]
+    fn same_results_as_walking() {
+        let source = source();
+        let index = SearchIndex::build(&source).unwrap();
+        for pattern in [
+            "widget",
+            "WIDGET PRO",
+            "version",
+            "904",
+            "19041",
+            "s",
+            "so",
+            "zzz",
+            "*ware",
+            "?:\\*",
+            "contoso\\name",
+            "",
+        ] {
+            let query = Query::new(pattern);
+            assert_eq!(
+                index.lookup(&query),
+                query.run(&source).unwrap(),
+                "{:?}",
+                pattern
+            );
+        }
+        assert_eq!(
+            index.lookup(&Query::new("widget")),
+            [
+                (String::from("HKEY_TEST\\Software\\Contoso\\Name"), Some(22)),
+                (
+                    String::from("HKEY_TEST\\Software\\Fabrikam\\Paths"),
+                    Some(32)
+                ),
+            ],
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let dir = std::env::temp_dir().join(format!("regfs-index-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("test.reg");
+        let cache_path = dir.join("test.idx");
+        let write = |source: &MemSource| {
+            let text = reg_file::export(source, "HKEY_TEST").unwrap();
+            std::fs::write(&file, text).unwrap();
+        };
+
+        let source = source();
+        write(&source);
+        let lookup = |source: &MemSource| {
+            let index = LazyIndex::new(file.clone()).with_cache_path(cache_path.clone());
+            index
+                .get(source)
+                .unwrap()
+                .lookup(&Query::new("gadget"))
+                .len()
+        };
+        assert_eq!(lookup(&source), 0);
+        assert!(cache_path.exists());
+
+        // The saved index is used as long as the file is unchanged, even
+        // though this source differs from the file.
+        let mut changed = self::source();
+        changed.set_value(
+            "HKEY_TEST\\Software\\Contoso",
+            "Name",
+            Value::encode(REG_SZ, &Data::String("Gadget".into())).unwrap(),
+        );
+        assert_eq!(lookup(&changed), 0);
+
+        // Once the file changes, the index is rebuilt.
+        write(&changed);
+        assert_eq!(lookup(&changed), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_cache() {
+        let path = std::env::temp_dir().join(format!("regfs-index-{}.idx", uuid::Uuid::new_v4()));
+        let stamp = Stamp {
+            size: 1,
+            modified: 2,
+            sequence: (3, 4),
+        };
+        let index = SearchIndex::build(&source()).unwrap();
+        index.save(&path, stamp).unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        assert!(SearchIndex::load(&path, stamp).unwrap().is_some());
+
+        // Lengths far beyond the end of the file
+        let header = MAGIC.len() + 8 + 8 + 4 + 4;
+        for offset in [header, header + 4] {
+            let mut damaged = saved.clone();
+            damaged[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            std::fs::write(&path, &damaged).unwrap();
+            assert!(SearchIndex::load(&path, stamp).is_err());
+        }
+        // Truncated
+        std::fs::write(&path, &saved[..saved.len() - 1]).unwrap();
+        assert!(SearchIndex::load(&path, stamp).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,49 +1,695 @@
+mod audit;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod cleanup;
+mod cli;
+mod commands;
+mod config;
 mod dir_enum;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod file_data;
+mod file_name;
+#[cfg(windows)]
 mod fs_helper;
+#[cfg(target_os = "linux")]
+mod fuse_fs;
+mod hive;
+mod http_api;
+mod index;
+mod mem_source;
+mod ninep_fs;
+mod patch;
+#[cfg(windows)]
 mod projfs;
+#[cfg(test)]
+mod projfs_sim;
+mod reg_file;
+mod reg_name;
+#[cfg(windows)]
 mod reg_ops;
 mod regfs;
+mod render;
+mod search;
+mod service;
+mod shell;
 #[allow(unused)]
 mod simple_fs;
+mod source;
+mod vfs;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod virt_root;
+mod watch;
 
-use std::path::PathBuf;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use projfs::ProjFs;
-use windows::{core::PCWSTR, Win32::Storage::ProjectedFileSystem::*};
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+
+use config::{Config, LogLevel};
+use regfs::Rendering;
+use source::{RegSource, SourceSpec};
+
+/// Projects the Windows registry as a file system, or queries it directly.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Read settings from a TOML configuration file; flags override them
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Log messages of this level and above, overriding `RUST_LOG`
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+    /// Read the registry from an offline hive file instead of the running
+    /// system
+    #[arg(long, global = true, value_name = "FILE", conflicts_with = "reg")]
+    hive: Option<PathBuf>,
+    /// Read the registry from a registry editor (.reg) file instead of the
+    /// running system
+    #[arg(long, global = true, value_name = "FILE")]
+    reg: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the subkeys and values of a key
+    Ls {
+        /// Key to list; `\` separates the components
+        path: Option<String>,
+        /// Also print the type and size of each value
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// Print the data of a value
+    Cat {
+        value: String,
+        /// Print the data as stored, byte for byte
+        #[arg(long, group = "format")]
+        raw: bool,
+        /// Print the data interpreted according to its type (default)
+        #[arg(long, group = "format")]
+        decoded: bool,
+        /// Print the type of the value
+        #[arg(long = "type", group = "format")]
+        type_only: bool,
+    },
+    /// Print a key and everything below it as a tree
+    Tree { path: Option<String> },
+    /// Print the paths of keys and values whose names match a wildcard
+    /// pattern
+    Find {
+        pattern: String,
+        /// Key to search below
+        path: Option<String>,
+    },
+    /// Print the paths of keys and values whose names or data contain a
+    /// pattern, using a search index for offline sources
+    Search { pattern: String },
+    /// Export a key and everything below it as a .reg file
+    Export {
+        path: Option<String>,
+        /// File to write instead of standard output
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Browse the registry interactively, with tab completion of names
+    Shell,
+    /// Project the registry at a directory, with ProjFS on Windows or FUSE on
+    /// Linux
+    Mount {
+        /// Directory to project at; `mount.root` in the configuration file
+        /// if omitted
+        root_path: Option<PathBuf>,
+        #[command(flatten)]
+        write: WriteOptions,
+        /// How to show values as files
+        #[arg(long)]
+        render: Option<Rendering>,
+        /// Number of threads ProjFS keeps for callbacks; 0 lets it choose
+        #[arg(long, value_name = "N")]
+        pool_threads: Option<u32>,
+        /// Number of callbacks ProjFS runs at once; 0 lets it choose
+        #[arg(long, value_name = "N")]
+        concurrent_threads: Option<u32>,
+        /// Run without console interaction, until SIGINT or SIGTERM (Ctrl-C
+        /// or closing the console on Windows)
+        #[arg(long, overrides_with = "no_daemon")]
+        daemon: bool,
+        /// Stop on Enter too, even if `mount.daemon` is set
+        #[arg(long, overrides_with = "daemon")]
+        no_daemon: bool,
+        /// Write the process ID to this file once the projection is ready
+        #[arg(long, value_name = "FILE")]
+        pid_file: Option<PathBuf>,
+        /// Delete and re-create the directory if it is a virtualization root
+        /// for another source (or for this one)
+        #[arg(long)]
+        reinit: bool,
+        /// Follow changes to the source, and update the files they make
+        /// stale
+        #[arg(long, overrides_with = "no_watch")]
+        watch: bool,
+        /// Do not follow changes, even if `mount.watch` is set
+        #[arg(long, overrides_with = "watch")]
+        no_watch: bool,
+    },
+    /// Remove the files a projection left in its root, once it is stopped
+    Cleanup {
+        /// Root of the projection; `mount.root` in the configuration file if
+        /// omitted
+        root_path: Option<PathBuf>,
+        /// Remove the whole root, including files changed locally and the
+        /// instance ID
+        #[arg(long)]
+        remove_root: bool,
+    },
+    /// Serve the registry read-only over 9P2000.L
+    #[command(name = "serve-9p")]
+    Serve9p {
+        /// `tcp:<host>:<port>` or `unix:<path>`
+        address: ninep_fs::Address,
+        /// How to show values as files
+        #[arg(long)]
+        render: Option<Rendering>,
+        /// Accept TCP connections from other hosts, although they are not
+        /// authenticated
+        #[arg(long)]
+        allow_remote: bool,
+    },
+    /// Serve the registry as a JSON API on localhost
+    ServeHttp {
+        port: u16,
+        #[command(flatten)]
+        write: WriteOptions,
+    },
+}
+
+/// What happens to changes made through a frontend.
+#[derive(clap::Args)]
+struct WriteOptions {
+    /// Append a JSON line for every attempted change to this file
+    #[arg(long, value_name = "FILE")]
+    audit_log: Option<PathBuf>,
+    /// Record changes to this .reg file instead of applying them
+    #[arg(long, value_name = "PATCH FILE")]
+    dry_run: Option<PathBuf>,
+    /// Record the keys of a hive source below this key (guessed from the
+    /// hive's file name by default)
+    #[arg(long, value_name = "KEY")]
+    patch_root: Option<String>,
+}
 
 fn main() {
-    env_logger::init();
+    let args = Args::parse();
+    let config = configure(&args).unwrap_or_else(|err| {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    });
+    if let Err(err) = init_logging(&config.log, args.log_level) {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    }
 
-    let mut args = std::env::args_os();
-    if args.len() != 2 {
-        eprintln!("Usage: regfs-rs.exe <Virtualization Root Path>");
+    // Only offline sources are indexed; the live registry keeps changing.
+    // The index is set up first, as it records the state of the file that
+    // the source is then read from.
+    let spec = config.source_spec();
+    let index = match &spec {
+        SourceSpec::Hive(path) | SourceSpec::RegFile(path) => {
+            let index = index::LazyIndex::new(path.clone());
+            Some(if config.search.cache_index {
+                index.cached()
+            } else {
+                index
+            })
+        }
+        SourceSpec::Live => None,
+    };
+    let source = spec.open().unwrap_or_else(|err| {
+        eprintln!("Failed to open registry source: {:#}", err);
+        std::process::exit(1);
+    });
+
+    if let Err(err) = run_command(args.command, source, index, &config) {
+        eprintln!("{:#}", err);
         std::process::exit(1);
     }
+}
 
-    let root_path = PathBuf::from(args.nth(1).unwrap());
+/// Reads the configuration file, if any, and applies the flags given on the
+/// command line on top of it.
+fn configure(args: &Args) -> anyhow::Result<Config> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+
+    // The flags pick one source, whichever the configuration file picks.
+    if args.hive.is_some() || args.reg.is_some() {
+        config.source.hive = args.hive.clone();
+        config.source.reg = args.reg.clone();
+    }
 
-    let mut notification_mappings = PRJ_NOTIFICATION_MAPPING {
-        NotificationBitMask: PRJ_NOTIFY_FILE_OPENED | PRJ_NOTIFY_PRE_RENAME | PRJ_NOTIFY_PRE_DELETE,
-        NotificationRoot: PCWSTR::from_raw(b"\0\0".as_ptr().cast()),
+    fn set<T: Clone>(setting: &mut Option<T>, flag: &Option<T>) {
+        if flag.is_some() {
+            setting.clone_from(flag);
+        }
+    }
+    /// Applies a pair of flags such as `--watch` and `--no-watch`, of which
+    /// clap keeps only the last given.
+    fn switch(setting: &mut bool, on: bool, off: bool) {
+        if on || off {
+            *setting = on;
+        }
+    }
+    let (write, render) = match &args.command {
+        Command::Mount {
+            root_path,
+            write,
+            render,
+            pool_threads,
+            concurrent_threads,
+            daemon,
+            no_daemon,
+            pid_file,
+            reinit,
+            watch,
+            no_watch,
+        } => {
+            set(&mut config.mount.root, root_path);
+            set(&mut config.mount.pid_file, pid_file);
+            switch(&mut config.mount.daemon, *daemon, *no_daemon);
+            switch(&mut config.mount.watch, *watch, *no_watch);
+            config.mount.reinit = *reinit;
+            if let Some(n) = pool_threads {
+                config.projfs.pool_threads = *n;
+            }
+            if let Some(n) = concurrent_threads {
+                config.projfs.concurrent_threads = *n;
+            }
+            (Some(write), *render)
+        }
+        Command::Cleanup { root_path, .. } => {
+            set(&mut config.mount.root, root_path);
+            (None, None)
+        }
+        Command::Serve9p { render, .. } => (None, *render),
+        // The API returns values decoded or raw, as asked for in each
+        // request, so it has no rendering.
+        Command::ServeHttp { write, .. } => (Some(write), None),
+        _ => (None, None),
     };
-    let opts = PRJ_STARTVIRTUALIZING_OPTIONS {
+    if let Some(write) = write {
+        set(&mut config.write.audit_log, &write.audit_log);
+        set(&mut config.write.dry_run, &write.dry_run);
+        set(&mut config.write.patch_root, &write.patch_root);
+    }
+    if let Some(render) = render {
+        config.mount.render = render;
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+/// Sets up logging at the level from the configuration file, overridden by
+/// `RUST_LOG`, overridden by `--log-level`.
+fn init_logging(config: &config::LogConfig, level: Option<LogLevel>) -> anyhow::Result<()> {
+    let mut builder = env_logger::Builder::new();
+    if let Some(level) = config.level {
+        builder.filter_level(level.into());
+    }
+    builder.parse_default_env();
+    if let Some(level) = level {
+        builder.filter_level(level.into());
+    }
+    if let Some(path) = &config.file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open log file {}", path.display()))?;
+        builder
+            .target(env_logger::Target::Pipe(Box::new(file)))
+            .write_style(env_logger::WriteStyle::Never);
+    }
+    builder.init();
+    Ok(())
+}
+
+fn run_command(
+    command: Command,
+    source: Arc<dyn RegSource>,
+    index: Option<index::LazyIndex>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let path = |path: Option<String>| cli::source_path(&path.unwrap_or_default());
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match command {
+        Command::Ls { path: p, long } => cli::ls(&*source, &path(p), long, &mut out),
+        Command::Cat {
+            value,
+            raw,
+            decoded: _,
+            type_only,
+        } => {
+            let format = match (raw, type_only) {
+                (true, _) => cli::CatFormat::Raw,
+                (_, true) => cli::CatFormat::Type,
+                _ => cli::CatFormat::Decoded,
+            };
+            cli::cat(&*source, &cli::source_path(&value), format, &mut out)
+        }
+        Command::Tree { path: p } => cli::tree(&*source, &path(p), &mut out),
+        Command::Find { pattern, path: p } => cli::find(&*source, &pattern, &path(p), &mut out),
+        Command::Search { pattern } => cli::search(&*source, index.as_ref(), &pattern, &mut out),
+        Command::Export { path: p, output } => match output {
+            Some(output) => {
+                let mut file = std::fs::File::create(output)?;
+                cli::export(&*source, &path(p), &mut file)?;
+                Ok(file.flush()?)
+            }
+            None => cli::export(&*source, &path(p), &mut out),
+        },
+        Command::Shell => {
+            drop(out);
+            shell::run(source)
+        }
+        Command::Mount { .. } => {
+            drop(out);
+            let root_path = config.mount.root.clone().context(
+                "no directory to mount at; give one, or set `mount.root` in the configuration file",
+            )?;
+            run(root_path, source, index, config)
+        }
+        Command::Cleanup { remove_root, .. } => {
+            let root_path = config.mount.root.clone().context(
+                "no root to clean up; give one, or set `mount.root` in the configuration file",
+            )?;
+            // The saved index goes too, even if saving has since been turned
+            // off.
+            if let Some(index) = &index {
+                if let Some(removed) = index.remove_cached()? {
+                    writeln!(out, "Removed the saved search index {}", removed.display())?;
+                }
+            }
+            let report = clean_up(&root_path, remove_root)?;
+            report.print(&mut out)
+        }
+        Command::Serve9p {
+            address,
+            allow_remote,
+            ..
+        } => {
+            drop(out);
+            if config.write.audit_log.is_some() || config.write.dry_run.is_some() {
+                eprintln!("The 9P server is read-only; the [write] settings are ignored.");
+            }
+            println!("Serving registry over 9P at {}", address);
+            let mut backend =
+                regfs::RegFs::new(PathBuf::new(), source).with_rendering(config.mount.render);
+            if let Some(index) = index {
+                backend = backend.with_index(index);
+            }
+            ninep_fs::serve(backend, &address, allow_remote)
+        }
+        Command::ServeHttp { port, .. } => {
+            drop(out);
+            println!("Serving HTTP API at http://127.0.0.1:{}/", port);
+            http_api::serve(build_reg_fs(PathBuf::new(), source, index, config)?, port)
+        }
+    }
+}
+
+/// Creates the registry backend, with the write policy and rendering from the
+/// configuration.
+fn build_reg_fs(
+    root_path: PathBuf,
+    source: Arc<dyn RegSource>,
+    index: Option<index::LazyIndex>,
+    config: &Config,
+) -> anyhow::Result<regfs::RegFs> {
+    let write = &config.write;
+    let mut backend = regfs::RegFs::new(root_path, source).with_rendering(config.mount.render);
+    if let Some(index) = index {
+        backend = backend.with_index(index);
+    }
+    if let Some(audit_log_path) = &write.audit_log {
+        let audit_log = audit::AuditLog::open(audit_log_path)
+            .with_context(|| format!("open audit log {}", audit_log_path.display()))?;
+        backend = backend.with_audit_log(audit_log);
+    }
+    if let Some(patch_path) = &write.dry_run {
+        let root = patch_root(config)?;
+        let recorder = patch::PatchRecorder::create(patch_path)
+            .with_context(|| format!("create patch file {}", patch_path.display()))?
+            .with_root(&root);
+        backend = backend.with_mode(regfs::WriteMode::DryRun(recorder));
+    }
+    Ok(backend)
+}
+
+/// Finds the key that the source's root is recorded as in a patch. Sources
+/// other than hives start at the predefined keys, and need none.
+fn patch_root(config: &Config) -> anyhow::Result<String> {
+    if let Some(root) = &config.write.patch_root {
+        return Ok(root.clone());
+    }
+    match config.source_spec() {
+        SourceSpec::Hive(path) => patch::hive_root(&path).map(String::from).with_context(|| {
+            format!(
+                "cannot tell where {} is loaded in the registry; pass --patch-root \
+                 (e.g. HKEY_LOCAL_MACHINE\\SOFTWARE)",
+                path.display()
+            )
+        }),
+        SourceSpec::Live | SourceSpec::RegFile(_) => Ok(String::new()),
+    }
+}
+
+#[cfg(windows)]
+fn clean_up(root_path: &Path, remove_root: bool) -> anyhow::Result<cleanup::CleanupReport> {
+    cleanup::cleanup(
+        root_path,
+        remove_root,
+        &projfs::OnDiskFileStates,
+        &projfs::EmptyProjection,
+        &virt_root::RootRecords::open_default()?,
+    )
+}
+
+#[cfg(not(windows))]
+fn clean_up(_root_path: &Path, _remove_root: bool) -> anyhow::Result<cleanup::CleanupReport> {
+    anyhow::bail!("Virtualization roots are only used by the ProjFS frontend on Windows.");
+}
+
+/// Makes the source reloadable if it is to be watched. The search index is
+/// not used then, since it is only rebuilt on the next run.
+fn reloadable(
+    source: Arc<dyn RegSource>,
+    index: Option<index::LazyIndex>,
+    config: &Config,
+) -> (
+    Arc<dyn RegSource>,
+    Option<index::LazyIndex>,
+    Option<Arc<watch::ReloadableSource>>,
+) {
+    if !config.mount.watch {
+        return (source, index, None);
+    }
+    let reloadable = Arc::new(watch::ReloadableSource::new(config.source_spec(), source));
+    (reloadable.clone(), None, Some(reloadable))
+}
+
+/// Reloads a watched source, returning whether that worked. On failure, the
+/// old contents are kept.
+fn reload(source: &watch::ReloadableSource) -> bool {
+    match source.reload() {
+        Ok(()) => {
+            log::info!("Reloaded the changed source");
+            true
+        }
+        Err(err) => {
+            log::error!("Failed to reload the changed source: {:#}", err);
+            false
+        }
+    }
+}
+
+#[cfg(windows)]
+fn run(
+    root_path: PathBuf,
+    source: Arc<dyn RegSource>,
+    index: Option<index::LazyIndex>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    use projfs::NotificationMappings;
+    use windows::Win32::Storage::ProjectedFileSystem::*;
+
+    let (source, index, reloadable) = reloadable(source, index, config);
+    let source_name = virt_root::source_name(&config.source_spec());
+    let backend =
+        build_reg_fs(root_path.clone(), source, index, config)?.with_provider_id(&source_name);
+
+    let mut notification_mappings = NotificationMappings::new(
+        config
+            .projfs
+            .notifications
+            .iter()
+            .map(|mapping| (mapping.path.as_str(), mapping.events.as_slice())),
+    );
+    let mut opts = PRJ_STARTVIRTUALIZING_OPTIONS {
         Flags: PRJ_FLAG_NONE,
-        PoolThreadCount: 1,
-        ConcurrentThreadCount: 1,
-        NotificationMappings: &mut notification_mappings,
-        NotificationMappingsCount: 1,
+        PoolThreadCount: config.projfs.pool_threads,
+        ConcurrentThreadCount: config.projfs.concurrent_threads,
+        NotificationMappings: std::ptr::null_mut(),
+        NotificationMappingsCount: 0,
     };
+    notification_mappings.apply(&mut opts);
 
-    let mut proj_fs = ProjFs::new(root_path, opts, regfs::RegFs::new());
-    proj_fs
-        .start()
-        .expect("failed to start projection file system");
+    virt_root::prepare(
+        &root_path,
+        &source_name,
+        config.mount.reinit,
+        &virt_root::RootRecords::open_default()?,
+        &projfs::PlaceholderMarker,
+        &projfs::OnDiskFileStates,
+        &projfs::EmptyProjection,
+    )?;
 
-    println!("Press Enter to stop projection.");
-    let mut buf = String::new();
-    std::io::stdin()
-        .read_line(&mut buf)
-        .expect("failed to read stdin");
+    match reloadable {
+        Some(source) => {
+            let notifier = watch::notifier(&config.source_spec())?;
+            let backend = watch::Tracked::new(backend);
+            let tracked = backend.clone();
+            project(root_path, opts, backend, config, move |proj_fs| {
+                let invalidator = proj_fs.invalidator();
+                let watcher = watch::Watcher::spawn(notifier, move || {
+                    if reload(&source) {
+                        let changed = tracked.refresh(&invalidator);
+                        log::info!("Updated {} stale placeholders", changed);
+                    }
+                })?;
+                Ok(Some(watcher))
+            })
+        }
+        None => project(root_path, opts, backend, config, |_| Ok(None)),
+    }
+}
+
+/// Runs the projection until asked to stop, along with the watcher started
+/// by `watch` once it is running. Placeholders left by earlier runs are
+/// brought up to date first.
+#[cfg(windows)]
+fn project<B: vfs::VirtualFs + 'static>(
+    root_path: PathBuf,
+    opts: windows::Win32::Storage::ProjectedFileSystem::PRJ_STARTVIRTUALIZING_OPTIONS,
+    backend: B,
+    config: &Config,
+    watch: impl FnOnce(&projfs::ProjFs<B>) -> anyhow::Result<Option<watch::Watcher>>,
+) -> anyhow::Result<()> {
+    // Signals are caught from the start, so that the projection is always
+    // stopped once it has started.
+    let stop = service::StopSignal::install()?;
+    let on_disk = watch::placeholders_on_disk(&root_path, &projfs::OnDiskFileStates)
+        .context("find placeholders from earlier runs")?;
+    let mut proj_fs = projfs::ProjFs::new(root_path, opts, backend);
+    proj_fs.start().context("start projection file system")?;
+    let refreshed = watch::refresh_on_disk(&on_disk, proj_fs.backend(), &proj_fs.invalidator());
+    log::info!(
+        "Checked {} placeholders from earlier runs: {} current or updated, {} deleted, {} left \
+         as they are",
+        on_disk.len(),
+        refreshed.updated,
+        refreshed.deleted,
+        refreshed.failed,
+    );
+    let watcher = watch(&proj_fs)?;
+    let _pid_file = service::ready(config.mount.pid_file.as_deref())?;
 
+    if !config.mount.daemon {
+        stop.watch_console();
+        println!("Press Enter or Ctrl-C to stop projection.");
+    }
+    service::stopping(stop.wait());
+    // Placeholders can only be updated while the projection is running.
+    if let Some(watcher) = watcher {
+        watcher.stop();
+    }
     proj_fs.stop();
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn run(
+    root_path: PathBuf,
+    source: Arc<dyn RegSource>,
+    index: Option<index::LazyIndex>,
+    config: &Config,
+) -> anyhow::Result<()> {
+    if config.write.audit_log.is_some() || config.write.dry_run.is_some() {
+        eprintln!("The FUSE file system is read-only; the [write] settings are ignored.");
+    }
+
+    // FUSE asks for everything again once its caches expire, so reloading
+    // the source is enough.
+    let (source, index, reloadable) = reloadable(source, index, config);
+    let watcher = match reloadable {
+        Some(source) => Some(watch::Watcher::spawn(
+            watch::notifier(&config.source_spec())?,
+            move || {
+                reload(&source);
+            },
+        )?),
+        None => None,
+    };
+
+    let stop = service::StopSignal::install()?;
+    let mut backend =
+        regfs::RegFs::new(root_path.clone(), source).with_rendering(config.mount.render);
+    if let Some(index) = index {
+        backend = backend.with_index(index);
+    }
+    let session = fuse_fs::spawn_mount(backend, &root_path)?;
+    let _pid_file = service::ready(config.mount.pid_file.as_deref())?;
+
+    if !config.mount.daemon {
+        stop.watch_console();
+        println!(
+            "Serving registry at {}; press Enter or Ctrl-C, or unmount it, to stop.",
+            root_path.display()
+        );
+    }
+    let result = loop {
+        if let Some(reason) = stop.wait_timeout(std::time::Duration::from_millis(200)) {
+            service::stopping(reason);
+            break session
+                .umount_and_join()
+                .context("unmount FUSE file system");
+        }
+        if session.guard.is_finished() {
+            log::info!("Unmounted {}", root_path.display());
+            break session.join().context("serve FUSE file system");
+        }
+    };
+    if let Some(watcher) = watcher {
+        watcher.stop();
+    }
+    result
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn run(
+    _root_path: PathBuf,
+    _source: Arc<dyn RegSource>,
+    _index: Option<index::LazyIndex>,
+    _config: &Config,
+) -> anyhow::Result<()> {
+    anyhow::bail!("No file system frontend is available on this platform.");
 }
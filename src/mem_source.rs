@@ -0,0 +1,299 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use crate::{
+    reg_name::sort_key,
+    source::{split_value_path, KeyEntries, RegSource, Value, ValueEntry},
+};
+
+/// A registry tree held entirely in memory, such as one loaded from a `.reg`
+/// file.
+#[derive(Default)]
+pub struct MemSource {
+    root: MemKey,
+}
+
+#[derive(Default)]
+struct MemKey {
+    // Both maps are indexed by the sort keys of names, so that they are kept
+    // in the order the registry lists them, and hold the original names
+    // alongside the items.
+    subkeys: BTreeMap<Vec<u16>, (String, MemKey)>,
+    values: BTreeMap<Vec<u16>, (String, Value)>,
+}
+
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('\\').filter(|name| !name.is_empty())
+}
+
+impl MemSource {
+    pub fn new() -> MemSource {
+        MemSource::default()
+    }
+
+    fn key(&self, path: &str) -> Option<&MemKey> {
+        components(path).try_fold(&self.root, |key, name| {
+            key.subkeys.get(&sort_key(name)).map(|(_, subkey)| subkey)
+        })
+    }
+
+    /// Opens a key for modification, creating it and its parents if needed.
+    fn key_mut(&mut self, path: &str) -> &mut MemKey {
+        components(path).fold(&mut self.root, |key, name| {
+            &mut key
+                .subkeys
+                .entry(sort_key(name))
+                .or_insert_with(|| (String::from(name), MemKey::default()))
+                .1
+        })
+    }
+
+    pub fn create_key(&mut self, path: &str) {
+        self.key_mut(path);
+    }
+
+    pub fn delete_key(&mut self, path: &str) {
+        let (parent, name) = split_value_path(path);
+        let parent = components(parent).try_fold(&mut self.root, |key, name| {
+            key.subkeys
+                .get_mut(&sort_key(name))
+                .map(|(_, subkey)| subkey)
+        });
+        if let Some(parent) = parent {
+            parent.subkeys.remove(&sort_key(name));
+        }
+    }
+
+    pub fn set_value(&mut self, key: &str, name: &str, value: Value) {
+        self.key_mut(key)
+            .values
+            .insert(sort_key(name), (String::from(name), value));
+    }
+
+    pub fn delete_value(&mut self, key: &str, name: &str) {
+        let key = components(key).try_fold(&mut self.root, |key, name| {
+            key.subkeys
+                .get_mut(&sort_key(name))
+                .map(|(_, subkey)| subkey)
+        });
+        if let Some(key) = key {
+            key.values.remove(&sort_key(name));
+        }
+    }
+}
+
+impl RegSource for MemSource {
+    fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+        Ok(self.key(path).map(|key| {
+            let keys = key.subkeys.values().map(|(name, _)| (name.clone(), None));
+            let values = key.values.values().map(|(name, value)| {
+                (
+                    name.clone(),
+                    Some(value.bytes.len().try_into().expect("integer overflow")),
+                )
+            });
+            keys.chain(values).collect()
+        }))
+    }
+
+    fn visit_key(
+        &self,
+        path: &str,
+        after: Option<(&str, bool)>,
+        f: &mut dyn FnMut(String, Option<u32>) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        let key = match self.key(path) {
+            Some(key) => key,
+            None => return Ok(false),
+        };
+        // A subkey comes before a value of the same name.
+        let (keys_from, values_from) = match after {
+            Some((name, false)) => (
+                Bound::Excluded(sort_key(name)),
+                Bound::Included(sort_key(name)),
+            ),
+            Some((name, true)) => (
+                Bound::Excluded(sort_key(name)),
+                Bound::Excluded(sort_key(name)),
+            ),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let mut keys = key.subkeys.range((keys_from, Bound::Unbounded)).peekable();
+        let mut values = key.values.range((values_from, Bound::Unbounded)).peekable();
+        loop {
+            let key_first = match (keys.peek(), values.peek()) {
+                (Some((key, _)), Some((value, _))) => key <= value,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let more = if key_first {
+                let (_, (name, _)) = keys.next().unwrap();
+                f(name.clone(), None)?
+            } else {
+                let (_, (name, value)) = values.next().unwrap();
+                let size = value.bytes.len().try_into().expect("integer overflow");
+                f(name.clone(), Some(size))?
+            };
+            if !more {
+                break;
+            }
+        }
+        Ok(true)
+    }
+
+    fn streams_keys(&self) -> bool {
+        true
+    }
+
+    fn enum_values(&self, path: &str) -> anyhow::Result<Option<Vec<ValueEntry>>> {
+        Ok(self.key(path).map(|key| {
+            key.values
+                .values()
+                .map(|(name, value)| ValueEntry {
+                    name: name.clone(),
+                    vtype: value.vtype,
+                    size: value.bytes.len().try_into().expect("integer overflow"),
+                })
+                .collect()
+        }))
+    }
+
+    fn key_exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(self.key(path).is_some())
+    }
+
+    fn read_value(&self, path: &str) -> anyhow::Result<Option<Value>> {
+        let (key, name) = split_value_path(path);
+        Ok(self.key(key).and_then(|key| {
+            key.values
+                .get(&sort_key(name))
+                .map(|(_, value)| value.clone())
+        }))
+    }
+
+    fn visit_value(
+        &self,
+        path: &str,
+        f: &mut dyn FnMut(u32, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        let (key, name) = split_value_path(path);
+        match self
+            .key(key)
+            .and_then(|key| key.values.get(&sort_key(name)))
+        {
+            Some((_, value)) => {
+                f(value.vtype, &value.bytes)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn value_exists(&self, path: &str) -> anyhow::Result<bool> {
+        let (key, name) = split_value_path(path);
+        Ok(self
+            .key(key)
+            .is_some_and(|key| key.values.contains_key(&sort_key(name))))
+    }
+
+    fn value_size(&self, path: &str) -> anyhow::Result<Option<u32>> {
+        let (key, name) = split_value_path(path);
+        Ok(self.key(key).and_then(|key| {
+            key.values
+                .get(&sort_key(name))
+                .map(|(_, value)| value.bytes.len().try_into().expect("integer overflow"))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::REG_DWORD;
+
+    #[test]
+    fn case_insensitive_lookup() {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_LOCAL_MACHINE\\SOFTWARE\\Überprüfung");
+        source.set_value(
+            "HKEY_LOCAL_MACHINE\\SOFTWARE",
+            "Größe",
+            Value {
+                vtype: REG_DWORD,
+                bytes: vec![1, 0, 0, 0],
+            },
+        );
+
+        assert!(source.key_exists("hkey_local_machine\\software").unwrap());
+        assert!(source
+            .key_exists("HKEY_LOCAL_MACHINE\\Software\\ÜBERPRÜFUNG")
+            .unwrap());
+        assert!(source
+            .read_value("HKEY_LOCAL_MACHINE\\software\\GRÖßE")
+            .unwrap()
+            .is_some());
+        assert!(source
+            .value_exists("HKEY_LOCAL_MACHINE\\SOFTWARE\\größe")
+            .unwrap());
+        assert!(!source
+            .value_exists("HKEY_LOCAL_MACHINE\\SOFTWARE\\Überprüfung")
+            .unwrap());
+
+        // The original names are kept.
+        source.create_key("hkey_local_machine\\software\\überprüfung\\Sub");
+        let entries = source
+            .enum_key("HKEY_LOCAL_MACHINE\\SOFTWARE")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            entries,
+            [
+                (String::from("Überprüfung"), None),
+                (String::from("Größe"), Some(4)),
+            ],
+        );
+    }
+
+    #[test]
+    fn visit_in_order() {
+        let mut source = MemSource::new();
+        for name in ["Beta", "alpha", "Gamma", "\u{ff21}"] {
+            source.create_key(&format!("HKEY_TEST\\{}", name));
+        }
+        for name in ["gamma", "Zed", "a", "\u{1f600}"] {
+            let value = Value {
+                vtype: REG_DWORD,
+                bytes: vec![1, 0, 0, 0],
+            };
+            source.set_value("HKEY_TEST", name, value);
+        }
+
+        // Subkeys are marked with a backslash.
+        let visit = |after| {
+            let mut names = Vec::new();
+            let found = source
+                .visit_key("HKEY_TEST", after, &mut |name, size| {
+                    names.push(match size {
+                        Some(_) => name,
+                        None => format!("{}\\", name),
+                    });
+                    Ok(names.len() < 3)
+                })
+                .unwrap();
+            assert!(found);
+            names
+        };
+        // Names are in the registry's order, which compares UTF-16 code
+        // units rather than characters.
+        assert_eq!(visit(None), ["a", "alpha\\", "Beta\\"]);
+        assert_eq!(visit(Some(("beta", false))), ["Gamma\\", "gamma", "Zed"]);
+        assert_eq!(visit(Some(("GAMMA", false))), ["gamma", "Zed", "\u{1f600}"]);
+        assert_eq!(
+            visit(Some(("Gamma", true))),
+            ["Zed", "\u{1f600}", "\u{ff21}\\"]
+        );
+        assert!(!source
+            .visit_key("HKEY_MISSING", None, &mut |_, _| Ok(true))
+            .unwrap());
+    }
+}
@@ -0,0 +1,861 @@
+//! A 9P2000.L frontend, which serves a `VirtualFs` backend as a read-only
+//! file system over TCP or a Unix socket. It can be mounted with the Linux
+//! v9fs client, e.g.:
+//!
+//! ```text
+//! mount -t 9p -o trans=tcp,port=5640,version=9p2000.L 127.0.0.1 /mnt/registry
+//! ```
+//!
+//! Every connection is handled on its own thread, and its requests are
+//! answered in order. There is no authentication, so TCP connections are
+//! only accepted on loopback addresses unless remote ones are allowed.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    reg_name, source,
+    vfs::{self, EntryInfo, VfsError, VirtualFs},
+};
+
+const MAX_MSIZE: u32 = 128 * 1024;
+/// Smaller messages could not even hold a useful read or directory entry.
+const MIN_MSIZE: u32 = 4096;
+/// The size of the header of `Rread` and `Twrite` messages.
+const IO_HEADER_SIZE: u32 = 24;
+const VERSION: &str = "9P2000.L";
+const NO_FID: u32 = !0;
+/// The most names a single `Twalk` may walk.
+const MAXWELEM: u16 = 16;
+
+// Message types
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+// Linux error numbers
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EINTR: u32 = 4;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const ENOTDIR: u32 = 20;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const ENOSYS: u32 = 38;
+const EOPNOTSUPP: u32 = 95;
+
+const QT_DIR: u8 = 0x80;
+const QT_FILE: u8 = 0;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const GETATTR_BASIC: u64 = 0x7ff;
+const V9FS_MAGIC: u32 = 0x01021997;
+const O_ACCMODE: u32 = 3;
+const O_TRUNC: u32 = 0o1000;
+
+/// Where the server listens for connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// A TCP address such as `127.0.0.1:5640`
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl std::str::FromStr for Address {
+    type Err = anyhow::Error;
+
+    /// Parses `tcp:<Host>:<Port>` or `unix:<Path>`.
+    fn from_str(s: &str) -> anyhow::Result<Address> {
+        match s.split_once(':') {
+            Some(("tcp", addr)) => Ok(Address::Tcp(String::from(addr))),
+            #[cfg(unix)]
+            Some(("unix", path)) => Ok(Address::Unix(path.into())),
+            _ => bail!(
+                "invalid 9P address {:?}; expected tcp:<Host>:<Port> or unix:<Path>",
+                s
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "tcp:{}", addr),
+            #[cfg(unix)]
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+struct Server<B> {
+    backend: B,
+    /// Assigns qid paths to file paths, indexed by folded path so that
+    /// every spelling of a path gets the same qid.
+    qid_paths: Mutex<HashMap<String, u64>>,
+}
+
+/// A file opened by the client, identified by a fid.
+#[derive(Clone)]
+struct Fid {
+    path: String,
+    info: EntryInfo,
+    /// Directory entries, filled in when a directory is opened.
+    entries: Option<Vec<(String, EntryInfo)>>,
+}
+
+struct Connection<'a, B> {
+    server: &'a Server<B>,
+    msize: u32,
+    uid: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+/// An error which is reported to the client with `Rlerror`.
+struct Errno(u32);
+
+impl From<VfsError> for Errno {
+    fn from(err: VfsError) -> Errno {
+        match err {
+            VfsError::NotFound => Errno(ENOENT),
+            VfsError::AccessDenied => Errno(EACCES),
+            VfsError::CannotDelete => Errno(EPERM),
+            VfsError::InvalidArgument => Errno(EINVAL),
+            VfsError::Cancelled => Errno(EINTR),
+            VfsError::Other(err) => {
+                log::error!("Error serving 9P request: {:#}", err);
+                Errno(EIO)
+            }
+        }
+    }
+}
+
+impl From<anyhow::Error> for Errno {
+    fn from(err: anyhow::Error) -> Errno {
+        log::debug!("Malformed 9P request: {:#}", err);
+        Errno(EINVAL)
+    }
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('\\')
+        .map(|(parent, _)| parent)
+        .unwrap_or("")
+}
+
+/// Reads fields from a message body.
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("message too short");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).context("invalid string")
+    }
+}
+
+/// Writes fields of a message body.
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, v: u8) -> &mut Self {
+        self.0.push(v);
+        self
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, v: u64) -> &mut Self {
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, s: &str) -> &mut Self {
+        self.u16(s.len() as u16);
+        self.0.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    fn qid(&mut self, qid: (u8, u64)) -> &mut Self {
+        self.u8(qid.0).u32(0).u64(qid.1)
+    }
+}
+
+impl<B: VirtualFs> Server<B> {
+    fn qid(&self, path: &str, info: &EntryInfo) -> (u8, u64) {
+        let mut qid_paths = self.qid_paths.lock().unwrap();
+        let next = qid_paths.len() as u64 + 1;
+        let qid_path = *qid_paths.entry(reg_name::fold(path)).or_insert(next);
+        (if info.is_dir { QT_DIR } else { QT_FILE }, qid_path)
+    }
+}
+
+impl<B: VirtualFs> Connection<'_, B> {
+    fn fid(&self, fid: u32) -> Result<&Fid, Errno> {
+        self.fids.get(&fid).ok_or(Errno(EBADF))
+    }
+
+    fn new_fid(&self, path: String) -> Result<Fid, Errno> {
+        let info = self.server.backend.get_entry_info(&path)?;
+        Ok(Fid {
+            path,
+            info,
+            entries: None,
+        })
+    }
+
+    /// Handles a request, returning the type and body of the response.
+    fn handle(&mut self, msg_type: u8, body: &mut Decoder) -> Result<(u8, Encoder), Errno> {
+        let mut r = Encoder::default();
+        match msg_type {
+            TVERSION => {
+                let msize = body.u32()?;
+                let version = body.string()?;
+                if msize < MIN_MSIZE {
+                    return Err(Errno(EINVAL));
+                }
+                self.msize = msize.min(MAX_MSIZE);
+                self.fids.clear();
+                let version = if version.starts_with(VERSION) {
+                    VERSION
+                } else {
+                    "unknown"
+                };
+                r.u32(self.msize).string(version);
+            }
+            TATTACH => {
+                let fid = body.u32()?;
+                let _afid = body.u32()?;
+                let _uname = body.string()?;
+                let _aname = body.string()?;
+                let n_uname = body.u32()?;
+                if n_uname != NO_FID {
+                    self.uid = n_uname;
+                }
+                let root = self.new_fid(String::new())?;
+                r.qid(self.server.qid(&root.path, &root.info));
+                self.fids.insert(fid, root);
+            }
+            TAUTH => return Err(Errno(EOPNOTSUPP)),
+            TFLUSH => {
+                // Requests are answered in order, so the request to flush has
+                // already been answered.
+            }
+            TWALK => {
+                let fid = body.u32()?;
+                let new_fid = body.u32()?;
+                let count = body.u16()?;
+                if count > MAXWELEM {
+                    return Err(Errno(EINVAL));
+                }
+                let mut cur = self.fid(fid)?.clone();
+                if new_fid != fid && self.fids.contains_key(&new_fid) {
+                    return Err(Errno(EBADF));
+                }
+
+                let mut qids = Vec::new();
+                for i in 0..count {
+                    let name = body.string()?;
+                    let next = match name.as_str() {
+                        ".." => self.new_fid(String::from(parent(&cur.path))),
+                        "." => Ok(cur.clone()),
+                        _ if !cur.info.is_dir => Err(Errno(ENOTDIR)),
+                        // Each name is a single registry component.
+                        _ if name.contains('\\') => Err(Errno(ENOENT)),
+                        _ => self.new_fid(source::join_path(&cur.path, &name)),
+                    };
+                    match next {
+                        Ok(next) => cur = next,
+                        // Only a failure on the first name is an error;
+                        // otherwise the qids walked so far are returned.
+                        Err(err) if i == 0 => return Err(err),
+                        Err(_) => break,
+                    }
+                    qids.push(self.server.qid(&cur.path, &cur.info));
+                }
+
+                if qids.len() == count as usize {
+                    self.fids.insert(new_fid, cur);
+                }
+                r.u16(qids.len() as u16);
+                for qid in qids {
+                    r.qid(qid);
+                }
+            }
+            TLOPEN => {
+                let fid = body.u32()?;
+                let flags = body.u32()?;
+                if flags & O_ACCMODE != 0 || flags & O_TRUNC != 0 {
+                    return Err(Errno(EROFS));
+                }
+                let file = self.fid(fid)?;
+                let entries = if file.info.is_dir {
+                    Some(vfs::list_dir(&self.server.backend, &file.path)?)
+                } else {
+                    None
+                };
+                r.qid(self.server.qid(&file.path, &file.info))
+                    .u32(self.msize - IO_HEADER_SIZE);
+                self.fids.get_mut(&fid).unwrap().entries = entries;
+            }
+            TGETATTR => {
+                let fid = body.u32()?;
+                let _request_mask = body.u64()?;
+                let file = self.fid(fid)?;
+                let (mode, nlink) = if file.info.is_dir {
+                    (S_IFDIR | 0o555, 2)
+                } else {
+                    (S_IFREG | 0o444, 1)
+                };
+                r.u64(GETATTR_BASIC)
+                    .qid(self.server.qid(&file.path, &file.info))
+                    .u32(mode)
+                    .u32(self.uid)
+                    .u32(0)
+                    .u64(nlink)
+                    .u64(0)
+                    .u64(file.info.size)
+                    .u64(4096)
+                    .u64(file.info.size.div_ceil(512));
+                // atime, mtime, ctime, btime, gen and data_version
+                for _ in 0..10 {
+                    r.u64(0);
+                }
+            }
+            TREADDIR => {
+                let fid = body.u32()?;
+                let offset = body.u64()?;
+                let count = body.u32()?.min(self.msize - IO_HEADER_SIZE) as usize;
+                let file = self.fid(fid)?;
+                if !file.info.is_dir {
+                    return Err(Errno(ENOTDIR));
+                }
+                let listed;
+                let entries = match &file.entries {
+                    Some(entries) => entries,
+                    None => {
+                        listed = vfs::list_dir(&self.server.backend, &file.path)?;
+                        &listed
+                    }
+                };
+
+                // Offsets count `.` and `..`, then the entries, including
+                // those which are left out.
+                let item = |i: usize| match i {
+                    0 => Some((".", file.path.clone(), file.info)),
+                    1 => Some(("..", String::from(parent(&file.path)), EntryInfo::dir())),
+                    _ => entries.get(i - 2).map(|(name, info)| {
+                        (name.as_str(), source::join_path(&file.path, name), *info)
+                    }),
+                };
+                let mut data = Encoder::default();
+                let mut i = offset as usize;
+                while let Some((name, path, info)) = item(i) {
+                    i += 1;
+                    // These names cannot be represented.
+                    if i > 2 && (name.contains('/') || name == "." || name == "..") {
+                        continue;
+                    }
+                    let size = 13 + 8 + 1 + 2 + name.len();
+                    if data.0.len() + size > count {
+                        if data.0.is_empty() {
+                            // An empty reply would mean the end of the
+                            // directory.
+                            return Err(Errno(EINVAL));
+                        }
+                        break;
+                    }
+                    data.qid(self.server.qid(&path, &info))
+                        .u64(i as u64)
+                        .u8(if info.is_dir { DT_DIR } else { DT_REG })
+                        .string(name);
+                }
+                r.u32(data.0.len() as u32);
+                r.0.extend_from_slice(&data.0);
+            }
+            TREAD => {
+                let fid = body.u32()?;
+                let offset = body.u64()?;
+                let count = body.u32()?.min(self.msize - IO_HEADER_SIZE);
+                let file = self.fid(fid)?;
+                if file.info.is_dir {
+                    return Err(Errno(EISDIR));
+                }
+                let mut data = vfs::read_to_vec(&self.server.backend, &file.path, offset, count)?;
+                data.truncate(count as usize);
+                r.u32(data.len() as u32);
+                r.0.extend_from_slice(&data);
+            }
+            TCLUNK => {
+                let fid = body.u32()?;
+                self.fids.remove(&fid).ok_or(Errno(EBADF))?;
+            }
+            TSTATFS => {
+                let fid = body.u32()?;
+                self.fid(fid)?;
+                r.u32(V9FS_MAGIC)
+                    .u32(4096)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u32(255);
+            }
+            TFSYNC => {}
+            TXATTRWALK | TXATTRCREATE => return Err(Errno(EOPNOTSUPP)),
+            TREADLINK => return Err(Errno(EINVAL)),
+            TLCREATE | TSYMLINK | TMKNOD | TRENAME | TSETATTR | TLINK | TMKDIR | TRENAMEAT
+            | TUNLINKAT | TWRITE | TREMOVE => return Err(Errno(EROFS)),
+            other => {
+                log::debug!("Unsupported 9P message type {}", other);
+                return Err(Errno(ENOSYS));
+            }
+        }
+        Ok((msg_type + 1, r))
+    }
+
+    fn serve(&mut self, mut stream: impl Read + Write) -> anyhow::Result<()> {
+        loop {
+            let mut size = [0; 4];
+            match stream.read_exact(&mut size) {
+                Ok(()) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err).context("read message"),
+            }
+            let size = u32::from_le_bytes(size);
+            if !(7..=self.msize).contains(&size) {
+                bail!("invalid message size {}", size);
+            }
+            let mut msg = vec![0; size as usize - 4];
+            stream.read_exact(&mut msg).context("read message")?;
+
+            let mut body = Decoder(&msg);
+            let msg_type = body.u8()?;
+            let tag = body.u16()?;
+            let (msg_type, r) = match self.handle(msg_type, &mut body) {
+                Ok(response) => response,
+                Err(Errno(errno)) => {
+                    let mut r = Encoder::default();
+                    r.u32(errno);
+                    (RLERROR, r)
+                }
+            };
+
+            let mut response = Encoder::default();
+            response.u32(r.0.len() as u32 + 7).u8(msg_type).u16(tag);
+            response.0.extend_from_slice(&r.0);
+            stream.write_all(&response.0).context("write response")?;
+        }
+    }
+}
+
+fn serve_connection<B: VirtualFs>(server: &Server<B>, stream: impl Read + Write) {
+    let mut connection = Connection {
+        server,
+        msize: MAX_MSIZE,
+        uid: 0,
+        fids: HashMap::new(),
+    };
+    match connection.serve(stream) {
+        Ok(()) => log::debug!("9P connection closed"),
+        Err(err) => log::warn!("9P connection failed: {:#}", err),
+    }
+}
+
+fn accept<B, S>(backend: B, incoming: impl Iterator<Item = std::io::Result<S>>)
+where
+    B: VirtualFs + 'static,
+    S: Read + Write + Send + 'static,
+{
+    let server = Arc::new(Server {
+        backend,
+        qid_paths: Mutex::new(HashMap::new()),
+    });
+    for stream in incoming {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                // Such as a client resetting the connection, or running out
+                // of file descriptors for a moment.
+                log::warn!("Failed to accept 9P connection: {}", err);
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        log::debug!("New 9P connection");
+        let server = Arc::clone(&server);
+        std::thread::spawn(move || serve_connection(&server, stream));
+    }
+}
+
+/// Listens on the given address, and serves each connection on a new thread.
+/// A TCP address must be a loopback one unless `allow_remote` is set.
+pub fn serve<B: VirtualFs + 'static>(
+    backend: B,
+    address: &Address,
+    allow_remote: bool,
+) -> anyhow::Result<()> {
+    match address {
+        Address::Tcp(addr) => {
+            let addrs: Vec<_> = addr
+                .to_socket_addrs()
+                .with_context(|| format!("resolve {}", addr))?
+                .collect();
+            if let Some(remote) = addrs.iter().find(|addr| !addr.ip().is_loopback()) {
+                if !allow_remote {
+                    bail!(
+                        "{} is not a loopback address, and the 9P server has no \
+                         authentication; pass --allow-remote to serve other hosts",
+                        remote.ip(),
+                    );
+                }
+            }
+            let listener = TcpListener::bind(&addrs[..]).context("listen")?;
+            accept(backend, listener.incoming());
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            let listener = std::os::unix::net::UnixListener::bind(path).context("listen")?;
+            accept(backend, listener.incoming());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        regfs::RegFs,
+        source::{Value, REG_BINARY, REG_SZ},
+    };
+
+    const RVERSION: u8 = TVERSION + 1;
+
+    struct Client(TcpStream);
+
+    impl Client {
+        fn connect() -> Client {
+            let mut source = MemSource::new();
+            source.create_key("HKEY_TEST\\Software\\Vendor");
+            source.set_value(
+                "HKEY_TEST\\Software",
+                "Name",
+                Value {
+                    vtype: REG_SZ,
+                    bytes: b"R\0e\0g\0\0\0".to_vec(),
+                },
+            );
+            source.set_value(
+                "HKEY_TEST\\Software",
+                "Big",
+                Value {
+                    vtype: REG_BINARY,
+                    bytes: (0..200_000u32).map(|i| i as u8).collect(),
+                },
+            );
+            let backend = RegFs::new(std::path::PathBuf::new(), Arc::new(source));
+
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            std::thread::spawn(move || accept(backend, listener.incoming()));
+            let mut client = Client(TcpStream::connect(addr).unwrap());
+
+            let mut body = Encoder::default();
+            body.u32(8192).string("9P2000.L");
+            let (msg_type, r) = client.rpc(TVERSION, body);
+            assert_eq!(msg_type, RVERSION);
+            let mut r = Decoder(&r);
+            assert_eq!(r.u32().unwrap(), 8192);
+            assert_eq!(r.string().unwrap(), VERSION);
+
+            let mut body = Encoder::default();
+            body.u32(0).u32(NO_FID).string("user").string("").u32(1000);
+            client.ok(TATTACH, body);
+            client
+        }
+
+        fn rpc(&mut self, msg_type: u8, body: Encoder) -> (u8, Vec<u8>) {
+            let mut msg = Encoder::default();
+            msg.u32(body.0.len() as u32 + 7).u8(msg_type).u16(1);
+            msg.0.extend_from_slice(&body.0);
+            self.0.write_all(&msg.0).unwrap();
+
+            let mut size = [0; 4];
+            self.0.read_exact(&mut size).unwrap();
+            let mut msg = vec![0; u32::from_le_bytes(size) as usize - 4];
+            self.0.read_exact(&mut msg).unwrap();
+            assert_eq!(msg[1..3], [1, 0]);
+            (msg[0], msg[3..].to_vec())
+        }
+
+        /// Sends a request which is expected to succeed.
+        fn ok(&mut self, msg_type: u8, body: Encoder) -> Vec<u8> {
+            let (r_type, r) = self.rpc(msg_type, body);
+            assert_eq!(r_type, msg_type + 1, "error {:?}", r);
+            r
+        }
+
+        /// Sends a request which is expected to fail, returning the errno.
+        fn err(&mut self, msg_type: u8, body: Encoder) -> u32 {
+            let (r_type, r) = self.rpc(msg_type, body);
+            assert_eq!(r_type, RLERROR);
+            Decoder(&r).u32().unwrap()
+        }
+
+        fn walk(&mut self, fid: u32, new_fid: u32, names: &[&str]) -> Result<usize, u32> {
+            let mut body = Encoder::default();
+            body.u32(fid).u32(new_fid).u16(names.len() as u16);
+            for name in names {
+                body.string(name);
+            }
+            match self.rpc(TWALK, body) {
+                (RLERROR, r) => Err(Decoder(&r).u32().unwrap()),
+                (_, r) => Ok(Decoder(&r).u16().unwrap() as usize),
+            }
+        }
+
+        fn open(&mut self, fid: u32) {
+            let mut body = Encoder::default();
+            body.u32(fid).u32(0);
+            self.ok(TLOPEN, body);
+        }
+
+        fn read(&mut self, fid: u32, offset: u64, count: u32) -> Vec<u8> {
+            let mut body = Encoder::default();
+            body.u32(fid).u64(offset).u32(count);
+            let r = self.ok(TREAD, body);
+            let mut r = Decoder(&r);
+            let len = r.u32().unwrap() as usize;
+            r.bytes(len).unwrap().to_vec()
+        }
+
+        fn readdir(&mut self, fid: u32) -> Vec<(String, u8)> {
+            let mut entries = Vec::new();
+            let mut offset = 0;
+            loop {
+                let mut body = Encoder::default();
+                body.u32(fid).u64(offset).u32(100);
+                let r = self.ok(TREADDIR, body);
+                let mut r = Decoder(&r);
+                let len = r.u32().unwrap();
+                if len == 0 {
+                    return entries;
+                }
+                while !r.0.is_empty() {
+                    r.bytes(13).unwrap();
+                    offset = r.u64().unwrap();
+                    let dtype = r.u8().unwrap();
+                    entries.push((r.string().unwrap(), dtype));
+                }
+            }
+        }
+
+        fn size(&mut self, fid: u32) -> (u32, u64) {
+            let mut body = Encoder::default();
+            body.u32(fid).u64(GETATTR_BASIC);
+            let r = self.ok(TGETATTR, body);
+            let mut r = Decoder(&r);
+            r.bytes(8 + 13).unwrap();
+            let mode = r.u32().unwrap();
+            r.bytes(4 + 4 + 8 + 8).unwrap();
+            (mode, r.u64().unwrap())
+        }
+    }
+
+    #[test]
+    fn walk_and_read() {
+        let mut client = Client::connect();
+        assert_eq!(client.walk(0, 1, &["HKEY_TEST", "Software", "Name"]), Ok(3));
+        assert_eq!(client.size(1), (S_IFREG | 0o444, 8));
+        client.open(1);
+        assert_eq!(client.read(1, 0, 100), b"R\0e\0g\0\0\0");
+        assert_eq!(client.read(1, 6, 100), b"\0\0");
+        assert!(client.read(1, 8, 100).is_empty());
+
+        // Names are case-insensitive, and reads are limited by msize.
+        assert_eq!(client.walk(0, 2, &["hkey_test", "SOFTWARE", "big"]), Ok(3));
+        assert_eq!(client.size(2).1, 200_000);
+        client.open(2);
+        let data = client.read(2, 1000, 100_000);
+        assert_eq!(data.len(), 8192 - IO_HEADER_SIZE as usize);
+        assert_eq!(data[0], (1000 % 256) as u8);
+    }
+
+    #[test]
+    fn readdir() {
+        let mut client = Client::connect();
+        assert_eq!(client.walk(0, 1, &["HKEY_TEST", "Software"]), Ok(2));
+        assert_eq!(client.size(1).0, S_IFDIR | 0o555);
+        client.open(1);
+        assert_eq!(
+            client.readdir(1),
+            [
+                (String::from("."), DT_DIR),
+                (String::from(".."), DT_DIR),
+                (String::from("Big"), DT_REG),
+                (String::from("Name"), DT_REG),
+                (String::from("Vendor"), DT_DIR),
+            ],
+        );
+
+        // Walking back up
+        assert_eq!(client.walk(1, 2, &["..", ".."]), Ok(2));
+        client.open(2);
+        assert_eq!(
+            client.readdir(2)[2..],
+            [
+                (String::from("@search"), DT_DIR),
+                (String::from("HKEY_TEST"), DT_DIR),
+            ],
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let mut client = Client::connect();
+        assert_eq!(client.walk(0, 1, &["Missing"]), Err(ENOENT));
+        // A partial walk returns the qids walked so far, without creating
+        // the new fid.
+        assert_eq!(client.walk(0, 1, &["HKEY_TEST", "Missing"]), Ok(1));
+        let mut body = Encoder::default();
+        body.u32(1).u64(GETATTR_BASIC);
+        assert_eq!(client.err(TGETATTR, body), EBADF);
+
+        assert_eq!(client.walk(0, 1, &["HKEY_TEST", "Software", "Name"]), Ok(3));
+        let mut body = Encoder::default();
+        body.u32(1).u32(1);
+        assert_eq!(client.err(TLOPEN, body), EROFS);
+        let mut body = Encoder::default();
+        body.u32(1).u64(0).u32(1).u8(b'x');
+        assert_eq!(client.err(TWRITE, body), EROFS);
+
+        let mut body = Encoder::default();
+        body.u32(1);
+        client.ok(TCLUNK, body);
+        let mut body = Encoder::default();
+        body.u32(1);
+        assert_eq!(client.err(TCLUNK, body), EBADF);
+
+        // Names are single components, and walks are limited to MAXWELEM
+        // names.
+        assert_eq!(client.walk(0, 1, &["HKEY_TEST\\Software"]), Err(ENOENT));
+        assert_eq!(client.walk(0, 1, &["."; 17]), Err(EINVAL));
+        assert_eq!(client.walk(0, 1, &["."; 16]), Ok(16));
+
+        // A directory entry which does not fit is not taken for the end of
+        // the directory.
+        client.open(1);
+        let mut body = Encoder::default();
+        body.u32(1).u64(0).u32(10);
+        assert_eq!(client.err(TREADDIR, body), EINVAL);
+    }
+
+    #[test]
+    fn remote_addresses() {
+        let serve = |addr: &str, allow_remote| {
+            let backend = RegFs::new(std::path::PathBuf::new(), Arc::new(MemSource::new()));
+            serve(backend, &addr.parse().unwrap(), allow_remote)
+        };
+        let err = serve("tcp:0.0.0.0:0", false).unwrap_err();
+        assert!(format!("{:#}", err).contains("--allow-remote"));
+        // Loopback addresses are fine, and so it gets as far as listening.
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp:{}", taken.local_addr().unwrap());
+        let err = serve(&addr, false).unwrap_err();
+        assert_eq!(format!("{:#}", err).split(':').next(), Some("listen"));
+    }
+
+    #[test]
+    fn tiny_msize() {
+        let mut client = Client::connect();
+        for msize in [0, 7, IO_HEADER_SIZE, MIN_MSIZE - 1] {
+            let mut body = Encoder::default();
+            body.u32(msize).string(VERSION);
+            assert_eq!(client.err(TVERSION, body), EINVAL);
+        }
+
+        // The connection goes on with the msize negotiated before.
+        assert_eq!(client.walk(0, 1, &["HKEY_TEST", "Software", "Big"]), Ok(3));
+        client.open(1);
+        assert_eq!(
+            client.read(1, 0, 100_000).len(),
+            8192 - IO_HEADER_SIZE as usize
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::{fs::File, io::Write, path::Path, sync::Mutex};
+
+use crate::{
+    reg_file,
+    source::{split_value_path, RegSource, Value},
+};
+
+/// Records changes made to the projection as a `.reg` file, instead of
+/// applying them to the registry. The resulting file can be reviewed, then
+/// imported with the Registry Editor.
+pub struct PatchRecorder {
+    file: Mutex<File>,
+    /// The registry key at which the source's root is mounted, such as
+    /// `HKEY_LOCAL_MACHINE\SOFTWARE` for a `SOFTWARE` hive. Empty for sources
+    /// whose top level keys are the predefined keys.
+    root: String,
+}
+
+impl PatchRecorder {
+    pub fn create(path: &Path) -> std::io::Result<PatchRecorder> {
+        let mut file = File::create(path)?;
+        file.write_all(&reg_file::encode(&format!(
+            "\u{feff}{}\r\n",
+            reg_file::HEADER
+        )))?;
+        Ok(PatchRecorder {
+            file: Mutex::new(file),
+            root: String::new(),
+        })
+    }
+
+    /// Writes keys below `root`, for sources which start at the root key of
+    /// a hive rather than at the predefined keys.
+    pub fn with_root(mut self, root: &str) -> PatchRecorder {
+        self.root = String::from(root.trim_end_matches('\\'));
+        self
+    }
+
+    /// Returns the full registry path of a key in the source.
+    fn key_path(&self, key: &str) -> String {
+        match (self.root.as_str(), key) {
+            ("", key) => String::from(key),
+            (root, "") => String::from(root),
+            (root, key) => format!("{}\\{}", root, key),
+        }
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn create_key(&self, key: &str) {
+        self.write_section(&[reg_file::key_line(&self.key_path(key))]);
+    }
+
+    pub fn delete_key(&self, key: &str) {
+        self.write_section(&[reg_file::delete_key_line(&self.key_path(key))]);
+    }
+
+    pub fn set_value(&self, path: &str, value: &Value) {
+        match split_value_path(path) {
+            ("", _) => log::warn!("Not recording value outside of any key: {:?}", path),
+            (key, name) => self.write_section(&[
+                reg_file::key_line(&self.key_path(key)),
+                reg_file::value_line(name, value),
+            ]),
+        }
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn delete_value(&self, path: &str) {
+        match split_value_path(path) {
+            ("", _) => log::warn!("Not recording value outside of any key: {:?}", path),
+            (key, name) => self.write_section(&[
+                reg_file::key_line(&self.key_path(key)),
+                reg_file::delete_value_line(name),
+            ]),
+        }
+    }
+
+    /// Records a copy of the key `src` and all of its contents, as they are
+    /// currently in the source, to the key `dest`.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn copy_key(&self, source: &dyn RegSource, src: &str, dest: &str) -> anyhow::Result<()> {
+        let mut lines = Vec::new();
+        reg_file::key_lines(source, src, &self.key_path(dest), &mut lines)?;
+        self.write_section(&lines);
+        Ok(())
+    }
+
+    fn write_section(&self, lines: &[String]) {
+        let mut section = String::from("\r\n");
+        for line in lines {
+            section.push_str(line);
+            section.push_str("\r\n");
+        }
+        if let Err(err) = self
+            .file
+            .lock()
+            .unwrap()
+            .write_all(&reg_file::encode(&section))
+        {
+            log::error!("Failed to write to patch file: {}", err);
+        }
+    }
+}
+
+/// Guesses where a hive file is loaded in the registry from its usual file
+/// name, so that patches recorded against it can be imported as they are.
+pub fn hive_root(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_str()?;
+    [
+        ("SOFTWARE", "HKEY_LOCAL_MACHINE\\SOFTWARE"),
+        ("SYSTEM", "HKEY_LOCAL_MACHINE\\SYSTEM"),
+        ("SAM", "HKEY_LOCAL_MACHINE\\SAM"),
+        ("SECURITY", "HKEY_LOCAL_MACHINE\\SECURITY"),
+        ("DEFAULT", "HKEY_USERS\\.DEFAULT"),
+        ("NTUSER.DAT", "HKEY_CURRENT_USER"),
+        ("UsrClass.dat", "HKEY_CURRENT_USER\\Software\\Classes"),
+    ]
+    .into_iter()
+    .find(|(file, _)| file.eq_ignore_ascii_case(name))
+    .map(|(_, root)| root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mem_source::MemSource, source::REG_DWORD};
+
+    fn dword(n: u32) -> Value {
+        Value {
+            vtype: REG_DWORD,
+            bytes: n.to_le_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn round_trip_below_hive_root() {
+        // A source laid out like a SOFTWARE hive, starting at its root key.
+        let mut source = MemSource::new();
+        source.set_value("Contoso\\Old", "Count", dword(7));
+        source.set_value("Contoso\\Old\\Sub", "", dword(8));
+
+        let path = std::env::temp_dir().join(format!("regfs-patch-{}.reg", uuid::Uuid::new_v4()));
+        let recorder = PatchRecorder::create(&path)
+            .unwrap()
+            .with_root("HKEY_LOCAL_MACHINE\\SOFTWARE\\");
+        recorder.create_key("Contoso\\New");
+        recorder.set_value("Contoso\\New\\Size", &dword(1));
+        recorder.set_value("Contoso\\New\\Gone", &dword(2));
+        recorder.delete_value("Contoso\\New\\Gone");
+        recorder
+            .copy_key(&source, "Contoso\\Old", "Contoso\\Copy")
+            .unwrap();
+        recorder.create_key("Contoso\\Temp");
+        recorder.delete_key("Contoso\\Temp");
+        drop(recorder);
+
+        let patch = reg_file::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let root = "HKEY_LOCAL_MACHINE\\SOFTWARE\\Contoso";
+        let mut keys = patch.enum_key(root).unwrap().unwrap();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [(String::from("Copy"), None), (String::from("New"), None)]
+        );
+        assert_eq!(
+            patch.enum_key(&format!("{}\\New", root)).unwrap().unwrap(),
+            [(String::from("Size"), Some(4))]
+        );
+        assert_eq!(
+            patch.read_value(&format!("{}\\Copy\\Count", root)).unwrap(),
+            Some(dword(7))
+        );
+        assert_eq!(
+            patch.read_value(&format!("{}\\Copy\\Sub\\", root)).unwrap(),
+            Some(dword(8))
+        );
+        assert!(patch.enum_key("Contoso").unwrap().is_none());
+    }
+
+    #[test]
+    fn hive_roots() {
+        assert_eq!(
+            hive_root(Path::new("backup/ntuser.dat")),
+            Some("HKEY_CURRENT_USER")
+        );
+        assert_eq!(
+            hive_root(Path::new("SOFTWARE")),
+            Some("HKEY_LOCAL_MACHINE\\SOFTWARE")
+        );
+        assert_eq!(hive_root(Path::new("copy.hiv")), None);
+    }
+}
@@ -1,66 +1,53 @@
-use std::{io::ErrorKind, os::windows::prelude::OsStrExt, path::PathBuf, sync::Arc};
+use std::{
+    os::windows::prelude::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::Context;
 use uuid::Uuid;
 use windows::{
     core::{GUID, HRESULT, PCWSTR},
-    Win32::{Foundation::BOOLEAN, Storage::ProjectedFileSystem::*},
+    Win32::{
+        Foundation::{
+            BOOLEAN, ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, ERROR_INSUFFICIENT_BUFFER,
+            ERROR_IO_PENDING, ERROR_OPERATION_ABORTED, E_FAIL, E_INVALIDARG, STATUS_CANNOT_DELETE,
+            S_OK,
+        },
+        Storage::ProjectedFileSystem::*,
+    },
+};
+
+use crate::{
+    cleanup::{FileState, FileStates, RunEmpty},
+    commands::{CancelToken, Commands},
+    file_data::{aligned_range, ChunkedWriter, MAX_CHUNK_SIZE},
+    fs_helper::{placeholder_info, FileDataWriter, SimpleFsHelper},
+    vfs::{
+        DirEntrySink, EntryInfo, FileDataSink, Notification, NotificationKind, OptionalFeatures,
+        VersionInfo, VfsError, VfsResult, VirtualFs,
+    },
+    virt_root::MarkRoot,
+    watch::Invalidate,
 };
 
 pub struct ProjFs<B>
 where
-    B: ProjFsBackend,
+    B: VirtualFs + 'static,
 {
-    root_path: PathBuf,
     root_path_wide: Vec<u16>,
     options: PRJ_STARTVIRTUALIZING_OPTIONS,
-    backend: Box<Arc<B>>,
+    instance: Box<Instance<B>>,
     instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
     state: FsState,
 }
 
-pub trait ProjFsBackend: Send + Sync {
-    fn get_optional_features() -> OptionalFeatures;
-
-    fn set_instance_handle(self: &Arc<Self>, instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT);
-
-    unsafe fn start_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-    ) -> HRESULT;
-
-    unsafe fn end_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-    ) -> HRESULT;
-
-    unsafe fn get_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-        search_expr: PCWSTR,
-        dir_entry_buffer_handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> HRESULT;
-
-    unsafe fn get_placeholder_info(self: &Arc<Self>, callback_data: &PRJ_CALLBACK_DATA) -> HRESULT;
-
-    unsafe fn get_file_data(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        byte_offset: u64,
-        length: u32,
-    ) -> HRESULT;
-
-    unsafe fn notify(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        is_dir: bool,
-        kind: NotificationKind,
-        dest_filename: PCWSTR,
-        params: *mut PRJ_NOTIFICATION_PARAMETERS,
-    ) -> HRESULT;
+/// What callbacks get as their instance context.
+struct Instance<B> {
+    backend: Arc<B>,
+    /// Serves requests off the callback thread, for backends with
+    /// `CANCEL_COMMAND`.
+    commands: Option<Commands>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -70,33 +57,9 @@ enum FsState {
     Stopped,
 }
 
-bitflags::bitflags! {
-    pub struct OptionalFeatures: u32 {
-        const NOTIFY = 1;
-        const QUERY_FILE_NAME = 2;
-        const CANCEL_COMMAND = 4;
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum NotificationKind {
-    FileOpened,
-    NewFileCreated,
-    FileOverwritten,
-    PreDelete,
-    PreRename,
-    PreSetHardlink,
-    FileRenamed,
-    HardlinkCreated,
-    FileHandleClosedNoModification,
-    FileHandleClosedFileModified,
-    FileHandleClosedFileDeleted,
-    FilePreConvertToFull,
-}
-
 impl<B> ProjFs<B>
 where
-    B: ProjFsBackend,
+    B: VirtualFs + 'static,
 {
     pub fn new(
         root_path: PathBuf,
@@ -110,15 +73,19 @@ where
             .collect();
 
         ProjFs {
-            root_path,
             root_path_wide,
             options,
-            backend: Box::new(Arc::new(backend)),
+            instance: Box::new(Instance {
+                backend: Arc::new(backend),
+                commands: None,
+            }),
             instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT::default(),
             state: FsState::Ready,
         }
     }
 
+    /// Starts the projection. The root must already be set up, with
+    /// `virt_root::prepare`.
     pub fn start(&mut self) -> anyhow::Result<()> {
         if self.state != FsState::Ready {
             panic!(
@@ -127,69 +94,41 @@ where
             );
         }
 
-        self.ensure_virtualization_root()
-            .context("ensure_virtualization_root")?;
-
+        if B::get_optional_features().contains(OptionalFeatures::CANCEL_COMMAND) {
+            self.instance.commands = Some(Commands::new(self.command_threads())?);
+        }
         let callbacks = self.create_callbacks();
         let instance_handle = unsafe {
             PrjStartVirtualizing(
                 PCWSTR::from_raw(self.root_path_wide.as_ptr()),
                 &callbacks,
-                self.backend.as_ref() as *const Arc<B> as *const _,
+                self.instance.as_ref() as *const Instance<B> as *const _,
                 &self.options,
             )
         }
         .context("start virtualizing")?;
-        // FIXME: Potential race condition here
-        self.backend.set_instance_handle(instance_handle);
         self.instance_handle = instance_handle;
         self.state = FsState::Running;
         Ok(())
     }
 
-    fn ensure_virtualization_root(&self) -> anyhow::Result<()> {
-        const INSTANCE_ID_FILE: &str = ".projfs-id";
-
-        match std::fs::create_dir(&self.root_path) {
-            Ok(()) => {
-                // This directory is newly created. Create a UUID for this
-                // directory, and write it to a file named .projfs-id.
-                let instance_id = Uuid::new_v4();
-                log::debug!("Created new instance ID {}", instance_id);
-                std::fs::write(
-                    self.root_path.join(INSTANCE_ID_FILE),
-                    instance_id.as_bytes(),
-                )
-                .context("write instance ID file")?;
-                // TODO: cleanup on error return
-
-                unsafe {
-                    PrjMarkDirectoryAsPlaceholder(
-                        PCWSTR::from_raw(self.root_path_wide.as_ptr()),
-                        PCWSTR::null(),
-                        std::ptr::null(),
-                        instance_id.as_bytes().as_ptr().cast(),
-                    )
-                }
-                .context("mark directory as placeholder")?;
-
-                Ok(())
-            }
-            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
-                let instance_id = std::fs::read(self.root_path.join(INSTANCE_ID_FILE))
-                    .context("read instance ID file")?;
-                let instance_id = Uuid::from_slice(&instance_id).context("parse instance ID")?;
-                log::debug!("Found old instance ID {}", instance_id);
-                // TODO: do something with instance_id
-                Ok(())
-            }
-            result => result.context("create virtualization root"),
+    /// Runs as many commands at once as ProjFS has threads to make callbacks
+    /// on, with the defaults ProjFS uses for counts left at zero: as many
+    /// concurrent threads as processors, and a pool twice that size.
+    fn command_threads(&self) -> usize {
+        let concurrent = match self.options.ConcurrentThreadCount {
+            0 => std::thread::available_parallelism().map_or(4, |n| n.get()),
+            n => n as usize,
+        };
+        match self.options.PoolThreadCount {
+            0 => concurrent * 2,
+            n => n as usize,
         }
     }
 
     fn create_callbacks(&self) -> PRJ_CALLBACKS {
         unsafe fn backend<'a, B>(callback_data: *const PRJ_CALLBACK_DATA) -> &'a Arc<B> {
-            &*((*callback_data).InstanceContext as *const Arc<B>)
+            &instance::<B>(callback_data).backend
         }
 
         unsafe fn uuid(guid: *const GUID) -> Uuid {
@@ -202,62 +141,166 @@ where
             .unwrap() // this must not cause errors
         }
 
-        unsafe extern "system" fn start_dir_enum_cb<B: ProjFsBackend>(
+        unsafe fn fs_helper(callback_data: *const PRJ_CALLBACK_DATA) -> SimpleFsHelper {
+            SimpleFsHelper::new((*callback_data).NamespaceVirtualizationContext)
+        }
+
+        unsafe fn req_path(callback_data: *const PRJ_CALLBACK_DATA) -> VfsResult<String> {
+            fs_helper(callback_data)
+                .get_req_path(&*callback_data)
+                .map_err(|_| VfsError::InvalidArgument)
+        }
+
+        unsafe fn to_string(s: PCWSTR) -> Option<String> {
+            Option::<PCWSTR>::from(s).and_then(|s| s.to_string().ok())
+        }
+
+        unsafe extern "system" fn start_dir_enum_cb<B: VirtualFs>(
             callback_data: *const PRJ_CALLBACK_DATA,
             enumeration_id: *const GUID,
         ) -> HRESULT {
-            backend::<B>(callback_data).start_dir_enum(&*callback_data, uuid(enumeration_id))
+            let result = req_path(callback_data).and_then(|path| {
+                backend::<B>(callback_data).start_dir_enum(&path, uuid(enumeration_id))
+            });
+            to_hresult("start_dir_enum", result)
         }
 
-        unsafe extern "system" fn end_dir_enum_cb<B: ProjFsBackend>(
+        unsafe extern "system" fn end_dir_enum_cb<B: VirtualFs>(
             callback_data: *const PRJ_CALLBACK_DATA,
             enumeration_id: *const GUID,
         ) -> HRESULT {
-            backend::<B>(callback_data).end_dir_enum(&*callback_data, uuid(enumeration_id))
+            let result = backend::<B>(callback_data).end_dir_enum(uuid(enumeration_id));
+            to_hresult("end_dir_enum", result)
         }
 
-        unsafe extern "system" fn get_dir_enum_cb<B: ProjFsBackend>(
+        unsafe extern "system" fn get_dir_enum_cb<B: VirtualFs>(
             callback_data: *const PRJ_CALLBACK_DATA,
             enumeration_id: *const GUID,
             search_expr: PCWSTR,
             dir_entry_buffer_handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
         ) -> HRESULT {
-            backend::<B>(callback_data).get_dir_enum(
-                &*callback_data,
+            let restart = (*callback_data).Flags.0 & PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN.0 != 0;
+            let search_expr = to_string(search_expr);
+            let result = backend::<B>(callback_data).get_dir_enum(
                 uuid(enumeration_id),
-                search_expr,
-                dir_entry_buffer_handle,
-            )
+                search_expr.as_deref(),
+                restart,
+                &mut DirEntryBuffer(dir_entry_buffer_handle),
+            );
+            to_hresult("get_dir_enum", result)
         }
 
-        unsafe extern "system" fn get_placeholder_info_cb<B: ProjFsBackend>(
+        unsafe extern "system" fn get_placeholder_info_cb<B: VirtualFs + 'static>(
             callback_data: *const PRJ_CALLBACK_DATA,
         ) -> HRESULT {
-            backend::<B>(callback_data).get_placeholder_info(&*callback_data)
+            dispatch::<B>(
+                callback_data,
+                "get_placeholder_info",
+                |backend, request, cancel| {
+                    log::trace!("Get placeholder info: {:?}", request.path);
+                    let (info, version) = backend.get_placeholder_info(&request.path, cancel)?;
+                    cancel.check()?;
+                    unsafe {
+                        request.helper.write_placeholder_info(
+                            PCWSTR::from_raw(request.path_wide.as_ptr()),
+                            &placeholder_info(&info, &version),
+                        )
+                    }
+                    .context("write placeholder info")?;
+                    Ok(())
+                },
+            )
         }
 
-        unsafe extern "system" fn get_file_data_cb<B: ProjFsBackend>(
+        unsafe extern "system" fn get_file_data_cb<B: VirtualFs + 'static>(
             callback_data: *const PRJ_CALLBACK_DATA,
             byte_offset: u64,
             length: u32,
         ) -> HRESULT {
-            backend::<B>(callback_data).get_file_data(&*callback_data, byte_offset, length)
+            dispatch::<B>(
+                callback_data,
+                "get_file_data",
+                move |backend, request, cancel| {
+                    log::trace!(
+                        "Get file data: {:?}; offset {}, len {}",
+                        request.path,
+                        byte_offset,
+                        length,
+                    );
+                    // Writes which do not reach the end of the file must be
+                    // aligned, so the requested range is widened to whole
+                    // units. The data is then written in chunks, so that
+                    // large values do not need a buffer of their own size.
+                    let alignment = request
+                        .helper
+                        .write_alignment()
+                        .context("get write alignment")?
+                        .max(1);
+                    let (start, len) = aligned_range(byte_offset, length, alignment);
+                    let chunk_size =
+                        (MAX_CHUNK_SIZE & !(alignment as usize - 1)).max(alignment as usize);
+                    let writer = FileDataWriter {
+                        helper: request.helper,
+                        data_stream_id: request.data_stream_id,
+                    };
+                    let mut chunks = ChunkedWriter::new(writer, start, len, chunk_size);
+                    let len = len.min(u32::MAX as u64) as u32;
+                    backend.read_file_cancellable(
+                        &request.path,
+                        start,
+                        len,
+                        &mut chunks,
+                        cancel,
+                    )?;
+                    cancel.check()?;
+                    chunks.finish().context("write file data")?;
+                    Ok(())
+                },
+            )
         }
 
-        unsafe extern "system" fn notification_cb<B: ProjFsBackend>(
+        unsafe extern "system" fn notification_cb<B: VirtualFs>(
             callback_data: *const PRJ_CALLBACK_DATA,
             is_dir: BOOLEAN,
             notification: PRJ_NOTIFICATION,
             dest_filename: PCWSTR,
-            params: *mut PRJ_NOTIFICATION_PARAMETERS,
+            _params: *mut PRJ_NOTIFICATION_PARAMETERS,
         ) -> HRESULT {
-            backend::<B>(callback_data).notify(
-                &*callback_data,
-                is_dir.0 != 0,
-                notification.into(),
-                dest_filename,
-                params,
-            )
+            let result = req_path(callback_data).and_then(|path| {
+                let dest_path = to_string(dest_filename).filter(|dest| !dest.is_empty());
+                backend::<B>(callback_data).notify(&Notification {
+                    kind: notification.into(),
+                    path: &path,
+                    dest_path: dest_path.as_deref(),
+                    is_dir: is_dir.0 != 0,
+                    process_id: (*callback_data).TriggeringProcessId,
+                })
+            });
+            to_hresult("notify", result)
+        }
+
+        unsafe extern "system" fn query_file_name_cb<B: VirtualFs>(
+            callback_data: *const PRJ_CALLBACK_DATA,
+        ) -> HRESULT {
+            let result = req_path(callback_data).and_then(|path| {
+                log::trace!("Query file name: {:?}", path);
+                match backend::<B>(callback_data).entry_exists(&path)? {
+                    true => Ok(()),
+                    false => Err(VfsError::NotFound),
+                }
+            });
+            to_hresult("query_file_name", result)
+        }
+
+        unsafe extern "system" fn cancel_command_cb<B: VirtualFs>(
+            callback_data: *const PRJ_CALLBACK_DATA,
+        ) {
+            let command_id = (*callback_data).CommandId;
+            if let Some(commands) = &instance::<B>(callback_data).commands {
+                if commands.cancel(command_id) {
+                    log::debug!("Cancelled command {}", command_id);
+                }
+            }
         }
 
         let features = B::get_optional_features();
@@ -268,13 +311,21 @@ where
             GetDirectoryEnumerationCallback: Some(get_dir_enum_cb::<B>),
             GetPlaceholderInfoCallback: Some(get_placeholder_info_cb::<B>),
             GetFileDataCallback: Some(get_file_data_cb::<B>),
-            QueryFileNameCallback: None,
+            QueryFileNameCallback: if features.contains(OptionalFeatures::QUERY_FILE_NAME) {
+                Some(query_file_name_cb::<B>)
+            } else {
+                None
+            },
             NotificationCallback: if features.contains(OptionalFeatures::NOTIFY) {
                 Some(notification_cb::<B>)
             } else {
                 None
             },
-            CancelCommandCallback: None,
+            CancelCommandCallback: if features.contains(OptionalFeatures::CANCEL_COMMAND) {
+                Some(cancel_command_cb::<B>)
+            } else {
+                None
+            },
         }
     }
 
@@ -287,16 +338,42 @@ where
         }
 
         log::debug!("Stopping projection FS");
+        // Commands still being served are cancelled, and waited for, so that
+        // none is completed once the instance is gone. Callbacks made
+        // meanwhile fail, as the commands are shut down.
+        if let Some(commands) = &self.instance.commands {
+            commands.shutdown();
+        }
+        // This returns once the callbacks in flight have returned, and no
+        // more are made.
         unsafe {
             PrjStopVirtualizing(self.instance_handle);
         }
+        self.instance.backend.shutdown();
         self.state = FsState::Stopped;
     }
 }
 
+impl<B> ProjFs<B>
+where
+    B: VirtualFs + 'static,
+{
+    pub fn backend(&self) -> &B {
+        &self.instance.backend
+    }
+
+    /// Changes placeholders of this projection, until it is stopped.
+    pub fn invalidator(&self) -> Invalidator {
+        assert_eq!(self.state, FsState::Running);
+        Invalidator {
+            instance_handle: self.instance_handle,
+        }
+    }
+}
+
 impl<B> Drop for ProjFs<B>
 where
-    B: ProjFsBackend,
+    B: VirtualFs + 'static,
 {
     fn drop(&mut self) {
         if self.state == FsState::Running {
@@ -305,6 +382,342 @@ where
     }
 }
 
+/// Gets the instance context of a callback.
+unsafe fn instance<'a, B>(callback_data: *const PRJ_CALLBACK_DATA) -> &'a Instance<B> {
+    &*((*callback_data).InstanceContext as *const Instance<B>)
+}
+
+/// What is needed to answer a callback, copied since the callback data is
+/// only valid until the callback returns.
+struct Request {
+    helper: SimpleFsHelper,
+    path: String,
+    /// The path, null-terminated.
+    path_wide: Vec<u16>,
+    data_stream_id: GUID,
+    command_id: i32,
+}
+
+impl Request {
+    unsafe fn of(callback_data: *const PRJ_CALLBACK_DATA) -> VfsResult<Request> {
+        let data = &*callback_data;
+        let helper = SimpleFsHelper::new(data.NamespaceVirtualizationContext);
+        let path = helper
+            .get_req_path(data)
+            .map_err(|_| VfsError::InvalidArgument)?;
+        Ok(Request {
+            helper,
+            path_wide: path.encode_utf16().chain(std::iter::once(0)).collect(),
+            path,
+            data_stream_id: data.DataStreamId,
+            command_id: data.CommandId,
+        })
+    }
+}
+
+/// Serves a request on the callback thread or, for backends with
+/// `CANCEL_COMMAND`, on a command worker, returning `ERROR_IO_PENDING` and
+/// completing it with `PrjCompleteCommand` unless it is cancelled first.
+unsafe fn dispatch<B: VirtualFs + 'static>(
+    callback_data: *const PRJ_CALLBACK_DATA,
+    callback: &'static str,
+    serve: impl FnOnce(&B, &Request, &CancelToken) -> VfsResult<()> + Send + 'static,
+) -> HRESULT {
+    let instance = instance::<B>(callback_data);
+    let request = match Request::of(callback_data) {
+        Ok(request) => request,
+        Err(err) => return to_hresult(callback, Err(err)),
+    };
+    let commands = match &instance.commands {
+        Some(commands) => commands,
+        None => {
+            return to_hresult(
+                callback,
+                serve(&instance.backend, &request, &CancelToken::new()),
+            )
+        }
+    };
+
+    let backend = instance.backend.clone();
+    let command_id = request.command_id;
+    let spawned = commands.spawn(command_id, move |cancel| {
+        let result = serve(&backend, &request, cancel);
+        if cancel.is_cancelled() {
+            return;
+        }
+        let result = to_hresult(callback, result);
+        if let Err(err) = request.helper.complete_command(command_id, result) {
+            log::warn!(
+                "Cannot complete {} command {}: {}",
+                callback,
+                command_id,
+                err
+            );
+        }
+    });
+    match spawned {
+        Ok(()) => ERROR_IO_PENDING.to_hresult(),
+        Err(err) => to_hresult(callback, Err(err.into())),
+    }
+}
+
+/// Marks virtualization roots with `PrjMarkDirectoryAsPlaceholder`.
+pub struct PlaceholderMarker;
+
+impl MarkRoot for PlaceholderMarker {
+    fn mark_root(&self, root: &Path, instance_id: Uuid) -> anyhow::Result<()> {
+        let root_wide: Vec<u16> = root
+            .as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        unsafe {
+            PrjMarkDirectoryAsPlaceholder(
+                PCWSTR::from_raw(root_wide.as_ptr()),
+                PCWSTR::null(),
+                std::ptr::null(),
+                instance_id.as_bytes().as_ptr().cast(),
+            )
+        }?;
+        Ok(())
+    }
+}
+
+/// Updates and deletes placeholders with `PrjUpdateFileIfNeeded` and
+/// `PrjDeleteFile`. Files changed locally are left alone, and so are
+/// placeholders whose content ID is already the one given.
+pub struct Invalidator {
+    instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
+}
+
+const UPDATE_FLAGS: PRJ_UPDATE_TYPES =
+    PRJ_UPDATE_TYPES(PRJ_UPDATE_ALLOW_DIRTY_METADATA.0 | PRJ_UPDATE_ALLOW_TOMBSTONE.0);
+
+impl Invalidate for Invalidator {
+    fn update(&self, path: &str, info: &EntryInfo, version: &VersionInfo) -> anyhow::Result<()> {
+        let path_wide: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+        let placeholder_info = placeholder_info(info, version);
+        unsafe {
+            PrjUpdateFileIfNeeded(
+                self.instance_handle,
+                PCWSTR::from_raw(path_wide.as_ptr()),
+                &placeholder_info,
+                std::mem::size_of::<PRJ_PLACEHOLDER_INFO>() as u32,
+                UPDATE_FLAGS,
+            )
+        }
+        .context("update placeholder")?;
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> anyhow::Result<()> {
+        self.delete_with(path, UPDATE_FLAGS)
+    }
+
+    fn delete_changed(&self, path: &str) -> anyhow::Result<()> {
+        self.delete_with(
+            path,
+            PRJ_UPDATE_TYPES(UPDATE_FLAGS.0 | PRJ_UPDATE_ALLOW_DIRTY_DATA.0),
+        )
+    }
+}
+
+impl Invalidator {
+    fn delete_with(&self, path: &str, flags: PRJ_UPDATE_TYPES) -> anyhow::Result<()> {
+        let path_wide: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+        unsafe {
+            PrjDeleteFile(
+                self.instance_handle,
+                PCWSTR::from_raw(path_wide.as_ptr()),
+                flags,
+            )
+        }
+        .context("delete placeholder")?;
+        Ok(())
+    }
+}
+
+/// Runs a projection of an empty tree while cleaning up a root.
+pub struct EmptyProjection;
+
+impl RunEmpty for EmptyProjection {
+    fn run_empty(
+        &self,
+        root: &Path,
+        f: &mut dyn FnMut(&dyn Invalidate) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut proj_fs = ProjFs::new(
+            root.to_owned(),
+            PRJ_STARTVIRTUALIZING_OPTIONS::default(),
+            EmptyFs,
+        );
+        proj_fs.start().context("start empty projection")?;
+        let result = f(&proj_fs.invalidator());
+        proj_fs.stop();
+        result
+    }
+}
+
+/// Has no entries, so that only what is on disk shows in a root.
+struct EmptyFs;
+
+impl VirtualFs for EmptyFs {
+    fn get_optional_features() -> OptionalFeatures {
+        OptionalFeatures::empty()
+    }
+
+    fn start_dir_enum(&self, _path: &str, _enumeration_id: Uuid) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn end_dir_enum(&self, _enumeration_id: Uuid) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn get_dir_enum(
+        &self,
+        _enumeration_id: Uuid,
+        _search_expr: Option<&str>,
+        _restart: bool,
+        _sink: &mut dyn DirEntrySink,
+    ) -> VfsResult<()> {
+        Ok(())
+    }
+
+    fn get_entry_info(&self, _path: &str) -> VfsResult<EntryInfo> {
+        Err(VfsError::NotFound)
+    }
+
+    fn read_file(
+        &self,
+        _path: &str,
+        _byte_offset: u64,
+        _length: u32,
+        _sink: &mut dyn FileDataSink,
+    ) -> VfsResult<()> {
+        Err(VfsError::NotFound)
+    }
+
+    fn notify(&self, _notification: &Notification) -> VfsResult<()> {
+        Ok(())
+    }
+}
+
+/// Reads file states with `PrjGetOnDiskFileState`.
+pub struct OnDiskFileStates;
+
+impl FileStates for OnDiskFileStates {
+    fn file_state(&self, path: &Path) -> anyhow::Result<FileState> {
+        let path_wide: Vec<u16> = path
+            .as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect();
+        let state = unsafe { PrjGetOnDiskFileState(PCWSTR::from_raw(path_wide.as_ptr())) }?;
+        // Only one state is set, but the most local one wins just in case.
+        Ok(if state.0 & PRJ_FILE_STATE_TOMBSTONE.0 != 0 {
+            FileState::Tombstone
+        } else if state.0 & PRJ_FILE_STATE_FULL.0 != 0 {
+            FileState::Full
+        } else if state.0 & PRJ_FILE_STATE_DIRTY_PLACEHOLDER.0 != 0 {
+            FileState::DirtyPlaceholder
+        } else if state.0 & PRJ_FILE_STATE_HYDRATED_PLACEHOLDER.0 != 0 {
+            FileState::HydratedPlaceholder
+        } else if state.0 & PRJ_FILE_STATE_PLACEHOLDER.0 != 0 {
+            FileState::Placeholder
+        } else {
+            // Not known to ProjFS, so not ours to remove.
+            FileState::Full
+        })
+    }
+}
+
+/// Adds directory entries to a ProjFS directory entry buffer.
+struct DirEntryBuffer(PRJ_DIR_ENTRY_BUFFER_HANDLE);
+
+impl DirEntrySink for DirEntryBuffer {
+    fn add(&mut self, name: &str, info: &EntryInfo) -> anyhow::Result<bool> {
+        let name_wstr: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+        let file_info = PRJ_FILE_BASIC_INFO {
+            IsDirectory: BOOLEAN(info.is_dir as u8),
+            FileSize: info.size as i64,
+            ..Default::default()
+        };
+
+        match unsafe {
+            PrjFillDirEntryBuffer(PCWSTR::from_raw(name_wstr.as_ptr()), &file_info, self.0)
+        } {
+            Ok(()) => Ok(true),
+            Err(err) if err.code() == ERROR_INSUFFICIENT_BUFFER.to_hresult() => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn to_hresult(callback: &str, result: VfsResult<()>) -> HRESULT {
+    match result {
+        Ok(()) => S_OK,
+        Err(VfsError::NotFound) => ERROR_FILE_NOT_FOUND.to_hresult(),
+        Err(VfsError::AccessDenied) => ERROR_ACCESS_DENIED.to_hresult(),
+        Err(VfsError::CannotDelete) => STATUS_CANNOT_DELETE.to_hresult(),
+        Err(VfsError::InvalidArgument) => E_INVALIDARG,
+        Err(VfsError::Cancelled) => ERROR_OPERATION_ABORTED.to_hresult(),
+        Err(VfsError::Other(err)) => {
+            log::error!("Error in {}: {:#}", callback, err);
+            err.downcast::<windows::core::Error>()
+                .map(Into::into)
+                .unwrap_or(E_FAIL)
+        }
+    }
+}
+
+/// Notification mappings in the form `PrjStartVirtualizing` takes them,
+/// along with the root paths they point to.
+pub struct NotificationMappings {
+    _roots: Vec<Vec<u16>>,
+    mappings: Vec<PRJ_NOTIFICATION_MAPPING>,
+}
+
+impl NotificationMappings {
+    /// Creates mappings from the paths of subtrees, relative to the
+    /// virtualization root, and the notifications to send for them. An empty
+    /// list suppresses all notifications for the subtree.
+    pub fn new<'a>(
+        mappings: impl IntoIterator<Item = (&'a str, &'a [NotificationKind])>,
+    ) -> NotificationMappings {
+        let mut roots = Vec::new();
+        let mut result = Vec::new();
+        for (path, kinds) in mappings {
+            let root: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+            let mask = if kinds.is_empty() {
+                PRJ_NOTIFY_SUPPRESS_NOTIFICATIONS
+            } else {
+                // Each notification has the same value as the bit which
+                // requests it.
+                kinds.iter().fold(PRJ_NOTIFY_NONE, |mask, &kind| {
+                    mask | PRJ_NOTIFY_TYPES(PRJ_NOTIFICATION::from(kind).0 as u32)
+                })
+            };
+            result.push(PRJ_NOTIFICATION_MAPPING {
+                NotificationBitMask: mask,
+                NotificationRoot: PCWSTR::from_raw(root.as_ptr()),
+            });
+            // Moving the root does not move the buffer the mapping points to.
+            roots.push(root);
+        }
+        NotificationMappings {
+            _roots: roots,
+            mappings: result,
+        }
+    }
+
+    /// Points the options at the mappings, which must outlive them.
+    pub fn apply(&mut self, options: &mut PRJ_STARTVIRTUALIZING_OPTIONS) {
+        options.NotificationMappings = self.mappings.as_mut_ptr();
+        options.NotificationMappingsCount = self.mappings.len() as u32;
+    }
+}
+
 impl From<PRJ_NOTIFICATION> for NotificationKind {
     #[rustfmt::skip]
     fn from(n: PRJ_NOTIFICATION) -> Self {
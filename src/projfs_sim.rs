@@ -0,0 +1,1037 @@
+//! An in-process simulation of the ProjFS side of the provider contract, so
+//! that `VirtualFs` backends can be tested without Windows.
+//!
+//! Callbacks are invoked the way ProjFS invokes them, and their results are
+//! collected the way a ProjFS client would observe them: enumerations are
+//! drained through size-limited directory entry buffers, and placeholder info
+//! and file data are recorded as they are written.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc, Arc,
+};
+
+use uuid::Uuid;
+
+use crate::{
+    commands::{CancelToken, Commands},
+    file_data::{aligned_range, ChunkWriter, ChunkedWriter},
+    reg_name,
+    vfs::{DirEntrySink, EntryInfo, OptionalFeatures, VersionInfo, VfsError, VfsResult, VirtualFs},
+};
+
+/// Size of the fixed part of a `FILE_ID_BOTH_DIR_INFORMATION` entry, which is
+/// what directory entry buffers are usually filled with.
+const DIR_ENTRY_HEADER_SIZE: usize = 104;
+
+/// The status ProjFS sees for a callback, as converted by `projfs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    FileNotFound,
+    AccessDenied,
+    CannotDelete,
+    InvalidArgument,
+    /// `ERROR_OPERATION_ABORTED`
+    Cancelled,
+    Failed,
+}
+
+/// Flags passed to callbacks in `PRJ_CALLBACK_DATA`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallbackFlags {
+    /// `PRJ_CB_DATA_FLAG_ENUM_RESTART_SCAN`
+    pub restart_scan: bool,
+    /// `PRJ_CB_DATA_FLAG_ENUM_RETURN_SINGLE_ENTRY`
+    pub return_single_entry: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub info: EntryInfo,
+}
+
+/// A placeholder written with `PrjWritePlaceholderInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placeholder {
+    pub path: String,
+    pub info: EntryInfo,
+    pub version: VersionInfo,
+}
+
+/// A chunk of data written with `PrjWriteFileData`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileWrite {
+    pub path: String,
+    pub byte_offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// A directory entry buffer with a fixed capacity in bytes, which behaves
+/// like the one filled by `PrjFillDirEntryBuffer`.
+pub struct DirEntryBuffer {
+    capacity: usize,
+    used: usize,
+    single_entry: bool,
+    pub entries: Vec<DirEntry>,
+}
+
+impl DirEntryBuffer {
+    pub fn new(capacity: usize) -> DirEntryBuffer {
+        DirEntryBuffer {
+            capacity,
+            used: 0,
+            single_entry: false,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns the number of bytes an entry with the given name takes up.
+    pub fn entry_size(name: &str) -> usize {
+        let size = DIR_ENTRY_HEADER_SIZE + name.encode_utf16().count() * 2;
+        (size + 7) & !7
+    }
+}
+
+impl DirEntrySink for DirEntryBuffer {
+    fn add(&mut self, name: &str, info: &EntryInfo) -> anyhow::Result<bool> {
+        if name.is_empty() || name.chars().any(|c| c < ' ' || "\\/:*?\"<>|".contains(c)) {
+            anyhow::bail!("invalid file name {:?}", name);
+        }
+
+        let size = Self::entry_size(name);
+        if self.used + size > self.capacity || (self.single_entry && !self.entries.is_empty()) {
+            // ERROR_INSUFFICIENT_BUFFER
+            return Ok(false);
+        }
+        self.used += size;
+        self.entries.push(DirEntry {
+            name: String::from(name),
+            info: *info,
+        });
+        Ok(true)
+    }
+}
+
+/// Collects the chunks of one file data request.
+struct ChunkRecorder {
+    path: String,
+    writes: Vec<FileWrite>,
+}
+
+impl ChunkWriter for ChunkRecorder {
+    type Buffer = Vec<u8>;
+
+    fn alloc(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        Ok(vec![0; len])
+    }
+
+    fn write(&mut self, buffer: &Vec<u8>, len: usize, byte_offset: u64) -> anyhow::Result<()> {
+        self.writes.push(FileWrite {
+            path: self.path.clone(),
+            byte_offset,
+            data: buffer[..len].to_vec(),
+        });
+        Ok(())
+    }
+}
+
+/// Drives a backend through the ProjFS callbacks.
+pub struct Simulator<B: VirtualFs> {
+    pub backend: Arc<B>,
+    pub placeholders: Vec<Placeholder>,
+    pub file_writes: Vec<FileWrite>,
+    /// What `PrjGetVirtualizationInstanceInfo` reports as `WriteAlignment`.
+    pub write_alignment: u32,
+    /// The chunk size the provider uses, as in `projfs`.
+    pub chunk_size: usize,
+    commands: Commands,
+    stopped: Arc<AtomicBool>,
+    /// Commands completed after the projection stopped, which ProjFS would
+    /// fail on.
+    pub late_completions: Arc<AtomicUsize>,
+}
+
+impl<B: VirtualFs> Simulator<B> {
+    pub fn new(backend: B) -> Simulator<B> {
+        Simulator {
+            backend: Arc::new(backend),
+            placeholders: Vec::new(),
+            file_writes: Vec::new(),
+            write_alignment: 1,
+            chunk_size: crate::file_data::MAX_CHUNK_SIZE,
+            commands: Commands::new(4).unwrap(),
+            stopped: Arc::new(AtomicBool::new(false)),
+            late_completions: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn start_dir_enum(&self, path: &str, enumeration_id: Uuid) -> Status {
+        to_status(self.backend.start_dir_enum(path, enumeration_id))
+    }
+
+    pub fn end_dir_enum(&self, enumeration_id: Uuid) -> Status {
+        to_status(self.backend.end_dir_enum(enumeration_id))
+    }
+
+    /// Invokes the directory enumeration callback once, with an empty buffer
+    /// of the given capacity.
+    pub fn get_dir_enum(
+        &self,
+        enumeration_id: Uuid,
+        search_expr: Option<&str>,
+        flags: CallbackFlags,
+        capacity: usize,
+    ) -> (Status, Vec<DirEntry>) {
+        let mut buffer = DirEntryBuffer::new(capacity);
+        buffer.single_entry = flags.return_single_entry;
+        let status = to_status(self.backend.get_dir_enum(
+            enumeration_id,
+            search_expr,
+            flags.restart_scan,
+            &mut buffer,
+        ));
+        (status, buffer.entries)
+    }
+
+    /// Lists a directory the way a client does: the enumeration callback is
+    /// invoked until it returns no more entries, the first time with the
+    /// restart flag set.
+    pub fn list_dir(
+        &self,
+        path: &str,
+        search_expr: Option<&str>,
+        capacity: usize,
+    ) -> Result<Vec<DirEntry>, Status> {
+        let enumeration_id = Uuid::new_v4();
+        check(self.start_dir_enum(path, enumeration_id))?;
+
+        let mut result = Vec::new();
+        let mut flags = CallbackFlags {
+            restart_scan: true,
+            ..Default::default()
+        };
+        let status = loop {
+            let (status, entries) = self.get_dir_enum(enumeration_id, search_expr, flags, capacity);
+            flags.restart_scan = false;
+            if status != Status::Ok || entries.is_empty() {
+                break status;
+            }
+            result.extend(entries);
+        };
+
+        check(self.end_dir_enum(enumeration_id))?;
+        check(status)?;
+        // ProjFS merges the entries with those on disk, which only works if
+        // they are sorted.
+        for pair in result.windows(2) {
+            assert!(
+                reg_name::cmp(&pair[0].name, &pair[1].name).is_lt(),
+                "entries out of order: {:?}, {:?}",
+                pair[0].name,
+                pair[1].name,
+            );
+        }
+        Ok(result)
+    }
+
+    /// Invokes the placeholder info callback, recording the placeholder
+    /// written by the backend.
+    pub fn get_placeholder_info(&mut self, path: &str) -> Status {
+        let result = self
+            .backend
+            .get_placeholder_info(path, &CancelToken::new())
+            .map(|(info, version)| Placeholder {
+                path: String::from(path),
+                info,
+                version,
+            });
+        match result {
+            Ok(placeholder) => {
+                self.placeholders.push(placeholder);
+                Status::Ok
+            }
+            Err(err) => to_status(Err(err)),
+        }
+    }
+
+    /// Invokes the query file name callback, which tells whether an entry
+    /// exists without writing a placeholder.
+    pub fn query_file_name(&self, path: &str) -> Status {
+        assert!(B::get_optional_features().contains(OptionalFeatures::QUERY_FILE_NAME));
+        match self.backend.entry_exists(path) {
+            Ok(true) => Status::Ok,
+            Ok(false) => Status::FileNotFound,
+            Err(err) => to_status(Err(err)),
+        }
+    }
+
+    /// Invokes the file data callback the way `projfs` does, recording the
+    /// chunks written by the provider.
+    pub fn get_file_data(&mut self, path: &str, byte_offset: u64, length: u32) -> Status {
+        let request = self.file_data_request(path, byte_offset, length);
+        match request.serve(&*self.backend, &CancelToken::new()) {
+            Ok(writes) => {
+                self.file_writes.extend(writes);
+                Status::Ok
+            }
+            Err(status) => status,
+        }
+    }
+
+    fn file_data_request(&self, path: &str, byte_offset: u64, length: u32) -> FileDataRequest {
+        let size = match self.placeholders.iter().rev().find(|p| p.path == path) {
+            Some(placeholder) if !placeholder.info.is_dir => placeholder.info.size,
+            _ => panic!("file data requested for {:?} without a placeholder", path),
+        };
+        FileDataRequest {
+            path: String::from(path),
+            size,
+            byte_offset,
+            length,
+            write_alignment: self.write_alignment,
+            chunk_size: self.chunk_size,
+        }
+    }
+
+    /// Opens and reads a whole file the way a client does, hydrating its
+    /// placeholder first.
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Status> {
+        check(self.get_placeholder_info(path))?;
+        let size = self.placeholders.last().unwrap().info.size;
+        let first = self.file_writes.len();
+        check(self.get_file_data(path, 0, size as u32))?;
+
+        Ok(self.file_writes[first..]
+            .iter()
+            .flat_map(|write| write.data.iter().copied())
+            .collect())
+    }
+}
+
+impl<B: VirtualFs + 'static> Simulator<B> {
+    /// Invokes the file data callback for a backend with `CANCEL_COMMAND`,
+    /// which `projfs` serves on a command worker. The chunks written are
+    /// returned once the command completes, rather than recorded.
+    pub fn get_file_data_async(
+        &self,
+        command_id: i32,
+        path: &str,
+        byte_offset: u64,
+        length: u32,
+    ) -> PendingCommand {
+        assert!(B::get_optional_features().contains(OptionalFeatures::CANCEL_COMMAND));
+        let request = self.file_data_request(path, byte_offset, length);
+        let backend = self.backend.clone();
+        let (stopped, late_completions) = (self.stopped.clone(), self.late_completions.clone());
+        let (completion, receiver) = mpsc::channel();
+        self.commands
+            .spawn(command_id, move |cancel| {
+                let result = request.serve(&*backend, cancel);
+                // As in `projfs`, cancelled commands are not completed.
+                if !cancel.is_cancelled() {
+                    if stopped.load(Ordering::SeqCst) {
+                        late_completions.fetch_add(1, Ordering::SeqCst);
+                    }
+                    let _ = completion.send(result);
+                }
+            })
+            .unwrap();
+        PendingCommand(receiver)
+    }
+
+    /// Invokes the cancel command callback.
+    pub fn cancel_command(&self, command_id: i32) -> bool {
+        self.commands.cancel(command_id)
+    }
+
+    /// Stops the projection the way `ProjFs::stop` does.
+    pub fn stop(&self) {
+        self.commands.shutdown();
+        self.stopped.store(true, Ordering::SeqCst);
+        self.backend.shutdown();
+    }
+}
+
+/// A command for which the callback returned `ERROR_IO_PENDING`.
+pub struct PendingCommand(mpsc::Receiver<Result<Vec<FileWrite>, Status>>);
+
+impl PendingCommand {
+    /// Waits for `PrjCompleteCommand`, returning `None` if the command was
+    /// never completed.
+    pub fn wait(self) -> Option<Result<Vec<FileWrite>, Status>> {
+        self.0.recv().ok()
+    }
+}
+
+/// What `projfs` copies from a file data callback to answer it.
+struct FileDataRequest {
+    path: String,
+    /// The size of the file, as recorded in its placeholder.
+    size: u64,
+    byte_offset: u64,
+    length: u32,
+    write_alignment: u32,
+    chunk_size: usize,
+}
+
+impl FileDataRequest {
+    /// Serves the request, checking the writes the way ProjFS does: writing
+    /// less than requested is an error unless the end of the file is
+    /// reached, and so are unaligned writes which do not reach it.
+    fn serve(
+        &self,
+        backend: &dyn VirtualFs,
+        cancel: &CancelToken,
+    ) -> Result<Vec<FileWrite>, Status> {
+        let (start, len) = aligned_range(self.byte_offset, self.length, self.write_alignment);
+        let recorder = ChunkRecorder {
+            path: self.path.clone(),
+            writes: Vec::new(),
+        };
+        let mut chunks = ChunkedWriter::new(recorder, start, len, self.chunk_size);
+        let len = len.min(u32::MAX as u64) as u32;
+        check(to_status(backend.read_file_cancellable(
+            &self.path,
+            start,
+            len,
+            &mut chunks,
+            cancel,
+        )))?;
+        let writes = chunks.finish().unwrap().writes;
+
+        let alignment = self.write_alignment as u64;
+        let mut end = start;
+        for write in &writes {
+            let write_end = write.byte_offset + write.data.len() as u64;
+            assert!(write.data.len() <= self.chunk_size);
+            if write.byte_offset != end
+                || !write.byte_offset.is_multiple_of(alignment)
+                || (write_end < self.size && !write_end.is_multiple_of(alignment))
+                || write_end > self.size
+            {
+                return Err(Status::InvalidArgument);
+            }
+            end = write_end;
+        }
+        if end < self.size.min(self.byte_offset + self.length as u64) {
+            return Err(Status::InvalidArgument);
+        }
+        Ok(writes)
+    }
+}
+
+fn to_status(result: VfsResult<()>) -> Status {
+    match result {
+        Ok(()) => Status::Ok,
+        Err(VfsError::NotFound) => Status::FileNotFound,
+        Err(VfsError::AccessDenied) => Status::AccessDenied,
+        Err(VfsError::CannotDelete) => Status::CannotDelete,
+        Err(VfsError::InvalidArgument) => Status::InvalidArgument,
+        Err(VfsError::Cancelled) => Status::Cancelled,
+        Err(VfsError::Other(_)) => Status::Failed,
+    }
+}
+
+fn check(status: Status) -> Result<(), Status> {
+    match status {
+        Status::Ok => Ok(()),
+        other => Err(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        regfs::{RegFs, Rendering},
+        simple_fs::SimpleFs,
+        source::{KeyEntries, RegSource, Value, REG_BINARY, REG_DWORD},
+        vfs,
+    };
+
+    fn reg_fs() -> RegFs {
+        RegFs::new(PathBuf::from("unused"), Arc::new(mem_source()))
+    }
+
+    fn mem_source() -> MemSource {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_TEST\\Empty");
+        for name in ["Delta", "alpha", "Charlie", "bravo"] {
+            source.create_key(&format!("HKEY_TEST\\Keys\\{}", name));
+        }
+        source.set_value(
+            "HKEY_TEST\\Keys",
+            "Count",
+            Value {
+                vtype: REG_DWORD,
+                bytes: vec![4, 0, 0, 0],
+            },
+        );
+        source.set_value(
+            "HKEY_TEST\\Keys",
+            "Bad*Name",
+            Value {
+                vtype: REG_BINARY,
+                bytes: vec![1],
+            },
+        );
+        source.set_value(
+            "HKEY_TEST",
+            "Blob",
+            Value {
+                vtype: REG_BINARY,
+                bytes: (0..=255).collect(),
+            },
+        );
+        source
+    }
+
+    /// Counts reads of values, and only reads them once allowed to, after
+    /// `slow` is set.
+    struct SlowSource {
+        inner: MemSource,
+        slow: AtomicBool,
+        gate: Mutex<mpsc::Receiver<()>>,
+        reads: AtomicUsize,
+        /// The last write time of every key, if known.
+        last_write: Option<u64>,
+    }
+
+    impl SlowSource {
+        fn new(gate: mpsc::Receiver<()>) -> SlowSource {
+            SlowSource {
+                inner: mem_source(),
+                slow: AtomicBool::new(false),
+                gate: Mutex::new(gate),
+                reads: AtomicUsize::new(0),
+                last_write: None,
+            }
+        }
+    }
+
+    impl RegSource for SlowSource {
+        fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+            self.inner.enum_key(path)
+        }
+
+        fn key_exists(&self, path: &str) -> anyhow::Result<bool> {
+            self.inner.key_exists(path)
+        }
+
+        fn read_value(&self, path: &str) -> anyhow::Result<Option<Value>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            if self.slow.load(Ordering::SeqCst) {
+                self.gate.lock().unwrap().recv()?;
+            }
+            self.inner.read_value(path)
+        }
+
+        fn value_size(&self, path: &str) -> anyhow::Result<Option<u32>> {
+            self.inner.value_size(path)
+        }
+
+        fn key_last_write(&self, path: &str) -> anyhow::Result<Option<u64>> {
+            Ok(self.last_write.filter(|_| self.key_exists(path).unwrap()))
+        }
+    }
+
+    /// Has one huge value and one huge key, made up as they are read, which
+    /// are never read whole.
+    #[derive(Default)]
+    struct HugeSource {
+        visited: AtomicUsize,
+        listed: AtomicUsize,
+    }
+
+    const HUGE_SIZE: usize = 1 << 30;
+    const HUGE_KEYS: usize = 1_000_000;
+
+    fn huge_byte(offset: usize) -> u8 {
+        (offset % 251) as u8
+    }
+
+    fn huge_key_name(i: usize) -> String {
+        format!("Key{:07}", i)
+    }
+
+    impl RegSource for HugeSource {
+        fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+            anyhow::bail!("the stub only visits keys, and cannot list {:?}", path)
+        }
+
+        fn visit_key(
+            &self,
+            path: &str,
+            after: Option<(&str, bool)>,
+            f: &mut dyn FnMut(String, Option<u32>) -> anyhow::Result<bool>,
+        ) -> anyhow::Result<bool> {
+            if path != "HKEY_TEST\\Many" {
+                return Ok(false);
+            }
+            let start = match after {
+                Some((name, _)) => name["Key".len()..].parse::<usize>()? + 1,
+                None => 0,
+            };
+            for i in start..HUGE_KEYS {
+                self.listed.fetch_add(1, Ordering::SeqCst);
+                if !f(huge_key_name(i), None)? {
+                    break;
+                }
+            }
+            Ok(true)
+        }
+
+        fn streams_keys(&self) -> bool {
+            true
+        }
+
+        fn key_exists(&self, _path: &str) -> anyhow::Result<bool> {
+            Ok(false)
+        }
+
+        fn read_value(&self, _path: &str) -> anyhow::Result<Option<Value>> {
+            panic!("huge value read whole");
+        }
+
+        fn visit_value(
+            &self,
+            path: &str,
+            f: &mut dyn FnMut(u32, &[u8]) -> anyhow::Result<bool>,
+        ) -> anyhow::Result<bool> {
+            if path != "HKEY_TEST\\Huge" {
+                return Ok(false);
+            }
+            let mut piece = vec![0; 1 << 16];
+            for start in (0..HUGE_SIZE).step_by(piece.len()) {
+                for (i, b) in piece.iter_mut().enumerate() {
+                    *b = huge_byte(start + i);
+                }
+                self.visited.fetch_add(piece.len(), Ordering::SeqCst);
+                if !f(REG_BINARY, &piece)? {
+                    break;
+                }
+            }
+            Ok(true)
+        }
+    }
+
+    fn names(entries: &[DirEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn list_with_small_buffers() {
+        let sim = Simulator::new(reg_fs());
+        let expected = ["alpha", "bravo", "Charlie", "Count", "Delta"];
+
+        // Buffers holding one, two and plenty of entries should all produce
+        // the same listing; the invalid name is skipped.
+        for capacity in [DirEntryBuffer::entry_size("Charlie"), 250, 4096] {
+            let entries = sim.list_dir("HKEY_TEST\\Keys", None, capacity).unwrap();
+            assert_eq!(names(&entries), expected, "capacity {}", capacity);
+        }
+
+        let entries = sim.list_dir("HKEY_TEST\\Keys", None, 4096).unwrap();
+        assert_eq!(entries[0].info, EntryInfo::dir());
+        assert_eq!(entries[3].info, EntryInfo::file(4));
+    }
+
+    #[test]
+    fn list_empty_and_missing() {
+        let sim = Simulator::new(reg_fs());
+        assert_eq!(sim.list_dir("HKEY_TEST\\Empty", None, 4096), Ok(vec![]));
+        assert_eq!(
+            sim.list_dir("HKEY_TEST\\Missing", None, 4096),
+            Err(Status::FileNotFound),
+        );
+        assert_eq!(
+            sim.get_dir_enum(Uuid::new_v4(), None, CallbackFlags::default(), 4096)
+                .0,
+            Status::InvalidArgument,
+        );
+    }
+
+    #[test]
+    fn restart_scan() {
+        let sim = Simulator::new(reg_fs());
+        let id = Uuid::new_v4();
+        assert_eq!(sim.start_dir_enum("HKEY_TEST\\Keys", id), Status::Ok);
+
+        let restart = CallbackFlags {
+            restart_scan: true,
+            ..Default::default()
+        };
+        let single = CallbackFlags {
+            return_single_entry: true,
+            ..Default::default()
+        };
+        let (_, first) = sim.get_dir_enum(id, None, restart, 4096);
+        assert_eq!(
+            names(&first),
+            ["alpha", "bravo", "Charlie", "Count", "Delta"]
+        );
+        let (_, rest) = sim.get_dir_enum(id, None, single, 4096);
+        assert!(rest.is_empty());
+
+        let (_, first) = sim.get_dir_enum(
+            id,
+            None,
+            CallbackFlags {
+                restart_scan: true,
+                ..single
+            },
+            4096,
+        );
+        assert_eq!(names(&first), ["alpha"]);
+        let (_, next) = sim.get_dir_enum(id, None, single, 4096);
+        assert_eq!(names(&next), ["bravo"]);
+
+        assert_eq!(sim.end_dir_enum(id), Status::Ok);
+    }
+
+    #[test]
+    fn text_sizes_when_listed() {
+        let source = Arc::new(SlowSource::new(mpsc::channel().1));
+        let fs =
+            RegFs::new(PathBuf::from("unused"), source.clone()).with_rendering(Rendering::Text);
+        let sim = Simulator::new(fs);
+        let id = Uuid::new_v4();
+        assert_eq!(sim.start_dir_enum("HKEY_TEST", id), Status::Ok);
+        // Nothing is read until values are listed, and then only those.
+        assert_eq!(source.reads.load(Ordering::SeqCst), 0);
+        let single = CallbackFlags {
+            return_single_entry: true,
+            ..Default::default()
+        };
+        let (_, first) = sim.get_dir_enum(id, None, single, 4096);
+        assert_eq!(names(&first), ["Blob"]);
+        assert_eq!(source.reads.load(Ordering::SeqCst), 1);
+        let text = vfs::read_to_vec(&*sim.backend, "HKEY_TEST\\Blob", 0, u32::MAX).unwrap();
+        assert_eq!(first[0].info, EntryInfo::file(text.len() as u64));
+
+        let restart = CallbackFlags {
+            restart_scan: true,
+            ..Default::default()
+        };
+        let (_, all) = sim.get_dir_enum(id, None, restart, 4096);
+        assert_eq!(names(&all), ["Blob", "Empty", "Keys"]);
+        assert_eq!(all[0].info, first[0].info);
+        assert_eq!(sim.end_dir_enum(id), Status::Ok);
+    }
+
+    #[test]
+    fn search_expressions() {
+        let sim = Simulator::new(reg_fs());
+        let list = |expr| {
+            let entries = sim.list_dir("HKEY_TEST\\Keys", Some(expr), 250).unwrap();
+            names(&entries).join(",")
+        };
+        assert_eq!(list("*"), "alpha,bravo,Charlie,Count,Delta");
+        assert_eq!(list("c*"), "Charlie,Count");
+        assert_eq!(list("?RAVO"), "bravo");
+        assert_eq!(list("DELTA"), "Delta");
+        assert_eq!(list("x*"), "");
+        assert_eq!(list("<t"), "Count");
+        assert_eq!(list(">>>>>"), "alpha,bravo,Count,Delta");
+    }
+
+    #[test]
+    fn placeholders_and_file_data() {
+        let mut sim = Simulator::new(reg_fs());
+        assert_eq!(sim.get_placeholder_info("HKEY_TEST\\Keys"), Status::Ok);
+        assert_eq!(
+            sim.get_placeholder_info("HKEY_TEST\\Nope"),
+            Status::FileNotFound
+        );
+        assert_eq!(
+            sim.read_file("HKEY_TEST\\Keys\\Count"),
+            Ok(vec![4, 0, 0, 0])
+        );
+        let placeholders: Vec<_> = sim
+            .placeholders
+            .iter()
+            .map(|p| (p.path.as_str(), p.info))
+            .collect();
+        assert_eq!(
+            placeholders,
+            [
+                ("HKEY_TEST\\Keys", EntryInfo::dir()),
+                ("HKEY_TEST\\Keys\\Count", EntryInfo::file(4)),
+            ],
+        );
+
+        // Partial reads at an offset
+        assert_eq!(sim.get_placeholder_info("HKEY_TEST\\Blob"), Status::Ok);
+        assert_eq!(sim.get_file_data("HKEY_TEST\\Blob", 200, 16), Status::Ok);
+        let write = sim.file_writes.last().unwrap();
+        assert_eq!(write.byte_offset, 200);
+        assert_eq!(write.data, (200..216).collect::<Vec<u8>>());
+        let writes = sim.file_writes.len();
+        assert_eq!(sim.get_file_data("HKEY_TEST\\Blob", 256, 16), Status::Ok);
+        assert_eq!(sim.file_writes.len(), writes);
+    }
+
+    #[test]
+    fn query_file_name() {
+        let sim = Simulator::new(reg_fs());
+        assert_eq!(sim.query_file_name("HKEY_TEST\\Keys\\alpha"), Status::Ok);
+        assert_eq!(sim.query_file_name("hkey_test\\keys\\COUNT"), Status::Ok);
+        assert_eq!(sim.query_file_name("HKEY_TEST\\Nope"), Status::FileNotFound);
+        assert_eq!(
+            sim.query_file_name("HKEY_TEST\\Keys\\Count\\Below"),
+            Status::FileNotFound
+        );
+        assert_eq!(
+            sim.query_file_name("@search\\count\\HKEY_TEST%5CKeys%5CCount"),
+            Status::Ok
+        );
+        assert_eq!(
+            sim.query_file_name("@search\\count\\HKEY_TEST%5CBlob"),
+            Status::FileNotFound
+        );
+        // Nothing is written for the client.
+        assert!(sim.placeholders.is_empty());
+    }
+
+    #[test]
+    fn aligned_chunks() {
+        let mut sim = Simulator::new(reg_fs());
+        sim.write_alignment = 64;
+        sim.chunk_size = 128;
+        let blob: Vec<u8> = (0..=255).collect();
+        assert_eq!(sim.read_file("HKEY_TEST\\Blob"), Ok(blob));
+        let chunks: Vec<_> = sim
+            .file_writes
+            .iter()
+            .map(|write| (write.byte_offset, write.data.len()))
+            .collect();
+        assert_eq!(chunks, [(0, 128), (128, 128)]);
+
+        // Requests are widened to whole units of the alignment.
+        sim.file_writes.clear();
+        assert_eq!(sim.get_file_data("HKEY_TEST\\Blob", 100, 10), Status::Ok);
+        let write = &sim.file_writes[0];
+        assert_eq!((write.byte_offset, write.data.len()), (64, 64));
+        assert_eq!(write.data[36..46], (100..110).collect::<Vec<u8>>()[..]);
+        // Files which do not fill the last unit end with a short chunk.
+        sim.file_writes.clear();
+        assert_eq!(
+            sim.read_file("HKEY_TEST\\Keys\\Count"),
+            Ok(vec![4, 0, 0, 0])
+        );
+        assert_eq!(sim.file_writes.len(), 1);
+    }
+
+    #[test]
+    fn huge_value_ranges() {
+        let source = Arc::new(HugeSource::default());
+        let fs = RegFs::new(PathBuf::from("unused"), source.clone());
+        let offset = 5_000_000;
+        let data = vfs::read_to_vec(&fs, "HKEY_TEST\\Huge", offset as u64, 100).unwrap();
+        let expected: Vec<u8> = (offset..offset + 100).map(huge_byte).collect();
+        assert_eq!(data, expected);
+        // Reading stops once the range is done.
+        assert!(source.visited.load(Ordering::SeqCst) < offset + (1 << 17));
+
+        // So does the text, of which only the lines in range are made.
+        source.visited.store(0, Ordering::SeqCst);
+        let fs =
+            RegFs::new(PathBuf::from("unused"), source.clone()).with_rendering(Rendering::Text);
+        let line_len = crate::source::hex_line_len(16);
+        let text = vfs::read_to_vec(
+            &fs,
+            "HKEY_TEST\\Huge",
+            (line_len * 300_000) as u64,
+            line_len as u32,
+        )
+        .unwrap();
+        let mut expected = String::new();
+        let line: Vec<u8> = (300_000 * 16..300_001 * 16).map(huge_byte).collect();
+        crate::source::hex_line(&mut expected, 300_000 * 16, &line);
+        assert_eq!(String::from_utf8(text).unwrap(), expected);
+        assert!(source.visited.load(Ordering::SeqCst) < 300_001 * 16 + (1 << 17));
+
+        assert!(matches!(
+            vfs::read_to_vec(&fs, "HKEY_TEST\\Tiny", 0, 1),
+            Err(VfsError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn huge_key_listing() {
+        let source = Arc::new(HugeSource::default());
+        let sim = Simulator::new(RegFs::new(PathBuf::from("unused"), source.clone()));
+        let id = Uuid::new_v4();
+        assert_eq!(sim.start_dir_enum("HKEY_TEST\\Many", id), Status::Ok);
+        let (_, first) = sim.get_dir_enum(id, None, CallbackFlags::default(), 4096);
+        assert!(!first.is_empty());
+        // Only a page of the key is read before its first entries are listed.
+        assert!(source.listed.load(Ordering::SeqCst) < 1000);
+
+        // Later pages carry on where the last one stopped.
+        let mut listed: Vec<String> = first.iter().map(|entry| entry.name.clone()).collect();
+        while listed.len() < 1000 {
+            let (_, next) = sim.get_dir_enum(id, None, CallbackFlags::default(), 4096);
+            listed.extend(next.into_iter().map(|entry| entry.name));
+        }
+        let expected: Vec<String> = (0..listed.len()).map(huge_key_name).collect();
+        assert_eq!(listed, expected);
+        assert!(source.listed.load(Ordering::SeqCst) < 2000);
+
+        let restart = CallbackFlags {
+            restart_scan: true,
+            ..Default::default()
+        };
+        let (_, again) = sim.get_dir_enum(id, None, restart, 4096);
+        assert_eq!(again, first);
+        assert_eq!(sim.end_dir_enum(id), Status::Ok);
+        assert_eq!(
+            sim.list_dir("HKEY_TEST\\Missing", None, 4096),
+            Err(Status::FileNotFound),
+        );
+    }
+
+    #[test]
+    fn parallel_callbacks() {
+        let sim = Simulator::new(reg_fs());
+        let blob: Vec<u8> = (0..=255).collect();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let (sim, blob) = (&sim, &blob);
+                scope.spawn(move || {
+                    for round in 0..200 {
+                        // Small buffers keep several sessions open at once.
+                        let entries = sim.list_dir("HKEY_TEST\\Keys", None, 250).unwrap();
+                        assert_eq!(
+                            names(&entries),
+                            ["alpha", "bravo", "Charlie", "Count", "Delta"],
+                        );
+                        let offset = (thread * 200 + round) % 256;
+                        let data =
+                            vfs::read_to_vec(&*sim.backend, "HKEY_TEST\\Blob", offset as u64, 16)
+                                .unwrap();
+                        assert_eq!(data, blob[offset..(offset + 16).min(256)]);
+                        assert_eq!(
+                            sim.backend.get_entry_info("HKEY_TEST\\Empty").unwrap(),
+                            EntryInfo::dir(),
+                        );
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn async_commands() {
+        let (release, gate) = mpsc::channel();
+        let source = Arc::new(SlowSource::new(gate));
+        let mut sim = Simulator::new(RegFs::new(PathBuf::from("unused"), source.clone()));
+        assert_eq!(sim.get_placeholder_info("HKEY_TEST\\Blob"), Status::Ok);
+        source.slow.store(true, Ordering::SeqCst);
+
+        let cancelled = sim.get_file_data_async(1, "HKEY_TEST\\Blob", 0, 256);
+        let completed = sim.get_file_data_async(2, "HKEY_TEST\\Blob", 0, 256);
+        assert!(sim.cancel_command(1));
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+
+        let writes = completed.wait().unwrap().unwrap();
+        assert_eq!(writes[0].data, (0..=255).collect::<Vec<u8>>());
+        assert!(cancelled.wait().is_none());
+    }
+
+    #[test]
+    fn stop_with_pending_command() {
+        let (release, gate) = mpsc::channel();
+        let source = Arc::new(SlowSource::new(gate));
+        let mut sim = Simulator::new(RegFs::new(PathBuf::from("unused"), source.clone()));
+        assert_eq!(sim.get_placeholder_info("HKEY_TEST\\Blob"), Status::Ok);
+        source.slow.store(true, Ordering::SeqCst);
+
+        let pending = sim.get_file_data_async(1, "HKEY_TEST\\Blob", 0, 256);
+        // The command waits for the source, so it is still pending. The
+        // source only answers once stopping has begun.
+        let token = sim.commands.token(1).unwrap();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                while !token.is_cancelled() {
+                    std::thread::yield_now();
+                }
+                drop(release);
+            });
+            sim.stop();
+        });
+        assert!(pending.wait().is_none());
+        assert_eq!(sim.late_completions.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn version_info() {
+        let version = |fs: RegFs, path| {
+            let mut sim = Simulator::new(fs);
+            assert_eq!(sim.get_placeholder_info(path), Status::Ok);
+            sim.placeholders.pop().unwrap().version
+        };
+        let count = version(reg_fs(), "HKEY_TEST\\Keys\\Count");
+        let blob = version(reg_fs(), "HKEY_TEST\\Blob");
+        assert_eq!(count.provider_id, blob.provider_id);
+        assert!(!count.content_id.is_empty());
+        assert_ne!(count.content_id, blob.content_id);
+        // Search results are versioned like the entries they stand for.
+        assert_eq!(
+            version(reg_fs(), "@search\\count\\HKEY_TEST%5CKeys%5CCount"),
+            count,
+        );
+        // The source does not know when keys were written.
+        assert!(version(reg_fs(), "HKEY_TEST\\Keys").content_id.is_empty());
+
+        // The same data shown otherwise, or from another source, is another
+        // version.
+        let text = version(
+            reg_fs().with_rendering(Rendering::Text),
+            "HKEY_TEST\\Keys\\Count",
+        );
+        assert_ne!(text.content_id, count.content_id);
+        let other = version(
+            reg_fs().with_provider_id("hive:other"),
+            "HKEY_TEST\\Keys\\Count",
+        );
+        assert_eq!(other.content_id, count.content_id);
+        assert_ne!(other.provider_id, count.provider_id);
+
+        // Where the source knows when keys were written, values are not
+        // read.
+        let source = Arc::new(SlowSource {
+            last_write: Some(1),
+            ..SlowSource::new(mpsc::channel().1)
+        });
+        let written = version(
+            RegFs::new(PathBuf::from("unused"), source.clone()),
+            "HKEY_TEST\\Keys\\Count",
+        );
+        assert_ne!(written.content_id, count.content_id);
+        assert_eq!(source.reads.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn simple_fs() {
+        let mut sim = Simulator::new(SimpleFs::new());
+        let entries = sim.list_dir("", None, 4096).unwrap();
+        assert_eq!(names(&entries), ["Hello.txt"]);
+        assert_eq!(
+            sim.read_file("Hello.txt").unwrap(),
+            b"Hello, Windows 10 projected FS!\r\n",
+        );
+    }
+}
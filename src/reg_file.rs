@@ -0,0 +1,316 @@
+//! Helpers for reading and writing registry editor (`.reg`) files.
+
+use std::{fmt::Write, path::Path};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    mem_source::MemSource,
+    reg_name,
+    source::{RegSource, Value, REG_BINARY, REG_DWORD, REG_SZ},
+};
+
+pub const HEADER: &str = "Windows Registry Editor Version 5.00";
+const HEADER_V4: &str = "REGEDIT4";
+
+/// Formats the `[Key]` line that starts a section of a `.reg` file.
+pub fn key_line(key: &str) -> String {
+    format!("[{}]", key)
+}
+
+/// Formats the `[-Key]` line that deletes a key and all of its subkeys.
+pub fn delete_key_line(key: &str) -> String {
+    format!("[-{}]", key)
+}
+
+/// Formats the `"name"=-` line that deletes a value.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn delete_value_line(name: &str) -> String {
+    format!("{}=-", value_name(name))
+}
+
+/// Formats a `"name"=data` line, using the most readable representation
+/// that round-trips the value's type and data.
+pub fn value_line(name: &str, value: &Value) -> String {
+    format!("{}={}", value_name(name), value_data(value))
+}
+
+fn value_name(name: &str) -> String {
+    if name.is_empty() {
+        // The default value of a key
+        String::from("@")
+    } else {
+        format!("\"{}\"", escape(name))
+    }
+}
+
+fn value_data(value: &Value) -> String {
+    match value.vtype {
+        REG_SZ => {
+            if let Some(s) = decode_sz(&value.bytes) {
+                return format!("\"{}\"", escape(&s));
+            }
+        }
+        REG_DWORD => {
+            if let Ok(bytes) = <[u8; 4]>::try_from(value.bytes.as_slice()) {
+                return format!("dword:{:08x}", u32::from_le_bytes(bytes));
+            }
+        }
+        REG_BINARY => return format!("hex:{}", hex_list(&value.bytes)),
+        _ => (),
+    }
+    format!("hex({:x}):{}", value.vtype, hex_list(&value.bytes))
+}
+
+/// Decodes a null-terminated UTF-16 string, as long as it can be written
+/// back as a quoted string without losing information.
+fn decode_sz(bytes: &[u8]) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    if wide.pop() != Some(0) || wide.contains(&0) {
+        return None;
+    }
+    String::from_utf16(&wide)
+        .ok()
+        .filter(|s| !s.contains(['\r', '\n']))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn hex_list(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 3);
+    for (i, b) in bytes.iter().enumerate() {
+        if i != 0 {
+            s.push(',');
+        }
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+/// Encodes the text of a `.reg` file. The Registry Editor expects version
+/// 5.00 files to be UTF-16.
+pub fn encode(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Appends the lines which recreate the key `src` and all of its contents, as
+/// they are currently in the source, at the key `dest`.
+pub fn key_lines(
+    source: &dyn RegSource,
+    src: &str,
+    dest: &str,
+    lines: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    let mut items = source
+        .enum_key(src)
+        .context("enumerate key")?
+        .with_context(|| format!("key {:?} does not exist", src))?;
+    items.sort_unstable_by(|(a, _), (b, _)| reg_name::cmp(a, b));
+
+    if !lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines.push(key_line(dest));
+    let mut subkeys = Vec::new();
+    for (name, len) in items {
+        let src_path = format!("{}\\{}", src, name);
+        if len.is_none() {
+            subkeys.push(name);
+        } else if let Some(value) = source.read_value(&src_path).context("read value")? {
+            lines.push(value_line(&name, &value));
+        }
+    }
+    for name in subkeys {
+        key_lines(
+            source,
+            &format!("{}\\{}", src, name),
+            &format!("{}\\{}", dest, name),
+            lines,
+        )?;
+    }
+    Ok(())
+}
+
+/// Exports a key and all of its contents as the text of a `.reg` file. For
+/// the root, every top level key is exported.
+pub fn export(source: &dyn RegSource, path: &str) -> anyhow::Result<String> {
+    let mut lines = Vec::new();
+    if path.is_empty() {
+        let mut items = source
+            .enum_key("")
+            .context("enumerate root")?
+            .unwrap_or_default();
+        items.sort_unstable_by(|(a, _), (b, _)| reg_name::cmp(a, b));
+        for (name, len) in items {
+            match len {
+                None => key_lines(source, &name, &name, &mut lines)?,
+                Some(_) => log::warn!("Cannot export value outside of any key: {:?}", name),
+            }
+        }
+    } else {
+        key_lines(source, path, path, &mut lines)?;
+    }
+
+    let mut text = format!("{}\r\n\r\n", HEADER);
+    for line in lines {
+        text.push_str(&line);
+        text.push_str("\r\n");
+    }
+    Ok(text)
+}
+
+/// Loads a `.reg` file into memory, applying its sections in order.
+pub fn load(path: &Path) -> anyhow::Result<MemSource> {
+    let bytes = std::fs::read(path).context("read file")?;
+    parse(&decode(&bytes)?)
+}
+
+fn decode(bytes: &[u8]) -> anyhow::Result<String> {
+    if let Some(bytes) = bytes.strip_prefix(&[0xff, 0xfe]) {
+        let wide: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16(&wide).context("file is not valid UTF-16")
+    } else {
+        let bytes = bytes.strip_prefix(&[0xef, 0xbb, 0xbf]).unwrap_or(bytes);
+        String::from_utf8(bytes.to_vec()).context("file is not valid UTF-8")
+    }
+}
+
+pub fn parse(text: &str) -> anyhow::Result<MemSource> {
+    let mut source = MemSource::new();
+    let mut lines = text.lines().enumerate().peekable();
+
+    match lines.find(|(_, line)| !line.trim().is_empty()) {
+        Some((_, line)) if matches!(line.trim(), HEADER | HEADER_V4) => (),
+        _ => bail!("missing registry editor header"),
+    }
+
+    let mut cur_key: Option<String> = None;
+    while let Some((line_no, line)) = lines.next() {
+        // Join lines continued with a trailing backslash.
+        let mut line = String::from(line.trim());
+        while line.ends_with('\\') && !line.starts_with('[') {
+            line.pop();
+            match lines.next() {
+                Some((_, next)) => line.push_str(next.trim()),
+                None => break,
+            }
+        }
+        let result = (|| {
+            if line.is_empty() || line.starts_with(';') {
+                // Blank line or comment
+            } else if let Some(key) = line.strip_prefix("[-") {
+                let key = key.strip_suffix(']').context("unterminated key")?;
+                source.delete_key(key);
+                cur_key = None;
+            } else if let Some(key) = line.strip_prefix('[') {
+                let key = key.strip_suffix(']').context("unterminated key")?;
+                source.create_key(key);
+                cur_key = Some(String::from(key));
+            } else {
+                let key = cur_key.as_deref().context("value outside of any key")?;
+                let (name, data) = parse_value_name(&line)?;
+                match parse_value_data(data)? {
+                    Some(value) => source.set_value(key, &name, value),
+                    None => source.delete_value(key, &name),
+                }
+            }
+            anyhow::Ok(())
+        })();
+        result.with_context(|| format!("line {}", line_no + 1))?;
+    }
+
+    Ok(source)
+}
+
+/// Parses the name of a value, and returns it along with the rest of the
+/// line after the `=` sign.
+fn parse_value_name(line: &str) -> anyhow::Result<(String, &str)> {
+    let (name, rest) = if let Some(rest) = line.strip_prefix('@') {
+        (String::new(), rest)
+    } else if let Some(rest) = line.strip_prefix('"') {
+        parse_quoted(rest)?
+    } else {
+        bail!("expected a value name");
+    };
+    let rest = rest
+        .trim_start()
+        .strip_prefix('=')
+        .context("expected '=' after value name")?;
+    Ok((name, rest.trim_start()))
+}
+
+/// Parses the rest of a quoted string, whose opening quote has already been
+/// consumed.
+fn parse_quoted(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut result = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((result, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, c)) => result.push(c),
+                None => break,
+            },
+            c => result.push(c),
+        }
+    }
+    bail!("unterminated string")
+}
+
+/// Parses the data of a value, returning `None` if the value is deleted.
+fn parse_value_data(data: &str) -> anyhow::Result<Option<Value>> {
+    if data == "-" {
+        Ok(None)
+    } else if let Some(rest) = data.strip_prefix('"') {
+        let (s, rest) = parse_quoted(rest)?;
+        if !rest.trim().is_empty() {
+            bail!("unexpected characters after string");
+        }
+        Ok(Some(Value {
+            vtype: REG_SZ,
+            bytes: s
+                .encode_utf16()
+                .chain(std::iter::once(0))
+                .flat_map(u16::to_le_bytes)
+                .collect(),
+        }))
+    } else if let Some(dword) = data.strip_prefix("dword:") {
+        let dword = u32::from_str_radix(dword.trim(), 16).context("invalid dword")?;
+        Ok(Some(Value {
+            vtype: REG_DWORD,
+            bytes: dword.to_le_bytes().to_vec(),
+        }))
+    } else if let Some(hex) = data.strip_prefix("hex:") {
+        Ok(Some(Value {
+            vtype: REG_BINARY,
+            bytes: parse_hex_list(hex)?,
+        }))
+    } else if let Some(rest) = data.strip_prefix("hex(") {
+        let (vtype, hex) = rest.split_once("):").context("invalid hex value")?;
+        Ok(Some(Value {
+            vtype: u32::from_str_radix(vtype, 16).context("invalid value type")?,
+            bytes: parse_hex_list(hex)?,
+        }))
+    } else {
+        bail!("unrecognized value data")
+    }
+}
+
+fn parse_hex_list(s: &str) -> anyhow::Result<Vec<u8>> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|b| !b.is_empty())
+        .map(|b| u8::from_str_radix(b, 16).with_context(|| format!("invalid byte {:?}", b)))
+        .collect()
+}
@@ -0,0 +1,128 @@
+//! Case-insensitive comparison of registry key and value names.
+//!
+//! The registry compares names by converting them to uppercase one UTF-16
+//! code unit at a time, with an upcase table much like that of NTFS. Unlike
+//! Unicode case folding, this never changes the length of a name: characters
+//! whose uppercase form takes more than one code unit (such as `ß`) are left
+//! alone, and so are characters outside the Basic Multilingual Plane.
+//!
+//! The table is not embedded here. Instead, the Unicode simple uppercase
+//! mappings known to Rust are used, except for those Unicode has added since
+//! the Windows table was made. Recently added mappings not listed here may
+//! still differ from Windows.
+
+use std::cmp::Ordering;
+
+/// Converts a UTF-16 code unit to uppercase.
+pub fn upcase_unit(unit: u16) -> u16 {
+    match unit {
+        // ASCII is by far the most common case.
+        0x61..=0x7a => unit - 0x20,
+        0..=0x7f => unit,
+        // Windows does not map these to ASCII letters, even though Unicode
+        // gives 'I' and 'S' as their uppercase forms.
+        0x131 | 0x17f => unit,
+        // Unicode has given these uppercase forms since the Windows table was
+        // made: Cherokee small letters and `ꭓ` (Unicode 8), Cyrillic
+        // Extended-C (Unicode 9) and Georgian Mkhedruli, whose Mtavruli
+        // capitals came with Unicode 11.
+        0x13f8..=0x13fd | 0xab53 | 0xab70..=0xabbf | 0x1c80..=0x1c88 | 0x10d0..=0x10ff => unit,
+        _ => match char::from_u32(unit as u32) {
+            Some(c) => {
+                let mut upper = c.to_uppercase();
+                match (upper.next(), upper.next()) {
+                    (Some(u), None) if (u as u32) <= 0xffff => u as u16,
+                    _ => unit,
+                }
+            }
+            // Surrogates
+            None => unit,
+        },
+    }
+}
+
+/// Converts a character to uppercase.
+pub fn upcase(c: char) -> char {
+    match u16::try_from(c as u32) {
+        Ok(unit) => char::from_u32(upcase_unit(unit) as u32).unwrap_or(c),
+        Err(_) => c,
+    }
+}
+
+/// Folds a name so that names the registry considers equal are equal, e.g. to
+/// be used as a map key.
+pub fn fold(name: &str) -> String {
+    name.chars().map(upcase).collect()
+}
+
+/// Returns a key which sorts names in the order of `cmp`, e.g. for a map
+/// which is to be walked in that order.
+pub fn sort_key(name: &str) -> Vec<u16> {
+    name.encode_utf16().map(upcase_unit).collect()
+}
+
+/// Checks whether two names refer to the same key or value.
+pub fn eq(a: &str, b: &str) -> bool {
+    cmp(a, b) == Ordering::Equal
+}
+
+/// Compares two names the way the registry orders them.
+pub fn cmp(a: &str, b: &str) -> Ordering {
+    a.encode_utf16()
+        .map(upcase_unit)
+        .cmp(b.encode_utf16().map(upcase_unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        assert!(eq("SOFTWARE", "software"));
+        assert!(eq("Software\\Microsoft", "SOFTWARE\\microsoft"));
+        assert!(!eq("Software", "Softwar"));
+        assert!(!eq("a_", "A^"));
+        assert_eq!(fold("HKEY_local_Machine"), "HKEY_LOCAL_MACHINE");
+    }
+
+    #[test]
+    fn non_ascii() {
+        assert!(eq("Übersicht", "üBERSICHT"));
+        assert!(eq("ΣΊΣΥΦΟΣ", "σίσυφος"));
+        assert!(eq("Кириллица", "КИРИЛЛИЦА"));
+        assert!(eq("ÿ", "Ÿ"));
+        assert_eq!(fold("straße"), "STRAßE");
+        assert!(!eq("straße", "STRASSE"));
+        assert!(!eq("ı", "I"));
+        assert!(!eq("ſ", "s"));
+        // Characters outside the BMP are compared as they are.
+        assert!(!eq("𐐨", "𐐀"));
+    }
+
+    #[test]
+    fn newer_than_windows() {
+        // Rust maps each of these to uppercase, but Windows does not.
+        for (lower, upper) in [
+            ("ა", "Ა"),
+            ("ჿ", "Ჿ"),
+            ("ᲀ", "В"),
+            ("ᲈ", "Ꙋ"),
+            ("ꭰ", "Ꭰ"),
+            ("ᏸ", "Ᏸ"),
+            ("ꭓ", "Ꭓ"),
+        ] {
+            assert_eq!(fold(lower), lower);
+            assert!(!eq(lower, upper), "{} {}", lower, upper);
+        }
+        // Older Georgian case pairs are still mapped.
+        assert!(eq("ⴀ", "Ⴀ"));
+    }
+
+    #[test]
+    fn ordering() {
+        assert_eq!(cmp("abc", "ABD"), Ordering::Less);
+        assert_eq!(cmp("_", "a"), Ordering::Greater);
+        assert_eq!(cmp("Ä", "b"), Ordering::Greater);
+    }
+}
@@ -1,8 +1,28 @@
-use std::{collections::HashMap, io::ErrorKind};
+use std::{collections::HashMap, io::ErrorKind, time::Duration};
 
-use windows::{core::HRESULT, Win32::Foundation::E_FAIL};
+use anyhow::Context;
+use itertools::Itertools;
+use windows::{
+    core::{HRESULT, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::{
+            CloseHandle, E_FAIL, ERROR_FILE_NOT_FOUND, ERROR_MORE_DATA, ERROR_NO_MORE_ITEMS,
+            ERROR_SUCCESS, HANDLE, WAIT_FAILED, WAIT_OBJECT_0,
+        },
+        System::{
+            Registry,
+            Threading::{CreateEventW, WaitForMultipleObjects},
+        },
+    },
+};
 use winreg::{RegKey, RegValue, HKEY};
 
+use crate::{
+    reg_name,
+    source::{KeyEntries, RegSource, Value, ValueEntry},
+    watch::ChangeNotifier,
+};
+
 lazy_static::lazy_static! {
     // Sadly, winreg::HKEY does not implement Sync, so we cannot store it in a
     // global variable. Since HKEY is just an alias for HANDLE, while HANDLEs
@@ -18,8 +38,17 @@ lazy_static::lazy_static! {
     };
 }
 
+/// Finds a predefined key by its name, which is case-insensitive like any
+/// other key name.
+fn predef_key(name: &str) -> Option<usize> {
+    HKEYS
+        .iter()
+        .find(|(hkey, _)| reg_name::eq(hkey, name))
+        .map(|(_, &hkey)| hkey)
+}
+
 fn open_key_internal(hkey: &str, path: &str) -> Result<Option<RegKey>, windows::core::Error> {
-    if let Some(&hkey) = HKEYS.get(hkey) {
+    if let Some(hkey) = predef_key(hkey) {
         match RegKey::predef(hkey as HKEY).open_subkey(path) {
             Ok(key) => Ok(Some(key)),
             Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
@@ -38,7 +67,7 @@ pub fn open_key(key: &str) -> windows::core::Result<Option<RegKey>> {
     if let Some((hkey, path)) = key.split_once('\\') {
         // The user specified a subkey.
         open_key_internal(hkey, path)
-    } else if let Some(&hkey) = HKEYS.get(&key) {
+    } else if let Some(hkey) = predef_key(key) {
         // The user specified an HKEY.
         Ok(Some(RegKey::predef(hkey as HKEY)))
     } else {
@@ -53,7 +82,7 @@ pub fn does_key_exist(key: &str) -> windows::core::Result<bool> {
         open_key_internal(hkey, path).map(|key| key.is_some())
     } else {
         // The user specified an HKEY.
-        Ok(HKEYS.contains_key(key))
+        Ok(predef_key(key).is_some())
     }
 }
 
@@ -79,3 +108,265 @@ pub fn read_value(path: &str) -> windows::core::Result<Option<RegValue>> {
         Ok(None)
     }
 }
+
+/// Returns the size of a value, or `None` if it does not exist, without
+/// reading its data.
+pub fn query_value_size(path: &str) -> windows::core::Result<Option<u32>> {
+    let (path, name) = match path.rsplit_once('\\') {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let key = match open_key(path)? {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let name: Vec<u16> = name.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut size = 0;
+    let result = unsafe {
+        Registry::RegQueryValueExW(
+            Registry::HKEY(key.raw_handle() as isize),
+            PCWSTR::from_raw(name.as_ptr()),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut size,
+        )
+    };
+    match result {
+        ERROR_SUCCESS => Ok(Some(size)),
+        ERROR_FILE_NOT_FOUND => Ok(None),
+        err => Err(err.to_hresult().into()),
+    }
+}
+
+/// Lists the names, types and sizes of the values of a key, without reading
+/// their data as `RegKey::enum_values` does.
+fn value_entries(key: &RegKey) -> anyhow::Result<Vec<ValueEntry>> {
+    let info = key.query_info().context("query key")?;
+    let mut name = vec![0u16; info.max_value_name_len as usize + 1];
+    let mut items = Vec::with_capacity(info.values as usize);
+    let mut index = 0;
+    loop {
+        let mut name_len = name.len() as u32;
+        let mut vtype = 0;
+        let mut size = 0;
+        let result = unsafe {
+            Registry::RegEnumValueW(
+                Registry::HKEY(key.raw_handle() as isize),
+                index,
+                PWSTR(name.as_mut_ptr()),
+                &mut name_len,
+                std::ptr::null_mut(),
+                &mut vtype,
+                std::ptr::null_mut(),
+                &mut size,
+            )
+        };
+        match result {
+            ERROR_SUCCESS => {
+                items.push(ValueEntry {
+                    name: String::from_utf16_lossy(&name[..name_len as usize]),
+                    vtype,
+                    size,
+                });
+                index += 1;
+            }
+            ERROR_NO_MORE_ITEMS => return Ok(items),
+            // A value with a longer name was added since the key was queried.
+            ERROR_MORE_DATA => name.resize(name.len() * 2, 0),
+            err => {
+                return Err(windows::core::Error::from(err.to_hresult()))
+                    .context("enumerate values")
+            }
+        }
+    }
+}
+
+/// The registry of the running system.
+pub struct LiveSource;
+
+impl RegSource for LiveSource {
+    fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+        if path.is_empty() {
+            // Root directory
+            return Ok(Some(
+                HKEYS
+                    .keys()
+                    .map(|&name| (String::from(name), None))
+                    .collect(),
+            ));
+        }
+
+        if let Some(key) = open_key(path).context("open key")? {
+            // Enumerate both subkeys and values
+            let mut items: KeyEntries = key
+                .enum_keys()
+                .map_ok(|name| (name, None))
+                .try_collect()
+                .context("enumerate subkeys")?;
+            items.extend(
+                value_entries(&key)?
+                    .into_iter()
+                    .map(|value| (value.name, Some(value.size))),
+            );
+            Ok(Some(items))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn enum_values(&self, path: &str) -> anyhow::Result<Option<Vec<ValueEntry>>> {
+        if path.is_empty() {
+            return Ok(Some(Vec::new()));
+        }
+        match open_key(path).context("open key")? {
+            Some(key) => Ok(Some(value_entries(&key)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn key_exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(path.is_empty() || does_key_exist(path)?)
+    }
+
+    fn read_value(&self, path: &str) -> anyhow::Result<Option<Value>> {
+        Ok(read_value(path)?.map(|value| Value {
+            vtype: value.vtype as u32,
+            bytes: value.bytes,
+        }))
+    }
+
+    fn value_exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(query_value_size(path)?.is_some())
+    }
+
+    fn value_size(&self, path: &str) -> anyhow::Result<Option<u32>> {
+        Ok(query_value_size(path)?)
+    }
+
+    fn key_last_write(&self, path: &str) -> anyhow::Result<Option<u64>> {
+        if path.is_empty() {
+            return Ok(None);
+        }
+        match open_key(path).context("open key")? {
+            Some(key) => {
+                let time = key.query_info().context("query key")?.last_write_time;
+                Ok(Some(
+                    (time.dwHighDateTime as u64) << 32 | time.dwLowDateTime as u64,
+                ))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Waits for changes anywhere in the live registry, with
+/// `RegNotifyChangeKeyValue` on each predefined key.
+pub struct LiveNotifier {
+    watches: Vec<KeyWatch>,
+}
+
+struct KeyWatch {
+    name: &'static str,
+    key: Registry::HKEY,
+    event: HANDLE,
+    armed: bool,
+}
+
+impl LiveNotifier {
+    pub fn new() -> anyhow::Result<LiveNotifier> {
+        let mut watches = Vec::new();
+        for (&name, &predef) in HKEYS.iter() {
+            let mut key = Registry::HKEY::default();
+            let result = unsafe {
+                Registry::RegOpenKeyExW(
+                    Registry::HKEY(predef as isize),
+                    PCWSTR::null(),
+                    0,
+                    Registry::KEY_NOTIFY,
+                    &mut key,
+                )
+            };
+            if result != ERROR_SUCCESS {
+                log::warn!("Cannot watch {} for changes: error {}", name, result.0);
+                continue;
+            }
+            let event = match unsafe { CreateEventW(std::ptr::null(), false, false, PCWSTR::null()) }
+            {
+                Ok(event) => event,
+                Err(err) => {
+                    unsafe { Registry::RegCloseKey(key) };
+                    return Err(err).context("create event");
+                }
+            };
+            watches.push(KeyWatch {
+                name,
+                key,
+                event,
+                armed: false,
+            });
+        }
+        Ok(LiveNotifier { watches })
+    }
+}
+
+impl KeyWatch {
+    /// Asks for the next change to be signaled. This is done on the thread
+    /// which waits, since the request ends with the thread that made it.
+    fn arm(&mut self) -> anyhow::Result<()> {
+        if self.armed {
+            return Ok(());
+        }
+        let result = unsafe {
+            Registry::RegNotifyChangeKeyValue(
+                self.key,
+                true,
+                Registry::REG_NOTIFY_CHANGE_NAME | Registry::REG_NOTIFY_CHANGE_LAST_SET,
+                self.event,
+                true,
+            )
+        };
+        if result != ERROR_SUCCESS {
+            anyhow::bail!("watch {} for changes: error {}", self.name, result.0);
+        }
+        self.armed = true;
+        Ok(())
+    }
+}
+
+impl ChangeNotifier for LiveNotifier {
+    fn wait_for_change(&mut self, timeout: Duration) -> anyhow::Result<bool> {
+        for watch in &mut self.watches {
+            watch.arm()?;
+        }
+        if self.watches.is_empty() {
+            std::thread::sleep(timeout);
+            return Ok(false);
+        }
+        let events: Vec<HANDLE> = self.watches.iter().map(|watch| watch.event).collect();
+        let result = unsafe { WaitForMultipleObjects(&events, false, timeout.as_millis() as u32) };
+        if result == WAIT_FAILED.0 {
+            return Err(windows::core::Error::from_win32()).context("wait for registry changes");
+        }
+        match self
+            .watches
+            .get_mut(result.wrapping_sub(WAIT_OBJECT_0.0) as usize)
+        {
+            Some(watch) => {
+                // Each request is signaled once.
+                watch.armed = false;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for KeyWatch {
+    fn drop(&mut self) {
+        unsafe {
+            Registry::RegCloseKey(self.key);
+            CloseHandle(self.event);
+        }
+    }
+}
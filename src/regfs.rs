@@ -1,291 +1,878 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Context;
-use itertools::Itertools;
+use serde::Deserialize;
 use uuid::Uuid;
-use windows::Win32::{
-    Foundation::{
-        ERROR_ACCESS_DENIED, ERROR_FILE_NOT_FOUND, E_FAIL, E_INVALIDARG, STATUS_CANNOT_DELETE, S_OK,
-    },
-    Storage::ProjectedFileSystem::*,
-};
 
 use crate::{
-    dir_enum::SimpleDirEnumerator,
-    fs_helper::SimpleFsHelper,
-    projfs::{NotificationKind, OptionalFeatures, ProjFsBackend},
-    reg_ops,
+    audit::{AuditLog, Mutation, Operation, Outcome},
+    commands::CancelToken,
+    dir_enum::{DirEnumSessions, SimpleDirEnumerator},
+    file_name,
+    index::{self, LazyIndex},
+    patch::PatchRecorder,
+    render::TextRenderer,
+    search::{self, Query, Route},
+    source::{self, KeyEntries, RegSource, Value},
+    vfs::{
+        DirEntrySink, EntryInfo, FileDataSink, Notification, NotificationKind, OptionalFeatures,
+        RangeSink, VersionInfo, VfsError, VfsResult, VirtualFs,
+    },
 };
 
 pub struct RegFs {
-    state: Mutex<RegFsState>,
+    dir_enums: DirEnumSessions<DirEnumerator>,
+    source: Arc<dyn RegSource>,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    root_path: PathBuf,
+    mode: WriteMode,
+    audit_log: Option<AuditLog>,
+    index: Option<LazyIndex>,
+    rendering: Rendering,
+    #[cfg_attr(not(windows), allow(dead_code))]
+    provider_id: Vec<u8>,
+}
+
+/// Decides how values are shown as file contents.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rendering {
+    /// The data as stored, byte for byte
+    #[default]
+    Raw,
+    /// The data decoded according to its type, as UTF-8 text
+    Text,
+}
+
+/// Decides what happens to changes made to the projection.
+pub enum WriteMode {
+    /// Deletions and renames are denied. Other changes stay in the
+    /// virtualization root, and never reach the registry.
+    ReadOnly,
+    /// All changes are allowed in the projection, and recorded as a `.reg`
+    /// patch instead of being applied to the registry.
+    DryRun(PatchRecorder),
+}
+
+/// Starts the provider ID of every placeholder.
+const PROVIDER_ID: &[u8] = b"regfs-rs\0";
+
+/// Hashes bytes with 64-bit FNV-1a. Unlike `DefaultHasher`, this gives the
+/// same hash in every build, as needed for IDs kept on disk.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    stable_hash_more(0xcbf2_9ce4_8422_2325, bytes)
+}
+
+/// Continues a `stable_hash` with more bytes, for data read in pieces.
+fn stable_hash_more(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+type DirEnumerator = SimpleDirEnumerator<Listing>;
+
+/// How many entries of a key are read from the source at a time.
+const LISTING_PAGE: usize = 256;
+
+/// The entries of a directory, in the order ProjFS wants them.
+#[derive(Clone)]
+enum Listing {
+    /// Entries known up front, such as the results of a query.
+    Entries {
+        entries: Arc<[(String, Option<u32>)]>,
+        next: usize,
+    },
+    Key(KeyListing),
+}
+
+impl Listing {
+    fn new(mut entries: KeyEntries) -> Listing {
+        file_name::sort_by_name(&mut entries, |(name, _)| name);
+        Listing::Entries {
+            entries: entries.into(),
+            next: 0,
+        }
+    }
+}
+
+impl Iterator for Listing {
+    type Item = (String, Option<u32>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Listing::Entries { entries, next } => {
+                let entry = entries.get(*next)?.clone();
+                *next += 1;
+                Some(entry)
+            }
+            Listing::Key(listing) => listing.next(),
+        }
+    }
 }
 
-struct RegFsState {
-    fs_helper: SimpleFsHelper,
-    dir_enums: HashMap<Uuid, DirEnumerator>,
+/// The entries of a key, read from the source a page at a time as they are
+/// listed, or all at once from sources which cannot list keys in order
+/// without reading them whole. Sizes of values shown as text take rendering
+/// the data, so they are only worked out as each value is listed.
+#[derive(Clone)]
+struct KeyListing {
+    source: Arc<dyn RegSource>,
+    key: Arc<str>,
+    rendering: Rendering,
+    /// The entries last read from the source, which restarted listings
+    /// share, and how many of them have been listed.
+    page: Arc<[(String, Option<u32>)]>,
+    next: usize,
+    /// The name of the last entry read, and whether it is a value, which the
+    /// next page carries on from.
+    last: Option<(String, bool)>,
+    /// Whether the source has no entries left.
+    done: bool,
+    /// A directory listed among the entries of the key, which is not in the
+    /// source.
+    extra: Option<&'static str>,
 }
 
-type DirEnumerator = SimpleDirEnumerator<std::vec::IntoIter<(String, Option<u32>)>>;
+impl KeyListing {
+    /// Starts listing a key with its first page of entries, or returns
+    /// `None` if the key does not exist.
+    fn new(
+        source: Arc<dyn RegSource>,
+        key: &str,
+        rendering: Rendering,
+        extra: Option<&'static str>,
+    ) -> anyhow::Result<Option<KeyListing>> {
+        let mut listing = KeyListing {
+            source,
+            key: Arc::from(key),
+            rendering,
+            page: Arc::from([]),
+            next: 0,
+            last: None,
+            done: false,
+            extra,
+        };
+        Ok(listing.read_page()?.then_some(listing))
+    }
+
+    /// Reads the next page of entries, and returns whether the key exists.
+    fn read_page(&mut self) -> anyhow::Result<bool> {
+        let limit = if self.source.streams_keys() {
+            LISTING_PAGE
+        } else {
+            usize::MAX
+        };
+        let mut page = Vec::new();
+        let after = self
+            .last
+            .as_ref()
+            .map(|(name, is_value)| (name.as_str(), *is_value));
+        let found = self.source.visit_key(&self.key, after, &mut |name, len| {
+            page.push((name, len));
+            Ok(page.len() < limit)
+        })?;
+        self.done = page.len() < limit;
+        if let Some((name, len)) = page.last() {
+            self.last = Some((name.clone(), len.is_some()));
+        }
+        self.page = page.into();
+        self.next = 0;
+        Ok(found)
+    }
+}
+
+impl Iterator for KeyListing {
+    type Item = (String, Option<u32>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == self.page.len() && !self.done {
+            if let Err(err) = self.read_page() {
+                log::warn!("Cannot list the rest of {:?}: {:#}", self.key, err);
+                self.done = true;
+            }
+        }
+        // The extra directory is listed where it sorts among the entries.
+        let extra_first = match (self.extra, self.page.get(self.next)) {
+            (Some(extra), Some((name, len))) => {
+                source::entry_cmp((extra, false), (name, len.is_some())).is_lt()
+            }
+            (extra, _) => extra.is_some(),
+        };
+        if extra_first {
+            return self.extra.take().map(|extra| (String::from(extra), None));
+        }
+
+        let (name, len) = self.page.get(self.next)?.clone();
+        self.next += 1;
+        let len = match (self.rendering, len) {
+            (Rendering::Text, Some(_)) => {
+                let path = source::join_path(&self.key, &name);
+                match listed_text_len(self.source.as_ref(), &path) {
+                    Ok(text_len) => text_len.or(len),
+                    Err(err) => {
+                        log::warn!("Cannot read {:?} to list it: {:#}", path, err);
+                        len
+                    }
+                }
+            }
+            _ => len,
+        };
+        Some((name, len))
+    }
+}
+
+/// Counts the bytes written to it, without keeping them.
+#[derive(Default)]
+struct ByteCount(u64);
+
+impl FileDataSink for ByteCount {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.0 += data.len() as u64;
+        Ok(())
+    }
+}
+
+/// Works out the length of a value's text by rendering it without keeping
+/// the text, or returns `None` if the value does not exist.
+fn text_len(source: &dyn RegSource, path: &str) -> anyhow::Result<Option<u64>> {
+    let mut count = ByteCount::default();
+    let mut renderer = TextRenderer::new(&mut count, 0, u32::MAX);
+    if !source.visit_value(path, &mut |vtype, data| renderer.push(vtype, data))? {
+        return Ok(None);
+    }
+    if !renderer.finish()? {
+        count = ByteCount::default();
+        let mut renderer = TextRenderer::binary(&mut count, 0, u32::MAX);
+        source.visit_value(path, &mut |vtype, data| renderer.push(vtype, data))?;
+        renderer.finish()?;
+    }
+    Ok(Some(count.0))
+}
+
+/// Like `text_len`, for listings, which give the sizes of values as `u32`.
+fn listed_text_len(source: &dyn RegSource, path: &str) -> anyhow::Result<Option<u32>> {
+    text_len(source, path)?
+        .map(u32::try_from)
+        .transpose()
+        .context("rendered value is too large to list")
+}
 
 impl RegFs {
-    pub fn new() -> RegFs {
+    pub fn new(root_path: PathBuf, source: Arc<dyn RegSource>) -> RegFs {
         RegFs {
-            state: Mutex::new(RegFsState {
-                fs_helper: SimpleFsHelper::default(),
-                dir_enums: HashMap::new(),
-            }),
+            dir_enums: DirEnumSessions::new(),
+            source,
+            root_path,
+            mode: WriteMode::ReadOnly,
+            audit_log: None,
+            index: None,
+            rendering: Rendering::Raw,
+            provider_id: PROVIDER_ID.to_vec(),
         }
     }
-}
 
-impl ProjFsBackend for RegFs {
-    fn get_optional_features() -> OptionalFeatures {
-        OptionalFeatures::NOTIFY
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> RegFs {
+        self.audit_log = Some(audit_log);
+        self
     }
 
-    fn set_instance_handle(
-        self: &Arc<Self>,
-        instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
-    ) {
-        log::debug!("RegFS backend initialized");
-        self.state.lock().unwrap().fs_helper = SimpleFsHelper::new(instance_handle);
+    pub fn with_mode(mut self, mode: WriteMode) -> RegFs {
+        self.mode = mode;
+        self
     }
 
-    unsafe fn start_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-    ) -> windows::core::HRESULT {
-        let mut state = self.state.lock().unwrap();
-        let result = (|| {
-            let path = state
-                .fs_helper
-                .get_req_path(callback_data)
-                .context("path is not valid UTF-8")?;
-
-            log::trace!(
-                "Start directory enumeration: ID {}, path {:?}",
-                enumeration_id,
+    /// Shows values as text instead of raw bytes. Changes to text files
+    /// cannot be turned back into values, so this should only be used in
+    /// read-only mode.
+    pub fn with_rendering(mut self, rendering: Rendering) -> RegFs {
+        self.rendering = rendering;
+        self
+    }
+
+    /// Names the source in the provider ID of placeholders, so that
+    /// placeholders made from other sources can be told apart.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn with_provider_id(mut self, source_name: &str) -> RegFs {
+        self.provider_id = [
+            PROVIDER_ID,
+            &stable_hash(source_name.as_bytes()).to_le_bytes(),
+        ]
+        .concat();
+        self
+    }
+
+    /// Answers queries from the index of the file the source was loaded
+    /// from, instead of walking the source.
+    pub fn with_index(mut self, index: LazyIndex) -> RegFs {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn source(&self) -> &dyn RegSource {
+        self.source.as_ref()
+    }
+
+    /// Returns the real paths of all keys and values matching a query.
+    pub fn search(&self, pattern: &str) -> anyhow::Result<source::KeyEntries> {
+        index::search(self.source.as_ref(), self.index.as_ref(), pattern)
+    }
+
+    /// Sets a value on behalf of an API client, following the same policy as
+    /// changes made to the projection. In read-only mode there is no
+    /// projection to keep the change in, so it is denied.
+    pub fn set_value(&self, path: &str, value: &Value) -> VfsResult<()> {
+        let (key, _) = source::split_value_path(path);
+        let operation = match self.read_old_value(path, false) {
+            Some(_) => Operation::Modify,
+            None => Operation::Create,
+        };
+        self.apply(operation, path, Some(value), |recorder| {
+            if !self.source.key_exists(key).context("check key existence")? {
+                return Err(VfsError::NotFound);
+            }
+            recorder.set_value(path, value);
+            Ok(())
+        })
+    }
+
+    /// Deletes a key on behalf of an API client, following the same policy
+    /// as deletions in the projection.
+    pub fn delete_key(&self, path: &str) -> VfsResult<()> {
+        self.apply(Operation::Delete, path, None, |recorder| {
+            if !self
+                .source
+                .key_exists(path)
+                .context("check key existence")?
+            {
+                return Err(VfsError::NotFound);
+            }
+            recorder.delete_key(path);
+            Ok(())
+        })
+    }
+
+    fn apply(
+        &self,
+        operation: Operation,
+        path: &str,
+        new_value: Option<&Value>,
+        record: impl FnOnce(&PatchRecorder) -> VfsResult<()>,
+    ) -> VfsResult<()> {
+        let result = match &self.mode {
+            // Top level keys can never be deleted, and top level values
+            // cannot exist.
+            WriteMode::DryRun(_) if !path.contains('\\') => Err(VfsError::AccessDenied),
+            WriteMode::DryRun(recorder) => record(recorder),
+            WriteMode::ReadOnly => Err(VfsError::AccessDenied),
+        };
+        let outcome = match result {
+            Ok(()) => Outcome::Recorded,
+            Err(VfsError::AccessDenied) => Outcome::Denied,
+            Err(_) => return result,
+        };
+
+        if let Some(audit_log) = &self.audit_log {
+            let is_key = new_value.is_none();
+            let old_value = self.read_old_value(path, is_key);
+            audit_log.record(&Mutation {
+                operation,
                 path,
-            );
+                dest_path: None,
+                is_key,
+                old_value: old_value.as_ref(),
+                new_value,
+                outcome,
+                // There is no triggering process.
+                process_id: 0,
+            });
+        }
+        result
+    }
 
-            let enumerator = if path.is_empty() {
-                // Root directory
-                SimpleDirEnumerator::new(
-                    reg_ops::HKEYS
-                        .keys()
-                        .map(|&name| (String::from(name), None))
-                        .sorted_unstable(),
-                )
-            } else if let Some(key) = reg_ops::open_key(&path).context("open key")? {
-                // Enumerate both subkeys and values
-                let keys_iter = key.enum_keys().map_ok(|name| (name, None));
-                let values_iter = key.enum_values().map_ok(|(name, value)| {
-                    (
-                        name,
-                        Some(value.bytes.len().try_into().expect("integer overflow")),
-                    )
-                });
-
-                let mut items: Vec<(String, Option<u32>)> =
-                    Itertools::try_collect(keys_iter.chain(values_iter))
-                        .context("enumerate subkeys and values")?;
-                items.sort_unstable();
-
-                SimpleDirEnumerator::new(items.into_iter())
-            } else {
-                // A non-existent key is specified
-                return anyhow::Ok(ERROR_FILE_NOT_FOUND.to_hresult());
-            };
+    fn read_old_value(&self, path: &str, is_dir: bool) -> Option<Value> {
+        if is_dir {
+            return None;
+        }
+        self.source.read_value(path).unwrap_or_else(|err| {
+            log::warn!("Failed to read old value of {:?}: {:#}", path, err);
+            None
+        })
+    }
 
-            state.dir_enums.insert(enumeration_id, enumerator);
-            anyhow::Ok(S_OK)
-        })();
-        match result {
-            Ok(hresult) => hresult,
-            Err(err) => {
-                log::error!("Error enumerating directory: {:#}", err);
-                err.downcast::<windows::core::Error>()
-                    .map(Into::into)
-                    .unwrap_or(E_FAIL)
+    /// Reads the new contents of a created or modified file. These can be
+    /// found in the virtualization root, since such files are already full
+    /// files.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn read_new_value(&self, path: &str, old_value: Option<&Value>) -> Option<Value> {
+        std::fs::read(self.root_path.join(path))
+            .map(|bytes| source::value_from_file(old_value, bytes))
+            .map_err(|err| log::warn!("Failed to read new value of {:?}: {}", path, err))
+            .ok()
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn outcome(&self) -> Outcome {
+        match self.mode {
+            WriteMode::ReadOnly => Outcome::NotApplied,
+            WriteMode::DryRun(_) => Outcome::Recorded,
+        }
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn audit(&self, notification: &Notification, operation: Operation, outcome: Outcome) {
+        let audit_log = match &self.audit_log {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+
+        let path = notification.path;
+        let old_value = self.read_old_value(path, notification.is_dir);
+        let new_value = match operation {
+            Operation::Create | Operation::Modify if !notification.is_dir => {
+                self.read_new_value(path, old_value.as_ref())
+            }
+            _ => None,
+        };
+
+        audit_log.record(&Mutation {
+            operation,
+            path,
+            dest_path: notification.dest_path,
+            is_key: notification.is_dir,
+            old_value: old_value.as_ref(),
+            new_value: new_value.as_ref(),
+            outcome,
+            process_id: notification.process_id,
+        });
+    }
+
+    /// Records a change into the patch, if running in dry-run mode.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn record(&self, notification: &Notification, operation: Operation) {
+        let recorder = match &self.mode {
+            WriteMode::DryRun(recorder) => recorder,
+            WriteMode::ReadOnly => return,
+        };
+
+        let path = notification.path;
+        let is_dir = notification.is_dir;
+        match (operation, notification.dest_path) {
+            (Operation::Create, _) if is_dir => recorder.create_key(path),
+            (Operation::Create | Operation::Modify, _) => {
+                let old_value = self.read_old_value(path, is_dir);
+                if let Some(value) = self.read_new_value(path, old_value.as_ref()) {
+                    recorder.set_value(path, &value);
+                }
+            }
+            (Operation::Delete, _) if is_dir => recorder.delete_key(path),
+            (Operation::Delete, _) => recorder.delete_value(path),
+            (Operation::Rename, Some(dest_path)) if is_dir => {
+                // Keys that only exist in the projection have nothing to copy.
+                match self.source.key_exists(path) {
+                    Ok(true) => {
+                        if let Err(err) = recorder.copy_key(self.source.as_ref(), path, dest_path) {
+                            log::error!("Failed to record copy of key {:?}: {:#}", path, err);
+                        }
+                    }
+                    _ => recorder.create_key(dest_path),
+                }
+                recorder.delete_key(path);
+            }
+            (Operation::Rename, Some(dest_path)) => {
+                let value = self
+                    .read_old_value(path, is_dir)
+                    .or_else(|| self.read_new_value(dest_path, None));
+                if let Some(value) = value {
+                    recorder.set_value(dest_path, &value);
+                }
+                recorder.delete_value(path);
+            }
+            (Operation::Rename, None) => {
+                log::warn!("Rename of {:?} has no destination", path);
             }
         }
     }
 
-    unsafe fn end_dir_enum(
-        self: &Arc<Self>,
-        _callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-    ) -> windows::core::HRESULT {
-        self.state.lock().unwrap().dir_enums.remove(&enumeration_id);
-        S_OK
+    /// Returns the size of the file showing a value, or `None` for a key.
+    fn rendered_len(&self, path: &str, len: Option<u32>) -> VfsResult<Option<u32>> {
+        match (self.rendering, len) {
+            (Rendering::Text, Some(_)) => Ok(listed_text_len(self.source.as_ref(), path)
+                .context("render value")?
+                .or(len)),
+            _ => Ok(len),
+        }
+    }
+
+    /// Reads a value a piece at a time, stopping when cancelled.
+    fn visit_value(
+        &self,
+        path: &str,
+        cancel: &CancelToken,
+        mut f: impl FnMut(u32, &[u8]) -> anyhow::Result<bool>,
+    ) -> VfsResult<()> {
+        cancel.check()?;
+        let found = self
+            .source
+            .visit_value(path, &mut |vtype, data| {
+                Ok(!cancel.is_cancelled() && f(vtype, data)?)
+            })
+            .context("read value")?;
+        cancel.check()?;
+        if found {
+            Ok(())
+        } else {
+            Err(VfsError::NotFound)
+        }
+    }
+
+    /// Lists a key, reading its first page of entries. The rest are read as
+    /// they are listed.
+    fn list_key(&self, path: &str, extra: Option<&'static str>) -> VfsResult<Listing> {
+        let listing = KeyListing::new(self.source.clone(), path, self.rendering, extra)
+            .context("enumerate key")?
+            // A non-existent key is specified
+            .ok_or(VfsError::NotFound)?;
+        Ok(Listing::Key(listing))
+    }
+
+    /// Returns the real path a search result or an entry below it stands for,
+    /// once the result is checked to match its query.
+    fn result_path(&self, route: Route) -> VfsResult<String> {
+        match route {
+            Route::Result {
+                pattern,
+                result,
+                path,
+            } => match Query::new(pattern)
+                .find(self.source.as_ref(), &result)
+                .context("check search result")?
+            {
+                Some(_) => Ok(path),
+                None => Err(VfsError::NotFound),
+            },
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    /// Handles a notification which denies the operation in read-only mode.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn pre_notify(&self, notification: &Notification, operation: Operation) -> bool {
+        // Top level keys can never be deleted or renamed.
+        let allowed = matches!(self.mode, WriteMode::DryRun(_)) && notification.path.contains('\\');
+        if !allowed {
+            log::debug!(
+                "Denying {:?} of {:?} (destination {:?})",
+                operation,
+                notification.path,
+                notification.dest_path,
+            );
+            self.audit(notification, operation, Outcome::Denied);
+        }
+        allowed
+    }
+}
+
+impl VirtualFs for RegFs {
+    fn get_optional_features() -> OptionalFeatures {
+        // Hives can be large, and the live registry slow to answer, so
+        // requests are served asynchronously.
+        OptionalFeatures::NOTIFY
+            | OptionalFeatures::QUERY_FILE_NAME
+            | OptionalFeatures::CANCEL_COMMAND
+    }
+
+    fn start_dir_enum(&self, path: &str, enumeration_id: Uuid) -> VfsResult<()> {
+        log::trace!(
+            "Start directory enumeration: ID {}, path {:?}",
+            enumeration_id,
+            path,
+        );
+
+        let listing = match search::route(path) {
+            Route::Real(path) => {
+                let extra = path.is_empty().then_some(search::SEARCH_DIR);
+                self.list_key(path, extra)?
+            }
+            // Queries are only run once they are opened.
+            Route::SearchDir => Listing::new(Vec::new()),
+            Route::Query(pattern) => Listing::new(
+                self.search(pattern)
+                    .context("run query")?
+                    .into_iter()
+                    .map(|(path, len)| {
+                        Ok((search::result_name(&path), self.rendered_len(&path, len)?))
+                    })
+                    .collect::<VfsResult<_>>()?,
+            ),
+            route @ Route::Result { .. } => self.list_key(&self.result_path(route)?, None)?,
+        };
+
+        self.dir_enums
+            .insert(enumeration_id, SimpleDirEnumerator::new(listing));
+        Ok(())
     }
 
-    unsafe fn get_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
+    fn end_dir_enum(&self, enumeration_id: Uuid) -> VfsResult<()> {
+        self.dir_enums.remove(enumeration_id);
+        Ok(())
+    }
+
+    fn get_dir_enum(
+        &self,
         enumeration_id: Uuid,
-        search_expr: windows::core::PCWSTR,
-        dir_entry_buffer_handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> windows::core::HRESULT {
-        match self
-            .state
-            .lock()
-            .unwrap()
-            .dir_enums
-            .get_mut(&enumeration_id)
-        {
+        search_expr: Option<&str>,
+        restart: bool,
+        sink: &mut dyn DirEntrySink,
+    ) -> VfsResult<()> {
+        match self.dir_enums.get(enumeration_id) {
             Some(dir_enum) => {
-                dir_enum.get_dir_enum(callback_data, search_expr, dir_entry_buffer_handle);
-                S_OK
+                dir_enum
+                    .lock()
+                    .unwrap()
+                    .get_dir_enum(search_expr, restart, sink);
+                Ok(())
             }
-            None => E_INVALIDARG,
+            None => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    fn get_entry_info(&self, path: &str) -> VfsResult<EntryInfo> {
+        self.get_entry_info_cancellable(path, &CancelToken::new())
+    }
+
+    fn get_entry_info_cancellable(&self, path: &str, cancel: &CancelToken) -> VfsResult<EntryInfo> {
+        let path = match search::route(path) {
+            Route::Real(path) => path.to_owned(),
+            Route::SearchDir | Route::Query(_) => return Ok(EntryInfo::dir()),
+            route @ Route::Result { .. } => self.result_path(route)?,
+        };
+        cancel.check()?;
+        if self
+            .source
+            .key_exists(&path)
+            .context("check key existence")?
+        {
+            return Ok(EntryInfo::dir());
         }
+        cancel.check()?;
+        // Raw values are as large as their data, which need not be read.
+        let size = match self.rendering {
+            Rendering::Raw => self
+                .source
+                .value_size(&path)
+                .context("query value size")?
+                .map(u64::from),
+            Rendering::Text => text_len(self.source.as_ref(), &path).context("render value")?,
+        };
+        size.map(EntryInfo::file).ok_or(VfsError::NotFound)
     }
 
-    unsafe fn get_placeholder_info(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-    ) -> windows::core::HRESULT {
-        let state = self.state.lock().unwrap();
-        let result = (|| {
-            let path = state
-                .fs_helper
-                .get_req_path(callback_data)
-                .context("invalid path specified")?;
-            log::trace!("Get placeholder info: {:?}", path);
-
-            if reg_ops::does_key_exist(&path).context("check key existence")? {
-                state
-                    .fs_helper
-                    .write_placeholder_info(callback_data, None)
-                    .context("write placeholder info")?;
-                anyhow::Ok(S_OK)
-            } else if let Some(value) =
-                reg_ops::read_value(&path).context("check value existence")?
+    /// Unlike `get_entry_info`, values are not read, let alone rendered.
+    fn entry_exists(&self, path: &str) -> VfsResult<bool> {
+        let path = match search::route(path) {
+            Route::Real(path) => path.to_owned(),
+            Route::SearchDir | Route::Query(_) => return Ok(true),
+            route @ Route::Result { .. } => match self.result_path(route) {
+                Ok(path) => path,
+                Err(VfsError::NotFound) => return Ok(false),
+                Err(err) => return Err(err),
+            },
+        };
+        Ok(self
+            .source
+            .key_exists(&path)
+            .context("check key existence")?
+            || self
+                .source
+                .value_exists(&path)
+                .context("check value existence")?)
+    }
+
+    /// Keys are identified by their last write time, where the source knows
+    /// it. So are values, along with their size and the rendering, since
+    /// changing a value updates the time of its key; otherwise, they are
+    /// identified by a hash of their type and data, read piece by piece.
+    fn get_version_info(&self, path: &str) -> VfsResult<VersionInfo> {
+        let path = match search::route(path) {
+            Route::Real(path) => Some(path.to_owned()),
+            Route::SearchDir | Route::Query(_) => None,
+            route @ Route::Result { .. } => Some(self.result_path(route)?),
+        };
+        let mut content_id = Vec::new();
+        if let Some(path) = path {
+            if self
+                .source
+                .key_exists(&path)
+                .context("check key existence")?
             {
-                state
-                    .fs_helper
-                    .write_placeholder_info(
-                        callback_data,
-                        Some(value.bytes.len().try_into().expect("integer overflow")),
-                    )
-                    .context("write placeholder info")?;
-                anyhow::Ok(S_OK)
+                if let Some(time) = self.source.key_last_write(&path).context("query key")? {
+                    content_id.push(b'k');
+                    content_id.extend(time.to_le_bytes());
+                }
             } else {
-                anyhow::Ok(ERROR_FILE_NOT_FOUND.to_hresult())
-            }
-        })();
-        match result {
-            Ok(hresult) => hresult,
-            Err(err) => {
-                log::error!("Error in get_placeholder_info: {:#}", err);
-                err.downcast::<windows::core::Error>()
-                    .map(Into::into)
-                    .unwrap_or(E_FAIL)
+                let (key, _) = source::split_value_path(&path);
+                match self.source.key_last_write(key).context("query key")? {
+                    Some(time) => {
+                        let size = self
+                            .source
+                            .value_size(&path)
+                            .context("query value size")?
+                            .ok_or(VfsError::NotFound)?;
+                        content_id.extend([b'w', self.rendering as u8]);
+                        content_id.extend((size as u64).to_le_bytes());
+                        content_id.extend(time.to_le_bytes());
+                    }
+                    None => {
+                        let (mut vtype, mut len, mut hash) = (0, 0u64, stable_hash(&[]));
+                        let found = self
+                            .source
+                            .visit_value(&path, &mut |piece_type, piece| {
+                                vtype = piece_type;
+                                len += piece.len() as u64;
+                                hash = stable_hash_more(hash, piece);
+                                Ok(true)
+                            })
+                            .context("read value")?;
+                        if !found {
+                            return Err(VfsError::NotFound);
+                        }
+                        content_id.extend([b'v', self.rendering as u8]);
+                        content_id.extend(vtype.to_le_bytes());
+                        content_id.extend(len.to_le_bytes());
+                        content_id.extend(hash.to_le_bytes());
+                    }
+                }
             }
         }
+        Ok(VersionInfo {
+            provider_id: self.provider_id.clone(),
+            content_id,
+        })
     }
 
-    unsafe fn get_file_data(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
+    fn read_file(
+        &self,
+        path: &str,
         byte_offset: u64,
         length: u32,
-    ) -> windows::core::HRESULT {
-        let state = self.state.lock().unwrap();
-        let result = (|| {
-            let path = state
-                .fs_helper
-                .get_req_path(callback_data)
-                .context("invalid path specified")?;
-            log::trace!(
-                "Get file data: {:?}; offset {}, len {}",
-                path,
-                byte_offset,
-                length,
-            );
+        sink: &mut dyn FileDataSink,
+    ) -> VfsResult<()> {
+        self.read_file_cancellable(path, byte_offset, length, sink, &CancelToken::new())
+    }
 
-            if let Some(value) = reg_ops::read_value(&path).context("read value")? {
-                let mut buffer = state
-                    .fs_helper
-                    .alloc_aligned_buffer(value.bytes.len())
-                    .context("allocate buffer")?;
-                buffer.copy_from_slice(&value.bytes);
-                state
-                    .fs_helper
-                    .write_file_data(callback_data, &buffer, 0)
-                    .context("write file data")?;
-                anyhow::Ok(S_OK)
-            } else {
-                anyhow::Ok(ERROR_FILE_NOT_FOUND.to_hresult())
+    fn read_file_cancellable(
+        &self,
+        path: &str,
+        byte_offset: u64,
+        length: u32,
+        sink: &mut dyn FileDataSink,
+        cancel: &CancelToken,
+    ) -> VfsResult<()> {
+        let path = match search::route(path) {
+            Route::Real(path) => path.to_owned(),
+            Route::SearchDir | Route::Query(_) => return Err(VfsError::NotFound),
+            route @ Route::Result { .. } => self.result_path(route)?,
+        };
+        // Only the part asked for is passed on, as the data is read, so
+        // that neither the value nor its text is held whole.
+        match self.rendering {
+            Rendering::Raw => {
+                let mut out = RangeSink::new(sink, byte_offset, length);
+                self.visit_value(&path, cancel, |_, data| {
+                    out.write(data)?;
+                    Ok(!out.is_full())
+                })
             }
-        })();
-        match result {
-            Ok(hresult) => hresult,
-            Err(err) => {
-                log::error!("Error in get_file_data: {:#}", err);
-                err.downcast::<windows::core::Error>()
-                    .map(Into::into)
-                    .unwrap_or(E_FAIL)
+            Rendering::Text => {
+                // Strings may only turn out not to be valid at their end, and
+                // are then shown as binary instead, so the part asked for is
+                // kept until then.
+                let mut text = Vec::new();
+                let mut renderer = TextRenderer::new(&mut text, byte_offset, length);
+                self.visit_value(&path, cancel, |vtype, data| renderer.push(vtype, data))?;
+                if !renderer.finish()? {
+                    text.clear();
+                    let mut renderer = TextRenderer::binary(&mut text, byte_offset, length);
+                    self.visit_value(&path, cancel, |vtype, data| renderer.push(vtype, data))?;
+                    renderer.finish()?;
+                }
+                sink.write(&text)?;
+                Ok(())
             }
         }
     }
 
-    unsafe fn notify(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        _is_dir: bool,
-        kind: NotificationKind,
-        dest_filename: windows::core::PCWSTR,
-        _params: *mut PRJ_NOTIFICATION_PARAMETERS,
-    ) -> windows::core::HRESULT {
-        match kind {
+    fn notify(&self, notification: &Notification) -> VfsResult<()> {
+        let path = notification.path;
+        let dest_path = notification.dest_path;
+        let in_search_dir = |path| !matches!(search::route(path), Route::Real(_));
+        if in_search_dir(path) || dest_path.is_some_and(in_search_dir) {
+            // Search results are only views of the real entries, so they
+            // cannot be changed. Anything else is kept out of the patch and
+            // the audit log.
+            log::debug!(
+                "Ignoring {:?} in search directory: {:?}",
+                notification.kind,
+                path
+            );
+            return match notification.kind {
+                NotificationKind::PreDelete => Err(VfsError::AccessDenied),
+                NotificationKind::PreRename => Err(VfsError::CannotDelete),
+                _ => Ok(()),
+            };
+        }
+
+        let outcome = self.outcome();
+        let handle = |operation| {
+            self.record(notification, operation);
+            self.audit(notification, operation, outcome);
+        };
+
+        match notification.kind {
             NotificationKind::FileOpened => (),
             NotificationKind::NewFileCreated => {
-                log::debug!(
-                    "New file created: {:?}",
-                    callback_data.FilePathName.to_string(),
-                );
+                log::debug!("New file created: {:?}", path);
+                handle(Operation::Create);
             }
-            NotificationKind::FileOverwritten | NotificationKind::FileHandleClosedFileModified => {
-                log::debug!(
-                    "File modified: {:?}",
-                    callback_data.FilePathName.to_string(),
-                );
+            NotificationKind::FileOverwritten => {
+                // The file has just been truncated; its new contents will be
+                // handled once the file handle gets closed.
+                log::debug!("File overwritten: {:?}", path);
+            }
+            NotificationKind::FileHandleClosedFileModified => {
+                log::debug!("File modified: {:?}", path);
+                handle(Operation::Modify);
             }
             NotificationKind::FileRenamed => {
-                log::debug!(
-                    "File renamed: {:?} -> {:?}",
-                    callback_data.FilePathName.to_string(),
-                    dest_filename.to_string(),
-                );
+                log::debug!("File renamed: {:?} -> {:?}", path, dest_path);
+                handle(Operation::Rename);
             }
             NotificationKind::FileHandleClosedFileDeleted => {
-                log::debug!("File deleted: {:?}", callback_data.FilePathName.to_string());
+                log::debug!("File deleted: {:?}", path);
+                handle(Operation::Delete);
             }
             NotificationKind::PreDelete => {
-                log::debug!(
-                    "Denying file deletion: {:?}",
-                    callback_data.FilePathName.to_string(),
-                );
-                return ERROR_ACCESS_DENIED.to_hresult();
+                if !self.pre_notify(notification, Operation::Delete) {
+                    return Err(VfsError::AccessDenied);
+                }
             }
             NotificationKind::PreRename => {
-                log::debug!(
-                    "Denying file rename: {:?}",
-                    callback_data.FilePathName.to_string(),
-                );
-                return STATUS_CANNOT_DELETE.to_hresult();
+                if !self.pre_notify(notification, Operation::Rename) {
+                    return Err(VfsError::CannotDelete);
+                }
             }
             other => {
                 log::warn!("Unknown notification kind: {:?}", other);
             }
         }
-        S_OK
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let closed = self.dir_enums.clear();
+        if closed > 0 {
+            log::debug!("Closing {} open enumerations", closed);
+        }
     }
 }
@@ -0,0 +1,365 @@
+//! Rendering values as text a piece at a time, so that reading part of the
+//! text of a large value neither holds the whole value nor the whole text.
+//!
+//! The text is the same as `Data::to_text` makes of the decoded value. Data
+//! which turns out not to be valid for its type is shown as a hex dump, as
+//! `Value::decode` does; for strings, that is only known once all of the
+//! data has been seen, so the caller then renders the value again with
+//! `TextRenderer::binary`.
+
+use crate::{
+    source::{
+        self, Data, HEX_LINE_BYTES, REG_DWORD, REG_DWORD_BIG_ENDIAN, REG_EXPAND_SZ, REG_LINK,
+        REG_MULTI_SZ, REG_QWORD, REG_SZ,
+    },
+    vfs::{FileDataSink, RangeSink},
+};
+
+/// Renders the part of a value's text within a range, from the value's data
+/// passed in piece by piece.
+pub struct TextRenderer<'a> {
+    out: RangeSink<'a>,
+    state: State,
+}
+
+enum State {
+    /// No data has been seen yet, so the type is not known.
+    Start,
+    Text(Utf16Text),
+    /// The data of a number, which is only as long as the number if it is
+    /// valid.
+    Number {
+        vtype: u32,
+        len: usize,
+        bytes: Vec<u8>,
+    },
+    Binary(HexDump),
+    /// The data is not valid text of its type.
+    Invalid,
+}
+
+impl<'a> TextRenderer<'a> {
+    pub fn new(sink: &'a mut dyn FileDataSink, byte_offset: u64, length: u32) -> TextRenderer<'a> {
+        TextRenderer {
+            out: RangeSink::new(sink, byte_offset, length),
+            state: State::Start,
+        }
+    }
+
+    /// Renders any data as a hex dump, whatever its type.
+    pub fn binary(
+        sink: &'a mut dyn FileDataSink,
+        byte_offset: u64,
+        length: u32,
+    ) -> TextRenderer<'a> {
+        TextRenderer {
+            out: RangeSink::new(sink, byte_offset, length),
+            state: State::Binary(HexDump::default()),
+        }
+    }
+
+    /// Takes the next piece of data, and returns whether more is needed.
+    pub fn push(&mut self, vtype: u32, data: &[u8]) -> anyhow::Result<bool> {
+        if let State::Start = self.state {
+            self.state = match vtype {
+                REG_SZ | REG_EXPAND_SZ | REG_LINK => State::Text(Utf16Text::new(false)),
+                REG_MULTI_SZ => State::Text(Utf16Text::new(true)),
+                REG_DWORD | REG_DWORD_BIG_ENDIAN => State::number(vtype, 4),
+                REG_QWORD => State::number(vtype, 8),
+                _ => State::Binary(HexDump::default()),
+            };
+        }
+        match &mut self.state {
+            State::Start => unreachable!(),
+            State::Text(text) => {
+                text.push(data, &mut self.out)?;
+                // Strings are only valid if all of their data is.
+                Ok(text.valid)
+            }
+            State::Number { len, bytes, .. } if bytes.len() + data.len() <= *len => {
+                bytes.extend_from_slice(data);
+                Ok(true)
+            }
+            State::Number { bytes, .. } => {
+                // Too long for a number, so shown as binary.
+                let mut dump = HexDump::default();
+                dump.push(bytes, &mut self.out)?;
+                dump.push(data, &mut self.out)?;
+                self.state = State::Binary(dump);
+                Ok(!self.out.is_full())
+            }
+            State::Binary(dump) => {
+                dump.push(data, &mut self.out)?;
+                Ok(!self.out.is_full())
+            }
+            State::Invalid => Ok(false),
+        }
+    }
+
+    /// Finishes the text, and returns whether it was valid. If not, the
+    /// value is to be rendered again with `binary`.
+    pub fn finish(mut self) -> anyhow::Result<bool> {
+        match std::mem::replace(&mut self.state, State::Invalid) {
+            State::Start => Ok(true),
+            State::Text(text) => text.finish(&mut self.out),
+            State::Number { vtype, len, bytes } if bytes.len() == len => {
+                let number = match vtype {
+                    REG_DWORD => u32::from_le_bytes(bytes.try_into().unwrap()) as u64,
+                    REG_DWORD_BIG_ENDIAN => u32::from_be_bytes(bytes.try_into().unwrap()) as u64,
+                    _ => u64::from_le_bytes(bytes.try_into().unwrap()),
+                };
+                self.out.write(Data::Number(number).to_text().as_bytes())?;
+                Ok(true)
+            }
+            State::Number { bytes, .. } => {
+                let mut dump = HexDump::default();
+                dump.push(&bytes, &mut self.out)?;
+                dump.finish(&mut self.out)?;
+                Ok(true)
+            }
+            State::Binary(dump) => {
+                dump.finish(&mut self.out)?;
+                Ok(true)
+            }
+            State::Invalid => Ok(false),
+        }
+    }
+}
+
+impl State {
+    fn number(vtype: u32, len: usize) -> State {
+        State::Number {
+            vtype,
+            len,
+            bytes: Vec::with_capacity(len),
+        }
+    }
+}
+
+/// Formats a hex dump a line at a time. Lines before the range are skipped
+/// without being formatted.
+#[derive(Default)]
+struct HexDump {
+    offset: u64,
+    line: Vec<u8>,
+}
+
+impl HexDump {
+    fn push(&mut self, mut data: &[u8], out: &mut RangeSink) -> anyhow::Result<()> {
+        while !data.is_empty() && !out.is_full() {
+            let len = data.len().min(HEX_LINE_BYTES - self.line.len());
+            self.line.extend_from_slice(&data[..len]);
+            data = &data[len..];
+            if self.line.len() == HEX_LINE_BYTES {
+                self.flush(out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, out: &mut RangeSink) -> anyhow::Result<()> {
+        if !self.line.is_empty() {
+            self.flush(out)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, out: &mut RangeSink) -> anyhow::Result<()> {
+        if !out.skip(source::hex_line_len(self.line.len()) as u64) {
+            let mut line = String::new();
+            source::hex_line(&mut line, self.offset, &self.line);
+            out.write(line.as_bytes())?;
+        }
+        self.offset += self.line.len() as u64;
+        self.line.clear();
+        Ok(())
+    }
+}
+
+/// Decodes UTF-16 strings as they come in. Trailing terminators are held
+/// back until it is known whether they end the data.
+struct Utf16Text {
+    /// Whether this is a list of strings, shown a line each.
+    multi: bool,
+    valid: bool,
+    /// The first byte of a code unit split between pieces.
+    odd_byte: Option<u8>,
+    high_surrogate: Option<u16>,
+    /// The number of terminators held back: one for a string, two for a
+    /// list.
+    nuls: usize,
+    written_any: bool,
+}
+
+impl Utf16Text {
+    fn new(multi: bool) -> Utf16Text {
+        Utf16Text {
+            multi,
+            valid: true,
+            odd_byte: None,
+            high_surrogate: None,
+            nuls: 0,
+            written_any: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8], out: &mut RangeSink) -> anyhow::Result<()> {
+        let mut data = data;
+        if let Some(first) = self.odd_byte.take() {
+            match data.split_first() {
+                Some((&second, rest)) => {
+                    self.unit(u16::from_le_bytes([first, second]), out)?;
+                    data = rest;
+                }
+                None => self.odd_byte = Some(first),
+            }
+        }
+        let mut units = data.chunks_exact(2);
+        for unit in &mut units {
+            if !self.valid {
+                return Ok(());
+            }
+            self.unit(u16::from_le_bytes([unit[0], unit[1]]), out)?;
+        }
+        if let [byte] = units.remainder() {
+            self.odd_byte = Some(*byte);
+        }
+        Ok(())
+    }
+
+    fn unit(&mut self, unit: u16, out: &mut RangeSink) -> anyhow::Result<()> {
+        let c = match (self.high_surrogate.take(), unit) {
+            (Some(high), 0xdc00..=0xdfff) => {
+                char::decode_utf16([high, unit]).next().unwrap().unwrap()
+            }
+            (Some(_), _) | (None, 0xdc00..=0xdfff) => {
+                self.valid = false;
+                return Ok(());
+            }
+            (None, 0xd800..=0xdbff) => {
+                self.high_surrogate = Some(unit);
+                return Ok(());
+            }
+            (None, 0) => {
+                self.nuls += 1;
+                if self.nuls > if self.multi { 2 } else { 1 } {
+                    self.nuls -= 1;
+                    self.nul(out)?;
+                }
+                return Ok(());
+            }
+            (None, _) => char::from_u32(unit as u32).unwrap(),
+        };
+        while self.nuls > 0 {
+            self.nuls -= 1;
+            self.nul(out)?;
+        }
+        self.written_any = true;
+        out.write(c.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    /// Writes a terminator found not to end the data.
+    fn nul(&mut self, out: &mut RangeSink) -> anyhow::Result<()> {
+        self.written_any = true;
+        out.write(if self.multi { b"\n" } else { b"\0" })
+    }
+
+    fn finish(self, out: &mut RangeSink) -> anyhow::Result<bool> {
+        if !self.valid || self.odd_byte.is_some() || self.high_surrogate.is_some() {
+            return Ok(false);
+        }
+        // A list ends each string with a line break, and has no lines if it
+        // has no strings.
+        if !self.multi || self.written_any {
+            out.write(b"\n")?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{Value, REG_BINARY, REG_NONE};
+
+    /// Renders every range of a value's text, with the data split into
+    /// pieces of every size, as a whole value would be rendered.
+    fn check(vtype: u32, bytes: &[u8]) {
+        let value = Value {
+            vtype,
+            bytes: bytes.to_vec(),
+        };
+        let text = value.decode().to_text().into_bytes();
+        for piece in 1..=bytes.len().max(1) {
+            for start in 0..=text.len() {
+                for length in [0, 1, 3, 17, text.len() as u32] {
+                    let mut out = Vec::new();
+                    let mut renderer = TextRenderer::new(&mut out, start as u64, length);
+                    let mut pieces = bytes.chunks(piece).peekable();
+                    if pieces.peek().is_none() {
+                        renderer.push(vtype, &[]).unwrap();
+                    }
+                    for data in pieces {
+                        if !renderer.push(vtype, data).unwrap() {
+                            break;
+                        }
+                    }
+                    if !renderer.finish().unwrap() {
+                        out.clear();
+                        let mut renderer = TextRenderer::binary(&mut out, start as u64, length);
+                        renderer.push(vtype, bytes).unwrap();
+                        renderer.finish().unwrap();
+                    }
+                    assert_eq!(
+                        out,
+                        crate::vfs::range(&text, start as u64, length),
+                        "type {}, {:?}, pieces of {}, {} bytes at {}",
+                        vtype,
+                        bytes,
+                        piece,
+                        length,
+                        start,
+                    );
+                }
+            }
+        }
+    }
+
+    fn utf16(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn strings() {
+        for s in ["", "\0", "abc", "abc\0", "a\0b\0\0", "Grüße, 🦀\0"] {
+            check(REG_SZ, &utf16(s));
+            check(REG_MULTI_SZ, &utf16(s));
+        }
+        check(REG_MULTI_SZ, &utf16("one\0two\0\0\0"));
+        check(REG_EXPAND_SZ, &utf16("%PATH%"));
+        // Odd lengths and unpaired surrogates are shown as binary.
+        check(REG_SZ, b"abc");
+        check(REG_SZ, &[0x3d, 0xd8, b'a', 0]);
+        check(REG_MULTI_SZ, &[b'a', 0, 0x00, 0xdc]);
+        check(REG_SZ, &[0x3d, 0xd8]);
+    }
+
+    #[test]
+    fn numbers() {
+        check(REG_DWORD, &[1, 2, 3, 4]);
+        check(REG_DWORD_BIG_ENDIAN, &[1, 2, 3, 4]);
+        check(REG_QWORD, &u64::MAX.to_le_bytes());
+        check(REG_DWORD, &[1, 2, 3]);
+        check(REG_DWORD, &[1, 2, 3, 4, 5]);
+        check(REG_DWORD, &[]);
+    }
+
+    #[test]
+    fn binary() {
+        let bytes: Vec<u8> = (0..=70).collect();
+        check(REG_BINARY, &bytes);
+        check(REG_BINARY, &bytes[..16]);
+        check(REG_NONE, &[]);
+        check(0x1234, b"Hello, world");
+    }
+}
@@ -0,0 +1,320 @@
+//! Queries over a registry source, and the `@search` directory which exposes
+//! them at the root of the projection.
+//!
+//! Opening `@search\<pattern>` runs a query for keys and values whose names or
+//! decoded data contain the pattern, ignoring case. Each result is named
+//! after its real path, with `\` written as `%5C` (and `%` as `%25`), and
+//! stands for the entry at that path: a key can be browsed, and a value read,
+//! through it. Patterns with wildcards (`*`, `?`) are matched against the
+//! whole name or string instead, where the file system allows typing them.
+
+use crate::{
+    file_name, reg_name,
+    source::{split_value_path, Data, KeyEntries, RegSource, Value},
+};
+
+/// The name of the search directory at the root of the projection.
+pub const SEARCH_DIR: &str = "@search";
+
+pub struct Query {
+    pattern: String,
+    wildcard: bool,
+}
+
+impl Query {
+    pub fn new(pattern: &str) -> Query {
+        Query {
+            pattern: reg_name::fold(pattern),
+            wildcard: pattern.contains(['*', '?']),
+        }
+    }
+
+    /// Checks whether a name or string matches. Wildcards are matched in one
+    /// pass over the text, which may be the whole data of a value.
+    fn matches_text(&self, text: &str) -> bool {
+        if self.wildcard {
+            file_name::matches(text, &self.pattern)
+        } else {
+            self.matches_folded(&reg_name::fold(text))
+        }
+    }
+
+    /// Checks whether a name or string, already folded, matches.
+    pub fn matches_folded(&self, text: &str) -> bool {
+        if self.wildcard {
+            file_name::matches(text, &self.pattern)
+        } else {
+            text.contains(&self.pattern)
+        }
+    }
+
+    /// Returns the folded pattern if it has no wildcards, i.e. if matching
+    /// texts contain it.
+    pub fn plain_pattern(&self) -> Option<&str> {
+        (!self.wildcard).then_some(self.pattern.as_str())
+    }
+
+    /// Checks whether a value matches by its data. Numbers are matched in
+    /// decimal; binary data never matches.
+    fn matches_data(&self, value: &Value) -> bool {
+        match value.decode() {
+            Data::String(s) => self.matches_text(&s),
+            Data::MultiString(list) => list.iter().any(|s| self.matches_text(s)),
+            Data::Number(n) => self.matches_text(&n.to_string()),
+            Data::Binary(_) => false,
+        }
+    }
+
+    /// Checks whether the entry at a path exists and matches, returning the
+    /// size of the value if it is one.
+    pub fn find(&self, source: &dyn RegSource, path: &str) -> anyhow::Result<Option<Option<u32>>> {
+        let (_, name) = split_value_path(path);
+        if source.key_exists(path)? {
+            return Ok(self.matches_text(name).then_some(None));
+        }
+        Ok(source.read_value(path)?.and_then(|value| {
+            (self.matches_text(name) || self.matches_data(&value))
+                .then_some(Some(value.bytes.len() as u32))
+        }))
+    }
+
+    /// Walks the whole source, returning the real paths of all matching keys
+    /// and values.
+    pub fn run(&self, source: &dyn RegSource) -> anyhow::Result<KeyEntries> {
+        let mut results = Vec::new();
+        self.walk(source, "", &mut results)?;
+        Ok(results)
+    }
+
+    fn walk(
+        &self,
+        source: &dyn RegSource,
+        key: &str,
+        results: &mut KeyEntries,
+    ) -> anyhow::Result<()> {
+        for (name, len) in source.enum_key(key)?.unwrap_or_default() {
+            let path = if key.is_empty() {
+                name.clone()
+            } else {
+                format!("{}\\{}", key, name)
+            };
+            match len {
+                None => {
+                    if self.matches_text(&name) {
+                        results.push((path.clone(), None));
+                    }
+                    self.walk(source, &path, results)?;
+                }
+                Some(len) => {
+                    let matches = self.matches_text(&name)
+                        || source
+                            .read_value(&path)?
+                            .is_some_and(|value| self.matches_data(&value));
+                    if matches {
+                        results.push((path, Some(len)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names a result after its real path.
+pub fn result_name(path: &str) -> String {
+    path.replace('%', "%25").replace('\\', "%5C")
+}
+
+/// Recovers the real path from the name of a result.
+pub fn result_path(name: &str) -> String {
+    let mut path = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(i) = rest.find('%') {
+        path.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let escape = rest.get(..3).unwrap_or(rest);
+        if escape.eq_ignore_ascii_case("%5C") {
+            path.push('\\');
+        } else if escape == "%25" {
+            path.push('%');
+        } else {
+            path.push_str(escape);
+        }
+        rest = &rest[escape.len()..];
+    }
+    path.push_str(rest);
+    path
+}
+
+/// Where a path in the projection leads.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Route<'a> {
+    /// A key or value outside of the search directory.
+    Real(&'a str),
+    /// The search directory itself.
+    SearchDir,
+    /// The results of a query.
+    Query(&'a str),
+    /// A result of a query, or an entry below it.
+    Result {
+        pattern: &'a str,
+        /// The real path of the result.
+        result: String,
+        /// The real path the projected path stands for.
+        path: String,
+    },
+}
+
+pub fn route(path: &str) -> Route<'_> {
+    let mut components = path.splitn(4, '\\');
+    if !components
+        .next()
+        .is_some_and(|c| reg_name::eq(c, SEARCH_DIR))
+    {
+        return Route::Real(path);
+    }
+    match (components.next(), components.next(), components.next()) {
+        (None, _, _) => Route::SearchDir,
+        (Some(pattern), None, _) => Route::Query(pattern),
+        (Some(pattern), Some(name), rest) => {
+            let result = result_path(name);
+            let path = match rest {
+                Some(rest) => format!("{}\\{}", result, rest),
+                None => result.clone(),
+            };
+            Route::Result {
+                pattern,
+                result,
+                path,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        regfs::RegFs,
+        source::{REG_BINARY, REG_DWORD, REG_SZ},
+        vfs::{self, EntryInfo, VfsError, VirtualFs},
+    };
+
+    fn source() -> MemSource {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_TEST\\Software\\Contoso 100%\\Version Info");
+        let value = |vtype, data| Value::encode(vtype, &data).unwrap();
+        let key = "HKEY_TEST\\Software\\Contoso 100%";
+        source.set_value(key, "Name", value(REG_SZ, Data::String("ReGfs".into())));
+        source.set_value(key, "Build", value(REG_DWORD, Data::Number(1903)));
+        source.set_value(
+            key,
+            "Blob",
+            value(REG_BINARY, Data::Binary(b"regfs".to_vec())),
+        );
+        source
+    }
+
+    #[test]
+    fn names() {
+        let path = "HKEY_TEST\\Contoso 100%\\Name";
+        assert_eq!(result_name(path), "HKEY_TEST%5CContoso 100%25%5CName");
+        assert_eq!(result_path(&result_name(path)), path);
+        assert_eq!(result_path("a%5cb%2%"), "a\\b%2%");
+    }
+
+    #[test]
+    fn routes() {
+        assert_eq!(route(""), Route::Real(""));
+        assert_eq!(
+            route("HKEY_TEST\\@search"),
+            Route::Real("HKEY_TEST\\@search")
+        );
+        assert_eq!(route("@SEARCH"), Route::SearchDir);
+        assert_eq!(route("@search\\ver"), Route::Query("ver"));
+        assert_eq!(
+            route("@search\\ver\\A%5CB\\C\\D"),
+            Route::Result {
+                pattern: "ver",
+                result: String::from("A\\B"),
+                path: String::from("A\\B\\C\\D"),
+            },
+        );
+    }
+
+    #[test]
+    fn queries() {
+        let source = source();
+        let run = |pattern| Query::new(pattern).run(&source).unwrap();
+        assert_eq!(
+            run("version"),
+            [(
+                String::from("HKEY_TEST\\Software\\Contoso 100%\\Version Info"),
+                None
+            )],
+        );
+        assert_eq!(
+            run("REGFS"),
+            [(
+                String::from("HKEY_TEST\\Software\\Contoso 100%\\Name"),
+                Some(12)
+            )],
+        );
+        assert_eq!(
+            run("190"),
+            [(
+                String::from("HKEY_TEST\\Software\\Contoso 100%\\Build"),
+                Some(4)
+            )],
+        );
+        assert_eq!(run("*soft*").len(), 1);
+        assert!(run("nothing").is_empty());
+    }
+
+    #[test]
+    fn long_data() {
+        // Wildcards are matched against the whole string, however long.
+        let mut source = source();
+        let data = format!("{}regfs", "a".repeat(1 << 16));
+        let value = Value::encode(REG_SZ, &Data::String(data)).unwrap();
+        source.set_value("HKEY_TEST\\Software", "Long", value);
+        let run = |pattern| Query::new(pattern).run(&source).unwrap();
+        assert_eq!(run("*a*a*a*a*regfs").len(), 1);
+        assert!(run("*a*a*a*a*regfx").is_empty());
+    }
+
+    #[test]
+    fn search_dir() {
+        let fs = RegFs::new(PathBuf::new(), Arc::new(source()));
+        let names = |path| -> Vec<String> {
+            vfs::list_dir(&fs, path)
+                .unwrap()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect()
+        };
+        assert_eq!(names(""), ["@search", "HKEY_TEST"]);
+        assert!(names("@search").is_empty());
+        assert_eq!(
+            names("@search\\contoso"),
+            ["HKEY_TEST%5CSoftware%5CContoso 100%25"],
+        );
+
+        // Results stand for their real paths.
+        let result = "@search\\contoso\\HKEY_TEST%5CSoftware%5CContoso 100%25";
+        assert_eq!(names(result), ["Blob", "Build", "Name", "Version Info"]);
+        assert_eq!(fs.get_entry_info(result).unwrap(), EntryInfo::dir());
+        let value = "@search\\refgs\\HKEY_TEST%5CSoftware%5CContoso 100%25%5CName";
+        assert!(matches!(fs.get_entry_info(value), Err(VfsError::NotFound)));
+        let value = "@search\\regfs\\HKEY_TEST%5CSoftware%5CContoso 100%25%5CName";
+        assert_eq!(fs.get_entry_info(value).unwrap(), EntryInfo::file(12));
+        assert_eq!(
+            vfs::read_to_vec(&fs, value, 0, 12).unwrap(),
+            b"R\0e\0G\0f\0s\0\0\0"
+        );
+        assert_eq!(fs.get_entry_info("@search\\x").unwrap(), EntryInfo::dir());
+    }
+}
@@ -0,0 +1,178 @@
+//! Running a frontend as a service: stopping on signals instead of console
+//! input, a pid file, and readiness notifications for service managers.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+};
+
+use anyhow::Context;
+
+/// Why a frontend is asked to stop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// SIGINT, SIGTERM or SIGHUP; Ctrl-C, Ctrl-Break or closing the console
+    /// on Windows.
+    Signal,
+    /// A line (or the end of input) on standard input.
+    Console,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Signal => "signal",
+            StopReason::Console => "console input",
+        })
+    }
+}
+
+/// Receives requests to stop. Only one can be installed per process.
+pub struct StopSignal {
+    tx: mpsc::Sender<StopReason>,
+    rx: Receiver<StopReason>,
+}
+
+impl StopSignal {
+    /// Starts catching termination signals, which would otherwise kill the
+    /// process without stopping the frontend.
+    pub fn install() -> anyhow::Result<StopSignal> {
+        let (tx, rx) = mpsc::channel();
+        let signal_tx = tx.clone();
+        ctrlc::set_handler(move || {
+            // The receiver only goes away once the process is stopping.
+            let _ = signal_tx.send(StopReason::Signal);
+        })
+        .context("install signal handler")?;
+        Ok(StopSignal { tx, rx })
+    }
+
+    /// Also stops on a line from standard input, for interactive use.
+    pub fn watch_console(&self) {
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = std::io::stdin().read_line(&mut buf);
+            let _ = tx.send(StopReason::Console);
+        });
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn wait(&self) -> StopReason {
+        // The sender kept in `self` keeps the channel open.
+        self.rx.recv().unwrap()
+    }
+
+    /// Waits for a while, for frontends which can also stop by themselves.
+    #[cfg(target_os = "linux")]
+    pub fn wait_timeout(&self, timeout: std::time::Duration) -> Option<StopReason> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+}
+
+/// A file containing the ID of this process, removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> anyhow::Result<PidFile> {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("write pid file {}", path.display()))?;
+        Ok(PidFile {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("Failed to remove pid file {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Announces that the frontend is serving requests: writes the pid file, if
+/// any, and notifies the service manager. The pid file is written only now,
+/// so that its existence also tells that the service is ready.
+pub fn ready(pid_file: Option<&Path>) -> anyhow::Result<Option<PidFile>> {
+    let pid_file = pid_file.map(PidFile::create).transpose()?;
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+    log::info!("Ready");
+    Ok(pid_file)
+}
+
+/// Announces that the frontend is shutting down.
+pub fn stopping(reason: StopReason) {
+    log::info!("Stopping on {}", reason);
+    notify("STOPPING=1");
+}
+
+/// Sends a state change to the service manager with the systemd notification
+/// protocol, if it asked for them by setting `NOTIFY_SOCKET`.
+#[cfg(target_os = "linux")]
+fn notify(state: &str) {
+    if let Some(socket) = std::env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = notify_to(&socket, state) {
+            log::warn!("Failed to notify service manager: {:#}", err);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_state: &str) {}
+
+#[cfg(target_os = "linux")]
+fn notify_to(socket: &std::ffi::OsStr, state: &str) -> anyhow::Result<()> {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    };
+
+    // A leading `@` stands for a socket in the abstract namespace.
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(socket),
+    }
+    .context("parse NOTIFY_SOCKET")?;
+    UnixDatagram::unbound()?
+        .send_to_addr(state.as_bytes(), &addr)
+        .context("send notification")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file() {
+        let path = std::env::temp_dir().join(format!("regfs-{}.pid", uuid::Uuid::new_v4()));
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id()),
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifications() {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("regfs-{}.sock", uuid::Uuid::new_v4()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(path).unwrap();
+    }
+}
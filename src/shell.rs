@@ -0,0 +1,523 @@
+//! An interactive shell over a registry source, with tab completion of key
+//! and value names. The commands print the same listings and decoded data as
+//! the one-shot subcommands in [`crate::cli`].
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context as _};
+use rustyline::{
+    completion::{Completer, Pair},
+    config::{CompletionType, Config},
+    highlight::Highlighter,
+    hint::Hinter,
+    history::FileHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+
+use crate::{
+    cli::{self, CatFormat},
+    reg_file, reg_name,
+    source::{self, RegSource, Value},
+};
+
+const COMMANDS: &[&str] = &[
+    "cat", "cd", "diff", "exit", "export", "find", "help", "ls", "pwd", "stat",
+];
+
+const HELP: &str = "\
+cd [KEY]                    change the current key (the root without KEY)
+ls [-l] [KEY]               list subkeys and values
+cat [--raw | --type] VALUE  print the data of a value
+stat PATH                   print information about a key or value
+find PATTERN [KEY]          find keys and values matching a wildcard pattern
+export KEY [FILE]           export a key as a .reg file
+diff KEY KEY                compare two keys and everything below them
+pwd                         print the current key
+exit                        leave the shell
+Paths are relative to the current key unless they start with \\ or /.
+";
+
+/// The state of the shell: the source and the current key.
+pub struct Shell {
+    source: Arc<dyn RegSource>,
+    cwd: String,
+}
+
+/// Splits a command line into words. Double quotes group words containing
+/// spaces, as key names often do.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = None::<String>;
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    words
+}
+
+/// Finds the start of the word under the cursor, so that quoted names with
+/// spaces are completed as one word.
+fn word_start(line: &str) -> usize {
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => start = i + c.len_utf8(),
+            _ => (),
+        }
+    }
+    start
+}
+
+impl Shell {
+    pub fn new(source: Arc<dyn RegSource>) -> Shell {
+        Shell {
+            source,
+            cwd: String::new(),
+        }
+    }
+
+    fn prompt(&self) -> String {
+        format!("{}> ", if self.cwd.is_empty() { "\\" } else { &self.cwd })
+    }
+
+    /// Resolves a path given to a command against the current key. Only `\`
+    /// separates keys, since `/` may be part of a name. `.` and `..`
+    /// components are handled before the source is asked, so that they never
+    /// reach it.
+    fn resolve(&self, arg: &str) -> String {
+        let mut components: Vec<&str> = if arg.starts_with('\\') {
+            Vec::new()
+        } else {
+            self.cwd.split('\\').filter(|c| !c.is_empty()).collect()
+        };
+        for component in arg.split('\\') {
+            match component {
+                "" | "." => (),
+                ".." => {
+                    components.pop();
+                }
+                component => components.push(component),
+            }
+        }
+        components.join("\\")
+    }
+
+    /// Looks up the entry at a resolved path, returning the path with the
+    /// names as they are stored, and the size of the value if it is one.
+    fn lookup(&self, path: &str) -> anyhow::Result<(String, Option<u32>)> {
+        let mut found = String::new();
+        let mut len = None;
+        for component in path.split('\\').filter(|c| !c.is_empty()) {
+            if len.is_some() {
+                bail!("{:?} is a value, not a key", found);
+            }
+            let entries = self
+                .source
+                .enum_key(&found)
+                .context("enumerate key")?
+                .with_context(|| format!("key {:?} not found", found))?;
+            let (name, entry_len) = entries
+                .into_iter()
+                .find(|(name, _)| reg_name::eq(name, component))
+                .with_context(|| format!("{:?} not found", source::join_path(&found, component)))?;
+            found = source::join_path(&found, &name);
+            len = entry_len;
+        }
+        Ok((found, len))
+    }
+
+    fn key(&self, arg: Option<&String>) -> anyhow::Result<String> {
+        let path = match arg {
+            Some(arg) => self.resolve(arg),
+            None => self.cwd.clone(),
+        };
+        match self.lookup(&path)? {
+            (path, None) => Ok(path),
+            (path, Some(_)) => bail!("{:?} is a value, not a key", path),
+        }
+    }
+
+    /// Runs a command line, returning whether the shell should keep running.
+    pub fn execute(&mut self, line: &str, out: &mut dyn Write) -> anyhow::Result<bool> {
+        let words = split_words(line);
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (command.as_str(), args),
+            None => return Ok(true),
+        };
+        let flags: Vec<&str> = args
+            .iter()
+            .filter(|a| a.starts_with("--") || *a == "-l")
+            .map(String::as_str)
+            .collect();
+        let args: Vec<&String> = args
+            .iter()
+            .filter(|a| !flags.contains(&a.as_str()))
+            .collect();
+        let arg = |i: usize| args.get(i).copied();
+        let required = |i: usize| arg(i).with_context(|| format!("usage: {}", usage(command)));
+
+        match command {
+            "exit" | "quit" => return Ok(false),
+            "help" => write!(out, "{}", HELP)?,
+            "pwd" => writeln!(out, "\\{}", self.cwd)?,
+            "cd" => {
+                self.cwd = match arg(0) {
+                    Some(_) => self.key(arg(0))?,
+                    None => String::new(),
+                }
+            }
+            "ls" => cli::ls(
+                &*self.source,
+                &self.key(arg(0))?,
+                flags.contains(&"-l"),
+                out,
+            )?,
+            "cat" => {
+                let format = if flags.contains(&"--raw") {
+                    CatFormat::Raw
+                } else if flags.contains(&"--type") {
+                    CatFormat::Type
+                } else {
+                    CatFormat::Decoded
+                };
+                let path = self.resolve(required(0)?);
+                cli::cat(&*self.source, &path, format, out)?;
+            }
+            "stat" => self.stat(&self.resolve(required(0)?), out)?,
+            "find" => cli::find(&*self.source, required(0)?, &self.key(arg(1))?, out)?,
+            "export" => {
+                let key = self.key(Some(required(0)?))?;
+                match arg(1) {
+                    Some(file) => {
+                        let mut file = std::fs::File::create(file)
+                            .with_context(|| format!("failed to create {:?}", file))?;
+                        cli::export(&*self.source, &key, &mut file)?;
+                    }
+                    None => {
+                        let text = reg_file::export(&*self.source, &key)?;
+                        write!(out, "{}", text.replace("\r\n", "\n"))?;
+                    }
+                }
+            }
+            "diff" => {
+                let a = self.key(Some(required(0)?))?;
+                let b = self.key(Some(required(1)?))?;
+                self.diff(&a, &b, out)?;
+            }
+            _ => bail!("unknown command {:?}; type `help` for a list", command),
+        }
+        Ok(true)
+    }
+
+    fn stat(&self, path: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+        let (path, len) = self.lookup(path)?;
+        writeln!(out, "Path: \\{}", path)?;
+        match len {
+            None => {
+                let entries = self.source.enum_key(&path)?.unwrap_or_default();
+                let subkeys = entries.iter().filter(|(_, len)| len.is_none()).count();
+                writeln!(out, "Type: key")?;
+                writeln!(out, "Subkeys: {}", subkeys)?;
+                writeln!(out, "Values: {}", entries.len() - subkeys)?;
+            }
+            Some(len) => {
+                let value = self.source.read_value(&path)?;
+                let vtype = value.map(|value| value.vtype).unwrap_or_default();
+                writeln!(out, "Type: {}", source::type_name(vtype))?;
+                writeln!(out, "Size: {}", len)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects everything below a key, by folded path relative to the key.
+    /// Keys map to `None`.
+    fn collect(
+        &self,
+        key: &str,
+        prefix: &str,
+        entries: &mut BTreeMap<String, (String, Option<Value>)>,
+    ) -> anyhow::Result<()> {
+        for (name, len) in self.source.enum_key(key)?.unwrap_or_default() {
+            let rel = source::join_path(prefix, &name);
+            let path = source::join_path(key, &name);
+            match len {
+                None => {
+                    entries.insert(reg_name::fold(&rel), (format!("{}\\", rel), None));
+                    self.collect(&path, &rel, entries)?;
+                }
+                Some(_) => {
+                    let value = self.source.read_value(&path)?;
+                    entries.insert(reg_name::fold(&rel), (rel, value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints what differs between two keys: `-` for entries only below the
+    /// first, `+` for entries only below the second, and `~` for values whose
+    /// type or data differ.
+    fn diff(&self, a: &str, b: &str, out: &mut dyn Write) -> anyhow::Result<()> {
+        let mut old = BTreeMap::new();
+        let mut new = BTreeMap::new();
+        self.collect(a, "", &mut old)?;
+        self.collect(b, "", &mut new)?;
+
+        let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+        keys.sort_unstable();
+        keys.dedup();
+        for key in keys {
+            match (old.get(key), new.get(key)) {
+                (Some((rel, _)), None) => writeln!(out, "- {}", rel)?,
+                (None, Some((rel, _))) => writeln!(out, "+ {}", rel)?,
+                (Some((rel, Some(a))), Some((_, Some(b)))) if a != b => writeln!(out, "~ {}", rel)?,
+                (Some((rel, a)), Some((_, b))) if a.is_some() != b.is_some() => {
+                    writeln!(out, "- {}", rel)?;
+                    writeln!(out, "+ {}", new[key].0)?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Completes a path being typed: the names in the key it points into
+    /// which start with the last component, ignoring case. Keys are completed
+    /// with a trailing backslash, and names with spaces are quoted.
+    fn complete_path(&self, word: &str) -> Vec<String> {
+        let word = word.trim_start_matches('"');
+        let split = word.rfind('\\').map_or(0, |i| i + 1);
+        let (dir, prefix) = word.split_at(split);
+        let key = match self.lookup(&self.resolve(dir)) {
+            Ok((key, None)) => key,
+            _ => return Vec::new(),
+        };
+        let folded = reg_name::fold(prefix);
+        let mut entries = self
+            .source
+            .enum_key(&key)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        entries.sort_unstable_by(|(a, _), (b, _)| reg_name::cmp(a, b));
+        entries
+            .into_iter()
+            .filter(|(name, _)| reg_name::fold(name).starts_with(&folded))
+            .map(|(name, len)| {
+                let completed = format!("{}{}", dir, name);
+                match (completed.contains(' '), len) {
+                    (false, None) => format!("{}\\", completed),
+                    (false, Some(_)) => completed,
+                    (true, None) => format!("\"{}\\", completed),
+                    (true, Some(_)) => format!("\"{}\"", completed),
+                }
+            })
+            .collect()
+    }
+}
+
+fn usage(command: &str) -> &'static str {
+    HELP.lines()
+        .find(|line| line.split_whitespace().next() == Some(command))
+        .and_then(|line| line.split("  ").next())
+        .unwrap_or("")
+}
+
+impl Completer for Shell {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let start = word_start(line);
+        let word = &line[start..];
+        let candidates = if line[..start].trim().is_empty() {
+            COMMANDS
+                .iter()
+                .filter(|c| c.starts_with(word))
+                .map(|c| format!("{} ", c))
+                .collect()
+        } else if word.starts_with('-') {
+            Vec::new()
+        } else {
+            self.complete_path(word)
+        };
+        let candidates = candidates
+            .into_iter()
+            .map(|replacement| Pair {
+                display: replacement
+                    .trim_matches('"')
+                    .rsplit('\\')
+                    .find(|c| !c.is_empty())
+                    .unwrap_or(&replacement)
+                    .to_string(),
+                replacement,
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Shell {
+    type Hint = String;
+}
+
+impl Highlighter for Shell {}
+
+impl Validator for Shell {}
+
+impl Helper for Shell {}
+
+fn history_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(Path::new(&home).join(".regfs_history"))
+}
+
+/// Runs the shell on the terminal until `exit` or end of input.
+pub fn run(source: Arc<dyn RegSource>) -> anyhow::Result<()> {
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .auto_add_history(true)
+        .build();
+    let mut editor = Editor::<Shell, FileHistory>::with_config(config)?;
+    editor.set_helper(Some(Shell::new(source)));
+    let history = history_path();
+    if let Some(history) = &history {
+        // There is no history yet on the first run.
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let prompt = editor.helper().unwrap().prompt();
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(rustyline::error::ReadlineError::Interrupted) => continue,
+            Err(rustyline::error::ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let shell = editor.helper_mut().unwrap();
+        let stdout = std::io::stdout();
+        match shell.execute(&line, &mut stdout.lock()) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(err) => eprintln!("{:#}", err),
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            log::warn!("Failed to save shell history: {}", err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        source::{Data, REG_DWORD, REG_SZ},
+    };
+
+    fn shell() -> Shell {
+        let mut source = MemSource::new();
+        source.create_key("HKEY_TEST\\Old\\Sub Key");
+        source.create_key("HKEY_TEST\\New\\Added");
+        let string = |s: &str| Value::encode(REG_SZ, &Data::String(String::from(s))).unwrap();
+        let number = |n| Value::encode(REG_DWORD, &Data::Number(n)).unwrap();
+        source.set_value("HKEY_TEST\\Old", "Same", string("x"));
+        source.set_value("HKEY_TEST\\New", "same", string("x"));
+        source.set_value("HKEY_TEST\\Old", "Changed", number(1));
+        source.set_value("HKEY_TEST\\New", "Changed", number(2));
+        source.set_value("HKEY_TEST\\Old\\Sub Key", "Removed", string("y"));
+        Shell::new(Arc::new(source))
+    }
+
+    fn run(shell: &mut Shell, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(shell.execute(line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(split_words("  cd  \"Sub Key\"\\x "), ["cd", "Sub Key\\x"]);
+        assert_eq!(split_words("cat \"\""), ["cat", ""]);
+        assert_eq!(word_start("cd \"Sub K"), 3);
+    }
+
+    #[test]
+    fn navigation() {
+        let mut shell = shell();
+        run(&mut shell, "cd hkey_test\\old");
+        assert_eq!(shell.prompt(), "HKEY_TEST\\Old> ");
+        assert_eq!(run(&mut shell, "ls"), "Sub Key\\\nChanged\nSame\n");
+        run(&mut shell, "cd \"sub key\"");
+        assert_eq!(run(&mut shell, "cat removed"), "y\n");
+        assert_eq!(run(&mut shell, "cat ..\\changed --type"), "REG_DWORD\n");
+        run(&mut shell, "cd ..\\..\\New");
+        assert_eq!(run(&mut shell, "pwd"), "\\HKEY_TEST\\New\n");
+        assert!(shell.execute("cd Missing", &mut Vec::new()).is_err());
+        assert!(shell.execute("cd Changed", &mut Vec::new()).is_err());
+        assert_eq!(shell.prompt(), "HKEY_TEST\\New> ");
+        run(&mut shell, "cd");
+        assert_eq!(shell.prompt(), "\\> ");
+        // Slashes are part of names.
+        assert_eq!(shell.resolve("HKEY_TEST\\A/B\\..\\C"), "HKEY_TEST\\C");
+        assert_eq!(shell.resolve("\\HKEY_TEST\\A/B"), "HKEY_TEST\\A/B");
+        assert!(!shell.execute("exit", &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn stat_and_diff() {
+        let mut shell = shell();
+        assert_eq!(
+            run(&mut shell, "stat hkey_test\\OLD"),
+            "Path: \\HKEY_TEST\\Old\nType: key\nSubkeys: 1\nValues: 2\n",
+        );
+        assert_eq!(
+            run(&mut shell, "stat \\HKEY_TEST\\Old\\Changed"),
+            "Path: \\HKEY_TEST\\Old\\Changed\nType: REG_DWORD\nSize: 4\n",
+        );
+        assert_eq!(
+            run(&mut shell, "diff HKEY_TEST\\Old HKEY_TEST\\New"),
+            "+ Added\\\n~ Changed\n- Sub Key\\\n- Sub Key\\Removed\n",
+        );
+    }
+
+    #[test]
+    fn completion() {
+        let mut shell = shell();
+        assert_eq!(shell.complete_path("hkey_test\\o"), ["hkey_test\\Old\\"]);
+        run(&mut shell, "cd HKEY_TEST\\Old");
+        assert_eq!(shell.complete_path("s"), ["Same", "\"Sub Key\\"]);
+        assert_eq!(
+            shell.complete_path("\"sub key\\r"),
+            ["\"sub key\\Removed\""]
+        );
+        assert_eq!(shell.complete_path("..\\n"), ["..\\New\\"]);
+        assert!(shell.complete_path("Same\\").is_empty());
+    }
+}
@@ -1,31 +1,15 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
-
-use anyhow::Context;
 use uuid::Uuid;
-use windows::{
-    core::PCWSTR,
-    Win32::{
-        Foundation::{ERROR_FILE_NOT_FOUND, E_FAIL, E_INVALIDARG, S_OK},
-        Storage::ProjectedFileSystem::*,
-    },
-};
 
 use crate::{
-    dir_enum::SimpleDirEnumerator,
-    fs_helper::SimpleFsHelper,
-    projfs::{NotificationKind, ProjFsBackend},
+    dir_enum::{DirEnumSessions, SimpleDirEnumerator},
+    vfs::{
+        range, DirEntrySink, EntryInfo, FileDataSink, Notification, OptionalFeatures, VfsError,
+        VfsResult, VirtualFs,
+    },
 };
 
 pub struct SimpleFs {
-    state: Mutex<SimpleFsState>,
-}
-
-struct SimpleFsState {
-    fs_helper: SimpleFsHelper,
-    dir_enums: HashMap<Uuid, DirEnumerator>,
+    dir_enums: DirEnumSessions<DirEnumerator>,
 }
 
 type DirEnumerator = SimpleDirEnumerator<std::iter::Once<(&'static str, Option<u32>)>>;
@@ -35,10 +19,7 @@ const FILE_CONTENTS: &str = "Hello, Windows 10 projected FS!\r\n";
 impl SimpleFs {
     pub fn new() -> SimpleFs {
         SimpleFs {
-            state: Mutex::new(SimpleFsState {
-                fs_helper: SimpleFsHelper::default(),
-                dir_enums: HashMap::new(),
-            }),
+            dir_enums: DirEnumSessions::new(),
         }
     }
 
@@ -50,171 +31,73 @@ impl SimpleFs {
     }
 }
 
-impl ProjFsBackend for SimpleFs {
-    fn get_optional_features() -> crate::projfs::OptionalFeatures {
-        crate::projfs::OptionalFeatures::empty()
+impl VirtualFs for SimpleFs {
+    fn get_optional_features() -> OptionalFeatures {
+        OptionalFeatures::empty()
     }
 
-    fn set_instance_handle(
-        self: &Arc<Self>,
-        instance_handle: PRJ_NAMESPACE_VIRTUALIZATION_CONTEXT,
-    ) {
-        log::debug!("Simple FS backend initialized");
-        self.state.lock().unwrap().fs_helper = SimpleFsHelper::new(instance_handle);
-    }
-
-    unsafe fn start_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-    ) -> windows::core::HRESULT {
+    fn start_dir_enum(&self, path: &str, enumeration_id: Uuid) -> VfsResult<()> {
         log::trace!(
             "Start directory enumeration: ID {}, path {:?}",
             enumeration_id,
-            callback_data.FilePathName.to_string(),
+            path,
         );
-        self.state
-            .lock()
-            .unwrap()
-            .dir_enums
-            .insert(enumeration_id, Self::enum_root_dir());
-        S_OK
+        self.dir_enums.insert(enumeration_id, Self::enum_root_dir());
+        Ok(())
     }
 
-    unsafe fn end_dir_enum(
-        self: &Arc<Self>,
-        _callback_data: &PRJ_CALLBACK_DATA,
-        enumeration_id: Uuid,
-    ) -> windows::core::HRESULT {
+    fn end_dir_enum(&self, enumeration_id: Uuid) -> VfsResult<()> {
         log::trace!("End directory enumeration: ID {}", enumeration_id);
-        self.state.lock().unwrap().dir_enums.remove(&enumeration_id);
-        S_OK
+        self.dir_enums.remove(enumeration_id);
+        Ok(())
     }
 
-    unsafe fn get_dir_enum(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
+    fn get_dir_enum(
+        &self,
         enumeration_id: Uuid,
-        search_expr: windows::core::PCWSTR,
-        dir_entry_buffer_handle: PRJ_DIR_ENTRY_BUFFER_HANDLE,
-    ) -> windows::core::HRESULT {
+        search_expr: Option<&str>,
+        restart: bool,
+        sink: &mut dyn DirEntrySink,
+    ) -> VfsResult<()> {
         log::trace!(
-            "Get directory enumeration: ID {}, path {:?}, search {:?}",
+            "Get directory enumeration: ID {}, search {:?}",
             enumeration_id,
-            callback_data.FilePathName.to_string(),
-            Option::<PCWSTR>::from(search_expr).map(|p| p.to_string()),
+            search_expr,
         );
-        match self
-            .state
-            .lock()
-            .unwrap()
-            .dir_enums
-            .get_mut(&enumeration_id)
-        {
+        match self.dir_enums.get(enumeration_id) {
             Some(dir_enum) => {
-                dir_enum.get_dir_enum(callback_data, search_expr, dir_entry_buffer_handle);
-                S_OK
+                dir_enum
+                    .lock()
+                    .unwrap()
+                    .get_dir_enum(search_expr, restart, sink);
+                Ok(())
             }
-            None => E_INVALIDARG,
+            None => Err(VfsError::InvalidArgument),
         }
     }
 
-    unsafe fn get_placeholder_info(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
-    ) -> windows::core::HRESULT {
-        let state = self.state.lock().unwrap();
-        let result = (|| {
-            let path = state
-                .fs_helper
-                .get_req_path(callback_data)
-                .context("invalid path specified")?;
-            log::trace!("Get placeholder info: {:?}", path);
-            if path != "Hello.txt" {
-                return anyhow::Ok(ERROR_FILE_NOT_FOUND.to_hresult());
-            }
-
-            state
-                .fs_helper
-                .write_placeholder_info(callback_data, Some(FILE_CONTENTS.len() as i64))
-                .context("write placeholder info")?;
-
-            anyhow::Ok(S_OK)
-        })();
-        match result {
-            Ok(hresult) => hresult,
-            Err(err) => {
-                log::error!("Error in get_placeholder_info: {:#}", err);
-                err.downcast::<windows::core::Error>()
-                    .map(Into::into)
-                    .unwrap_or(E_FAIL)
-            }
+    fn get_entry_info(&self, path: &str) -> VfsResult<EntryInfo> {
+        if path != "Hello.txt" {
+            return Err(VfsError::NotFound);
         }
+        Ok(EntryInfo::file(FILE_CONTENTS.len() as u64))
     }
 
-    unsafe fn get_file_data(
-        self: &Arc<Self>,
-        callback_data: &PRJ_CALLBACK_DATA,
+    fn read_file(
+        &self,
+        path: &str,
         byte_offset: u64,
         length: u32,
-    ) -> windows::core::HRESULT {
-        let state = self.state.lock().unwrap();
-        let result = (|| {
-            let path = state
-                .fs_helper
-                .get_req_path(callback_data)
-                .context("invalid path specified")?;
-            log::trace!(
-                "Get file data: {:?}; offset {}, len {}",
-                path,
-                byte_offset,
-                length,
-            );
-            if path != "Hello.txt" {
-                return anyhow::Ok(ERROR_FILE_NOT_FOUND.to_hresult());
-            }
-
-            // The provider is allowed to provide more bytes than expected,
-            // since the data provided will be written to the underlying
-            // storage device.
-            // Here, we simply provide all of the data to the backend,
-            // eliminating the need to compute positions and buffer sizes.
-            // This also helps us avoid some unnecessary complications such
-            // as buffer alignments.
-            // For more details, see PrjWriteFileData's documentation.
-            assert!(byte_offset + length as u64 <= FILE_CONTENTS.len() as u64);
-            let length = FILE_CONTENTS.len();
-            let mut buffer = state
-                .fs_helper
-                .alloc_aligned_buffer(length)
-                .context("allocate buffer")?;
-            buffer.copy_from_slice(FILE_CONTENTS.as_bytes());
-            state
-                .fs_helper
-                .write_file_data(callback_data, &buffer, 0)
-                .context("write file data")?;
-
-            anyhow::Ok(S_OK)
-        })();
-        match result {
-            Ok(hresult) => hresult,
-            Err(err) => {
-                log::error!("Error in get_file_data: {:#}", err);
-                err.downcast::<windows::core::Error>()
-                    .map(Into::into)
-                    .unwrap_or(E_FAIL)
-            }
+        sink: &mut dyn FileDataSink,
+    ) -> VfsResult<()> {
+        if path != "Hello.txt" {
+            return Err(VfsError::NotFound);
         }
+        sink.write(range(FILE_CONTENTS.as_bytes(), byte_offset, length))?;
+        Ok(())
     }
 
-    unsafe fn notify(
-        self: &Arc<Self>,
-        _callback_data: &PRJ_CALLBACK_DATA,
-        _is_dir: bool,
-        _kind: NotificationKind,
-        _dest_filename: PCWSTR,
-        _params: *mut PRJ_NOTIFICATION_PARAMETERS,
-    ) -> windows::core::HRESULT {
+    fn notify(&self, _notification: &Notification) -> VfsResult<()> {
         unreachable!()
     }
 }
@@ -0,0 +1,405 @@
+//! Registry sources, which supply the keys and values shown by the frontends.
+//!
+//! Paths passed to a source are relative to its root, with components
+//! separated by backslashes; the root itself is the empty path. For the live
+//! registry and `.reg` files, the top level keys are the predefined keys
+//! (`HKEY_LOCAL_MACHINE`, etc.), while hive files start at their root key.
+
+use std::{borrow::Cow, cmp::Ordering, fmt::Write, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+
+use crate::reg_name;
+
+pub const REG_NONE: u32 = 0;
+pub const REG_SZ: u32 = 1;
+pub const REG_EXPAND_SZ: u32 = 2;
+pub const REG_BINARY: u32 = 3;
+pub const REG_DWORD: u32 = 4;
+pub const REG_DWORD_BIG_ENDIAN: u32 = 5;
+pub const REG_LINK: u32 = 6;
+pub const REG_MULTI_SZ: u32 = 7;
+pub const REG_RESOURCE_LIST: u32 = 8;
+pub const REG_FULL_RESOURCE_DESCRIPTOR: u32 = 9;
+pub const REG_RESOURCE_REQUIREMENTS_LIST: u32 = 10;
+pub const REG_QWORD: u32 = 11;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub vtype: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// The data of a value, interpreted according to its type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Data {
+    /// `REG_SZ`, `REG_EXPAND_SZ` and `REG_LINK`
+    String(String),
+    /// `REG_MULTI_SZ`
+    MultiString(Vec<String>),
+    /// `REG_DWORD`, `REG_DWORD_BIG_ENDIAN` and `REG_QWORD`
+    Number(u64),
+    /// Anything else, including malformed data of the types above
+    Binary(Vec<u8>),
+}
+
+/// The contents of a key: `(name, None)` for subkeys, and `(name, Some(size))`
+/// for values.
+pub type KeyEntries = Vec<(String, Option<u32>)>;
+
+/// Orders the entries of a key, given as their names and whether they are
+/// values: by name, as the registry orders names, with a subkey before a
+/// value of the same name. This is also the order ProjFS wants them in.
+pub fn entry_cmp(a: (&str, bool), b: (&str, bool)) -> Ordering {
+    reg_name::cmp(a.0, b.0).then(a.1.cmp(&b.1))
+}
+
+/// Sorts the entries of a key into the order of `entry_cmp`, and passes
+/// those after `after` to `f` until it returns `false`, as
+/// `RegSource::visit_key` does.
+pub fn visit_sorted(
+    mut entries: KeyEntries,
+    after: Option<(&str, bool)>,
+    f: &mut dyn FnMut(String, Option<u32>) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    entries.sort_by_cached_key(|(name, size)| (reg_name::sort_key(name), size.is_some()));
+    let is_after = |(name, size): &(String, Option<u32>)| {
+        after.is_none_or(|after| entry_cmp((name, size.is_some()), after).is_gt())
+    };
+    for (name, size) in entries.into_iter().filter(is_after) {
+        if !f(name, size)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A value of a key, as listed without its data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueEntry {
+    pub name: String,
+    pub vtype: u32,
+    pub size: u32,
+}
+
+pub trait RegSource: Send + Sync {
+    /// Lists the subkeys and values of a key, or returns `None` if the key
+    /// does not exist.
+    fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>>;
+
+    /// Passes the entries of a key to `f` in the order of `entry_cmp`, as
+    /// `enum_key` lists them, until `f` returns `false`, and returns whether
+    /// the key exists. Only entries after `after` are passed, so that a
+    /// listing can carry on where it stopped. Sources which keep names in
+    /// order should do so without listing the whole key first, and say so
+    /// with `streams_keys`.
+    fn visit_key(
+        &self,
+        path: &str,
+        after: Option<(&str, bool)>,
+        f: &mut dyn FnMut(String, Option<u32>) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        match self.enum_key(path)? {
+            Some(entries) => {
+                visit_sorted(entries, after, f)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Whether `visit_key` passes the entries of a key without listing the
+    /// whole key first, so that a listing can read a key a page at a time.
+    /// Otherwise, a listing reads the whole key once.
+    fn streams_keys(&self) -> bool {
+        false
+    }
+
+    /// Lists the values of a key with their types and sizes, or returns
+    /// `None` if the key does not exist. Sources which can tell without
+    /// reading the data should do so.
+    fn enum_values(&self, path: &str) -> anyhow::Result<Option<Vec<ValueEntry>>> {
+        let entries = match self.enum_key(path)? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+        let mut values = Vec::new();
+        for (name, size) in entries {
+            let value_path = match (path, size) {
+                (_, None) => continue,
+                ("", Some(_)) => name.clone(),
+                (path, Some(_)) => format!("{}\\{}", path, name),
+            };
+            if let Some(value) = self.read_value(&value_path)? {
+                values.push(ValueEntry {
+                    name,
+                    vtype: value.vtype,
+                    size: value.bytes.len().try_into().expect("integer overflow"),
+                });
+            }
+        }
+        Ok(Some(values))
+    }
+
+    fn key_exists(&self, path: &str) -> anyhow::Result<bool>;
+
+    fn read_value(&self, path: &str) -> anyhow::Result<Option<Value>>;
+
+    /// Passes the type and data of a value to `f` piece by piece, in order,
+    /// until `f` returns `false`, and returns whether the value exists. `f`
+    /// is called at least once, with no data for an empty value. Sources
+    /// which can do so without copying the whole value should.
+    fn visit_value(
+        &self,
+        path: &str,
+        f: &mut dyn FnMut(u32, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        match self.read_value(path)? {
+            Some(value) => {
+                f(value.vtype, &value.bytes)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Checks whether a value exists. Sources which can tell without reading
+    /// the data should do so.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn value_exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(self.read_value(path)?.is_some())
+    }
+
+    /// Returns the size of a value's data, or `None` if the value does not
+    /// exist. Sources which can tell without reading the data should do so.
+    fn value_size(&self, path: &str) -> anyhow::Result<Option<u32>> {
+        Ok(self
+            .read_value(path)?
+            .map(|value| value.bytes.len().try_into().expect("integer overflow")))
+    }
+
+    /// Returns when a key was last changed, as a `FILETIME`, if the source
+    /// keeps track of it.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn key_last_write(&self, _path: &str) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+/// Describes where the registry tree comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceSpec {
+    /// The registry of the running system.
+    Live,
+    /// An offline hive file, such as `SOFTWARE` or `NTUSER.DAT`.
+    Hive(PathBuf),
+    /// A registry editor (`.reg`) file.
+    RegFile(PathBuf),
+}
+
+impl SourceSpec {
+    pub fn open(&self) -> anyhow::Result<Arc<dyn RegSource>> {
+        match self {
+            #[cfg(windows)]
+            SourceSpec::Live => Ok(Arc::new(crate::reg_ops::LiveSource)),
+            #[cfg(not(windows))]
+            SourceSpec::Live => anyhow::bail!("the live registry is only available on Windows"),
+            SourceSpec::Hive(path) => Ok(Arc::new(
+                crate::hive::HiveSource::open(path)
+                    .with_context(|| format!("load hive {}", path.display()))?,
+            )),
+            SourceSpec::RegFile(path) => Ok(Arc::new(
+                crate::reg_file::load(path)
+                    .with_context(|| format!("load .reg file {}", path.display()))?,
+            )),
+        }
+    }
+}
+
+/// Splits the path of a value into the path of its key and its name.
+pub fn split_value_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('\\').unwrap_or(("", path))
+}
+
+/// Makes the path of an entry from the path of its key and its name.
+pub fn join_path(key: &str, name: &str) -> String {
+    if key.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}\\{}", key, name)
+    }
+}
+
+impl Value {
+    pub fn decode(&self) -> Data {
+        let bytes = self.bytes.as_slice();
+        let data = match self.vtype {
+            REG_SZ | REG_EXPAND_SZ | REG_LINK => decode_utf16(bytes).map(|mut s| {
+                // The terminator is optional, but only one is removed.
+                if s.ends_with('\0') {
+                    s.pop();
+                }
+                Data::String(s)
+            }),
+            REG_MULTI_SZ => decode_utf16(bytes).map(|s| {
+                let s = s.strip_suffix('\0').unwrap_or(&s);
+                let s = s.strip_suffix('\0').unwrap_or(s);
+                Data::MultiString(if s.is_empty() {
+                    Vec::new()
+                } else {
+                    s.split('\0').map(String::from).collect()
+                })
+            }),
+            REG_DWORD => bytes
+                .try_into()
+                .ok()
+                .map(|b| Data::Number(u32::from_le_bytes(b) as u64)),
+            REG_DWORD_BIG_ENDIAN => bytes
+                .try_into()
+                .ok()
+                .map(|b| Data::Number(u32::from_be_bytes(b) as u64)),
+            REG_QWORD => bytes
+                .try_into()
+                .ok()
+                .map(|b| Data::Number(u64::from_le_bytes(b))),
+            _ => None,
+        };
+        data.unwrap_or_else(|| Data::Binary(self.bytes.clone()))
+    }
+
+    /// Encodes data as a value of the given type, or returns `None` if the
+    /// data does not fit the type. Binary data fits any type.
+    pub fn encode(vtype: u32, data: &Data) -> Option<Value> {
+        let bytes = match (vtype, data) {
+            (_, Data::Binary(bytes)) => bytes.clone(),
+            (REG_SZ | REG_EXPAND_SZ, Data::String(s)) => encode_utf16(s.chars().chain(['\0'])),
+            (REG_LINK, Data::String(s)) => encode_utf16(s.chars()),
+            (REG_MULTI_SZ, Data::MultiString(list)) => encode_utf16(
+                list.iter()
+                    .flat_map(|s| s.chars().chain(['\0']))
+                    .chain(['\0']),
+            ),
+            (REG_DWORD, &Data::Number(n)) => u32::try_from(n).ok()?.to_le_bytes().to_vec(),
+            (REG_DWORD_BIG_ENDIAN, &Data::Number(n)) => {
+                u32::try_from(n).ok()?.to_be_bytes().to_vec()
+            }
+            (REG_QWORD, &Data::Number(n)) => n.to_le_bytes().to_vec(),
+            _ => return None,
+        };
+        Some(Value { vtype, bytes })
+    }
+}
+
+impl Data {
+    /// Formats the data for reading: strings as they are, one string per
+    /// line for multi-strings, numbers in decimal, and binary data as a hex
+    /// dump.
+    pub fn to_text(&self) -> String {
+        match self {
+            Data::String(s) => format!("{}\n", s),
+            Data::MultiString(list) => list.iter().map(|s| format!("{}\n", s)).collect(),
+            Data::Number(n) => format!("{}\n", n),
+            Data::Binary(bytes) => hex_dump(bytes),
+        }
+    }
+}
+
+/// Formats bytes as lines of offset, hex and printable characters.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for (i, chunk) in bytes.chunks(HEX_LINE_BYTES).enumerate() {
+        hex_line(&mut s, (i * HEX_LINE_BYTES) as u64, chunk);
+    }
+    s
+}
+
+/// The number of bytes shown on each line of a hex dump.
+pub const HEX_LINE_BYTES: usize = 16;
+
+/// Returns the length of a hex dump line showing `len` bytes.
+pub fn hex_line_len(len: usize) -> usize {
+    // The offset, the bytes in hex padded to a full line, and the bytes as
+    // characters between bars.
+    9 + 3 * HEX_LINE_BYTES + 3 + len + 2
+}
+
+/// Appends the line of a hex dump showing up to 16 bytes at `offset`.
+pub fn hex_line(s: &mut String, offset: u64, chunk: &[u8]) {
+    write!(s, "{:08x} ", offset).unwrap();
+    for b in chunk {
+        write!(s, " {:02x}", b).unwrap();
+    }
+    s.push_str(&"   ".repeat(HEX_LINE_BYTES - chunk.len()));
+    s.push_str("  |");
+    s.extend(chunk.iter().map(|&b| {
+        if b.is_ascii_graphic() || b == b' ' {
+            b as char
+        } else {
+            '.'
+        }
+    }));
+    s.push_str("|\n");
+}
+
+fn decode_utf16(bytes: &[u8]) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&wide).ok()
+}
+
+fn encode_utf16(chars: impl Iterator<Item = char>) -> Vec<u8> {
+    let mut buf = [0; 2];
+    chars
+        .flat_map(|c| {
+            c.encode_utf16(&mut buf)
+                .iter()
+                .flat_map(|unit| unit.to_le_bytes())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+pub fn type_name(vtype: u32) -> Cow<'static, str> {
+    match vtype {
+        REG_NONE => "REG_NONE".into(),
+        REG_SZ => "REG_SZ".into(),
+        REG_EXPAND_SZ => "REG_EXPAND_SZ".into(),
+        REG_BINARY => "REG_BINARY".into(),
+        REG_DWORD => "REG_DWORD".into(),
+        REG_DWORD_BIG_ENDIAN => "REG_DWORD_BIG_ENDIAN".into(),
+        REG_LINK => "REG_LINK".into(),
+        REG_MULTI_SZ => "REG_MULTI_SZ".into(),
+        REG_RESOURCE_LIST => "REG_RESOURCE_LIST".into(),
+        REG_FULL_RESOURCE_DESCRIPTOR => "REG_FULL_RESOURCE_DESCRIPTOR".into(),
+        REG_RESOURCE_REQUIREMENTS_LIST => "REG_RESOURCE_REQUIREMENTS_LIST".into(),
+        REG_QWORD => "REG_QWORD".into(),
+        other => format!("0x{:x}", other).into(),
+    }
+}
+
+/// Parses a type name as returned by `type_name`, or a type number.
+pub fn type_from_name(name: &str) -> Option<u32> {
+    if let Some(hex) = name.strip_prefix("0x") {
+        return u32::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(vtype) = name.parse() {
+        return Some(vtype);
+    }
+    (REG_NONE..=REG_QWORD).find(|&vtype| type_name(vtype).eq_ignore_ascii_case(name))
+}
+
+/// Interprets the contents of a file in a projection as a registry value.
+/// Files carry no type information, so the type of the value being replaced
+/// is kept; values that did not exist before are treated as `REG_BINARY`.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn value_from_file(old_value: Option<&Value>, bytes: Vec<u8>) -> Value {
+    Value {
+        vtype: old_value.map(|value| value.vtype).unwrap_or(REG_BINARY),
+        bytes,
+    }
+}
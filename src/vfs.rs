@@ -0,0 +1,321 @@
+//! A platform-neutral interface for virtual file system backends.
+//!
+//! Backends only deal with paths, entry descriptions and byte buffers;
+//! frontends such as the ProjFS adapter in `projfs` translate native requests
+//! to and from this interface.
+
+use uuid::Uuid;
+
+use crate::commands::CancelToken;
+
+pub trait VirtualFs: Send + Sync {
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn get_optional_features() -> OptionalFeatures
+    where
+        Self: Sized;
+
+    fn start_dir_enum(&self, path: &str, enumeration_id: Uuid) -> VfsResult<()>;
+
+    fn end_dir_enum(&self, enumeration_id: Uuid) -> VfsResult<()>;
+
+    /// Fills `sink` with the next entries of an enumeration, stopping when
+    /// the sink is full. If `restart` is set, the enumeration starts over
+    /// from its first entry.
+    fn get_dir_enum(
+        &self,
+        enumeration_id: Uuid,
+        search_expr: Option<&str>,
+        restart: bool,
+        sink: &mut dyn DirEntrySink,
+    ) -> VfsResult<()>;
+
+    fn get_entry_info(&self, path: &str) -> VfsResult<EntryInfo>;
+
+    /// Checks whether an entry exists, without the client making a
+    /// placeholder for it. Only called for backends with `QUERY_FILE_NAME`.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn entry_exists(&self, path: &str) -> VfsResult<bool> {
+        match self.get_entry_info(path) {
+            Ok(_) => Ok(true),
+            Err(VfsError::NotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Identifies the current version of an entry, which is recorded in its
+    /// placeholder. The default identifies nothing, so that placeholders are
+    /// never found stale.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn get_version_info(&self, _path: &str) -> VfsResult<VersionInfo> {
+        Ok(VersionInfo::default())
+    }
+
+    /// Streams the contents of a file into `sink`, starting at
+    /// `byte_offset`. At least `length` bytes should be written unless the
+    /// end of the file is reached; writing more is allowed.
+    fn read_file(
+        &self,
+        path: &str,
+        byte_offset: u64,
+        length: u32,
+        sink: &mut dyn FileDataSink,
+    ) -> VfsResult<()>;
+
+    /// As `get_entry_info`, for a request which the client may cancel while
+    /// it is served. Only called for backends with `CANCEL_COMMAND`.
+    fn get_entry_info_cancellable(&self, path: &str, cancel: &CancelToken) -> VfsResult<EntryInfo> {
+        cancel.check()?;
+        self.get_entry_info(path)
+    }
+
+    /// Describes an entry together with its version, which is what a
+    /// placeholder is made of. The client may cancel the request while it is
+    /// served.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn get_placeholder_info(
+        &self,
+        path: &str,
+        cancel: &CancelToken,
+    ) -> VfsResult<(EntryInfo, VersionInfo)> {
+        let info = self.get_entry_info_cancellable(path, cancel)?;
+        let version = self.get_version_info(path)?;
+        Ok((info, version))
+    }
+
+    /// As `read_file`, for a request which the client may cancel while it is
+    /// served. Only called for backends with `CANCEL_COMMAND`.
+    fn read_file_cancellable(
+        &self,
+        path: &str,
+        byte_offset: u64,
+        length: u32,
+        sink: &mut dyn FileDataSink,
+        cancel: &CancelToken,
+    ) -> VfsResult<()> {
+        cancel.check()?;
+        self.read_file(path, byte_offset, length, sink)
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn notify(&self, notification: &Notification) -> VfsResult<()>;
+
+    /// Called once the frontend has stopped, and no more requests are in
+    /// flight. Releases anything kept across requests, such as enumerations
+    /// the client never ended.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    fn shutdown(&self) {}
+}
+
+/// Receives directory entries on behalf of the client.
+pub trait DirEntrySink {
+    /// Adds an entry. Returns `Ok(false)` if there is no room for the entry,
+    /// or an error if the entry cannot be represented by the client.
+    fn add(&mut self, name: &str, info: &EntryInfo) -> anyhow::Result<bool>;
+}
+
+/// Collects every entry; it never runs out of room.
+impl DirEntrySink for Vec<(String, EntryInfo)> {
+    fn add(&mut self, name: &str, info: &EntryInfo) -> anyhow::Result<bool> {
+        self.push((String::from(name), *info));
+        Ok(true)
+    }
+}
+
+/// Receives file data on behalf of the client, in order.
+pub trait FileDataSink {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Collects all data.
+impl FileDataSink for Vec<u8> {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Returns the part of `data` which a read of `length` bytes at
+/// `byte_offset` asks for, cut short at the end.
+pub fn range(data: &[u8], byte_offset: u64, length: u32) -> &[u8] {
+    let start = (byte_offset as usize).min(data.len());
+    let end = start.saturating_add(length as usize).min(data.len());
+    &data[start..end]
+}
+
+/// Passes on the part of a stream of data which a read of `length` bytes at
+/// `byte_offset` asks for, as `range` does for data held in one piece.
+pub struct RangeSink<'a> {
+    sink: &'a mut dyn FileDataSink,
+    /// The number of bytes still to be skipped before the range.
+    skip: u64,
+    /// The number of bytes still to be passed on.
+    left: u64,
+}
+
+impl<'a> RangeSink<'a> {
+    pub fn new(sink: &'a mut dyn FileDataSink, byte_offset: u64, length: u32) -> RangeSink<'a> {
+        RangeSink {
+            sink,
+            skip: byte_offset,
+            left: length as u64,
+        }
+    }
+
+    /// Checks whether the whole range has been passed on, so that the rest
+    /// of the data is not needed.
+    pub fn is_full(&self) -> bool {
+        self.left == 0
+    }
+
+    /// Skips the next `len` bytes if all of them come before the range,
+    /// returning whether they did, so that they need not be made at all.
+    pub fn skip(&mut self, len: u64) -> bool {
+        let before = len <= self.skip;
+        if before {
+            self.skip -= len;
+        }
+        before
+    }
+}
+
+impl FileDataSink for RangeSink<'_> {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let skipped = self.skip.min(data.len() as u64);
+        self.skip -= skipped;
+        let data = &data[skipped as usize..];
+        let len = self.left.min(data.len() as u64);
+        self.left -= len;
+        if len != 0 {
+            self.sink.write(&data[..len as usize])?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads part of a file into memory, for frontends which send it in one
+/// piece anyway.
+pub fn read_to_vec(
+    fs: &dyn VirtualFs,
+    path: &str,
+    byte_offset: u64,
+    length: u32,
+) -> VfsResult<Vec<u8>> {
+    let mut data = Vec::new();
+    fs.read_file(path, byte_offset, length, &mut data)?;
+    Ok(data)
+}
+
+/// Lists all entries of a directory in one go, for frontends which do not
+/// enumerate directories incrementally.
+pub fn list_dir(fs: &dyn VirtualFs, path: &str) -> VfsResult<Vec<(String, EntryInfo)>> {
+    let enumeration_id = Uuid::new_v4();
+    fs.start_dir_enum(path, enumeration_id)?;
+    let mut entries = Vec::new();
+    let result = fs.get_dir_enum(enumeration_id, None, true, &mut entries);
+    fs.end_dir_enum(enumeration_id)?;
+    result.map(|()| entries)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// What a placeholder was made from: the provider, and a version of the
+/// entry's contents which changes whenever they do. ProjFS keeps up to 128
+/// bytes of each ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct VersionInfo {
+    pub provider_id: Vec<u8>,
+    pub content_id: Vec<u8>,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Notification<'a> {
+    pub kind: NotificationKind,
+    pub path: &'a str,
+    /// The new path of the file, for renames.
+    pub dest_path: Option<&'a str>,
+    pub is_dir: bool,
+    pub process_id: u32,
+}
+
+#[derive(Debug)]
+pub enum VfsError {
+    NotFound,
+    AccessDenied,
+    /// The file cannot be deleted or moved away.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    CannotDelete,
+    InvalidArgument,
+    /// The client cancelled the request.
+    Cancelled,
+    Other(anyhow::Error),
+}
+
+pub type VfsResult<T> = Result<T, VfsError>;
+
+bitflags::bitflags! {
+    pub struct OptionalFeatures: u32 {
+        const NOTIFY = 1;
+        const QUERY_FILE_NAME = 2;
+        /// Placeholder info and file data are served off the callback
+        /// thread, since the source may be slow, and requests can be
+        /// cancelled.
+        const CANCEL_COMMAND = 4;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotificationKind {
+    FileOpened,
+    NewFileCreated,
+    FileOverwritten,
+    PreDelete,
+    PreRename,
+    PreSetHardlink,
+    FileRenamed,
+    HardlinkCreated,
+    FileHandleClosedNoModification,
+    FileHandleClosedFileModified,
+    FileHandleClosedFileDeleted,
+    FilePreConvertToFull,
+}
+
+impl EntryInfo {
+    pub fn dir() -> EntryInfo {
+        EntryInfo {
+            is_dir: true,
+            size: 0,
+        }
+    }
+
+    pub fn file(size: u64) -> EntryInfo {
+        EntryInfo {
+            is_dir: false,
+            size,
+        }
+    }
+}
+
+impl From<anyhow::Error> for VfsError {
+    fn from(err: anyhow::Error) -> Self {
+        VfsError::Other(err)
+    }
+}
+
+impl std::fmt::Display for VfsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VfsError::NotFound => write!(f, "not found"),
+            VfsError::AccessDenied => write!(f, "access denied"),
+            VfsError::CannotDelete => write!(f, "cannot delete"),
+            VfsError::InvalidArgument => write!(f, "invalid argument"),
+            VfsError::Cancelled => write!(f, "cancelled"),
+            VfsError::Other(err) => write!(f, "{:#}", err),
+        }
+    }
+}
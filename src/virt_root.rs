@@ -0,0 +1,412 @@
+//! Setting up virtualization roots, and making sure an existing root is only
+//! reused for the source it was created for.
+//!
+//! Each root holds its instance ID in `.projfs-id`. The instance ID and the
+//! source of each root are also recorded in `roots.json` in the user's state
+//! directory (`%LOCALAPPDATA%\regfs`, or `~/.local/state/regfs`), so that a
+//! root left behind by another source, or re-created by someone else, is not
+//! silently mixed up with the registry being projected.
+
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    cleanup::{self, FileStates, RunEmpty},
+    source::SourceSpec,
+};
+
+pub const INSTANCE_ID_FILE: &str = ".projfs-id";
+
+/// Turns a directory into a virtualization root. Stubbed out in tests, since
+/// only ProjFS can do this.
+pub trait MarkRoot {
+    fn mark_root(&self, root: &Path, instance_id: Uuid) -> anyhow::Result<()>;
+}
+
+/// What a root was created for.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RootRecord {
+    pub instance_id: String,
+    pub source: String,
+}
+
+/// The records of all roots, keyed by their full paths.
+pub struct RootRecords {
+    path: PathBuf,
+}
+
+impl RootRecords {
+    pub fn open_default() -> anyhow::Result<RootRecords> {
+        let dir = state_dir().context("no directory to keep virtualization root records in")?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("create state directory {}", dir.display()))?;
+        Ok(RootRecords {
+            path: dir.join("roots.json"),
+        })
+    }
+
+    #[cfg(test)]
+    pub fn with_path(path: PathBuf) -> RootRecords {
+        RootRecords { path }
+    }
+
+    fn load(&self) -> anyhow::Result<BTreeMap<String, RootRecord>> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("parse {}", self.path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => Err(err).with_context(|| format!("read {}", self.path.display())),
+        }
+    }
+
+    pub fn get(&self, root: &Path) -> anyhow::Result<Option<RootRecord>> {
+        Ok(self.load()?.remove(&record_key(root)?))
+    }
+
+    /// Records what a root is for, or forgets it.
+    pub fn set(&self, root: &Path, record: Option<RootRecord>) -> anyhow::Result<()> {
+        let mut records = self.load()?;
+        let key = record_key(root)?;
+        match record {
+            Some(record) => records.insert(key, record),
+            None => records.remove(&key),
+        };
+        let json = serde_json::to_vec_pretty(&records)?;
+        std::fs::write(&self.path, json).with_context(|| format!("write {}", self.path.display()))
+    }
+}
+
+fn state_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("regfs"))
+    } else {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state"))
+            })
+            .map(|dir| dir.join("regfs"))
+    }
+}
+
+/// Names a root by its full path; the root must exist.
+fn record_key(root: &Path) -> anyhow::Result<String> {
+    // A root which is gone is found through its parent, so that it can be
+    // forgotten once removed.
+    let resolved = match (root.canonicalize(), root.parent(), root.file_name()) {
+        (Err(err), Some(parent), Some(name)) if err.kind() == std::io::ErrorKind::NotFound => {
+            parent.canonicalize().map(|parent| parent.join(name))
+        }
+        (resolved, ..) => resolved,
+    };
+    let root = resolved.with_context(|| format!("resolve {}", root.display()))?;
+    Ok(root.to_string_lossy().into_owned())
+}
+
+/// Names a source so that it can be compared with the one a root was
+/// created for.
+pub fn source_name(spec: &SourceSpec) -> String {
+    let path = |path: &Path| {
+        path.canonicalize()
+            .unwrap_or_else(|_| path.to_owned())
+            .display()
+            .to_string()
+    };
+    match spec {
+        SourceSpec::Live => String::from("live"),
+        SourceSpec::Hive(file) => format!("hive:{}", path(file)),
+        SourceSpec::RegFile(file) => format!("reg:{}", path(file)),
+    }
+}
+
+/// Reads the instance ID of a root, if the directory exists and is one.
+pub fn read_instance_id(root: &Path) -> anyhow::Result<Option<Uuid>> {
+    match std::fs::read(root.join(INSTANCE_ID_FILE)) {
+        Ok(bytes) => Ok(Some(Uuid::from_slice(&bytes).context("parse instance ID")?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).context("read instance ID file"),
+    }
+}
+
+/// Makes sure that `root` is a virtualization root for `source`, and returns
+/// its instance ID.
+///
+/// A missing or empty directory is made into a new root. An existing root is
+/// deleted and created anew if `reinit` is set, the way `cleanup` removes a
+/// whole root; otherwise it is reused if it is recorded for the same source,
+/// and refused if not.
+pub fn prepare(
+    root: &Path,
+    source: &str,
+    reinit: bool,
+    records: &RootRecords,
+    marker: &dyn MarkRoot,
+    states: &dyn FileStates,
+    projection: &dyn RunEmpty,
+) -> anyhow::Result<Uuid> {
+    if !root.exists() {
+        return create(root, source, records, marker);
+    }
+    let instance_id = match read_instance_id(root)? {
+        Some(instance_id) => instance_id,
+        None => {
+            let is_empty = std::fs::read_dir(root)
+                .with_context(|| format!("read directory {}", root.display()))?
+                .next()
+                .is_none();
+            if !is_empty {
+                bail!(
+                    "{} is not empty, and not a virtualization root; choose another directory",
+                    root.display(),
+                );
+            }
+            return create(root, source, records, marker);
+        }
+    };
+
+    let problem = match records.get(root)? {
+        None => Some(String::from(
+            "it was not created by regfs, or its record was lost",
+        )),
+        Some(record) if record.instance_id != instance_id.to_string() => Some(format!(
+            "its instance ID {} is not the recorded {}",
+            instance_id, record.instance_id,
+        )),
+        Some(record) if record.source != source => Some(format!(
+            "it was created for another source ({})",
+            record.source
+        )),
+        Some(_) => None,
+    };
+    match (problem, reinit) {
+        (None, false) => {
+            log::debug!(
+                "Reusing virtualization root with instance ID {}",
+                instance_id
+            );
+            Ok(instance_id)
+        }
+        (problem, true) => {
+            log::info!(
+                "Re-creating virtualization root {}{}",
+                root.display(),
+                problem.map(|p| format!(": {}", p)).unwrap_or_default(),
+            );
+            cleanup::cleanup(root, true, states, projection, records)
+                .with_context(|| format!("delete virtualization root {}", root.display()))?;
+            create(root, source, records, marker)
+        }
+        (Some(problem), false) => bail!(
+            "cannot reuse virtualization root {}: {}; pass --reinit to delete and re-create it",
+            root.display(),
+            problem,
+        ),
+    }
+}
+
+/// Makes a new root, undoing everything if any step fails.
+fn create(
+    root: &Path,
+    source: &str,
+    records: &RootRecords,
+    marker: &dyn MarkRoot,
+) -> anyhow::Result<Uuid> {
+    let created_dir = match std::fs::create_dir(root) {
+        Ok(()) => true,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => false,
+        Err(err) => return Err(err).context("create virtualization root"),
+    };
+    let instance_id = Uuid::new_v4();
+    let result = (|| {
+        std::fs::write(root.join(INSTANCE_ID_FILE), instance_id.as_bytes())
+            .context("write instance ID file")?;
+        marker
+            .mark_root(root, instance_id)
+            .context("mark directory as placeholder")?;
+        records.set(
+            root,
+            Some(RootRecord {
+                instance_id: instance_id.to_string(),
+                source: String::from(source),
+            }),
+        )
+    })();
+    if let Err(err) = result {
+        let rollback = if created_dir {
+            std::fs::remove_dir_all(root)
+        } else {
+            std::fs::remove_file(root.join(INSTANCE_ID_FILE))
+        };
+        if let Err(rollback_err) = rollback {
+            if rollback_err.kind() != ErrorKind::NotFound {
+                log::warn!("Failed to clean up {}: {}", root.display(), rollback_err);
+            }
+        }
+        return Err(err);
+    }
+    log::debug!("Created new instance ID {}", instance_id);
+    Ok(instance_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{
+        cleanup::FileState,
+        vfs::{EntryInfo, VersionInfo},
+        watch::Invalidate,
+    };
+
+    /// Counts the roots marked, failing if asked to.
+    #[derive(Default)]
+    struct StubMarker {
+        fail: bool,
+        marked: Cell<u32>,
+    }
+
+    impl MarkRoot for StubMarker {
+        fn mark_root(&self, root: &Path, _instance_id: Uuid) -> anyhow::Result<()> {
+            assert!(root.join(INSTANCE_ID_FILE).exists());
+            if self.fail {
+                bail!("stub failure");
+            }
+            self.marked.set(self.marked.get() + 1);
+            Ok(())
+        }
+    }
+
+    /// Deletes entries straight from disk, as a projection serving nothing
+    /// would, and takes everything for local changes.
+    struct StubProjection;
+
+    struct StubPlaceholders<'a>(&'a Path);
+
+    impl FileStates for StubProjection {
+        fn file_state(&self, _path: &Path) -> anyhow::Result<FileState> {
+            Ok(FileState::Full)
+        }
+    }
+
+    impl RunEmpty for StubProjection {
+        fn run_empty(
+            &self,
+            root: &Path,
+            f: &mut dyn FnMut(&dyn Invalidate) -> anyhow::Result<()>,
+        ) -> anyhow::Result<()> {
+            f(&StubPlaceholders(root))
+        }
+    }
+
+    impl Invalidate for StubPlaceholders<'_> {
+        fn update(&self, _: &str, _: &EntryInfo, _: &VersionInfo) -> anyhow::Result<()> {
+            unreachable!()
+        }
+
+        fn delete(&self, path: &str) -> anyhow::Result<()> {
+            let path = self.0.join(path);
+            if path.is_dir() {
+                std::fs::remove_dir(path)?;
+            } else {
+                std::fs::remove_file(path)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn prepare_with_stubs(
+        root: &Path,
+        source: &str,
+        reinit: bool,
+        records: &RootRecords,
+        marker: &dyn MarkRoot,
+    ) -> anyhow::Result<Uuid> {
+        prepare(
+            root,
+            source,
+            reinit,
+            records,
+            marker,
+            &StubProjection,
+            &StubProjection,
+        )
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("regfs-root-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reuse() {
+        let dir = temp_dir();
+        let records = RootRecords::with_path(dir.join("roots.json"));
+        let root = dir.join("root");
+        let marker = StubMarker::default();
+
+        let id = prepare_with_stubs(&root, "hive:a", false, &records, &marker).unwrap();
+        assert_eq!(read_instance_id(&root).unwrap(), Some(id));
+        assert_eq!(records.get(&root).unwrap().unwrap().source, "hive:a");
+        std::fs::write(root.join("Value"), b"hydrated").unwrap();
+
+        // The same source gets the same root, as it was left.
+        assert_eq!(
+            prepare_with_stubs(&root, "hive:a", false, &records, &marker).unwrap(),
+            id
+        );
+        assert!(root.join("Value").exists());
+        assert_eq!(marker.marked.get(), 1);
+
+        // Another source does not, unless asked to start over.
+        let err = prepare_with_stubs(&root, "hive:b", false, &records, &marker).unwrap_err();
+        assert!(format!("{:#}", err).contains("another source (hive:a)"));
+        let new_id = prepare_with_stubs(&root, "hive:b", true, &records, &marker).unwrap();
+        assert_ne!(new_id, id);
+        assert!(!root.join("Value").exists());
+        assert_eq!(records.get(&root).unwrap().unwrap().source, "hive:b");
+
+        // A root re-created behind our back is not trusted either.
+        std::fs::write(root.join(INSTANCE_ID_FILE), Uuid::new_v4().as_bytes()).unwrap();
+        let err = prepare_with_stubs(&root, "hive:b", false, &records, &marker).unwrap_err();
+        assert!(format!("{:#}", err).contains("is not the recorded"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rollback() {
+        let dir = temp_dir();
+        let records = RootRecords::with_path(dir.join("roots.json"));
+        let marker = StubMarker {
+            fail: true,
+            ..StubMarker::default()
+        };
+
+        let root = dir.join("root");
+        assert!(prepare_with_stubs(&root, "live", false, &records, &marker).is_err());
+        assert!(!root.exists());
+
+        // An empty directory is kept, but left as it was.
+        std::fs::create_dir(&root).unwrap();
+        assert!(prepare_with_stubs(&root, "live", false, &records, &marker).is_err());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        assert!(!records.path.exists());
+
+        // Directories which are not roots are never touched.
+        std::fs::write(root.join("file"), b"").unwrap();
+        let err =
+            prepare_with_stubs(&root, "live", true, &records, &StubMarker::default()).unwrap_err();
+        assert!(format!("{:#}", err).contains("not a virtualization root"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
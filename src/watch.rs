@@ -0,0 +1,926 @@
+//! Noticing changes to the source while it is projected, and bringing the
+//! placeholders ProjFS keeps on disk up to date.
+//!
+//! A `ChangeNotifier` tells when the source may have changed: the live
+//! registry reports changes itself, while files are polled. Offline sources
+//! are loaded into memory, so `ReloadableSource` loads them again. `Tracked`
+//! remembers every placeholder the projection has handed out, along with its
+//! version, so that after a change `plan` can tell which of them are stale by
+//! asking the backend again. Only ProjFS keeps placeholders;
+//! the other frontends ask the source on every request, and only need the
+//! source reloaded.
+//!
+//! Placeholders also outlive the projection. Those left by earlier runs are
+//! found with `placeholders_on_disk` before it starts, and brought up to date
+//! with `refresh_on_disk`, which relies on the version info recorded in each
+//! placeholder.
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::Hasher,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::Context;
+use uuid::Uuid;
+
+use crate::{
+    cleanup::{FileState, FileStates},
+    commands::CancelToken,
+    index::Stamp,
+    reg_name,
+    source::{KeyEntries, RegSource, SourceSpec, Value, ValueEntry},
+    vfs::{
+        DirEntrySink, EntryInfo, FileDataSink, Notification, NotificationKind, OptionalFeatures,
+        VersionInfo, VfsError, VfsResult, VirtualFs,
+    },
+    virt_root::INSTANCE_ID_FILE,
+};
+
+/// How long a notifier waits before the watcher checks whether to stop.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many placeholders `Tracked` remembers at most. Placeholders handed out
+/// beyond that are only brought up to date when the projection starts again.
+const MAX_TRACKED: usize = 100_000;
+
+pub trait ChangeNotifier: Send {
+    /// Waits up to `timeout` for the source to change, and returns whether
+    /// it did.
+    fn wait_for_change(&mut self, timeout: Duration) -> anyhow::Result<bool>;
+}
+
+/// Creates the notifier suited to a source.
+pub fn notifier(spec: &SourceSpec) -> anyhow::Result<Box<dyn ChangeNotifier>> {
+    match spec {
+        #[cfg(windows)]
+        SourceSpec::Live => Ok(Box::new(crate::reg_ops::LiveNotifier::new()?)),
+        #[cfg(not(windows))]
+        SourceSpec::Live => anyhow::bail!("the live registry is only available on Windows"),
+        SourceSpec::Hive(file) | SourceSpec::RegFile(file) => {
+            Ok(Box::new(FileNotifier::new(file.clone())))
+        }
+    }
+}
+
+/// Polls a file for changes of its size, modification time or hive sequence
+/// numbers. A change is only reported once the file has stayed the same for
+/// one more poll, so that a file is not reloaded halfway through a write.
+pub struct FileNotifier {
+    file: PathBuf,
+    current: Option<Stamp>,
+    pending: Option<Stamp>,
+}
+
+impl FileNotifier {
+    pub fn new(file: PathBuf) -> FileNotifier {
+        let current = Stamp::of(&file).ok();
+        FileNotifier {
+            file,
+            current,
+            pending: None,
+        }
+    }
+}
+
+impl ChangeNotifier for FileNotifier {
+    fn wait_for_change(&mut self, timeout: Duration) -> anyhow::Result<bool> {
+        std::thread::sleep(timeout);
+        // A file being replaced can be missing for a moment, which is not a
+        // change worth reporting.
+        let stamp = Stamp::of(&self.file).ok();
+        if stamp.is_none() || stamp == self.current {
+            self.pending = None;
+            return Ok(false);
+        }
+        if stamp == self.pending {
+            self.current = stamp;
+            self.pending = None;
+            return Ok(true);
+        }
+        self.pending = stamp;
+        Ok(false)
+    }
+}
+
+/// A source which can be opened again, for offline sources which are read
+/// into memory once.
+pub struct ReloadableSource {
+    spec: SourceSpec,
+    source: RwLock<Arc<dyn RegSource>>,
+}
+
+impl ReloadableSource {
+    pub fn new(spec: SourceSpec, source: Arc<dyn RegSource>) -> ReloadableSource {
+        ReloadableSource {
+            spec,
+            source: RwLock::new(source),
+        }
+    }
+
+    /// Opens the source again. Requests already running keep using the old
+    /// one.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        self.replace(self.spec.open()?);
+        Ok(())
+    }
+
+    fn replace(&self, source: Arc<dyn RegSource>) {
+        *self.source.write().unwrap() = source;
+    }
+
+    fn current(&self) -> Arc<dyn RegSource> {
+        self.source.read().unwrap().clone()
+    }
+}
+
+impl RegSource for ReloadableSource {
+    fn enum_key(&self, path: &str) -> anyhow::Result<Option<KeyEntries>> {
+        self.current().enum_key(path)
+    }
+
+    fn visit_key(
+        &self,
+        path: &str,
+        after: Option<(&str, bool)>,
+        f: &mut dyn FnMut(String, Option<u32>) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        self.current().visit_key(path, after, f)
+    }
+
+    fn streams_keys(&self) -> bool {
+        self.current().streams_keys()
+    }
+
+    fn enum_values(&self, path: &str) -> anyhow::Result<Option<Vec<ValueEntry>>> {
+        self.current().enum_values(path)
+    }
+
+    fn key_exists(&self, path: &str) -> anyhow::Result<bool> {
+        self.current().key_exists(path)
+    }
+
+    fn read_value(&self, path: &str) -> anyhow::Result<Option<Value>> {
+        self.current().read_value(path)
+    }
+
+    fn visit_value(
+        &self,
+        path: &str,
+        f: &mut dyn FnMut(u32, &[u8]) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<bool> {
+        self.current().visit_value(path, f)
+    }
+
+    fn value_exists(&self, path: &str) -> anyhow::Result<bool> {
+        self.current().value_exists(path)
+    }
+
+    fn value_size(&self, path: &str) -> anyhow::Result<Option<u32>> {
+        self.current().value_size(path)
+    }
+
+    fn key_last_write(&self, path: &str) -> anyhow::Result<Option<u64>> {
+        self.current().key_last_write(path)
+    }
+}
+
+/// What the projection handed out for a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Served {
+    pub path: String,
+    pub info: EntryInfo,
+    pub version: VersionInfo,
+    /// A hash of the contents of a file, for backends whose version does not
+    /// tell them apart; zero otherwise.
+    pub hash: u64,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Served {
+    /// Records an entry as described by the backend, reading the file to
+    /// hash it if its version does not tell.
+    fn of(
+        fs: &dyn VirtualFs,
+        path: &str,
+        info: EntryInfo,
+        version: VersionInfo,
+        cancel: &CancelToken,
+    ) -> VfsResult<Served> {
+        let hash = if info.is_dir || !version.content_id.is_empty() {
+            0
+        } else {
+            let mut sink = HashSink {
+                hasher: DefaultHasher::new(),
+                remaining: info.size,
+            };
+            // A read is at most 4 GiB long.
+            while sink.remaining > 0 {
+                let remaining = sink.remaining;
+                let length = u32::try_from(remaining).unwrap_or(u32::MAX);
+                fs.read_file_cancellable(path, info.size - remaining, length, &mut sink, cancel)?;
+                if sink.remaining == remaining {
+                    // The file ended early.
+                    break;
+                }
+            }
+            sink.hasher.finish()
+        };
+        Ok(Served {
+            path: String::from(path),
+            info,
+            version,
+            hash,
+        })
+    }
+}
+
+/// Hashes the data of a file as it is read, up to its size.
+struct HashSink {
+    hasher: DefaultHasher,
+    remaining: u64,
+}
+
+impl FileDataSink for HashSink {
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let len = data.len().min(self.remaining as usize);
+        self.hasher.write(&data[..len]);
+        self.remaining -= len as u64;
+        Ok(())
+    }
+}
+
+/// A change to make to a placeholder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub enum Action {
+    /// The file has other contents now; the placeholder is replaced, and
+    /// hydrated again when read.
+    Update(Served),
+    /// The entry is gone, or changed between a file and a directory.
+    Delete(String),
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Action {
+    pub fn path(&self) -> &str {
+        match self {
+            Action::Update(served) => &served.path,
+            Action::Delete(path) => path,
+        }
+    }
+}
+
+/// Compares what was served with what the backend serves now. Entries come
+/// before the directories containing them, so that directories are empty by
+/// the time they are deleted.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn plan<'a>(served: impl IntoIterator<Item = &'a Served>, fs: &dyn VirtualFs) -> Vec<Action> {
+    let mut actions = Vec::new();
+    for old in served {
+        let cancel = CancelToken::new();
+        let current = match fs.get_placeholder_info(&old.path, &cancel) {
+            Ok((info, version)) if info.is_dir == old.info.is_dir => {
+                Served::of(fs, &old.path, info, version, &cancel).map(Some)
+            }
+            Ok(_) | Err(VfsError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        };
+        match current {
+            Ok(Some(new)) if new == *old => {}
+            Ok(Some(new)) => actions.push(Action::Update(new)),
+            Ok(None) => actions.push(Action::Delete(old.path.clone())),
+            Err(err) => log::warn!("Failed to check {:?} for changes: {}", old.path, err),
+        }
+    }
+    actions.sort_by_key(|action| Reverse(action.path().matches('\\').count()));
+    actions
+}
+
+/// Changes the placeholders on disk. Stubbed out in tests, since only ProjFS
+/// can do this.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub trait Invalidate {
+    /// Replaces a placeholder, dropping the data it holds, unless it already
+    /// has the content ID of `version`.
+    fn update(&self, path: &str, info: &EntryInfo, version: &VersionInfo) -> anyhow::Result<()>;
+
+    /// Deletes a placeholder, or a tombstone, but nothing holding local
+    /// changes.
+    fn delete(&self, path: &str) -> anyhow::Result<()>;
+
+    /// Deletes an entry whatever it holds, including local changes.
+    fn delete_changed(&self, path: &str) -> anyhow::Result<()> {
+        self.delete(path)
+    }
+}
+
+/// Wraps a backend, and records what it serves for placeholders.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Tracked<B> {
+    inner: Arc<B>,
+    /// Keyed by the folded path.
+    served: Arc<Mutex<HashMap<String, Served>>>,
+}
+
+impl<B> Clone for Tracked<B> {
+    fn clone(&self) -> Self {
+        Tracked {
+            inner: self.inner.clone(),
+            served: self.served.clone(),
+        }
+    }
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl<B: VirtualFs> Tracked<B> {
+    pub fn new(inner: B) -> Tracked<B> {
+        Tracked {
+            inner: Arc::new(inner),
+            served: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn track(&self, served: Served) {
+        let key = reg_name::fold(&served.path);
+        let mut ledger = self.served.lock().unwrap();
+        if ledger.len() < MAX_TRACKED || ledger.contains_key(&key) {
+            ledger.insert(key, served);
+        } else {
+            log::debug!(
+                "Not tracking placeholder {:?}: {} are tracked already",
+                served.path,
+                MAX_TRACKED,
+            );
+        }
+    }
+
+    /// Forgets a path and, for a directory, everything under it, once it is
+    /// no longer a placeholder.
+    fn forget(&self, path: &str, is_dir: bool) {
+        let key = reg_name::fold(path);
+        let mut ledger = self.served.lock().unwrap();
+        ledger.remove(&key);
+        if is_dir {
+            let prefix = format!("{}\\", key);
+            ledger.retain(|path, _| !path.starts_with(&prefix));
+        }
+    }
+
+    /// Updates or deletes every stale placeholder, and returns how many were
+    /// changed. A placeholder which cannot be changed, for example since it
+    /// was modified or deleted locally, is no longer tracked.
+    pub fn refresh(&self, invalidator: &dyn Invalidate) -> usize {
+        // Callbacks made meanwhile need the lock, so it is not held while
+        // asking the backend or ProjFS.
+        let served: Vec<Served> = self.served.lock().unwrap().values().cloned().collect();
+        let mut changed = 0;
+        for action in plan(&served, &*self.inner) {
+            let path = String::from(action.path());
+            let result = match &action {
+                Action::Update(served) => invalidator.update(&path, &served.info, &served.version),
+                Action::Delete(_) => invalidator.delete(&path),
+            };
+            let mut ledger = self.served.lock().unwrap();
+            let key = reg_name::fold(&path);
+            match (action, result) {
+                (Action::Update(served), Ok(())) => {
+                    log::debug!("Updated placeholder {:?}", path);
+                    ledger.insert(key, served);
+                    changed += 1;
+                }
+                (Action::Delete(_), Ok(())) => {
+                    log::debug!("Deleted placeholder {:?}", path);
+                    ledger.remove(&key);
+                    changed += 1;
+                }
+                (_, Err(err)) => {
+                    log::debug!("Left placeholder {:?} as it is: {:#}", path, err);
+                    ledger.remove(&key);
+                }
+            }
+        }
+        changed
+    }
+}
+
+impl<B: VirtualFs> VirtualFs for Tracked<B> {
+    fn get_optional_features() -> OptionalFeatures {
+        B::get_optional_features()
+    }
+
+    fn start_dir_enum(&self, path: &str, enumeration_id: Uuid) -> VfsResult<()> {
+        self.inner.start_dir_enum(path, enumeration_id)
+    }
+
+    fn end_dir_enum(&self, enumeration_id: Uuid) -> VfsResult<()> {
+        self.inner.end_dir_enum(enumeration_id)
+    }
+
+    fn get_dir_enum(
+        &self,
+        enumeration_id: Uuid,
+        search_expr: Option<&str>,
+        restart: bool,
+        sink: &mut dyn DirEntrySink,
+    ) -> VfsResult<()> {
+        self.inner
+            .get_dir_enum(enumeration_id, search_expr, restart, sink)
+    }
+
+    fn get_entry_info(&self, path: &str) -> VfsResult<EntryInfo> {
+        self.get_entry_info_cancellable(path, &CancelToken::new())
+    }
+
+    fn get_entry_info_cancellable(&self, path: &str, cancel: &CancelToken) -> VfsResult<EntryInfo> {
+        self.inner.get_entry_info_cancellable(path, cancel)
+    }
+
+    fn get_version_info(&self, path: &str) -> VfsResult<VersionInfo> {
+        self.inner.get_version_info(path)
+    }
+
+    fn get_placeholder_info(
+        &self,
+        path: &str,
+        cancel: &CancelToken,
+    ) -> VfsResult<(EntryInfo, VersionInfo)> {
+        let (info, version) = self.inner.get_placeholder_info(path, cancel)?;
+        // The placeholder is made either way; it is only not refreshed while
+        // the projection runs.
+        match Served::of(&*self.inner, path, info, version.clone(), cancel) {
+            Ok(served) => self.track(served),
+            Err(err) => log::debug!("Not tracking placeholder {:?}: {}", path, err),
+        }
+        Ok((info, version))
+    }
+
+    fn entry_exists(&self, path: &str) -> VfsResult<bool> {
+        self.inner.entry_exists(path)
+    }
+
+    fn read_file(
+        &self,
+        path: &str,
+        byte_offset: u64,
+        length: u32,
+        sink: &mut dyn FileDataSink,
+    ) -> VfsResult<()> {
+        self.inner.read_file(path, byte_offset, length, sink)
+    }
+
+    fn read_file_cancellable(
+        &self,
+        path: &str,
+        byte_offset: u64,
+        length: u32,
+        sink: &mut dyn FileDataSink,
+        cancel: &CancelToken,
+    ) -> VfsResult<()> {
+        self.inner
+            .read_file_cancellable(path, byte_offset, length, sink, cancel)
+    }
+
+    fn notify(&self, notification: &Notification) -> VfsResult<()> {
+        self.inner.notify(notification)?;
+        // Files written to are full files rather than placeholders from now
+        // on, and entries deleted or renamed are gone.
+        if matches!(
+            notification.kind,
+            NotificationKind::FileOverwritten
+                | NotificationKind::FileHandleClosedFileModified
+                | NotificationKind::FileHandleClosedFileDeleted
+                | NotificationKind::FileRenamed
+        ) {
+            self.forget(notification.path, notification.is_dir);
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.inner.shutdown()
+    }
+}
+
+/// A placeholder found in a virtualization root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct OnDisk {
+    /// The path relative to the root, as ProjFS names it.
+    pub path: String,
+    pub is_dir: bool,
+}
+
+/// Lists the placeholders in a virtualization root, entries before the
+/// directories containing them. This must be done before the projection
+/// starts, since listing a running projection also lists what is not on disk.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn placeholders_on_disk(root: &Path, states: &dyn FileStates) -> anyhow::Result<Vec<OnDisk>> {
+    fn walk(
+        dir: &Path,
+        prefix: &str,
+        states: &dyn FileStates,
+        found: &mut Vec<OnDisk>,
+    ) -> anyhow::Result<()> {
+        let entries = std::fs::read_dir(dir).with_context(|| format!("read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.with_context(|| format!("read {}", dir.display()))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if prefix.is_empty() && name == INSTANCE_ID_FILE {
+                continue;
+            }
+            let path = entry.path();
+            let relative = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}\\{}", prefix, name)
+            };
+            let is_dir = entry.file_type()?.is_dir();
+            let state = states
+                .file_state(&path)
+                .with_context(|| format!("get state of {}", path.display()))?;
+            // A directory created or changed locally can still hold
+            // placeholders.
+            if is_dir && state != FileState::Tombstone {
+                walk(&path, &relative, states, found)?;
+            }
+            if matches!(
+                state,
+                FileState::Placeholder
+                    | FileState::HydratedPlaceholder
+                    | FileState::DirtyPlaceholder
+            ) {
+                found.push(OnDisk {
+                    path: relative,
+                    is_dir,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = Vec::new();
+    walk(root, "", states, &mut found)?;
+    Ok(found)
+}
+
+/// What `refresh_on_disk` did.
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(not(windows), allow(dead_code))]
+pub struct Refreshed {
+    /// Placeholders updated to the current version, if they were not on it
+    /// already.
+    pub updated: usize,
+    /// Placeholders of entries which are gone.
+    pub deleted: usize,
+    /// Placeholders which could not be changed, mostly since they were
+    /// changed locally meanwhile.
+    pub failed: usize,
+}
+
+/// Brings placeholders left by earlier runs up to date with the backend. The
+/// projection must be running. ProjFS compares the content IDs, so only
+/// placeholders made from other data are replaced.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn refresh_on_disk(
+    placeholders: &[OnDisk],
+    fs: &dyn VirtualFs,
+    invalidator: &dyn Invalidate,
+) -> Refreshed {
+    let mut refreshed = Refreshed::default();
+    for placeholder in placeholders {
+        let path = placeholder.path.as_str();
+        let current = match fs.get_placeholder_info(path, &CancelToken::new()) {
+            Ok((info, version)) if info.is_dir == placeholder.is_dir => Ok(Some((info, version))),
+            Ok(_) | Err(VfsError::NotFound) => Ok(None),
+            Err(err) => Err(err),
+        };
+        let result = match current {
+            Ok(Some((info, version))) => invalidator
+                .update(path, &info, &version)
+                .map(|()| &mut refreshed.updated),
+            Ok(None) => invalidator.delete(path).map(|()| &mut refreshed.deleted),
+            Err(err) => Err(anyhow::anyhow!("check entry: {}", err)),
+        };
+        match result {
+            Ok(count) => *count += 1,
+            Err(err) => {
+                log::debug!("Left placeholder {:?} as it is: {:#}", path, err);
+                refreshed.failed += 1;
+            }
+        }
+    }
+    refreshed
+}
+
+/// Runs a callback on a thread of its own whenever a notifier reports a
+/// change, until stopped.
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Watcher {
+    pub fn spawn(
+        mut notifier: Box<dyn ChangeNotifier>,
+        mut on_change: impl FnMut() + Send + 'static,
+    ) -> anyhow::Result<Watcher> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name(String::from("watcher"))
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    match notifier.wait_for_change(POLL_INTERVAL) {
+                        Ok(true) => on_change(),
+                        Ok(false) => {}
+                        Err(err) => {
+                            log::error!("Stopped watching the source for changes: {:#}", err);
+                            break;
+                        }
+                    }
+                }
+            })
+            .context("start watcher thread")?;
+        Ok(Watcher { stop, thread })
+    }
+
+    /// Stops watching, once the change being handled, if any, is done.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.thread.join().is_err() {
+            log::error!("The watcher thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{
+        mem_source::MemSource,
+        regfs::RegFs,
+        simple_fs::SimpleFs,
+        source::{REG_DWORD, REG_SZ},
+    };
+
+    /// Records the changes asked for, failing for paths containing `Fail`.
+    #[derive(Default)]
+    struct StubInvalidator(RefCell<Vec<String>>);
+
+    impl Invalidate for StubInvalidator {
+        fn update(
+            &self,
+            path: &str,
+            info: &EntryInfo,
+            version: &VersionInfo,
+        ) -> anyhow::Result<()> {
+            anyhow::ensure!(!path.contains("Fail"), "stub failure");
+            self.0.borrow_mut().push(format!(
+                "update {} {} {}",
+                path,
+                info.size,
+                version.content_id.len(),
+            ));
+            Ok(())
+        }
+
+        fn delete(&self, path: &str) -> anyhow::Result<()> {
+            anyhow::ensure!(!path.contains("Fail"), "stub failure");
+            self.0.borrow_mut().push(format!("delete {}", path));
+            Ok(())
+        }
+    }
+
+    fn dword(n: u32) -> Value {
+        Value {
+            vtype: REG_DWORD,
+            bytes: n.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn version(version: u32) -> Arc<dyn RegSource> {
+        let mut source = MemSource::new();
+        source.set_value("HKEY_TEST\\App", "Version", dword(version));
+        source.set_value("HKEY_TEST\\App", "Fail", dword(version));
+        source.set_value("HKEY_TEST\\App", "Same", dword(1));
+        if version == 1 {
+            source.set_value("HKEY_TEST\\Old\\Sub", "Value", dword(1));
+            source.create_key("HKEY_TEST\\Kind");
+        } else {
+            source.set_value(
+                "HKEY_TEST",
+                "Kind",
+                Value {
+                    vtype: REG_SZ,
+                    bytes: vec![b'x', 0, 0, 0],
+                },
+            );
+        }
+        Arc::new(source)
+    }
+
+    #[test]
+    fn refresh() {
+        let source = Arc::new(ReloadableSource::new(SourceSpec::Live, version(1)));
+        let fs = Tracked::new(RegFs::new(PathBuf::new(), source.clone()));
+        for path in [
+            "HKEY_TEST\\App",
+            "HKEY_TEST\\App\\Version",
+            "HKEY_TEST\\App\\Fail",
+            "HKEY_TEST\\App\\Same",
+            "HKEY_TEST\\Old",
+            "HKEY_TEST\\Old\\Sub",
+            "HKEY_TEST\\Old\\Sub\\Value",
+            "HKEY_TEST\\Kind",
+        ] {
+            fs.get_placeholder_info(path, &CancelToken::new()).unwrap();
+        }
+        assert_eq!(fs.refresh(&StubInvalidator::default()), 0);
+
+        source.replace(version(2));
+        let served: Vec<_> = fs.served.lock().unwrap().values().cloned().collect();
+        let actions = plan(&served, &*fs.inner);
+        let paths: Vec<_> = actions.iter().map(Action::path).collect();
+        let position = |path| paths.iter().position(|&p| p == path).unwrap();
+        assert_eq!(position("HKEY_TEST\\Old\\Sub\\Value"), 0);
+        assert!(position("HKEY_TEST\\Old\\Sub") < position("HKEY_TEST\\Old"));
+        assert_eq!(actions.len(), 6);
+
+        let invalidator = StubInvalidator::default();
+        assert_eq!(fs.refresh(&invalidator), 5);
+        let mut log = invalidator.0.into_inner();
+        log.sort();
+        assert_eq!(
+            log,
+            [
+                "delete HKEY_TEST\\Kind",
+                "delete HKEY_TEST\\Old",
+                "delete HKEY_TEST\\Old\\Sub",
+                "delete HKEY_TEST\\Old\\Sub\\Value",
+                "update HKEY_TEST\\App\\Version 4 22",
+            ],
+        );
+
+        // Only what is left on disk is tracked, and the new data is what
+        // the next change is compared with.
+        let mut tracked: Vec<_> = fs.served.lock().unwrap().keys().cloned().collect();
+        tracked.sort();
+        assert_eq!(
+            tracked,
+            [
+                "HKEY_TEST\\APP",
+                "HKEY_TEST\\APP\\SAME",
+                "HKEY_TEST\\APP\\VERSION"
+            ]
+        );
+        assert_eq!(fs.refresh(&StubInvalidator::default()), 0);
+    }
+
+    #[test]
+    fn forgotten() {
+        let fs = Tracked::new(RegFs::new(PathBuf::new(), version(1)));
+        for path in [
+            "HKEY_TEST\\App\\Version",
+            "HKEY_TEST\\App\\Same",
+            "HKEY_TEST\\Old",
+            "HKEY_TEST\\Old\\Sub",
+            "HKEY_TEST\\Old\\Sub\\Value",
+        ] {
+            fs.get_placeholder_info(path, &CancelToken::new()).unwrap();
+        }
+        let notify = |kind, path, is_dir| {
+            fs.notify(&Notification {
+                kind,
+                path,
+                dest_path: None,
+                is_dir,
+                process_id: 0,
+            })
+            .unwrap()
+        };
+        notify(NotificationKind::FileOpened, "HKEY_TEST\\App\\Same", false);
+        notify(
+            NotificationKind::FileHandleClosedFileModified,
+            "HKEY_TEST\\App\\Version",
+            false,
+        );
+        notify(NotificationKind::FileRenamed, "HKEY_TEST\\old", true);
+        let tracked: Vec<_> = fs.served.lock().unwrap().keys().cloned().collect();
+        assert_eq!(tracked, ["HKEY_TEST\\APP\\SAME"]);
+    }
+
+    #[test]
+    fn unversioned() {
+        // Without versions, what a file holds tells whether it changed.
+        let fs = Tracked::new(SimpleFs::new());
+        fs.get_placeholder_info("Hello.txt", &CancelToken::new())
+            .unwrap();
+        let served = fs.served.lock().unwrap()["HELLO.TXT"].clone();
+        assert!(served.version.content_id.is_empty());
+        assert_ne!(served.hash, 0);
+        assert!(plan([&served], &*fs.inner).is_empty());
+        let changed = Served { hash: 1, ..served };
+        assert_eq!(plan([&changed], &*fs.inner).len(), 1);
+    }
+
+    /// Knows the state of some files by name; everything else is a
+    /// placeholder.
+    struct StubStates;
+
+    impl FileStates for StubStates {
+        fn file_state(&self, path: &Path) -> anyhow::Result<FileState> {
+            Ok(match path.file_name().unwrap().to_str().unwrap() {
+                "Local" | "Edited" => FileState::Full,
+                "Tomb" => FileState::Tombstone,
+                "Version" => FileState::HydratedPlaceholder,
+                _ => FileState::Placeholder,
+            })
+        }
+    }
+
+    #[test]
+    fn earlier_runs() {
+        let root = std::env::temp_dir().join(format!("regfs-watch-{}", Uuid::new_v4()));
+        for dir in ["HKEY_TEST/App", "HKEY_TEST/Local", "Tomb"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            INSTANCE_ID_FILE,
+            "HKEY_TEST/App/Version",
+            "HKEY_TEST/App/Gone",
+            "HKEY_TEST/Local/Edited",
+            "HKEY_TEST/Local/Cached",
+        ] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+
+        let on_disk = placeholders_on_disk(&root, &StubStates).unwrap();
+        let paths: Vec<_> = on_disk.iter().map(|p| p.path.as_str()).collect();
+        let position = |path| paths.iter().position(|&p| p == path).unwrap();
+        assert_eq!(paths.len(), 5, "{:?}", paths);
+        assert!(position("HKEY_TEST\\App\\Gone") < position("HKEY_TEST\\App"));
+        assert!(position("HKEY_TEST\\App") < position("HKEY_TEST"));
+        assert!(position("HKEY_TEST\\Local\\Cached") < position("HKEY_TEST"));
+
+        let fs = RegFs::new(PathBuf::new(), version(1));
+        let invalidator = StubInvalidator::default();
+        assert_eq!(
+            refresh_on_disk(&on_disk, &fs, &invalidator),
+            Refreshed {
+                updated: 3,
+                deleted: 2,
+                failed: 0,
+            },
+        );
+        let mut log = invalidator.0.into_inner();
+        log.sort();
+        assert_eq!(
+            log,
+            [
+                "delete HKEY_TEST\\App\\Gone",
+                "delete HKEY_TEST\\Local\\Cached",
+                "update HKEY_TEST 0 0",
+                "update HKEY_TEST\\App 0 0",
+                "update HKEY_TEST\\App\\Version 4 22",
+            ],
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn file_notifier() {
+        let file = std::env::temp_dir().join(format!("regfs-watch-{}.reg", Uuid::new_v4()));
+        std::fs::write(&file, b"one").unwrap();
+        let mut notifier = FileNotifier::new(file.clone());
+        let mut poll = || notifier.wait_for_change(Duration::ZERO).unwrap();
+        assert!(!poll());
+
+        // Reported once the file settles, and only once.
+        std::fs::write(&file, b"three").unwrap();
+        assert!(!poll());
+        assert!(poll());
+        assert!(!poll());
+
+        // A file missing for a while is not reported until it comes back.
+        std::fs::remove_file(&file).unwrap();
+        assert!(!poll());
+        assert!(!poll());
+        std::fs::write(&file, b"eleven").unwrap();
+        assert!(!poll());
+        assert!(poll());
+
+        std::fs::remove_file(file).unwrap();
+    }
+}